@@ -0,0 +1,154 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::error;
+
+use super::request::Request;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    // Combined Log Format (CLF plus referer and user agent), with the duration in ms appended
+    Combined,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    pub target: AccessLogTarget,
+}
+
+// Everything about a request / response pair which ends up in the access log
+pub struct AccessLogEntry {
+    pub remote_ip: String,
+    pub time: DateTime<Utc>,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub user_agent: String,
+    pub referer: String,
+    pub status: u16,
+    pub body_size: usize,
+    pub duration: Duration,
+}
+
+impl AccessLogEntry {
+    // Status, size and duration are filled in once the response has been written
+    pub fn from_request(request: &Request) -> Self {
+        Self {
+            remote_ip: request
+                .client_ip
+                .map_or("-".to_string(), |ip| ip.to_string()),
+            time: Utc::now(),
+            method: request.method.to_string(),
+            uri: request.uri.clone(),
+            version: format!("HTTP/1.{}", request.version),
+            user_agent: request
+                .get_header("user-agent")
+                .cloned()
+                .unwrap_or_default(),
+            referer: request.get_header("referer").cloned().unwrap_or_default(),
+            status: 0,
+            body_size: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn format(&self, format: &AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}",
+                self.remote_ip,
+                self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                self.uri,
+                self.version,
+                self.status,
+                self.body_size,
+                Self::or_dash(&self.referer),
+                Self::or_dash(&self.user_agent),
+                self.duration.as_millis(),
+            ),
+            AccessLogFormat::Json => json!({
+                "remote_ip": self.remote_ip,
+                "time": self.time.to_rfc3339(),
+                "method": self.method,
+                "uri": self.uri,
+                "version": self.version,
+                "status": self.status,
+                "body_size": self.body_size,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "duration_ms": self.duration.as_secs_f64() * 1000.0,
+            })
+            .to_string(),
+        }
+    }
+
+    // CLF uses "-" for missing values, and quotes inside values must be escaped
+    fn or_dash(value: &str) -> String {
+        if value.is_empty() {
+            "-".to_string()
+        } else {
+            value.replace('"', "\\\"")
+        }
+    }
+}
+
+// Cheap to clone handle. Lines are written by a background task so requests never wait on log IO
+#[derive(Clone)]
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    sender: UnboundedSender<String>,
+}
+
+impl AccessLogger {
+    // Must be called from within the tokio runtime
+    pub async fn start(config: &AccessLogConfig) -> std::io::Result<Self> {
+        let mut writer: Box<dyn AsyncWrite + Send + Unpin> = match &config.target {
+            AccessLogTarget::Stdout => Box::new(tokio::io::stdout()),
+            AccessLogTarget::File(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?,
+            ),
+        };
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                let result = async {
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                    writer.flush().await
+                }
+                .await;
+                if let Err(e) = result {
+                    error!("Could not write access log: {}", e);
+                }
+            }
+        });
+
+        Ok(Self {
+            format: config.format.clone(),
+            sender,
+        })
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let _ = self.sender.send(entry.format(&self.format));
+    }
+}
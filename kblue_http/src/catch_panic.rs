@@ -0,0 +1,66 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Resolves to Err with the panic message if polling the future panics, so one bad handler
+// doesn't take the connection task (and the client's response) down with it
+pub struct CatchPanic<F> {
+    // Dropped as soon as it panics, releasing any locks it was holding
+    future: Option<F>,
+}
+
+pub fn catch_panic<F: Future + Unpin>(future: F) -> CatchPanic<F> {
+    CatchPanic {
+        future: Some(future),
+    }
+}
+
+impl<F: Future + Unpin> Future for CatchPanic<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self
+            .future
+            .as_mut()
+            .expect("CatchPanic polled after completion");
+        // The future is never polled again after a panic, so nothing can observe the state it
+        // was left in
+        match catch_unwind(AssertUnwindSafe(|| Pin::new(future).poll(cx))) {
+            Ok(Poll::Ready(output)) => {
+                self.future = None;
+                Poll::Ready(Ok(output))
+            }
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => {
+                self.future = None;
+                Poll::Ready(Err(panic_message(payload)))
+            }
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panics_become_errors() {
+        let result = catch_panic(Box::pin(async { panic!("boom") })).await;
+        assert_eq!(result, Err::<(), _>("boom".to_string()));
+
+        let result = catch_panic(Box::pin(async { 1 })).await;
+        assert_eq!(result, Ok(1));
+    }
+}
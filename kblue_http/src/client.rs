@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "tls")]
+use rustls::pki_types::ServerName;
+#[cfg(feature = "tls")]
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::{BodyStream, HttpMethod, StatusCode, ONE_KB, ONE_MB};
+
+// Minimal async HTTP/1.1 client for outbound calls (webhooks, verification APIs).
+// One request per connection, the body is read until the server closes it
+
+pub type ClientError = Box<dyn Error + Send + Sync>;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a streamed body may go without any of it arriving
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_HEAD_LEN: usize = 64 * ONE_KB;
+
+#[cfg(feature = "tls")]
+static TLS_CONNECTOR: Lazy<TlsConnector> = Lazy::new(|| {
+    let root_store = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+pub struct ClientResponse {
+    pub status_code: u16,
+    // Lower case header names
+    pub headers: HashMap<String, String>,
+    // Every header as received, including repeats such as Set-Cookie
+    pub header_list: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// A response whose body is still arriving, see stream_request
+pub struct StreamingResponse {
+    pub status_code: u16,
+    // Lower case header names
+    pub headers: HashMap<String, String>,
+    // Every header as received, including repeats such as Set-Cookie
+    pub header_list: Vec<(String, String)>,
+    // None if the body is chunked, or runs until the connection closes. For HEAD and 304
+    // responses, the length the upstream says the body would have had
+    pub content_length: Option<usize>,
+    pub body: BodyStream,
+}
+
+impl ClientResponse {
+    pub fn is_success(&self) -> bool {
+        StatusCode::from(self.status_code).is_success()
+    }
+    pub fn get_body_as_string(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+pub async fn post_json(url: &str, body: &serde_json::Value) -> Result<ClientResponse, ClientError> {
+    request(
+        HttpMethod::POST,
+        url,
+        &[("Content-Type", "application/json")],
+        Some(body.to_string().as_bytes()),
+    )
+    .await
+}
+
+pub async fn post_form(url: &str, body: &str) -> Result<ClientResponse, ClientError> {
+    request(
+        HttpMethod::POST,
+        url,
+        &[("Content-Type", "application/x-www-form-urlencoded")],
+        Some(body.as_bytes()),
+    )
+    .await
+}
+
+pub async fn request(
+    method: HttpMethod,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<ClientResponse, ClientError> {
+    tokio::time::timeout(REQUEST_TIMEOUT, send_request(method, url, headers, body))
+        .await
+        .map_err(|_| format!("Request to {} timed out", url))?
+}
+
+async fn send_request(
+    method: HttpMethod,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<ClientResponse, ClientError> {
+    let is_head = method == HttpMethod::HEAD;
+    let url = Url::parse(url)?;
+    let request_bytes = serialise_request(method, &url, headers, body)?;
+    let stream = connect(&url).await?;
+    let response_bytes = exchange(stream, &request_bytes).await?;
+    parse_response(&response_bytes, is_head)
+}
+
+// Like request, but returns as soon as the head arrives, with the body following through a
+// BodyStream, e.g. to relay a large download without holding it all. The timeout only covers
+// the head. After that the body can take as long as it likes, as long as it keeps arriving
+pub async fn stream_request(
+    method: HttpMethod,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<StreamingResponse, ClientError> {
+    tokio::time::timeout(REQUEST_TIMEOUT, start_stream(method, url, headers, body))
+        .await
+        .map_err(|_| format!("Request to {} timed out", url))?
+}
+
+async fn start_stream(
+    method: HttpMethod,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<StreamingResponse, ClientError> {
+    let is_head = method == HttpMethod::HEAD;
+    let url = Url::parse(url)?;
+    let request_bytes = serialise_request(method, &url, headers, body)?;
+    let mut stream = connect(&url).await?;
+    stream.write_all(&request_bytes).await?;
+    stream.flush().await?;
+
+    let mut reader = BufReader::new(stream);
+    let head_bytes = read_head(&mut reader).await?;
+    let head = parse_head(&head_bytes)?;
+    let framing = body_framing(&head, is_head)?;
+    let content_length = match framing {
+        // Kept so a relayed HEAD or 304 describes the body it stands for, rather than none
+        BodyFraming::Empty => Some(
+            head.headers
+                .get("content-length")
+                .and_then(|length| length.trim().parse().ok())
+                .unwrap_or(0),
+        ),
+        BodyFraming::Length(length) => Some(length),
+        BodyFraming::Chunked | BodyFraming::UntilClose => None,
+    };
+    let (sender, body) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let result = match framing {
+            BodyFraming::Empty => Ok(()),
+            BodyFraming::Length(length) => forward(&mut reader, Some(length), &sender).await,
+            BodyFraming::Chunked => forward_chunked(&mut reader, &sender).await,
+            BodyFraming::UntilClose => forward(&mut reader, None, &sender).await,
+        };
+        if let Err(e) = result {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+    Ok(StreamingResponse {
+        status_code: head.status_code,
+        headers: head.headers,
+        header_list: head.header_list,
+        content_length,
+        body,
+    })
+}
+
+// TCP or TLS, whichever the URL's scheme needs
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for S {}
+
+async fn connect(url: &Url) -> Result<Box<dyn ClientStream>, ClientError> {
+    let host = url.host_str().ok_or("URL has no host")?.to_string();
+    let port = url
+        .port_or_known_default()
+        .ok_or("URL has no port or known scheme")?;
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+    stream.set_nodelay(true)?;
+    match url.scheme() {
+        #[cfg(feature = "tls")]
+        "https" => {
+            let server_name = ServerName::try_from(host)?;
+            Ok(Box::new(TLS_CONNECTOR.connect(server_name, stream).await?))
+        }
+        "http" => Ok(Box::new(stream)),
+        scheme => Err(format!("Unsupported scheme: {}", scheme).into()),
+    }
+}
+
+fn serialise_request(
+    method: HttpMethod,
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>, ClientError> {
+    let host = url.host_str().ok_or("URL has no host")?;
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let mut request_bytes = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, target, host
+    );
+    if !headers
+        .iter()
+        .any(|(key, _)| key.eq_ignore_ascii_case("user-agent"))
+    {
+        request_bytes.push_str("User-Agent: kblue-backend\r\n");
+    }
+    for (key, value) in headers {
+        request_bytes.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    if let Some(body) = body {
+        request_bytes.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request_bytes.push_str("\r\n");
+    let mut request_bytes = request_bytes.into_bytes();
+    if let Some(body) = body {
+        request_bytes.extend_from_slice(body);
+    }
+    Ok(request_bytes)
+}
+
+async fn exchange(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request_bytes: &[u8],
+) -> Result<Vec<u8>, ClientError> {
+    stream.write_all(request_bytes).await?;
+    stream.flush().await?;
+
+    let mut response_bytes = Vec::new();
+    let mut buffer = [0; 8 * 1024];
+    loop {
+        let num_bytes = match stream.read(&mut buffer).await {
+            Ok(num_bytes) => num_bytes,
+            // Plenty of servers close TLS connections without a close_notify
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if num_bytes == 0 {
+            break;
+        }
+        response_bytes.extend_from_slice(&buffer[..num_bytes]);
+        if response_bytes.len() > 10 * ONE_MB {
+            return Err("Response bigger than 10MB".into());
+        }
+    }
+    Ok(response_bytes)
+}
+
+struct ResponseHead {
+    status_code: u16,
+    headers: HashMap<String, String>,
+    header_list: Vec<(String, String)>,
+    len: usize,
+}
+
+fn parse_head(bytes: &[u8]) -> Result<ResponseHead, ClientError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let len = match response.parse(bytes)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Err("Incomplete response".into()),
+    };
+
+    let header_list: Vec<(String, String)> = response
+        .headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_string(),
+                String::from_utf8_lossy(header.value).to_string(),
+            )
+        })
+        .collect();
+    let headers: HashMap<String, String> = header_list
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value.clone()))
+        .collect();
+    Ok(ResponseHead {
+        status_code: response.code.ok_or("Response has no status code")?,
+        headers,
+        header_list,
+        len,
+    })
+}
+
+// Up to and including the blank line, for parse_head
+async fn read_head(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Vec<u8>, ClientError> {
+    let mut head = Vec::new();
+    loop {
+        let read = reader.read_until(b'\n', &mut head).await?;
+        if read == 0 {
+            return Err("Incomplete response".into());
+        }
+        if head.len() > MAX_HEAD_LEN {
+            return Err("Response head bigger than 64KB".into());
+        }
+        let line = &head[head.len() - read..];
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(head);
+        }
+    }
+}
+
+enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+// HEAD responses, and statuses which never have one, carry no body whatever their Content-Length says
+fn body_framing(head: &ResponseHead, is_head: bool) -> Result<BodyFraming, ClientError> {
+    if is_head || matches!(head.status_code, 100..=199 | 204 | 304) {
+        return Ok(BodyFraming::Empty);
+    }
+    match head.headers.get("transfer-encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => Ok(BodyFraming::Chunked),
+        _ => match head.headers.get("content-length") {
+            Some(length) => Ok(BodyFraming::Length(length.trim().parse()?)),
+            None => Ok(BodyFraming::UntilClose),
+        },
+    }
+}
+
+fn parse_response(bytes: &[u8], is_head: bool) -> Result<ClientResponse, ClientError> {
+    let head = parse_head(bytes)?;
+    let raw_body = &bytes[head.len..];
+    let body = match body_framing(&head, is_head)? {
+        BodyFraming::Empty => Vec::new(),
+        BodyFraming::Chunked => decode_chunked(raw_body)?,
+        BodyFraming::Length(length) => raw_body
+            .get(..length)
+            .ok_or("Truncated response body")?
+            .to_vec(),
+        BodyFraming::UntilClose => raw_body.to_vec(),
+    };
+
+    Ok(ClientResponse {
+        status_code: head.status_code,
+        headers: head.headers,
+        header_list: head.header_list,
+        body,
+    })
+}
+
+fn decode_chunked(mut bytes: &[u8]) -> Result<Vec<u8>, ClientError> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = bytes
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or("Malformed chunk size")?;
+        let size = chunk_size(&bytes[..line_end])?;
+        bytes = &bytes[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend_from_slice(bytes.get(..size).ok_or("Truncated chunk")?);
+        bytes = bytes.get(size + 2..).ok_or("Truncated chunk")?;
+    }
+}
+
+fn chunk_size(line: &[u8]) -> Result<usize, ClientError> {
+    let line = std::str::from_utf8(line)?;
+    // Ignore chunk extensions
+    let size_hex = line.split(';').next().unwrap_or_default().trim();
+    Ok(usize::from_str_radix(size_hex, 16)?)
+}
+
+// Sends `length` bytes of body, or all of it until the connection closes. Stops early, without
+// an error, once nobody is reading the stream
+async fn forward(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    mut remaining: Option<usize>,
+    sender: &mpsc::Sender<io::Result<Vec<u8>>>,
+) -> io::Result<()> {
+    while remaining != Some(0) {
+        let buffer = match idle_timeout(reader.fill_buf()).await {
+            Ok(buffer) => buffer,
+            // Plenty of servers close TLS connections without a close_notify
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && remaining.is_none() => &[],
+            Err(e) => return Err(e),
+        };
+        if buffer.is_empty() {
+            return match remaining {
+                Some(_) => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Truncated response body",
+                )),
+                None => Ok(()),
+            };
+        }
+        let len = remaining.map_or(buffer.len(), |remaining| remaining.min(buffer.len()));
+        let data = buffer[..len].to_vec();
+        reader.consume(len);
+        if let Some(remaining) = remaining.as_mut() {
+            *remaining -= len;
+        }
+        if sender.send(Ok(data)).await.is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// Passes the chunks' data on as it arrives, rather than waiting for whole chunks. Trailers are
+// dropped
+async fn forward_chunked(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    sender: &mpsc::Sender<io::Result<Vec<u8>>>,
+) -> io::Result<()> {
+    let invalid = |e: ClientError| io::Error::new(io::ErrorKind::InvalidData, e);
+    loop {
+        let line = read_line(reader).await?;
+        let size = chunk_size(line.trim_ascii_end()).map_err(invalid)?;
+        if size == 0 || sender.is_closed() {
+            return Ok(());
+        }
+        forward(reader, Some(size), sender).await?;
+        if sender.is_closed() {
+            return Ok(());
+        }
+        if !read_line(reader).await?.trim_ascii_end().is_empty() {
+            return Err(invalid("Malformed chunk".into()));
+        }
+    }
+}
+
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    // Chunk size lines are short, this only stops one that never ends
+    let read = idle_timeout(reader.take(4 * 1024).read_until(b'\n', &mut line)).await?;
+    if read == 0 || !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Truncated chunk",
+        ));
+    }
+    Ok(line)
+}
+
+async fn idle_timeout<T>(read: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(STREAM_IDLE_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Response body stalled"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let body = decode_chunked(b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\n\r\n").unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(
+            decode_chunked(b"A\r\n0123456789\r\n0\r\n\r\n").unwrap(),
+            b"0123456789"
+        );
+        assert!(decode_chunked(b"5\r\nhel").is_err());
+        assert!(decode_chunked(b"zz\r\nhello\r\n0\r\n\r\n").is_err());
+        assert!(decode_chunked(b"5\r\nhello").is_err());
+    }
+
+    async fn forwarded(mut raw: &[u8]) -> (Vec<u8>, io::Result<()>) {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let result = forward_chunked(&mut raw, &sender).await;
+        drop(sender);
+        let mut body = Vec::new();
+        while let Some(data) = receiver.recv().await {
+            body.extend_from_slice(&data.unwrap());
+        }
+        (body, result)
+    }
+
+    #[tokio::test]
+    async fn forwards_chunked_bodies() {
+        let (body, result) = forwarded(b"5\r\nhello\r\n6;x=1\r\n world\r\n0\r\n\r\n").await;
+        assert!(result.is_ok());
+        assert_eq!(body, b"hello world");
+
+        let (body, result) = forwarded(b"5\r\nhello\r\n6\r\n wo").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(body, b"hello wo");
+        let (_, result) = forwarded(b"5\r\nhelloXX\r\n0\r\n\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (_, result) = forwarded(b"zz\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
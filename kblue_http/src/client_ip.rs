@@ -0,0 +1,244 @@
+use std::net::{IpAddr, SocketAddr};
+
+use super::request::Request;
+
+// Proxies (e.g. nginx, Cloudflare) whose X-Forwarded-For / Forwarded headers are believed.
+// Connections over a unix socket always come from a local proxy, so are always trusted
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    // Network address and prefix length
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    // ", " separated addresses or CIDR ranges, e.g. "127.0.0.1, 10.0.0.0/8, ::1"
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut networks = Vec::new();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (address, prefix) = match entry.split_once('/') {
+                Some((address, prefix)) => (address, Some(prefix)),
+                None => (entry, None),
+            };
+            let address: IpAddr = address
+                .parse()
+                .map_err(|_| format!("Invalid trusted proxy address: {}", entry))?;
+            let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|prefix| *prefix <= max_prefix)
+                    .ok_or_else(|| format!("Invalid trusted proxy prefix: {}", entry))?,
+                None => max_prefix,
+            };
+            networks.push((address, prefix));
+        }
+        Ok(Self { networks })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| in_network(ip, network, *prefix))
+    }
+
+    // Whether the peer's forwarding headers are believed. A unix socket peer (None) always is
+    pub fn trusts_peer(&self, remote_addr: Option<SocketAddr>) -> bool {
+        remote_addr.is_none_or(|addr| self.contains(&addr.ip()))
+    }
+
+    // The peer address unless it's a trusted proxy, in which case the forwarding headers are
+    // walked from the nearest hop back, skipping further trusted proxies
+    pub fn client_ip(&self, remote_addr: Option<SocketAddr>, request: &Request) -> Option<IpAddr> {
+        let peer = remote_addr.map(|addr| addr.ip());
+        if !self.trusts_peer(remote_addr) {
+            return peer;
+        }
+
+        let hops = forwarded_hops(request);
+        hops.iter()
+            .rev()
+            .find(|hop| !self.contains(hop))
+            // Every hop is one of ours, so the furthest is as close to the client as we get
+            .or(hops.first())
+            .copied()
+            .or(peer)
+    }
+}
+
+fn in_network(ip: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    // IPv4 clients can show up as mapped addresses on a dual stack socket
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+        IpAddr::V4(_) => *ip,
+    };
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(*network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(*network) & mask
+        }
+        _ => false,
+    }
+}
+
+// Client first, nearest proxy last. The standard Forwarded header wins over X-Forwarded-For.
+// Every line counts, a proxy may add its own line rather than append to the client's
+fn forwarded_hops(request: &Request) -> Vec<IpAddr> {
+    if request.headers.contains_key("forwarded") {
+        return request
+            .headers
+            .get_all("forwarded")
+            .flat_map(|forwarded| forwarded.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                        .flatten()
+                })
+            })
+            .collect();
+    }
+    request
+        .headers
+        .get_all("x-forwarded-for")
+        .flat_map(|forwarded_for| forwarded_for.split(','))
+        .filter_map(parse_node)
+        .collect()
+}
+
+// Accepts `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:4711"` and `2001:db8::1`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_ip(proxies: &str, peer: &str, headers: &[(&str, &str)]) -> Option<IpAddr> {
+        let mut request = Request::default();
+        for (name, value) in headers {
+            request.headers.append(name, value);
+        }
+        TrustedProxies::parse(proxies)
+            .unwrap()
+            .client_ip(Some(peer.parse().unwrap()), &request)
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_forwarding_headers_from_untrusted_peers() {
+        let spoofed = [("X-Forwarded-For", "1.1.1.1"), ("Forwarded", "for=1.1.1.1")];
+        assert_eq!(
+            client_ip("10.0.0.0/8", "203.0.113.9:5000", &spoofed),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            client_ip("", "203.0.113.9:5000", &spoofed),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn walks_back_through_trusted_hops() {
+        let proxies = "127.0.0.1, 10.0.0.0/8";
+        let chain = [("X-Forwarded-For", "1.1.1.1, 203.0.113.9, 10.0.0.3")];
+        assert_eq!(
+            client_ip(proxies, "127.0.0.1:80", &chain),
+            ip("203.0.113.9")
+        );
+        // A proxy adding its own line rather than appending to the client's
+        let lines = [
+            ("X-Forwarded-For", "1.1.1.1"),
+            ("X-Forwarded-For", "203.0.113.9"),
+        ];
+        assert_eq!(
+            client_ip(proxies, "127.0.0.1:80", &lines),
+            ip("203.0.113.9")
+        );
+        let lines = [
+            ("Forwarded", "for=1.1.1.1"),
+            ("Forwarded", "for=203.0.113.9;proto=https"),
+        ];
+        assert_eq!(
+            client_ip(proxies, "127.0.0.1:80", &lines),
+            ip("203.0.113.9")
+        );
+        // Forwarded wins over X-Forwarded-For
+        let both = [
+            ("X-Forwarded-For", "1.1.1.1"),
+            ("Forwarded", "for=203.0.113.9"),
+        ];
+        assert_eq!(client_ip(proxies, "127.0.0.1:80", &both), ip("203.0.113.9"));
+        // Only our own proxies, so the furthest of them
+        let ours = [("X-Forwarded-For", "10.0.0.5, 10.0.0.3")];
+        assert_eq!(client_ip(proxies, "127.0.0.1:80", &ours), ip("10.0.0.5"));
+        assert_eq!(client_ip(proxies, "127.0.0.1:80", &[]), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn parses_ipv6_and_bracketed_nodes() {
+        let proxies = "::1, fd00::/8";
+        let forwarded = [("Forwarded", "for=\"[2001:db8::1]:4711\", for=\"[fd00::2]\"")];
+        assert_eq!(
+            client_ip(proxies, "[::1]:80", &forwarded),
+            ip("2001:db8::1")
+        );
+        let forwarded_for = [("X-Forwarded-For", "2001:db8::7, 198.51.100.1:443")];
+        assert_eq!(
+            client_ip("::1", "[::1]:80", &forwarded_for),
+            ip("198.51.100.1")
+        );
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn matches_networks() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 2001:db8::/32, 192.0.2.1").unwrap();
+        assert!(proxies.contains(&"10.255.0.1".parse().unwrap()));
+        assert!(!proxies.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(proxies.contains(&"2001:db8:ffff::1".parse().unwrap()));
+        assert!(!proxies.contains(&"2001:db9::1".parse().unwrap()));
+        assert!(proxies.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(!proxies.contains(&"192.0.2.2".parse().unwrap()));
+        // IPv4 on a dual stack socket
+        assert!(proxies.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+
+        let everything = TrustedProxies::parse("0.0.0.0/0, ::/0").unwrap();
+        assert!(everything.contains(&"203.0.113.9".parse().unwrap()));
+        assert!(everything.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(TrustedProxies::default().trusts_peer(None));
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        for list in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "10.0.0.0/-1",
+            "localhost",
+        ] {
+            assert!(TrustedProxies::parse(list).is_err(), "{}", list);
+        }
+        assert!(TrustedProxies::parse(" 127.0.0.1 ,, ::1 ").is_ok());
+    }
+}
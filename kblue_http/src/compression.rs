@@ -0,0 +1,211 @@
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+
+use super::plugin::Plugin;
+use super::request::Request;
+use super::response::Response;
+use super::ONE_KB;
+
+// Gzips response bodies for clients sending `Accept-Encoding: gzip`, e.g.
+// `server.add_plugin(Compression::new())`. Only whole bodies of text like types are compressed.
+// Chunked and streamed bodies, partial content and anything already encoded go out as they are
+pub struct Compression {
+    min_size: usize,
+    level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            // Smaller bodies barely shrink, and gzip's own 18 bytes can outweigh the saving
+            min_size: ONE_KB,
+            level: 6,
+        }
+    }
+
+    // Bodies smaller than this are sent uncompressed
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    // 1 (fastest) to 9 (smallest)
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.clamp(1, 9);
+        self
+    }
+
+    fn gzip(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(self.level));
+        encoder.write_all(body)?;
+        encoder.finish()
+    }
+}
+
+impl Plugin for Compression {
+    fn on_response_ready(&self, request: &Request, response: &mut Response) {
+        let compressible = response
+            .headers
+            .get("Content-Type")
+            .is_some_and(|content_type| is_compressible(content_type));
+        if !compressible
+            || response.is_chunked()
+            || response.is_streaming()
+            || response.status_code == 206
+            || response.headers.contains_key("Content-Encoding")
+        {
+            return;
+        }
+        // Whether it's compressed depends on the client, so caches have to keep both
+        response.add_vary("Accept-Encoding");
+        let body = response.get_body_as_bytes();
+        if body.len() < self.min_size {
+            return;
+        }
+        let accepted = request
+            .get_header("Accept-Encoding")
+            .is_some_and(|accept_encoding| accepts_gzip(accept_encoding));
+        if !accepted {
+            return;
+        }
+        let Ok(compressed) = self.gzip(body) else {
+            return;
+        };
+        if compressed.len() >= body.len() {
+            return;
+        }
+
+        response.add_header("Content-Encoding", "gzip");
+        response.add_header("Content-Length", &compressed.len().to_string());
+        // The compressed bytes differ, so a strong validator no longer matches them
+        if let Some(etag) = response
+            .headers
+            .get("ETag")
+            .filter(|etag| etag.starts_with('"'))
+        {
+            let weak = format!("W/{}", etag);
+            response.add_header("ETag", &weak);
+        }
+        response.set_body(compressed);
+    }
+}
+
+// Text, and the structured formats served as application/*. Images, video and archives are
+// compressed already
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+// `gzip`, or `*` unless gzip itself is ruled out, with a q value above 0
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+            })
+            .unwrap_or(1.0);
+        if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(q);
+        } else if coding == "*" {
+            any = Some(q);
+        }
+    }
+    gzip.or(any).is_some_and(|q| q > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn compress(accept_encoding: Option<&str>, response: &mut Response) {
+        let mut request = Request::default();
+        if let Some(accept_encoding) = accept_encoding {
+            request.headers.insert("Accept-Encoding", accept_encoding);
+        }
+        Compression::new().on_response_ready(&request, response);
+    }
+
+    #[test]
+    fn gzips_text_for_clients_accepting_it() {
+        let text = "hello kyle ".repeat(200);
+        let mut response = Response::new();
+        response.text(&text);
+        response.add_header("ETag", "\"v1\"");
+        compress(Some("br, gzip;q=0.8"), &mut response);
+
+        assert_eq!(response.headers.get("Content-Encoding").unwrap(), "gzip");
+        assert_eq!(response.headers.get("Vary").unwrap(), "Accept-Encoding");
+        assert_eq!(response.headers.get("ETag").unwrap(), "W/\"v1\"");
+        let body = response.get_body_as_bytes();
+        assert_eq!(
+            response.headers.get("Content-Length").unwrap(),
+            &body.len().to_string()
+        );
+        let mut decompressed = String::new();
+        GzDecoder::new(body)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, text);
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let text = "hello kyle ".repeat(200);
+        for accept_encoding in [None, Some("br"), Some("gzip;q=0"), Some("*, gzip;q=0")] {
+            let mut response = Response::new();
+            response.text(&text);
+            compress(accept_encoding, &mut response);
+            assert!(
+                !response.headers.contains_key("Content-Encoding"),
+                "{:?}",
+                accept_encoding
+            );
+            assert_eq!(response.get_body_as_string(), text);
+        }
+
+        let mut small = Response::new();
+        small.text("hello");
+        compress(Some("gzip"), &mut small);
+        assert!(!small.headers.contains_key("Content-Encoding"));
+
+        let mut image = Response::new();
+        image.bytes("image/png", vec![0; 4 * ONE_KB]);
+        compress(Some("gzip"), &mut image);
+        assert!(!image.headers.contains_key("Content-Encoding"));
+        assert!(!image.headers.contains_key("Vary"));
+    }
+}
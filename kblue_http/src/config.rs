@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use crate::ONE_KB;
+
+use super::client_ip::TrustedProxies;
+use super::headers::HeaderMap;
+#[cfg(feature = "tls")]
+use super::tls::TlsConfig;
+
+// Socket options applied to TCP listeners and every accepted connection, and how clients are identified
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // Disable Nagle's algorithm, so small responses aren't held back waiting for more data
+    pub nodelay: bool,
+    pub keepalive: Option<TcpKeepaliveConfig>,
+    // How long close() blocks trying to flush unsent data. None uses the OS default
+    pub linger: Option<Duration>,
+    // Allow several listeners (or processes) to bind the same port
+    pub reuse_port: bool,
+    pub listen_backlog: i32,
+    // Acceptor tasks per TCP address. Above 1, each binds its own listener with SO_REUSEPORT
+    // and the kernel spreads new connections between them
+    pub workers: usize,
+    // How long ShutdownHandle::shutdown waits for in-flight requests before giving up on them
+    pub shutdown_timeout: Duration,
+    pub limits: RequestLimits,
+    // Decides when request.client_ip comes from forwarding headers rather than the peer address
+    pub trusted_proxies: TrustedProxies,
+    // Added to, or stripped from, every response as it's written
+    pub default_headers: DefaultHeaders,
+    // Serve HTTPS on TCP listeners. Unix sockets stay plain, they're only reachable locally
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+// Headers every response is written with unless it set its own, and ones it's never written with,
+// e.g. `DefaultHeaders::new().set("Server", "kblue").remove("X-Powered-By")`. Applied after the
+// handler, middlewares and plugins, so removals also catch headers relayed from a proxied upstream
+#[derive(Clone, Debug, Default)]
+pub struct DefaultHeaders {
+    headers: HeaderMap,
+    removed: Vec<String>,
+}
+
+impl DefaultHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, name: &str, value: &str) -> Self {
+        self.removed
+            .retain(|removed| !removed.eq_ignore_ascii_case(name));
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn remove(mut self, name: &str) -> Self {
+        self.headers.remove(name);
+        self.removed.push(name.to_string());
+        self
+    }
+
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        for name in self.removed.iter() {
+            headers.remove(name);
+        }
+        for (name, value) in self.headers.iter() {
+            if !headers.contains_key(name) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TcpKeepaliveConfig {
+    // Idle time before the first probe is sent
+    pub time: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+// Bounds on a request's head. Going over answers 431 Request Header Fields Too Large, or
+// 414 URI Too Long for the request target, and closes the connection
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    pub max_headers: usize,
+    // Name and value of a single header, in bytes
+    pub max_header_size: usize,
+    // The request target including the query string, in bytes
+    pub max_uri_len: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_headers: 100,
+            max_header_size: 8 * ONE_KB,
+            max_uri_len: 8 * ONE_KB,
+        }
+    }
+}
+
+impl RequestLimits {
+    // The longest head these limits allow, so an unfinished one can be rejected early
+    pub(crate) fn max_head_len(&self) -> usize {
+        // Method, version and line endings are allowed a little slack
+        let request_line = self.max_uri_len + 32;
+        // Each header also has ": " and a line ending
+        request_line + self.max_headers * (self.max_header_size + 4) + 2
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            linger: None,
+            reuse_port: false,
+            listen_backlog: 1024,
+            limits: RequestLimits::default(),
+            workers: 1,
+            shutdown_timeout: Duration::from_secs(30),
+            trusted_proxies: TrustedProxies::default(),
+            default_headers: DefaultHeaders::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::convert::Infallible;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum HttpMethod {
+    #[default]
+    GET,
+    POST,
+    PUT,
+    DELETE,
+    HEAD,
+    OPTIONS,
+    CONNECT,
+    TRACE,
+    PATCH,
+    // Any other method, as sent
+    OTHER(String),
+}
+
+impl HttpMethod {
+    // Every method with a variant of its own, i.e. all but OTHER
+    pub const ALL: [HttpMethod; 9] = [
+        HttpMethod::GET,
+        HttpMethod::POST,
+        HttpMethod::PUT,
+        HttpMethod::DELETE,
+        HttpMethod::HEAD,
+        HttpMethod::OPTIONS,
+        HttpMethod::CONNECT,
+        HttpMethod::TRACE,
+        HttpMethod::PATCH,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::OTHER(method) => method,
+        }
+    }
+
+    // Whether the method may be listed in Allow and Access-Control-Allow-Methods. CONNECT and
+    // TRACE are left out even if routed, since browsers refuse them anyway
+    pub fn is_advertised(&self) -> bool {
+        !matches!(
+            self,
+            HttpMethod::CONNECT | HttpMethod::TRACE | HttpMethod::OTHER(_)
+        )
+    }
+}
+
+impl Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// As the method's name, e.g. "GET"
+impl Serialize for HttpMethod {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+// Methods are case sensitive (RFC 9110 9.1), so `get` is an OTHER
+impl FromStr for HttpMethod {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(HttpMethod::ALL
+            .into_iter()
+            .find(|method| method.as_str() == s)
+            .unwrap_or_else(|| HttpMethod::OTHER(s.to_string())))
+    }
+}
+
+pub const ONE_KB: usize = 1_024;
+pub const ONE_MB: usize = 1_048_576;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_names_round_trip() {
+        for method in HttpMethod::ALL {
+            assert_eq!(method.to_string().parse::<HttpMethod>(), Ok(method));
+        }
+        let method: HttpMethod = "PROPFIND".parse().unwrap();
+        assert_eq!(method, HttpMethod::OTHER("PROPFIND".to_string()));
+        assert_eq!(method.to_string(), "PROPFIND");
+        assert!(!method.is_advertised());
+        assert_eq!("get".parse(), Ok(HttpMethod::OTHER("get".to_string())));
+    }
+}
@@ -0,0 +1,292 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::handler_error::HandlerError;
+use super::json_error::JsonError;
+use super::request::Request;
+use super::response::Response;
+use super::server::{BorrowedFuture, RouteHandler};
+
+// Handlers which declare what they need from the request as arguments, e.g.
+// `async fn create_project(State(state): State<AppState>, Json(input): Json<ProjectInput>)
+//     -> Result<Json<Project>, HandlerError>`
+// registered with `server.route(POST, "/projects", handler(create_project))`. A failed extraction
+// is answered with its error (usually a 400) without calling the handler
+
+// Something a handler argument can be built from. Extraction doesn't consume the request, so
+// arguments can come in any order
+pub trait FromRequest: Sized {
+    fn from_request(request: &Request) -> Result<Self, HandlerError>;
+}
+
+// The JSON body, with the same errors as Request::parse_json. Also a response body
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request) -> Result<Self, HandlerError> {
+        Ok(Json(request.parse_json()?))
+    }
+}
+
+// The route's only parameter, e.g. the id of `/projects/:id`, parsed as T
+pub struct Path<T>(pub T);
+
+impl<T: FromStr> FromRequest for Path<T> {
+    fn from_request(request: &Request) -> Result<Self, HandlerError> {
+        let mut params = request.params.values();
+        let (Some(value), None) = (params.next(), params.next()) else {
+            // A mistake in the route table rather than the request
+            return Err(HandlerError::internal(
+                "Path needs a route with exactly one parameter",
+            ));
+        };
+        value
+            .parse()
+            .map(Path)
+            .map_err(|_| HandlerError::bad_request(&format!("invalid path parameter: {}", value)))
+    }
+}
+
+// The query string as a T. Like Request::parse_form every value is a string, so T's fields should
+// be too, or a Vec<String> for a repeated key
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &Request) -> Result<Self, HandlerError> {
+        let value = serde_json::to_value(&request.query).map_err(HandlerError::from)?;
+        serde_path_to_error::deserialize(value)
+            .map(Query)
+            .map_err(|e| JsonError::from_path_error(e).into())
+    }
+}
+
+// State registered with Server::with_state. Missing state is a 500, since it's a setup mistake
+pub struct State<T>(pub Arc<T>);
+
+impl<T: Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(request: &Request) -> Result<Self, HandlerError> {
+        request.state::<T>().map(State).ok_or_else(|| {
+            HandlerError::internal(&format!(
+                "No state of type {} registered",
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+// What a handler returns, written onto the response
+pub trait IntoResponse {
+    fn into_response(self, response: &mut Response) -> Result<(), HandlerError>;
+}
+
+// 204 No Content
+impl IntoResponse for () {
+    fn into_response(self, response: &mut Response) -> Result<(), HandlerError> {
+        response.set_status_code(204);
+        response.headers.remove("Content-Type");
+        Ok(())
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self, response: &mut Response) -> Result<(), HandlerError> {
+        response.text(&self);
+        Ok(())
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self, response: &mut Response) -> Result<(), HandlerError> {
+        response.text(self);
+        Ok(())
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self, response: &mut Response) -> Result<(), HandlerError> {
+        response.json(&self.0)?;
+        Ok(())
+    }
+}
+
+// A status other than 200, e.g. `(201, Json(project))`
+impl<R: IntoResponse> IntoResponse for (u16, R) {
+    fn into_response(self, response: &mut Response) -> Result<(), HandlerError> {
+        self.1.into_response(response)?;
+        response.set_status_code(self.0);
+        Ok(())
+    }
+}
+
+// Implemented for async functions (and closures) taking up to six extractors. Args is the tuple of
+// their types, which is only there to keep the implementations apart
+pub trait Handler<Args>: Clone + Send + Sync + 'static {
+    fn call<'a>(
+        self,
+        request: &'a mut Request,
+        response: &'a mut Response,
+    ) -> BorrowedFuture<'a, Result<(), HandlerError>>;
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),*) => {
+        impl<F, Fut, R, $($arg),*> Handler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future<Output = Result<R, HandlerError>> + Send,
+            R: IntoResponse,
+            $($arg: FromRequest + Send,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call<'a>(
+                self,
+                request: &'a mut Request,
+                response: &'a mut Response,
+            ) -> BorrowedFuture<'a, Result<(), HandlerError>> {
+                Box::pin(async move {
+                    $(let $arg = <$arg as FromRequest>::from_request(request)?;)*
+                    let output = self($($arg),*).await?;
+                    output.into_response(response)?;
+                    response.send();
+                    Ok(())
+                })
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(A);
+impl_handler!(A, B);
+impl_handler!(A, B, C);
+impl_handler!(A, B, C, D);
+impl_handler!(A, B, C, D, E);
+impl_handler!(A, B, C, D, E, G);
+
+// Turns an extractor handler into a route handler
+pub fn handler<Args: 'static, H: Handler<Args>>(handler: H) -> impl RouteHandler {
+    move |request: &mut Request, response: &mut Response| handler.clone().call(request, response)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{HttpMethod, Router, Server};
+
+    struct Greeting(String);
+
+    #[derive(Deserialize)]
+    struct Filters {
+        tag: Vec<String>,
+        sort: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Rename {
+        name: String,
+    }
+
+    async fn show(
+        Path(id): Path<u32>,
+        Query(filters): Query<Filters>,
+        State(greeting): State<Greeting>,
+    ) -> Result<Json<Value>, HandlerError> {
+        Ok(Json(json!({
+            "id": id,
+            "tags": filters.tag,
+            "sort": filters.sort,
+            "greeting": greeting.0,
+        })))
+    }
+
+    async fn rename(
+        Path(id): Path<u32>,
+        Json(body): Json<Rename>,
+    ) -> Result<(u16, String), HandlerError> {
+        Ok((201, format!("{} is now {}", id, body.name)))
+    }
+
+    fn server() -> Server {
+        let mut server = Server::builder().build();
+        server.with_state(Greeting("hi".to_string()));
+        server
+            .route(HttpMethod::GET, "/projects/:id", handler(show))
+            .unwrap();
+        server
+            .route(HttpMethod::PUT, "/projects/:id", handler(rename))
+            .unwrap();
+        server
+    }
+
+    fn request(method: HttpMethod, uri: &str, body: Option<&str>) -> Request {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        Request {
+            method,
+            path: path.to_string(),
+            uri: uri.to_string(),
+            query: url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+            body: body.map(|body| body.as_bytes().to_vec()),
+            ..Request::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn extracts_handler_arguments() {
+        let server = server();
+
+        let response = server
+            .handle(request(
+                HttpMethod::GET,
+                "/projects/7?tag=rust&tag=web",
+                None,
+            ))
+            .await;
+        let body: Value = serde_json::from_slice(response.get_body_as_bytes()).unwrap();
+        assert_eq!(
+            body,
+            json!({ "id": 7, "tags": ["rust", "web"], "sort": null, "greeting": "hi" })
+        );
+
+        let response = server
+            .handle(request(
+                HttpMethod::PUT,
+                "/projects/7",
+                Some(r#"{"name": "kblue.io"}"#),
+            ))
+            .await;
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.get_body_as_string(), "7 is now kblue.io");
+    }
+
+    #[tokio::test]
+    async fn answers_failed_extractions() {
+        let server = server();
+
+        let response = server
+            .handle(request(HttpMethod::GET, "/projects/seven?tag=rust", None))
+            .await;
+        assert_eq!(response.status_code, 400);
+        // tag must be repeated to be a list
+        let response = server
+            .handle(request(HttpMethod::GET, "/projects/7?tag=rust", None))
+            .await;
+        assert_eq!(response.status_code, 400);
+        let response = server
+            .handle(request(
+                HttpMethod::PUT,
+                "/projects/7",
+                Some(r#"{"nme": "x"}"#),
+            ))
+            .await;
+        assert_eq!(response.status_code, 400);
+    }
+}
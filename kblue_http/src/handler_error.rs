@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use super::json_error::{FieldError, JsonError};
+use super::request::Request;
+use super::response::Response;
+use super::status::StatusCode;
+use super::template::Templates;
+
+// Returned by route handlers. Any std error converts into a 500 with `?`, while the constructors
+// below give a specific status and a message which is safe to show the client
+#[derive(Debug)]
+pub struct HandlerError {
+    pub status: u16,
+    pub message: String,
+    pub errors: Vec<FieldError>,
+    // Logged, never sent to the client
+    pub source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl HandlerError {
+    pub fn new(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            errors: Vec::new(),
+            source: None,
+        }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        Self::new(400, message)
+    }
+
+    // 422, for a body which parsed but failed validation
+    pub fn unprocessable(error: JsonError) -> Self {
+        Self {
+            status: 422,
+            ..error.into()
+        }
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        Self::new(404, message)
+    }
+
+    pub fn internal(message: &str) -> Self {
+        Self::new(500, message)
+    }
+
+    // Keeps the underlying error for the logs, e.g. a failed SMTP send behind a 500
+    pub fn with_source(mut self, source: impl std::error::Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        StatusCode::from(self.status).is_server_error()
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)?;
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for HandlerError {
+    fn from(error: E) -> Self {
+        Self {
+            source: Some(Box::new(error)),
+            ..Self::internal("internal server error")
+        }
+    }
+}
+
+impl From<JsonError> for HandlerError {
+    fn from(error: JsonError) -> Self {
+        Self {
+            errors: error.errors,
+            ..Self::bad_request(&error.message)
+        }
+    }
+}
+
+// Turns an error into the response, set with Server::set_error_renderer or
+// Server::set_error_page. Used for handler errors, 404s, panics and requests which can't be read,
+// so the request may be empty if it never parsed
+pub type ErrorRenderer = Arc<dyn Fn(&HandlerError, &Request, &mut Response) + Send + Sync>;
+
+// Which errors a page set with Server::set_error_page is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorStatuses {
+    Exactly(u16),
+    // 4xx
+    ClientErrors,
+    // 5xx
+    ServerErrors,
+}
+
+// The renderer for an error is the one set for its exact status, then for its class, then the
+// fallback
+#[derive(Clone)]
+pub(crate) struct ErrorPages {
+    pages: HashMap<ErrorStatuses, ErrorRenderer>,
+    fallback: ErrorRenderer,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self {
+            pages: HashMap::new(),
+            fallback: Arc::new(render_error),
+        }
+    }
+}
+
+impl ErrorPages {
+    pub(crate) fn set(&mut self, statuses: ErrorStatuses, renderer: ErrorRenderer) {
+        self.pages.insert(statuses, renderer);
+    }
+
+    pub(crate) fn set_fallback(&mut self, renderer: ErrorRenderer) {
+        self.fallback = renderer;
+    }
+
+    pub(crate) fn render(&self, error: &HandlerError, request: &Request, response: &mut Response) {
+        let status = StatusCode::from(error.status);
+        let class = if status.is_client_error() {
+            Some(ErrorStatuses::ClientErrors)
+        } else if status.is_server_error() {
+            Some(ErrorStatuses::ServerErrors)
+        } else {
+            None
+        };
+        let renderer = self
+            .pages
+            .get(&ErrorStatuses::Exactly(error.status))
+            .or_else(|| class.and_then(|class| self.pages.get(&class)))
+            .unwrap_or(&self.fallback);
+        renderer(error, request, response);
+    }
+}
+
+// The default: JSON for API clients, which is also what anything not saying gets, or a plain
+// page for browsers
+pub fn render_error(error: &HandlerError, request: &Request, response: &mut Response) {
+    match response.negotiate(request, &["application/json", "text/html"]) {
+        Some("text/html") => render_html_error(error, response),
+        _ => render_json_error(error, response),
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    message: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [FieldError],
+}
+
+// `{"message": ..., "errors": [...]}`, the same shape as Response::bad_request
+pub fn render_json_error(error: &HandlerError, response: &mut Response) {
+    let body = ErrorBody {
+        message: &error.message,
+        errors: &error.errors,
+    };
+    response
+        .status(error.status)
+        .json(&body)
+        .expect("ErrorBody always serialises");
+}
+
+const ERROR_TEMPLATES: Templates = Templates::new(&[(
+    "error.html",
+    "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>{{status}}</title></head>\n<body><h1>{{status}}</h1><p>{{message}}</p></body>\n</html>\n",
+)]);
+
+// A minimal page with the status, e.g. "404 Not Found", and the error's message
+pub fn render_html_error(error: &HandlerError, response: &mut Response) {
+    let status = StatusCode::from(error.status).to_string();
+    response
+        .status(error.status)
+        .render(
+            ERROR_TEMPLATES.get("error.html"),
+            &[("status", &status), ("message", &error.message)],
+        )
+        .expect("the error page only uses status and message");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn std_errors_become_opaque_500s() {
+        let error: HandlerError = std::io::Error::other("smtp down").into();
+        let mut response = Response::new();
+        render_json_error(&error, &mut response);
+        assert_eq!(response.status_code, 500);
+        assert_eq!(
+            response.get_body_as_string(),
+            r#"{"message":"internal server error"}"#
+        );
+        assert!(error.to_string().contains("smtp down"));
+    }
+
+    #[test]
+    fn json_errors_keep_their_fields() {
+        let error: HandlerError = JsonError::new("invalid request body")
+            .field("email", "is required")
+            .into();
+        let mut response = Response::new();
+        render_json_error(&error, &mut response);
+        assert_eq!(response.status_code, 400);
+        assert_eq!(
+            response.get_body_as_string(),
+            r#"{"message":"invalid request body","errors":[{"field":"email","message":"is required"}]}"#
+        );
+    }
+}
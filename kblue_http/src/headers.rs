@@ -0,0 +1,129 @@
+// Header fields in the order they were added. A name may appear more than once, e.g. `Set-Cookie`.
+// Names are case insensitive, so they're stored lowercased and looked up ignoring case
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds another value, keeping any existing ones
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_lowercase(), value.to_string()));
+    }
+
+    // Replaces every existing value. The header keeps the position of its first occurrence
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self.entries.iter().position(|(key, _)| matches(key, name)) {
+            Some(index) => {
+                self.entries[index].1 = value.to_string();
+                let mut seen = 0;
+                self.entries.retain(|(key, _)| {
+                    seen += matches(key, name) as usize;
+                    !matches(key, name) || seen == 1
+                });
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    // The first value
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(key, _)| matches(key, name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| matches(key, name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !matches(key, name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self {
+            entries: iter
+                .into_iter()
+                .map(|(name, value)| (name.to_lowercase(), value))
+                .collect(),
+        }
+    }
+}
+
+fn matches(key: &str, name: &str) -> bool {
+    key.eq_ignore_ascii_case(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_keeps_every_value_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1");
+        headers.append("vary", "Origin");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert_eq!(headers.get("set-cookie").unwrap(), "a=1");
+        assert_eq!(headers.len(), 3);
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.append("Origin", "https://kblue.io");
+        assert_eq!(headers.get("origin").unwrap(), "https://kblue.io");
+        assert_eq!(headers.get("ORIGIN").unwrap(), "https://kblue.io");
+        headers.insert("oRiGiN", "https://example.com");
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.iter().next().unwrap().0, "origin");
+    }
+
+    #[test]
+    fn insert_replaces_every_value_in_place() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1");
+        headers.append("vary", "Origin");
+        headers.append("set-cookie", "b=2");
+        headers.insert("set-cookie", "c=3");
+        let entries: Vec<_> = headers.iter().collect();
+        assert_eq!(
+            entries,
+            [
+                (&"set-cookie".to_string(), &"c=3".to_string()),
+                (&"vary".to_string(), &"Origin".to_string())
+            ]
+        );
+    }
+}
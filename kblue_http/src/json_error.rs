@@ -0,0 +1,131 @@
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct FieldError {
+    // Dotted path into the body, e.g. `email` or `tags[2]`
+    pub field: String,
+    pub message: String,
+}
+
+// Why a JSON body was rejected, in a shape that can be sent straight back to the client
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct JsonError {
+    pub message: String,
+    pub errors: Vec<FieldError>,
+}
+
+impl JsonError {
+    pub fn new(message: &str) -> Self {
+        Self {
+            message: message.to_string(),
+            errors: Vec::new(),
+        }
+    }
+
+    // For validation done after deserialising, e.g. an empty name
+    pub fn field(mut self, field: &str, message: &str) -> Self {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub(super) fn from_path_error(error: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        let inner = error.inner();
+        if !inner.is_data() {
+            return Self::new(&format!("invalid JSON: {}", inner));
+        }
+
+        let path = error.path().to_string();
+        // serde_json appends the position, which means nothing to someone filling in a form
+        let message = inner.to_string();
+        let message = message
+            .rsplit_once(" at line ")
+            .map_or(message.as_str(), |(message, _)| message);
+
+        // Missing fields are reported against their parent object
+        let field = match message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.strip_suffix('`'))
+        {
+            Some(missing) if path == "." => missing.to_string(),
+            Some(missing) => format!("{}.{}", path, missing),
+            None => path,
+        };
+        let message = if message.starts_with("missing field") {
+            "is required"
+        } else {
+            message
+        };
+        Self::new("invalid request body").field(&field, message)
+    }
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for error in self.errors.iter() {
+            write!(f, ", {}: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Contact {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn parse(body: &str) -> JsonError {
+        let mut deserializer = serde_json::Deserializer::from_str(body);
+        let error = serde_path_to_error::deserialize::<_, Contact>(&mut deserializer).unwrap_err();
+        JsonError::from_path_error(error)
+    }
+
+    #[test]
+    fn field_errors_name_the_field() {
+        let cases = [
+            (r#"{"tags": []}"#, "name", "is required"),
+            (
+                r#"{"name": 1, "tags": []}"#,
+                "name",
+                "invalid type: integer `1`, expected a string",
+            ),
+            (
+                r#"{"name": "a", "tags": ["b", 2]}"#,
+                "tags[1]",
+                "invalid type: integer `2`, expected a string",
+            ),
+        ];
+        for (body, field, message) in cases {
+            let error = parse(body);
+            assert_eq!(
+                error.errors,
+                vec![FieldError {
+                    field: field.to_string(),
+                    message: message.to_string()
+                }],
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn syntax_errors_have_no_fields() {
+        let error = parse(r#"{"name": "#);
+        assert!(error.errors.is_empty());
+        assert!(error.message.starts_with("invalid JSON"));
+    }
+}
@@ -0,0 +1,77 @@
+// The HTTP/1.1 server (and small client) behind kblue.io, usable by any tokio service, e.g.
+// `let mut server = Server::new(8080); server.add_routes(routes! { GET "/healthz" => healthz })?; server.start().await`
+// The tls feature (on by default) lets the client, and so Router::proxy, call https:// URLs, and
+// the server terminate TLS itself, see TlsConfig. The websocket and compression features (also on
+// by default) add WebSocketUpgrade and the Compression plugin. The test-util feature adds
+// test::TestServer
+
+mod access_log;
+mod catch_panic;
+pub mod client;
+mod client_ip;
+#[cfg(feature = "compression")]
+mod compression;
+mod config;
+mod constants;
+mod extensions;
+mod extract;
+mod handler_error;
+mod headers;
+mod json_error;
+mod r#macro;
+mod multipart;
+mod negotiation;
+mod openapi;
+mod plugin;
+mod proxy;
+mod query;
+mod range;
+mod request;
+mod request_reader;
+mod response;
+mod router;
+mod server;
+mod state;
+mod status;
+mod template;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test;
+#[cfg(feature = "tls")]
+mod tls;
+mod typed_headers;
+mod util;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod workers;
+
+pub use access_log::*;
+pub use client_ip::*;
+#[cfg(feature = "compression")]
+pub use compression::*;
+pub use config::*;
+pub use constants::*;
+pub use extensions::*;
+pub use extract::*;
+pub use handler_error::*;
+pub use headers::*;
+pub use json_error::*;
+pub use multipart::*;
+pub use negotiation::*;
+pub use openapi::*;
+pub use plugin::*;
+pub use query::*;
+pub use range::*;
+pub use request::*;
+pub use response::*;
+pub use router::*;
+pub use server::*;
+pub use state::*;
+pub use status::*;
+pub use template::*;
+#[cfg(feature = "tls")]
+pub use tls::*;
+pub use typed_headers::*;
+pub use util::assert_unique_routes;
+#[cfg(feature = "websocket")]
+pub use websocket::*;
+pub use workers::*;
@@ -0,0 +1,45 @@
+// The block returns Result<(), HandlerError>, so handlers can use `?`. It borrows the request and
+// response for as long as it runs, there's nothing to lock
+#[macro_export]
+macro_rules! route {
+    ($function_name:ident, $handler_block:expr) => {
+        #[allow(unused_variables, unused_mut)]
+        pub fn $function_name<'a>(
+            req: &'a mut $crate::Request,
+            res: &'a mut $crate::Response,
+        ) -> $crate::BorrowedFuture<'a, Result<(), $crate::HandlerError>> {
+            return Box::pin(async move { $handler_block(req, res).await });
+        }
+    };
+}
+
+// Like route!, but the block returns a Next to control the rest of the chain
+#[macro_export]
+macro_rules! middleware {
+    ($function_name:ident, $handler_block:expr) => {
+        #[allow(unused_variables, unused_mut)]
+        pub fn $function_name<'a>(
+            req: &'a mut $crate::Request,
+            res: &'a mut $crate::Response,
+        ) -> $crate::BorrowedFuture<'a, $crate::Next> {
+            return Box::pin(async move { $handler_block(req, res).await });
+        }
+    };
+}
+
+/// Declares a route table in one place, e.g.
+/// `server.add_routes(routes! { POST "/api/v1/send_email" => send_email_handler })`.
+/// Route scoped middlewares go in brackets before the method: `[auth_middleware] GET "/admin" => handler`.
+/// Registering the same method and path twice fails to compile.
+#[macro_export]
+macro_rules! routes {
+    ($($([$($middleware:expr),* $(,)?])? $method:ident $path:literal => $handler:expr),* $(,)?) => {{
+        const _: () = $crate::assert_unique_routes(&[$((stringify!($method), $path)),*]);
+        vec![$((
+            $crate::HttpMethod::$method,
+            $path,
+            vec![$($(std::sync::Arc::new($middleware) as $crate::MiddlewareFunc),*)?],
+            std::sync::Arc::new($handler) as $crate::RouteHandlerFunc,
+        )),*]
+    }};
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+// One field of a multipart/form-data body. `data` borrows from the request body
+#[derive(Debug)]
+pub struct Part<'a> {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    // Lowercased names
+    pub headers: HashMap<String, String>,
+    pub data: &'a [u8],
+}
+
+impl Part<'_> {
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(self.data).ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartError(pub String);
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed multipart body: {}", self.0)
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+// Iterates over the parts of a multipart body one at a time, so large uploads aren't copied.
+// Stops after the first error
+pub struct Multipart<'a> {
+    delimiter: Vec<u8>,
+    remaining: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Multipart<'a> {
+    pub fn new(body: &'a [u8], boundary: &str) -> Self {
+        Self {
+            delimiter: format!("--{}", boundary).into_bytes(),
+            remaining: body,
+            done: false,
+        }
+    }
+
+    // The boundary parameter of a `multipart/form-data; boundary=...` content type
+    pub fn boundary(content_type: &str) -> Option<String> {
+        let mut params = content_type.split(';');
+        let essence = params.next()?.trim();
+        if !essence.eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+        params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, value)| value.trim().trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+    }
+
+    fn next_part(&mut self) -> Result<Option<Part<'a>>, MultipartError> {
+        // The first delimiter may follow a preamble, later ones follow the previous part's CRLF
+        let start = find(self.remaining, &self.delimiter)
+            .ok_or_else(|| MultipartError("missing boundary".to_string()))?;
+        let after = &self.remaining[start + self.delimiter.len()..];
+        if after.starts_with(b"--") {
+            return Ok(None);
+        }
+        let after = after
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| MultipartError("boundary not followed by CRLF".to_string()))?;
+
+        let header_end = find(after, b"\r\n\r\n")
+            .ok_or_else(|| MultipartError("unterminated part headers".to_string()))?;
+        let headers = parse_headers(&after[..header_end])?;
+        let content = &after[header_end + 4..];
+
+        let mut closing = b"\r\n".to_vec();
+        closing.extend_from_slice(&self.delimiter);
+        let data_end = find(content, &closing)
+            .ok_or_else(|| MultipartError("missing closing boundary".to_string()))?;
+        // Leave the CRLF-less delimiter for the next call to find
+        self.remaining = &content[data_end + 2..];
+
+        let disposition = headers
+            .get("content-disposition")
+            .map(|value| disposition_params(value))
+            .unwrap_or_default();
+        Ok(Some(Part {
+            name: disposition.get("name").cloned(),
+            filename: disposition.get("filename").cloned(),
+            content_type: headers.get("content-type").cloned(),
+            headers,
+            data: &content[..data_end],
+        }))
+    }
+}
+
+impl<'a> Iterator for Multipart<'a> {
+    type Item = Result<Part<'a>, MultipartError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let part = self.next_part();
+        if !matches!(part, Ok(Some(_))) {
+            self.done = true;
+        }
+        part.transpose()
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_headers(raw: &[u8]) -> Result<HashMap<String, String>, MultipartError> {
+    let raw = std::str::from_utf8(raw)
+        .map_err(|_| MultipartError("part headers are not UTF-8".to_string()))?;
+    raw.split("\r\n")
+        .map(|line| {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| MultipartError(format!("invalid part header `{}`", line)))?;
+            Ok((name.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+// `form-data; name="file"; filename="a.png"` -> {name: file, filename: a.png}
+fn disposition_params(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().to_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"name\"\r\n\
+\r\n\
+Kyle\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+line one\r\nline two\r\n\
+--XyZ--\r\n";
+
+    #[test]
+    fn parses_fields_and_files() {
+        let parts: Vec<Part> = Multipart::new(BODY, "XyZ")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("name"));
+        assert_eq!(parts[0].text(), Some("Kyle"));
+        assert!(!parts[0].is_file());
+        assert_eq!(parts[1].filename.as_deref(), Some("a.txt"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(parts[1].data, b"line one\r\nline two");
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let body = &BODY[..BODY.len() - 20];
+        let results: Vec<_> = Multipart::new(body, "XyZ").collect();
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        let cases = [
+            ("multipart/form-data; boundary=abc", Some("abc")),
+            (
+                "Multipart/Form-Data; charset=utf-8; boundary=\"a b\"",
+                Some("a b"),
+            ),
+            ("multipart/form-data", None),
+            ("application/json; boundary=abc", None),
+        ];
+        for (content_type, expected) in cases {
+            assert_eq!(
+                Multipart::boundary(content_type).as_deref(),
+                expected,
+                "{}",
+                content_type
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use super::request::Request;
+use super::response::Response;
+
+// One entry of an `Accept` header, e.g. `text/html;q=0.8`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaRange {
+    // Lowercased `type/subtype`, either of which may be `*`
+    pub media_type: String,
+    pub q: f32,
+}
+
+impl MediaRange {
+    // Entries with a malformed q value are dropped, missing q values default to 1
+    pub fn parse_accept(header: &str) -> Vec<MediaRange> {
+        let mut ranges: Vec<MediaRange> = header
+            .split(',')
+            .filter_map(|entry| {
+                let mut params = entry.split(';');
+                let media_type = params.next()?.trim().to_lowercase();
+                if !media_type.contains('/') {
+                    return None;
+                }
+                let mut q = 1.0;
+                for param in params {
+                    if let Some((key, value)) = param.split_once('=') {
+                        if key.trim().eq_ignore_ascii_case("q") {
+                            q = value.trim().parse::<f32>().ok()?.clamp(0.0, 1.0);
+                        }
+                    }
+                }
+                Some(MediaRange { media_type, q })
+            })
+            .collect();
+        // Most preferred first, the sort is stable so ties keep the client's order
+        ranges.sort_by(|a, b| b.q.total_cmp(&a.q));
+        ranges
+    }
+
+    // 2 for an exact match, 1 for `type/*`, 0 for `*/*`, None if it doesn't match
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        let (range_type, range_subtype) = self.media_type.split_once('/')?;
+        let (offered_type, offered_subtype) = media_type.split_once('/')?;
+        match (range_type, range_subtype) {
+            ("*", "*") => Some(0),
+            (range_type, "*") if range_type.eq_ignore_ascii_case(offered_type) => Some(1),
+            (range_type, range_subtype)
+                if range_type.eq_ignore_ascii_case(offered_type)
+                    && range_subtype.eq_ignore_ascii_case(offered_subtype) =>
+            {
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Of the types the server can produce (in its order of preference), the one the client wants most.
+// Each offer takes the q value of the most specific range matching it, and q=0 rules it out
+pub fn best_match<'a>(accepts: &[MediaRange], offered: &[&'a str]) -> Option<&'a str> {
+    let mut best: Option<(&str, f32)> = None;
+    for offer in offered {
+        let q = accepts
+            .iter()
+            .filter_map(|range| {
+                range
+                    .specificity(offer)
+                    .map(|specificity| (specificity, range.q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q);
+        match q {
+            Some(q) if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) => {
+                best = Some((offer, q))
+            }
+            _ => {}
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+impl Response {
+    // Picks which of `offered` to send and marks the response as varying on Accept. None means
+    // nothing offered is acceptable, usually answered with a 406
+    pub fn negotiate<'a>(&mut self, request: &Request, offered: &[&'a str]) -> Option<&'a str> {
+        self.add_vary("Accept");
+        best_match(request.accept(), offered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_most_wanted_offer() {
+        const OFFERED: &[&str] = &["application/json", "text/html", "application/pdf"];
+        let cases = [
+            ("*/*", Some("application/json")),
+            ("text/html", Some("text/html")),
+            ("text/html;q=0.5, application/pdf", Some("application/pdf")),
+            (
+                "application/*;q=0.9, text/html;q=0.8",
+                Some("application/json"),
+            ),
+            // The specific range overrides the wildcard for json
+            (
+                "application/json;q=0, application/*",
+                Some("application/pdf"),
+            ),
+            ("text/*;q=0.1, */*;q=0.05", Some("text/html")),
+            ("image/png", None),
+            ("text/html;q=0", None),
+            ("text/html;q=nope, application/pdf", Some("application/pdf")),
+        ];
+        for (accept, expected) in cases {
+            let accepts = MediaRange::parse_accept(accept);
+            assert_eq!(best_match(&accepts, OFFERED), expected, "{}", accept);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Map, Value};
+
+use super::constants::HttpMethod;
+use super::handler_error::HandlerError;
+
+// Describes a type as JSON Schema for the OpenAPI document, see RouteDoc. Implemented for the
+// primitives, Option and Vec, and by hand for request and response types, usually with
+// ObjectSchema. Types with a NAME are listed once under components/schemas and referenced from
+// the routes using them
+pub trait JsonSchema {
+    const NAME: Option<&'static str> = None;
+    // Whether an ObjectSchema field of this type can be left out, like serde's Option fields
+    const OPTIONAL: bool = false;
+
+    fn schema() -> Value;
+}
+
+macro_rules! primitive_schema {
+    ($type_name:literal: $($rust_type:ty),*) => {
+        $(impl JsonSchema for $rust_type {
+            fn schema() -> Value {
+                json!({ "type": $type_name })
+            }
+        })*
+    };
+}
+
+primitive_schema!("string": String, &str);
+primitive_schema!("boolean": bool);
+primitive_schema!("integer": u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+primitive_schema!("number": f32, f64);
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    const OPTIONAL: bool = true;
+
+    fn schema() -> Value {
+        T::schema()
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+// The body of every error response, see render_json_error
+impl JsonSchema for HandlerError {
+    const NAME: Option<&'static str> = Some("Error");
+
+    fn schema() -> Value {
+        let field_error = ObjectSchema::new()
+            .field::<String>("field")
+            .field::<String>("message")
+            .build();
+        ObjectSchema::new()
+            .field::<String>("message")
+            .property(
+                "errors",
+                json!({ "type": "array", "items": field_error }),
+                false,
+            )
+            .build()
+    }
+}
+
+// The body Response::message sends, for documenting routes answering with one
+pub struct MessageBody;
+
+impl JsonSchema for MessageBody {
+    const NAME: Option<&'static str> = Some("Message");
+
+    fn schema() -> Value {
+        ObjectSchema::new().field::<String>("message").build()
+    }
+}
+
+// e.g. `ObjectSchema::new().field::<String>("name").field::<Option<String>>("website").build()`
+#[derive(Clone, Debug, Default)]
+pub struct ObjectSchema {
+    properties: Map<String, Value>,
+    required: Vec<String>,
+}
+
+impl ObjectSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Required unless T is an Option
+    pub fn field<T: JsonSchema>(self, name: &str) -> Self {
+        self.property(name, T::schema(), !T::OPTIONAL)
+    }
+
+    // For fields serde fills in when they're missing, e.g. with #[serde(default)]
+    pub fn optional<T: JsonSchema>(self, name: &str) -> Self {
+        self.property(name, T::schema(), false)
+    }
+
+    // A hand written schema, e.g. with a maxLength
+    pub fn property(mut self, name: &str, schema: Value, required: bool) -> Self {
+        self.properties.insert(name.to_string(), schema);
+        if required {
+            self.required.push(name.to_string());
+        }
+        self
+    }
+
+    pub fn build(self) -> Value {
+        let mut schema = json!({ "type": "object", "properties": self.properties });
+        if !self.required.is_empty() {
+            schema["required"] = json!(self.required);
+        }
+        schema
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SchemaUse {
+    name: Option<&'static str>,
+    schema: Value,
+}
+
+impl SchemaUse {
+    fn of<T: JsonSchema>() -> Self {
+        Self {
+            name: T::NAME,
+            schema: T::schema(),
+        }
+    }
+
+    // A $ref for named schemas, which are added to the components
+    fn reference(&self, components: &mut Map<String, Value>) -> Value {
+        match self.name {
+            Some(name) => {
+                components.insert(name.to_string(), self.schema.clone());
+                json!({ "$ref": format!("#/components/schemas/{}", name) })
+            }
+            None => self.schema.clone(),
+        }
+    }
+}
+
+// What a route takes and returns, registered with Router::document next to the route itself.
+// Path parameters are read from the route's pattern
+#[derive(Clone, Debug)]
+pub struct RouteDoc {
+    summary: String,
+    tags: Vec<String>,
+    request_body: Option<SchemaUse>,
+    // Status, description and the JSON body, if any
+    responses: Vec<(u16, String, Option<SchemaUse>)>,
+    bearer_auth: bool,
+}
+
+impl RouteDoc {
+    pub fn new(summary: &str) -> Self {
+        Self {
+            summary: summary.to_string(),
+            tags: Vec::new(),
+            request_body: None,
+            responses: Vec::new(),
+            bearer_auth: false,
+        }
+    }
+
+    // Groups routes in viewers like Swagger UI
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    // A JSON request body
+    pub fn request<T: JsonSchema>(mut self) -> Self {
+        self.request_body = Some(SchemaUse::of::<T>());
+        self
+    }
+
+    pub fn response<T: JsonSchema>(mut self, status: u16, description: &str) -> Self {
+        self.responses
+            .push((status, description.to_string(), Some(SchemaUse::of::<T>())));
+        self
+    }
+
+    // e.g. a 204
+    pub fn empty_response(mut self, status: u16, description: &str) -> Self {
+        self.responses.push((status, description.to_string(), None));
+        self
+    }
+
+    // Needs an `Authorization: Bearer` token
+    pub fn bearer_auth(mut self) -> Self {
+        self.bearer_auth = true;
+        self
+    }
+}
+
+// The documented routes of one or more servers, see Server::openapi
+#[derive(Clone, Debug, Default)]
+pub struct OpenApi {
+    // Path pattern -> method -> doc
+    routes: BTreeMap<String, BTreeMap<String, RouteDoc>>,
+}
+
+impl OpenApi {
+    pub(crate) fn add(&mut self, method: HttpMethod, pattern: &str, doc: RouteDoc) {
+        self.routes
+            .entry(pattern.to_string())
+            .or_default()
+            .insert(method.to_string().to_lowercase(), doc);
+    }
+
+    // e.g. with the routes of a server on another port. Its docs win for the same route
+    pub fn merge(&mut self, other: OpenApi) {
+        for (pattern, methods) in other.routes {
+            self.routes.entry(pattern).or_default().extend(methods);
+        }
+    }
+
+    // An OpenAPI 3.1 document
+    pub fn document(&self, title: &str, version: &str) -> Value {
+        let mut components = Map::new();
+        let mut any_bearer_auth = false;
+        let mut paths = Map::new();
+        for (pattern, methods) in self.routes.iter() {
+            let (path, params) = openapi_path(pattern);
+            let mut operations = Map::new();
+            for (method, doc) in methods.iter() {
+                let mut operation = json!({ "summary": doc.summary });
+                if !doc.tags.is_empty() {
+                    operation["tags"] = json!(doc.tags);
+                }
+                if !params.is_empty() {
+                    let parameters: Vec<Value> = params
+                        .iter()
+                        .map(|name| {
+                            json!({
+                                "name": name,
+                                "in": "path",
+                                "required": true,
+                                "schema": { "type": "string" },
+                            })
+                        })
+                        .collect();
+                    operation["parameters"] = json!(parameters);
+                }
+                if let Some(body) = &doc.request_body {
+                    operation["requestBody"] = json!({
+                        "required": true,
+                        "content": { "application/json": { "schema": body.reference(&mut components) } },
+                    });
+                }
+                let mut responses = Map::new();
+                for (status, description, body) in doc.responses.iter() {
+                    let mut response = json!({ "description": description });
+                    if let Some(body) = body {
+                        response["content"] = json!({
+                            "application/json": { "schema": body.reference(&mut components) },
+                        });
+                    }
+                    responses.insert(status.to_string(), response);
+                }
+                operation["responses"] = Value::Object(responses);
+                if doc.bearer_auth {
+                    any_bearer_auth = true;
+                    operation["security"] = json!([{ "bearerAuth": [] }]);
+                }
+                operations.insert(method.clone(), operation);
+            }
+            paths.insert(path, Value::Object(operations));
+        }
+
+        let mut document = json!({
+            "openapi": "3.1.0",
+            "info": { "title": title, "version": version },
+            "paths": paths,
+        });
+        if !components.is_empty() {
+            document["components"]["schemas"] = Value::Object(components);
+        }
+        if any_bearer_auth {
+            document["components"]["securitySchemes"] = json!({
+                "bearerAuth": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            });
+        }
+        document
+    }
+}
+
+// `/projects/:id` becomes `/projects/{id}`, returning the parameter names too. Optional segments
+// are documented as if they were given
+fn openapi_path(pattern: &str) -> (String, Vec<String>) {
+    let mut params = Vec::new();
+    let segments: Vec<String> = pattern
+        .split('/')
+        .map(|segment| {
+            let name = segment
+                .strip_prefix(':')
+                .map(|name| name.trim_end_matches('?'))
+                .or_else(|| segment.strip_prefix('*').filter(|name| !name.is_empty()));
+            match name {
+                Some(name) => {
+                    params.push(name.to_string());
+                    format!("{{{}}}", name)
+                }
+                None => segment.to_string(),
+            }
+        })
+        .collect();
+    (segments.join("/"), params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Message;
+
+    impl JsonSchema for Message {
+        const NAME: Option<&'static str> = Some("Message");
+
+        fn schema() -> Value {
+            ObjectSchema::new()
+                .field::<String>("name")
+                .field::<Option<String>>("website")
+                .optional::<Vec<String>>("tags")
+                .build()
+        }
+    }
+
+    #[test]
+    fn builds_object_schemas() {
+        assert_eq!(
+            Message::schema(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string" },
+                    "website": { "type": "string" },
+                    "tags": { "type": "array", "items": { "type": "string" } },
+                },
+                "required": ["name"],
+            })
+        );
+    }
+
+    #[test]
+    fn documents_routes() {
+        let mut openapi = OpenApi::default();
+        openapi.add(
+            HttpMethod::POST,
+            "/messages",
+            RouteDoc::new("Send a message")
+                .request::<Message>()
+                .empty_response(202, "Sent")
+                .response::<HandlerError>(400, "Invalid message"),
+        );
+        let mut other = OpenApi::default();
+        other.add(
+            HttpMethod::DELETE,
+            "/messages/:id",
+            RouteDoc::new("Delete a message")
+                .bearer_auth()
+                .empty_response(204, "Deleted"),
+        );
+        openapi.merge(other);
+
+        let document = openapi.document("Test", "1.0.0");
+        let send = &document["paths"]["/messages"]["post"];
+        assert_eq!(
+            send["requestBody"]["content"]["application/json"]["schema"],
+            json!({ "$ref": "#/components/schemas/Message" })
+        );
+        assert_eq!(send["responses"]["202"], json!({ "description": "Sent" }));
+        assert_eq!(
+            document["components"]["schemas"]["Message"],
+            Message::schema()
+        );
+        assert!(document["components"]["schemas"]["Error"].is_object());
+
+        let delete = &document["paths"]["/messages/{id}"]["delete"];
+        assert_eq!(delete["parameters"][0]["name"], "id");
+        assert_eq!(delete["security"], json!([{ "bearerAuth": [] }]));
+        assert!(document["components"]["securitySchemes"]["bearerAuth"].is_object());
+    }
+
+    #[test]
+    fn converts_patterns() {
+        assert_eq!(
+            openapi_path("/files/*path"),
+            ("/files/{path}".to_string(), vec!["path".to_string()])
+        );
+        assert_eq!(
+            openapi_path("/docs/:page?"),
+            ("/docs/{page}".to_string(), vec!["page".to_string()])
+        );
+        assert_eq!(openapi_path("/users/me"), ("/users/me".to_string(), vec![]));
+    }
+}
@@ -0,0 +1,126 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::request::Request;
+use super::response::Response;
+use super::server::ListenAddress;
+
+// Hooks into the life of a server and its connections, added with Server::add_plugin, e.g. for
+// metrics or tracing. Every callback does nothing unless overridden. They run in the order the
+// plugins were added, on the connection's task, so they shouldn't block
+pub trait Plugin: Send + Sync {
+    // Once each listen address is bound. Workers sharing an address count once
+    fn on_listen(&self, _address: &ListenAddress) {}
+
+    // Before the middlewares, with client_ip and states already filled in. Anything to pick up
+    // again in on_response_ready can go in request.extensions
+    fn on_request_parsed(&self, _request: &mut Request) {}
+
+    // Just before the response is written. Requests which couldn't be read come with an empty
+    // request
+    fn on_response_ready(&self, _request: &Request, _response: &mut Response) {}
+
+    fn on_connection_closed(&self, _connection: &ClosedConnection) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct ClosedConnection {
+    // None for unix sockets
+    pub remote_addr: Option<SocketAddr>,
+    // Responses written, including rejections of requests which couldn't be read
+    pub requests: u64,
+    pub duration: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{route, routes, RequestParam, ResponseParam, Router, Server};
+
+    route!(
+        hello_handler,
+        async move |_request: RequestParam, response: ResponseParam| {
+            response.text("hello");
+            Ok(())
+        }
+    );
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Plugin for Arc<Recorder> {
+        fn on_listen(&self, address: &ListenAddress) {
+            self.0.lock().unwrap().push(format!("listen {:?}", address));
+        }
+
+        fn on_request_parsed(&self, request: &mut Request) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("parsed {}", request.uri));
+        }
+
+        fn on_response_ready(&self, request: &Request, response: &mut Response) {
+            response.add_header("X-Recorded", "yes");
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("ready {} {}", request.uri, response.status_code));
+        }
+
+        fn on_connection_closed(&self, connection: &ClosedConnection) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("closed after {}", connection.requests));
+        }
+    }
+
+    #[tokio::test]
+    async fn sees_every_stage_of_a_connection() {
+        let recorder = Arc::new(Recorder::default());
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! { GET "/" => hello_handler })
+            .unwrap();
+        server.add_plugin(recorder.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert_eq!(
+            received.matches("x-recorded: yes\r\n").count(),
+            2,
+            "{}",
+            received
+        );
+        drop(stream);
+
+        // Closed once the server's done lingering for the client
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                format!("listen Tcp({:?})", address.to_string()),
+                "parsed /".to_string(),
+                "ready / 200".to_string(),
+                "parsed /missing".to_string(),
+                "ready /missing 404".to_string(),
+                "closed after 2".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use tracing::error;
+use url::Url;
+
+use super::handler_error::HandlerError;
+use super::request::Request;
+use super::response::Response;
+use super::server::{BorrowedFuture, Middleware, Next};
+use crate::client::{self, StreamingResponse};
+
+// Forwards requests to another HTTP server and relays its response, so internal tools can be
+// served under the same domain. The request's path and query are appended to the upstream, like
+// nginx's proxy_pass without a URI, so /grafana/login goes to http://127.0.0.1:3000/grafana/login.
+// Request bodies, at most 1MB, are sent whole. Response bodies are streamed through as they
+// arrive. The upstream has the client's 10 second timeout to start responding
+
+// Headers describing a single connection, which a proxy must not pass on (RFC 9110 7.6.1)
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Hop by hop headers, plus any the Connection header names
+fn hop_by_hop_headers<'a>(connection: impl Iterator<Item = &'a String>) -> HashSet<String> {
+    HOP_BY_HOP
+        .iter()
+        .map(|name| name.to_string())
+        .chain(
+            connection
+                .flat_map(|value| value.split(','))
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty()),
+        )
+        .collect()
+}
+
+// Describe the original request to the upstream. Only taken from the client when it's a trusted
+// proxy, otherwise anyone could claim to be any IP, e.g. to get past an upstream's allowlist
+const FORWARDING_HEADERS: [&str; 4] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+];
+
+// The client's headers minus hop by hop ones, with X-Forwarded-* describing the original request.
+// Host and Content-Length are set by the HTTP client
+pub fn forwarded_headers(request: &Request) -> Vec<(String, String)> {
+    let skipped = hop_by_hop_headers(request.headers.get_all("Connection"));
+    let trusted = request.from_trusted_proxy;
+    let mut headers: Vec<(String, String)> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.to_lowercase();
+            !skipped.contains(&name)
+                && !matches!(
+                    name.as_str(),
+                    "host" | "content-length" | "x-forwarded-for" | "x-forwarded-proto"
+                )
+                && (trusted || !FORWARDING_HEADERS.contains(&name.as_str()))
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    let earlier_hops = request
+        .headers
+        .get_all("X-Forwarded-For")
+        .filter(|_| trusted)
+        .cloned();
+    let forwarded_for = earlier_hops
+        .chain(request.remote_addr.map(|addr| addr.ip().to_string()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !forwarded_for.is_empty() {
+        headers.push(("X-Forwarded-For".to_string(), forwarded_for));
+    }
+    let scheme = if request.tls { "https" } else { "http" };
+    let proto = request
+        .get_header("X-Forwarded-Proto")
+        .filter(|_| trusted)
+        .map_or(scheme, String::as_str);
+    headers.push(("X-Forwarded-Proto".to_string(), proto.to_string()));
+    let forwarded_host = trusted && request.headers.contains_key("X-Forwarded-Host");
+    if let Some(host) = request.get_header("Host").filter(|_| !forwarded_host) {
+        headers.push(("X-Forwarded-Host".to_string(), host.clone()));
+    }
+    headers
+}
+
+// The upstream with the request's path and query on the end. Built from the parsed path rather
+// than the raw target, so nothing the client sends can change the upstream's host or port
+fn upstream_url(upstream: &str, request: &Request) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(upstream)?;
+    let (target_path, query) = match request.uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request.uri.as_str(), None),
+    };
+    // request.path always ends in a slash, which the upstream may not treat the same
+    let path = match target_path.ends_with('/') {
+        true => request.path.as_str(),
+        false => request.path.strip_suffix('/').unwrap_or(&request.path),
+    };
+    let base_path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&format!("{}{}", base_path, path));
+    url.set_query(query);
+    Ok(url)
+}
+
+// Copies the upstream status, headers (minus hop by hop ones) and body stream onto the response.
+// Headers already on the response, e.g. from the security headers middleware, are replaced
+pub fn relay(upstream: StreamingResponse, response: &mut Response) {
+    let skipped = hop_by_hop_headers(
+        upstream
+            .header_list
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+            .map(|(_, value)| value),
+    );
+    // Only the upstream knows what its body is
+    response.headers.remove("Content-Type");
+    let mut seen = HashSet::new();
+    for (name, value) in upstream.header_list.iter() {
+        let lower_name = name.to_lowercase();
+        if skipped.contains(&lower_name) || lower_name == "content-length" {
+            continue;
+        }
+        if seen.insert(lower_name) {
+            response.add_header(name, value);
+        } else {
+            response.append_header(name, value);
+        }
+    }
+    response.set_status_code(upstream.status_code);
+    response.set_body_stream(upstream.body, upstream.content_length);
+    response.send();
+}
+
+// Does the forwarding for Router::proxy, ending the chain with the relayed (or 502) response
+pub fn proxy_middleware(upstream: &str) -> impl Middleware {
+    let upstream = Arc::new(upstream.to_string());
+    move |request, response| {
+        let upstream = upstream.clone();
+        Box::pin(async move {
+            let url = match upstream_url(&upstream, request) {
+                Ok(url) => url,
+                Err(e) => {
+                    error!("Invalid proxy upstream {}: {}", upstream, e);
+                    response.status(502).message("bad gateway");
+                    return Next::Stop;
+                }
+            };
+            let headers = forwarded_headers(request);
+            let header_refs: Vec<(&str, &str)> = headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            let result = client::stream_request(
+                request.method.clone(),
+                url.as_str(),
+                &header_refs,
+                request.body.as_deref(),
+            )
+            .await;
+
+            match result {
+                Ok(upstream_response) => relay(upstream_response, response),
+                Err(e) => {
+                    error!("Proxying to {} failed: {}", url, e);
+                    response.status(502).message("bad gateway");
+                }
+            }
+            Next::Stop
+        })
+    }
+}
+
+// The route handler for proxied routes. Never runs, since the proxy middleware always stops
+pub fn proxy_handler<'a>(
+    _request: &'a mut Request,
+    _response: &'a mut Response,
+) -> BorrowedFuture<'a, Result<(), HandlerError>> {
+    Box::pin(async { Ok(()) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::test::TestServer;
+    use crate::{route, routes, HttpMethod, RequestParam, ResponseParam, Router, Server};
+
+    #[test]
+    fn strips_hop_by_hop_request_headers() {
+        let mut request = Request {
+            remote_addr: Some("10.0.0.2:5000".parse().unwrap()),
+            from_trusted_proxy: true,
+            ..Request::default()
+        };
+        request.headers.insert("Host", "kblue.io");
+        request.headers.insert("Connection", "keep-alive, X-Secret");
+        request.headers.insert("X-Secret", "1");
+        request.headers.insert("Transfer-Encoding", "chunked");
+        request.headers.insert("Content-Length", "5");
+        request.headers.insert("X-Forwarded-For", "203.0.113.7");
+        request.headers.insert("Cookie", "grafana_session=abc");
+
+        let headers: HashMap<String, String> = forwarded_headers(&request).into_iter().collect();
+        assert_eq!(
+            headers.get("X-Forwarded-For").unwrap(),
+            "203.0.113.7, 10.0.0.2"
+        );
+        assert_eq!(headers.get("X-Forwarded-Proto").unwrap(), "http");
+        assert_eq!(headers.get("X-Forwarded-Host").unwrap(), "kblue.io");
+        assert!(headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("cookie")));
+        for name in [
+            "connection",
+            "x-secret",
+            "transfer-encoding",
+            "content-length",
+            "host",
+        ] {
+            assert!(
+                !headers.keys().any(|key| key.eq_ignore_ascii_case(name)),
+                "{} was forwarded",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn replaces_forwarding_headers_from_untrusted_peers() {
+        let mut request = Request {
+            remote_addr: Some("198.51.100.4:5000".parse().unwrap()),
+            tls: true,
+            ..Request::default()
+        };
+        request.headers.insert("Host", "kblue.io");
+        request.headers.insert("X-Forwarded-For", "127.0.0.1");
+        request.headers.insert("X-Forwarded-Proto", "http");
+        request.headers.insert("X-Forwarded-Host", "admin.internal");
+        request.headers.insert("Forwarded", "for=127.0.0.1");
+
+        let headers = forwarded_headers(&request);
+        let values = |name: &str| {
+            headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values("X-Forwarded-For"), ["198.51.100.4"]);
+        assert_eq!(values("X-Forwarded-Proto"), ["https"]);
+        assert_eq!(values("X-Forwarded-Host"), ["kblue.io"]);
+        assert!(values("Forwarded").is_empty());
+    }
+
+    // Echoes the forwarding headers, in two chunks
+    route!(
+        forwarded_for_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.set_status_code(200);
+            for name in ["X-Forwarded-For", "X-Forwarded-Proto"] {
+                let value = request.get_header(name).cloned().unwrap_or_default();
+                response.write_chunk(format!("{}: {}\n", name, value).as_bytes());
+            }
+            response.send();
+            Ok(())
+        }
+    );
+
+    #[tokio::test]
+    async fn spoofed_forwarding_headers_do_not_reach_the_upstream() {
+        let mut upstream = Server::builder().build();
+        upstream
+            .add_routes(routes! { GET "/grafana/*" => forwarded_for_handler })
+            .unwrap();
+        let upstream = TestServer::spawn(upstream).await;
+        let mut server = Server::builder().build();
+        server.proxy("/grafana/*", &upstream.url("")).unwrap();
+        let server = TestServer::spawn(server).await;
+
+        let spoofed = [
+            ("X-Forwarded-For", "10.0.0.1"),
+            ("X-Forwarded-Proto", "https"),
+        ];
+        let response = server
+            .request(HttpMethod::GET, "/grafana/login", &spoofed, None)
+            .await;
+        assert_eq!(response.status, 200);
+        // Relayed as it arrived, so reframed for this connection
+        assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(
+            response.text(),
+            "X-Forwarded-For: 127.0.0.1\nX-Forwarded-Proto: http\n"
+        );
+    }
+
+    #[test]
+    fn builds_upstream_urls_from_the_parsed_path() {
+        let request = |uri: &str, path: &str| Request {
+            uri: uri.to_string(),
+            path: path.to_string(),
+            ..Request::default()
+        };
+        let url = |upstream: &str, request: &Request| {
+            upstream_url(upstream, request).unwrap().to_string()
+        };
+        assert_eq!(
+            url(
+                "http://127.0.0.1:3000",
+                &request("/grafana/login?next=%2Fd&a=1", "/grafana/login/")
+            ),
+            "http://127.0.0.1:3000/grafana/login?next=%2Fd&a=1"
+        );
+        assert_eq!(
+            url(
+                "http://127.0.0.1:3000/base/",
+                &request("/grafana/", "/grafana/")
+            ),
+            "http://127.0.0.1:3000/base/grafana/"
+        );
+        // Userinfo in the raw target can't become the upstream's host
+        assert_eq!(
+            url(
+                "http://127.0.0.1:3000",
+                &request("@evil.com:8080/grafana/x", "/grafana/x/")
+            ),
+            "http://127.0.0.1:3000/grafana/x"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_targets_that_would_reach_other_hosts() {
+        let mut server = Server::builder().build();
+        // Nothing listens here, the request must never get that far
+        server.proxy("/grafana/*", "http://127.0.0.1:9").unwrap();
+        let server = TestServer::spawn(server).await;
+        let address = server.url("").trim_start_matches("http://").to_string();
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET @127.0.0.1:1/grafana/x HTTP/1.1\r\nHost: kblue.io\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+    }
+
+    route!(
+        head_handler,
+        async move |_request: RequestParam, mut response: ResponseParam| {
+            response.text("hello");
+            Ok(())
+        }
+    );
+
+    #[tokio::test]
+    async fn keeps_the_content_length_of_head_responses() {
+        let mut upstream = Server::builder().build();
+        upstream
+            .add_routes(routes! { HEAD "/grafana/*" => head_handler })
+            .unwrap();
+        let upstream = TestServer::spawn(upstream).await;
+        let mut server = Server::builder().build();
+        server.proxy("/grafana/*", &upstream.url("")).unwrap();
+        let server = TestServer::spawn(server).await;
+
+        let response = server
+            .request(HttpMethod::HEAD, "/grafana/logo.png", &[], None)
+            .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relays_upstream_responses() {
+        let (sender, body) = tokio::sync::mpsc::channel(1);
+        sender.send(Ok(b"ok".to_vec())).await.unwrap();
+        let upstream = StreamingResponse {
+            status_code: 302,
+            headers: HashMap::new(),
+            header_list: vec![
+                ("Location".to_string(), "/grafana/login".to_string()),
+                ("Set-Cookie".to_string(), "a=1".to_string()),
+                ("Set-Cookie".to_string(), "b=2".to_string()),
+                ("Keep-Alive".to_string(), "timeout=5".to_string()),
+                ("Content-Length".to_string(), "2".to_string()),
+            ],
+            content_length: Some(2),
+            body,
+        };
+        let mut response = Response::new();
+        response.add_header("Location", "/elsewhere");
+        relay(upstream, &mut response);
+
+        assert_eq!(response.status_code, 302);
+        assert!(response.should_respond());
+        assert_eq!(response.headers.get("Location").unwrap(), "/grafana/login");
+        assert_eq!(response.headers.get_all("Set-Cookie").count(), 2);
+        assert!(!response.headers.contains_key("Keep-Alive"));
+        assert!(!response.headers.contains_key("Content-Type"));
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "2");
+        let mut body = response.stream.take().unwrap();
+        assert_eq!(body.recv().await.unwrap().unwrap(), b"ok");
+    }
+}
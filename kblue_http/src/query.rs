@@ -0,0 +1,111 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+// Query string parameters in the order they were sent. A key may appear more than once,
+// e.g. `?tag=rust&tag=web`. Unlike headers, keys are case sensitive
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryMap {
+    entries: Vec<(String, String)>,
+}
+
+impl QueryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.entries.push((key.to_string(), value.to_string()));
+    }
+
+    // The first value
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(existing, _)| existing == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for QueryMap {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
+
+// A JSON object, with an array for keys given more than once
+impl Serialize for QueryMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut keys: Vec<&String> = Vec::new();
+        for (key, _) in self.entries.iter() {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for key in keys {
+            let values: Vec<&String> = self.get_all(key).collect();
+            match values.as_slice() {
+                [value] => map.serialize_entry(key, value)?,
+                values => map.serialize_entry(key, values)?,
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_repeated_keys() {
+        let query: QueryMap = url::form_urlencoded::parse(b"tag=rust&page=2&tag=web")
+            .into_owned()
+            .collect();
+        assert_eq!(query.get("tag").unwrap(), "rust");
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), ["rust", "web"]);
+        assert!(!query.contains_key("Tag"));
+        assert_eq!(
+            serde_json::to_value(&query).unwrap(),
+            serde_json::json!({ "tag": ["rust", "web"], "page": "2" })
+        );
+    }
+
+    #[test]
+    fn parses_typed_values() {
+        let request = crate::Request {
+            query: QueryMap::from_iter([
+                ("page".to_string(), "2".to_string()),
+                ("per_page".to_string(), "lots".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(request.query_as::<u32>("page").unwrap(), Some(2));
+        assert_eq!(request.query_as::<u32>("tag").unwrap(), None);
+        assert_eq!(request.query_as::<u32>("per_page").unwrap_err().status, 400);
+    }
+}
@@ -0,0 +1,164 @@
+use super::response::Response;
+
+// What a `Range: bytes=...` header asks for, given the full body length
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    // Inclusive start and end offsets
+    Partial(usize, usize),
+    // The range lies past the end of the body
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    // None for anything we don't serve partially: other units, malformed headers and multiple
+    // ranges, which would need a multipart/byteranges body. The full body is sent instead
+    pub fn parse(header: &str, len: usize) -> Option<Self> {
+        let spec = header.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            // `-500` is the last 500 bytes
+            let suffix: usize = end.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            return Some(ByteRange::Partial(len.saturating_sub(suffix), len - 1));
+        }
+
+        let start: usize = start.parse().ok()?;
+        let end: Option<usize> = if end.is_empty() {
+            None
+        } else {
+            Some(end.parse().ok()?)
+        };
+        if end.is_some_and(|end| end < start) {
+            return None;
+        }
+        if start >= len {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        let end = end.map_or(len - 1, |end| end.min(len - 1));
+        Some(ByteRange::Partial(start, end))
+    }
+}
+
+// Cuts the body down to the requested range (206), or answers 416. Only applies to successful,
+// non chunked responses which opted in with `Accept-Ranges: bytes`. `If-Range` sends the full body
+// unless it matches the response's ETag or Last-Modified, so a resumed download never splices
+// two versions of a file together
+pub fn apply_range(response: &mut Response, range: Option<&str>, if_range: Option<&str>) {
+    let Some(range) = range else {
+        return;
+    };
+    let accepts_ranges = response
+        .headers
+        .get("accept-ranges")
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"));
+    if response.status_code != 200 || response.is_chunked() || !accepts_ranges {
+        return;
+    }
+    if let Some(if_range) = if_range {
+        let validator = if if_range.trim_start().starts_with("W/") || if_range.contains('"') {
+            response.headers.get("etag")
+        } else {
+            response.headers.get("last-modified")
+        };
+        if validator.map(|value| value.as_str()) != Some(if_range.trim()) {
+            return;
+        }
+    }
+
+    let len = response.get_body_len();
+    match ByteRange::parse(range, len) {
+        None => {}
+        Some(ByteRange::Unsatisfiable) => {
+            response.set_status_code(416);
+            response.add_header("Content-Range", &format!("bytes */{}", len));
+            response.body = None;
+        }
+        Some(ByteRange::Partial(start, end)) => {
+            let body = response.body.take().unwrap_or_default();
+            response.set_status_code(206);
+            response.add_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+            response.body = Some(body[start..=end].to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        let cases = [
+            ("bytes=0-99", 1000, Some(ByteRange::Partial(0, 99))),
+            ("bytes=500-", 1000, Some(ByteRange::Partial(500, 999))),
+            ("bytes=-100", 1000, Some(ByteRange::Partial(900, 999))),
+            ("bytes=-5000", 1000, Some(ByteRange::Partial(0, 999))),
+            ("bytes=900-5000", 1000, Some(ByteRange::Partial(900, 999))),
+            ("bytes=1000-", 1000, Some(ByteRange::Unsatisfiable)),
+            ("bytes=-0", 1000, Some(ByteRange::Unsatisfiable)),
+            ("bytes=0-", 0, Some(ByteRange::Unsatisfiable)),
+            ("bytes=5-1", 1000, None),
+            ("bytes=0-1,5-6", 1000, None),
+            ("items=0-1", 1000, None),
+            ("bytes=a-b", 1000, None),
+        ];
+        for (header, len, expected) in cases {
+            assert_eq!(ByteRange::parse(header, len), expected, "{}", header);
+        }
+    }
+
+    fn file_response() -> Response {
+        let mut response = Response::new();
+        response.add_header("Accept-Ranges", "bytes");
+        response.add_header("ETag", "\"v1\"");
+        response.set_body(b"0123456789".to_vec());
+        response
+    }
+
+    #[test]
+    fn serves_partial_content() {
+        let mut response = file_response();
+        apply_range(&mut response, Some("bytes=2-4"), None);
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.body.as_deref(), Some(&b"234"[..]));
+        assert_eq!(
+            response.headers.get("content-range").unwrap(),
+            "bytes 2-4/10"
+        );
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        let mut response = file_response();
+        apply_range(&mut response, Some("bytes=20-"), None);
+        assert_eq!(response.status_code, 416);
+        assert_eq!(response.headers.get("content-range").unwrap(), "bytes */10");
+    }
+
+    #[test]
+    fn stale_if_range_sends_everything() {
+        let mut response = file_response();
+        apply_range(&mut response, Some("bytes=2-4"), Some("\"v0\""));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.get_body_len(), 10);
+
+        let mut response = file_response();
+        apply_range(&mut response, Some("bytes=2-4"), Some("\"v1\""));
+        assert_eq!(response.status_code, 206);
+    }
+
+    #[test]
+    fn ignored_without_accept_ranges() {
+        let mut response = Response::new();
+        response.set_body(b"0123456789".to_vec());
+        apply_range(&mut response, Some("bytes=2-4"), None);
+        assert_eq!(response.status_code, 200);
+    }
+}
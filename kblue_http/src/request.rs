@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use tracing::debug;
+
+use super::constants::HttpMethod;
+use super::extensions::Extensions;
+use super::handler_error::HandlerError;
+use super::headers::HeaderMap;
+use super::json_error::JsonError;
+use super::multipart::Multipart;
+use super::query::QueryMap;
+use super::state::States;
+use super::typed_headers::TypedHeaders;
+
+#[derive(Clone, Default)]
+pub struct Request {
+    pub method: HttpMethod,
+    pub path: String,
+    // Request target exactly as sent, including the query string
+    pub uri: String,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+    pub params: HashMap<String, String>,
+    pub query: QueryMap,
+    pub version: String,
+    // Set by the server once the request has been parsed
+    pub states: States,
+    // Peer address of the connection, None for unix sockets
+    pub remote_addr: Option<SocketAddr>,
+    // The real client, taking trusted proxies into account. None if it can't be known
+    pub client_ip: Option<IpAddr>,
+    // Whether the peer is a trusted proxy, whose forwarding headers are believed
+    pub from_trusted_proxy: bool,
+    // Whether the connection is TLS terminated by this server, rather than by a proxy in front
+    pub tls: bool,
+    // Set by middlewares for whatever runs after them, e.g. the authenticated Principal
+    pub extensions: Extensions,
+    // Backs the typed header accessors, e.g. content_type() and authorization()
+    pub typed_headers: TypedHeaders,
+}
+
+impl Request {
+    // Invalid UTF-8 becomes U+FFFD rather than panicking
+    pub fn get_body_as_string(&self) -> String {
+        String::from_utf8_lossy(self.body.as_deref().unwrap_or_default()).into_owned()
+    }
+
+    // State registered with Server::with_state, looked up by type
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.states.get::<T>()
+    }
+
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers.get(name)
+    }
+
+    // Value of a cookie sent by the client
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.get_header("cookie")?
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    // Every value of a repeated query parameter, e.g. ["rust", "web"] for `?tag=rust&tag=web`
+    pub fn query_all(&self, key: &str) -> Vec<&str> {
+        self.query
+            .iter()
+            .filter(|(existing, _)| *existing == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    // The first value of a query parameter parsed as T, e.g. `request.query_as::<u32>("page")?`.
+    // Ok(None) if it wasn't given, a 400 if it doesn't parse
+    pub fn query_as<T: FromStr>(&self, key: &str) -> Result<Option<T>, HandlerError> {
+        self.query
+            .get(key)
+            .map(|value| {
+                value.parse::<T>().map_err(|_| {
+                    HandlerError::bad_request(&format!("invalid value for {}: {}", key, value))
+                })
+            })
+            .transpose()
+    }
+
+    pub fn get_body_as_json<T: DeserializeOwned>(&self) -> Option<T> {
+        match self.parse_json() {
+            Ok(json_body) => Some(json_body),
+            Err(e) => {
+                debug!("Could not deserialise JSON body: {}", e);
+                None
+            }
+        }
+    }
+
+    // Like get_body_as_json, but says which field was wrong. Pair with Response::bad_request
+    pub fn parse_json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        let Some(body) = self.body.as_deref().filter(|body| !body.is_empty()) else {
+            return Err(JsonError::new("request body is empty"));
+        };
+        let mut deserializer = serde_json::Deserializer::from_slice(body);
+        let value: T = serde_path_to_error::deserialize(&mut deserializer)
+            .map_err(JsonError::from_path_error)?;
+        // Trailing data after the JSON value
+        deserializer
+            .end()
+            .map_err(|e| JsonError::new(&format!("invalid JSON: {}", e)))?;
+        Ok(value)
+    }
+
+    // Fields of an `application/x-www-form-urlencoded` body, None for any other content type
+    pub fn get_body_as_form(&self) -> Option<HashMap<String, String>> {
+        if self.content_type() != Some("application/x-www-form-urlencoded") {
+            return None;
+        }
+        let body = self.body.as_deref().unwrap_or_default();
+        Some(url::form_urlencoded::parse(body).into_owned().collect())
+    }
+
+    // Like parse_json, for urlencoded forms. Every value is a string, so T's fields should be too
+    pub fn parse_form<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        let form = self
+            .get_body_as_form()
+            .ok_or_else(|| JsonError::new("expected a urlencoded form body"))?;
+        let value: serde_json::Map<String, serde_json::Value> = form
+            .into_iter()
+            .map(|(key, value)| (key, serde_json::Value::String(value)))
+            .collect();
+        serde_path_to_error::deserialize(serde_json::Value::Object(value))
+            .map_err(JsonError::from_path_error)
+    }
+
+    // Parts of a `multipart/form-data` body, None for any other content type
+    pub fn multipart(&self) -> Option<Multipart<'_>> {
+        let boundary = Multipart::boundary(self.get_header("content-type")?)?;
+        Some(Multipart::new(
+            self.body.as_deref().unwrap_or_default(),
+            &boundary,
+        ))
+    }
+
+    // HTTP/1.1 connections are persistent unless the client asks to close, HTTP/1.0 ones are the opposite
+    pub fn keep_alive(&self) -> bool {
+        let connection = self
+            .get_header("connection")
+            .map(|value| value.to_lowercase());
+        match connection.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => self.version == "1",
+        }
+    }
+
+    // Whether the client advertised `TE: trailers`, i.e. it will accept trailer fields after a chunked body
+    pub fn accepts_trailers(&self) -> bool {
+        self.headers
+            .get_all("te")
+            .flat_map(|value| value.split(','))
+            .any(|coding| {
+                let coding = coding.split(';').next().unwrap_or_default().trim();
+                coding.eq_ignore_ascii_case("trailers")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_trailers_from_te() {
+        let accepts = |values: &[&str]| {
+            let mut request = Request::default();
+            for value in values {
+                request.headers.append("TE", value);
+            }
+            request.accepts_trailers()
+        };
+        assert!(accepts(&["trailers"]));
+        assert!(accepts(&["gzip;q=0.5, Trailers"]));
+        assert!(accepts(&["gzip", "trailers"]));
+        assert!(!accepts(&["gzip"]));
+        assert!(!accepts(&[]));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use bytes::{Buf, BytesMut};
+use url::Url;
+
+use super::config::RequestLimits;
+use super::constants::HttpMethod;
+use super::extensions::Extensions;
+use super::headers::HeaderMap;
+use super::query::QueryMap;
+use super::request::Request;
+use super::state::States;
+use super::typed_headers::TypedHeaders;
+use super::util::normalise_path;
+use super::{ONE_KB, ONE_MB};
+
+pub(super) enum RequestParseError {
+    // More than 1MB without the head ending
+    TooLarge,
+    // The head is fine but its Content-Length is over 1MB, so the body isn't waited for
+    BodyTooLarge(Box<Request>),
+    // Too many headers or one too big, see RequestLimits
+    HeadersTooLarge,
+    UriTooLong,
+    // Chunked (or otherwise encoded) request bodies aren't supported
+    UnsupportedTransferEncoding,
+    Malformed(String),
+}
+
+impl<E: Display> From<E> for RequestParseError {
+    fn from(e: E) -> Self {
+        RequestParseError::Malformed(e.to_string())
+    }
+}
+
+// A parsed head waiting for the rest of its body
+struct PendingRequest {
+    request: Request,
+    head_len: usize,
+    // Head plus Content-Length bytes of body
+    request_len: usize,
+}
+
+// Bytes read from a connection which haven't been consumed by a request yet. Clients may pipeline
+// requests, so this can hold the start of the next one. The head is only parsed once all of it has
+// arrived, and only once, however many reads the body takes
+pub(super) struct RequestReader {
+    buffer: BytesMut,
+    // How far the buffer has been searched for the end of the head
+    scanned: usize,
+    pending: Option<PendingRequest>,
+    limits: RequestLimits,
+}
+
+impl RequestReader {
+    pub fn new(limits: RequestLimits) -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(8 * ONE_KB),
+            scanned: 0,
+            pending: None,
+            limits,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    // Whatever arrived after the last request, for a connection taken over by an upgrade
+    pub fn take_buffered(&mut self) -> BytesMut {
+        self.scanned = 0;
+        self.pending = None;
+        self.buffer.split()
+    }
+
+    // Space for the next read. Reuses the memory of requests already taken off the front
+    pub fn read_buffer(&mut self) -> &mut BytesMut {
+        self.buffer.reserve(8 * ONE_KB);
+        &mut self.buffer
+    }
+
+    // The next complete request, or None until more bytes arrive
+    pub fn next_request(&mut self) -> Result<Option<Request>, RequestParseError> {
+        if self.pending.is_none() {
+            let Some(head_end) = self.find_head_end() else {
+                // No need to wait for the rest of a head which is already too long
+                if !self.buffer.contains(&b'\n') && self.buffer.len() > self.limits.max_uri_len {
+                    return Err(RequestParseError::UriTooLong);
+                }
+                if self.buffer.len() > self.limits.max_head_len() {
+                    return Err(RequestParseError::HeadersTooLarge);
+                }
+                if self.buffer.len() > ONE_MB {
+                    return Err(RequestParseError::TooLarge);
+                }
+                return Ok(None);
+            };
+            let (request, head_len, content_length) =
+                parse_head(&self.buffer[..head_end], &self.limits)?;
+            let request_len = head_len + content_length;
+            // Decide on the declared size, rather than waiting for a too large body to arrive
+            if request_len > ONE_MB {
+                return Err(RequestParseError::BodyTooLarge(Box::new(request)));
+            }
+            self.pending = Some(PendingRequest {
+                request,
+                head_len,
+                request_len,
+            });
+        }
+
+        let Some(pending) = self
+            .pending
+            .take_if(|pending| self.buffer.len() >= pending.request_len)
+        else {
+            return Ok(None);
+        };
+        let mut request = pending.request;
+        self.buffer.advance(pending.head_len);
+        let body = self.buffer.split_to(pending.request_len - pending.head_len);
+        if !body.is_empty() {
+            request.body = Some(body.to_vec());
+        }
+        self.scanned = 0;
+        Ok(Some(request))
+    }
+
+    // Just past the blank line ending the head. Lines may end in a bare \n, which httparse accepts
+    fn find_head_end(&mut self) -> Option<usize> {
+        // The end may straddle the bytes already searched
+        let from = self.scanned.saturating_sub(3);
+        let found = self.buffer[from..]
+            .windows(2)
+            .enumerate()
+            .find_map(|(index, window)| match window {
+                b"\n\n" => Some(from + index + 2),
+                b"\n\r" if self.buffer.get(from + index + 2) == Some(&b'\n') => {
+                    Some(from + index + 3)
+                }
+                _ => None,
+            });
+        if found.is_none() {
+            self.scanned = self.buffer.len();
+        }
+        found
+    }
+}
+
+// Parses a complete head, returning the request without its body, the head's length and how many
+// bytes of body follow it
+fn parse_head(
+    head: &[u8],
+    limits: &RequestLimits,
+) -> Result<(Request, usize, usize), RequestParseError> {
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+    let mut req = httparse::Request::new(&mut headers);
+
+    let head_len = match req.parse(head) {
+        Ok(httparse::Status::Complete(head_len)) => head_len,
+        Ok(httparse::Status::Partial) => return Err("Incomplete request head".into()),
+        Err(httparse::Error::TooManyHeaders) => return Err(RequestParseError::HeadersTooLarge),
+        Err(e) => return Err(e.into()),
+    };
+
+    let method: HttpMethod = req.method.ok_or("Method not found")?.parse()?;
+    let url_str = req.path.ok_or("URI not found")?.to_string();
+    let version = req.version.ok_or("Version not found")?.to_string();
+    if url_str.len() > limits.max_uri_len {
+        return Err(RequestParseError::UriTooLong);
+    }
+    // Only origin-form, e.g. /a?b. Anything else, like @evil.com/a, would move the host of the
+    // URLs built from it
+    if !url_str.starts_with('/') {
+        return Err("Request target must start with /".into());
+    }
+
+    let mut headers_map = HeaderMap::new();
+    for header in req.headers.iter() {
+        if header.name.len() + header.value.len() > limits.max_header_size {
+            return Err(RequestParseError::HeadersTooLarge);
+        }
+        headers_map.append(header.name, std::str::from_utf8(header.value)?);
+    }
+
+    let content_length = body_length(&headers_map)?;
+
+    let mut url = Url::parse(format!("https://a.b{}", &url_str).as_str())
+        .map_err(|_| "Failed to parse URL")?;
+    let query: QueryMap = url.query_pairs().into_owned().collect();
+    url.set_query(None);
+
+    let path = normalise_path(url.path());
+    Ok((
+        Request {
+            path,
+            uri: url_str,
+            version,
+            body: None,
+            headers: headers_map,
+            method,
+            params: HashMap::new(),
+            query,
+            states: States::default(),
+            remote_addr: None,
+            client_ip: None,
+            from_trusted_proxy: false,
+            tls: false,
+            extensions: Extensions::default(),
+            typed_headers: TypedHeaders::default(),
+        },
+        head_len,
+        content_length,
+    ))
+}
+
+// The framing has to be unambiguous. A proxy in front of us picking a different end for the body
+// than we do would let a request smuggled inside it through, unchecked by the proxy
+fn body_length(headers: &HeaderMap) -> Result<usize, RequestParseError> {
+    if headers.contains_key("transfer-encoding") {
+        if headers.contains_key("content-length") {
+            return Err("Both Content-Length and Transfer-Encoding".into());
+        }
+        return Err(RequestParseError::UnsupportedTransferEncoding);
+    }
+
+    let mut content_length = None;
+    // Repeats are only allowed if they agree, whether as separate headers or a list
+    for value in headers
+        .get_all("content-length")
+        .flat_map(|value| value.split(','))
+    {
+        let value = value.trim();
+        // parse would also accept a leading +
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err("Invalid Content-Length".into());
+        }
+        let length = value
+            .parse::<usize>()
+            .map_err(|_| "Invalid Content-Length")?;
+        if content_length.is_some_and(|existing| existing != length) {
+            return Err("Conflicting Content-Length headers".into());
+        }
+        content_length = Some(length);
+    }
+    Ok(content_length.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(reader: &mut RequestReader, bytes: &[u8]) {
+        reader.read_buffer().extend_from_slice(bytes);
+    }
+
+    #[test]
+    fn waits_for_the_whole_request() {
+        let mut reader = RequestReader::new(RequestLimits::default());
+        feed(&mut reader, b"POST /messages HTTP/1.1\r\nContent-");
+        assert!(reader.next_request().ok().unwrap().is_none());
+        feed(&mut reader, b"Length: 5\r\n\r\nhel");
+        assert!(reader.next_request().ok().unwrap().is_none());
+        feed(&mut reader, b"lo");
+        let request = reader.next_request().ok().unwrap().unwrap();
+        assert_eq!(request.path, "/messages/");
+        assert_eq!(request.body.as_deref(), Some(&b"hello"[..]));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn splits_pipelined_requests() {
+        let mut reader = RequestReader::new(RequestLimits::default());
+        feed(
+            &mut reader,
+            b"GET /a?x=1 HTTP/1.1\n\nGET /b HTTP/1.1\r\nHost: kblue.io\r\n\r\nGET /c",
+        );
+        let first = reader.next_request().ok().unwrap().unwrap();
+        assert_eq!(first.query.get("x").unwrap(), "1");
+        assert_eq!(first.body, None);
+        let second = reader.next_request().ok().unwrap().unwrap();
+        assert_eq!(second.get_header("host").unwrap(), "kblue.io");
+        assert!(reader.next_request().ok().unwrap().is_none());
+        assert!(!reader.is_empty());
+    }
+
+    #[test]
+    fn rejects_targets_that_are_not_origin_form() {
+        for target in [
+            "@evil.com:8080/grafana/x",
+            "http://evil.com/",
+            "*",
+            "evil.com",
+        ] {
+            let mut reader = RequestReader::new(RequestLimits::default());
+            feed(
+                &mut reader,
+                format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes(),
+            );
+            assert!(
+                matches!(reader.next_request(), Err(RequestParseError::Malformed(_))),
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_requests() {
+        let mut reader = RequestReader::new(RequestLimits::default());
+        feed(
+            &mut reader,
+            format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", ONE_MB).as_bytes(),
+        );
+        let Err(RequestParseError::BodyTooLarge(request)) = reader.next_request() else {
+            panic!("a body over 1MB is rejected before it arrives");
+        };
+        assert_eq!(request.path, "/");
+
+        // A head that never ends, with limits that would allow it
+        let mut reader = RequestReader::new(RequestLimits {
+            max_header_size: 2 * ONE_MB,
+            ..RequestLimits::default()
+        });
+        feed(&mut reader, b"GET / HTTP/1.1\r\nX-Filler: ");
+        feed(&mut reader, &vec![b'a'; ONE_MB]);
+        assert!(matches!(
+            reader.next_request(),
+            Err(RequestParseError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn enforces_head_limits() {
+        let limits = RequestLimits {
+            max_headers: 2,
+            max_header_size: 16,
+            max_uri_len: 8,
+        };
+        let parse = |bytes: &[u8]| {
+            let mut reader = RequestReader::new(limits.clone());
+            feed(&mut reader, bytes);
+            reader.next_request()
+        };
+        assert!(matches!(
+            parse(b"GET /a HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n"),
+            Ok(Some(_))
+        ));
+        assert!(matches!(
+            parse(b"GET /a HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Err(RequestParseError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            parse(b"GET /a HTTP/1.1\r\nCookie: 0123456789ab\r\n\r\n"),
+            Err(RequestParseError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            parse(b"GET /abcdefgh HTTP/1.1\r\n\r\n"),
+            Err(RequestParseError::UriTooLong)
+        ));
+        // Before the request line has even finished arriving
+        assert!(matches!(
+            parse(b"GET /abcdefghijklmnopqrstuvwxyz0123456789"),
+            Err(RequestParseError::UriTooLong)
+        ));
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        let parse = |bytes: &[u8]| {
+            let mut reader = RequestReader::new(RequestLimits::default());
+            feed(&mut reader, bytes);
+            reader.next_request()
+        };
+        let malformed = |bytes: &[u8]| matches!(parse(bytes), Err(RequestParseError::Malformed(_)));
+
+        assert!(malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\nhello"
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"),
+            Err(RequestParseError::UnsupportedTransferEncoding)
+        ));
+        assert!(malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!"
+        ));
+        assert!(malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\nhello!"
+        ));
+        assert!(malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello"
+        ));
+        // Obsolete line folding
+        assert!(malformed(
+            b"GET / HTTP/1.1\r\nX-Folded: a\r\n b\r\nContent-Length: 0\r\n\r\n"
+        ));
+
+        // Repeats which agree are fine
+        let request =
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello")
+                .ok()
+                .unwrap()
+                .unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"hello"[..]));
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::format::strftime::StrftimeItems;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use super::handler_error::HandlerError;
+use super::headers::HeaderMap;
+use super::json_error::JsonError;
+use super::server::OnUpgrade;
+use super::status::get_status_text;
+
+// A body written out as it arrives, after the head, e.g. relayed from a proxied upstream. An Err
+// cuts the response short, closing the connection so the client can tell it's incomplete
+pub type BodyStream = mpsc::Receiver<std::io::Result<Vec<u8>>>;
+
+// Trailer fields for a BodyStream, sent once the whole body has been, e.g. a digest computed over
+// it while streaming. See set_stream_trailers
+pub type StreamTrailers = oneshot::Receiver<HashMap<String, String>>;
+
+// Fields a recipient has to find in the head, so may not be sent as trailers (RFC 9110 6.5.1)
+const FORBIDDEN_TRAILERS: [&str; 28] = [
+    "age",
+    "authorization",
+    "cache-control",
+    "connection",
+    "content-encoding",
+    "content-length",
+    "content-range",
+    "content-type",
+    "cookie",
+    "date",
+    "expect",
+    "expires",
+    "host",
+    "if-match",
+    "if-modified-since",
+    "if-none-match",
+    "if-unmodified-since",
+    "keep-alive",
+    "location",
+    "max-forwards",
+    "proxy-authenticate",
+    "range",
+    "retry-after",
+    "set-cookie",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "www-authenticate",
+];
+
+pub struct Response {
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+    pub status_code: u16,
+    pub status_text: String,
+    pub chunks: Option<Vec<Vec<u8>>>,
+    pub trailers: HashMap<String, String>,
+    pub stream: Option<BodyStream>,
+    pub stream_trailers: Option<StreamTrailers>,
+    // Takes the connection over once a 101 has been written, e.g. for WebSocketUpgrade
+    pub(crate) on_upgrade: Option<OnUpgrade>,
+    _should_respond: bool,
+    _fall_through: bool,
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Response {
+    // PUBLIC
+    pub fn new() -> Self {
+        Self {
+            headers: Response::get_default_headers(),
+            body: None,
+            status_code: 200,
+            status_text: get_status_text(200).to_owned(),
+            chunks: None,
+            trailers: HashMap::new(),
+            stream: None,
+            stream_trailers: None,
+            on_upgrade: None,
+            _should_respond: false,
+            _fall_through: false,
+        }
+    }
+    // Invalid UTF-8, e.g. in an image, becomes U+FFFD rather than panicking
+    pub fn get_body_as_string(&self) -> String {
+        String::from_utf8_lossy(self.get_body_as_bytes()).into_owned()
+    }
+    pub fn get_body_as_bytes(&self) -> &[u8] {
+        self.body.as_deref().unwrap_or_default()
+    }
+    // Size of the body payload, excluding any chunked framing
+    pub fn get_body_len(&self) -> usize {
+        match &self.chunks {
+            Some(chunks) => chunks.iter().map(|chunk| chunk.len()).sum(),
+            None => self.body.as_ref().map_or(0, |body| body.len()),
+        }
+    }
+    pub fn set_status_code(&mut self, code: u16) {
+        self.status_code = code;
+        self.status_text = get_status_text(code).to_owned();
+    }
+    pub fn set_body(&mut self, data: Vec<u8>) {
+        self.body = Some(data);
+    }
+    // A binary body whose type isn't known up front, e.g. an upload being served back. The
+    // Content-Type is sniffed from its first bytes, replacing the default JSON one. Use bytes()
+    // when the type is known
+    pub fn set_body_bytes(&mut self, data: Vec<u8>) -> &mut Self {
+        let content_type = sniff_media_type(&data);
+        self.with_body(content_type, data)
+    }
+    pub fn set_body_string(&mut self, data: String) {
+        self.body = Some(data.into_bytes());
+    }
+    pub fn set_body_str(&mut self, data: &str) {
+        self.body = Some(data.as_bytes().to_vec());
+    }
+    // 400 with the reasons a body was rejected, e.g. from Request::parse_json
+    pub fn bad_request(&mut self, error: &JsonError) {
+        self.status(400)
+            .json(error)
+            .expect("JsonError always serialises");
+    }
+
+    // BUILDER
+    // Chainable, e.g. `response.status(404).message("not found")`
+    pub fn status(&mut self, code: u16) -> &mut Self {
+        self.set_status_code(code);
+        self
+    }
+    pub fn json<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<&mut Self, serde_json::Error> {
+        let body = serde_json::to_vec(value)?;
+        Ok(self.with_body("application/json", body))
+    }
+    // `{"message": ...}`, the body every error response uses
+    pub fn message(&mut self, message: &str) -> &mut Self {
+        let body = json!({ "message": message }).to_string();
+        self.with_body("application/json", body.into_bytes())
+    }
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.with_body("text/plain; charset=utf-8", text.as_bytes().to_vec())
+    }
+    // e.g. a file, with its media type
+    pub fn bytes(&mut self, content_type: &str, body: Vec<u8>) -> &mut Self {
+        self.with_body(content_type, body)
+    }
+    pub fn html(&mut self, html: &str) -> &mut Self {
+        self.with_body("text/html; charset=utf-8", html.as_bytes().to_vec())
+    }
+    // Replaces any existing values of the header
+    pub fn add_header(&mut self, key: &str, value: &str) {
+        self.headers.insert(key, value);
+    }
+    // For headers which may be repeated, e.g. `Set-Cookie`
+    pub fn append_header(&mut self, key: &str, value: &str) {
+        self.headers.append(key, value);
+    }
+    // Tells caches the response depends on a request header, e.g. Origin
+    pub fn add_vary(&mut self, header: &str) {
+        let already_varies = self
+            .headers
+            .get_all("Vary")
+            .flat_map(|vary| vary.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(header));
+        if !already_varies {
+            self.headers.append("Vary", header);
+        }
+    }
+    // Send the response and stop propogating routes / middleware
+    pub fn send(&mut self) {
+        self._should_respond = true;
+    }
+    pub fn should_respond(&self) -> bool {
+        self._should_respond
+    }
+    // From a route handler, hand the request on to the next matching route (or a 404) instead
+    // of responding. Otherwise the first handler to run ends the routing, sent or not
+    pub fn next(&mut self) {
+        self._fall_through = true;
+    }
+    // Whether the handler called next(), clearing it for the next route
+    pub fn take_fall_through(&mut self) -> bool {
+        std::mem::take(&mut self._fall_through)
+    }
+
+    // STREAMING
+    // Switch to `transfer-encoding: chunked`. Any body set with set_body* becomes the first chunk
+    pub fn start_chunked(&mut self) {
+        if self.chunks.is_some() {
+            return;
+        }
+        let mut chunks = Vec::new();
+        if let Some(body) = self.body.take() {
+            chunks.push(body);
+        }
+        self.chunks = Some(chunks);
+        // Set by the body builders, and a response can't be framed both ways
+        self.headers.remove("Content-Length");
+        self.add_header("Transfer-Encoding", "chunked");
+    }
+    pub fn is_chunked(&self) -> bool {
+        self.chunks.is_some()
+    }
+    pub fn write_chunk(&mut self, data: &[u8]) {
+        self.start_chunked();
+        if let Some(chunks) = self.chunks.as_mut() {
+            // A zero length chunk would terminate the body early
+            if !data.is_empty() {
+                chunks.push(data.to_vec());
+            }
+        }
+    }
+    // Replaces any other body. Sent as is with its length known, otherwise chunked
+    pub fn set_body_stream(&mut self, stream: BodyStream, content_length: Option<usize>) {
+        self.body = None;
+        self.chunks = None;
+        match content_length {
+            Some(content_length) => {
+                self.headers.remove("Transfer-Encoding");
+                self.add_header("Content-Length", &content_length.to_string());
+            }
+            None => {
+                self.headers.remove("Content-Length");
+                self.add_header("Transfer-Encoding", "chunked");
+            }
+        }
+        self.stream = Some(stream);
+    }
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+    // Declare a trailer field up front in the `Trailer` header, so its value can be computed while
+    // streaming. Fields needed to handle the head, e.g. Content-Length, are refused
+    pub fn announce_trailer(&mut self, key: &str) -> Result<(), HandlerError> {
+        let key = key.to_lowercase();
+        if FORBIDDEN_TRAILERS.contains(&key.as_str()) {
+            return Err(HandlerError::internal(&format!(
+                "{} can't be sent as a trailer",
+                key
+            )));
+        }
+        let mut announced = self.announced_trailers();
+        if !announced.contains(&key) {
+            announced.push(key);
+        }
+        self.add_header("Trailer", &announced.join(", "));
+        Ok(())
+    }
+    // Lower case, as listed in the Trailer header
+    pub fn announced_trailers(&self) -> Vec<String> {
+        self.headers
+            .get_all("Trailer")
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+    // Trailers are only emitted after a chunked body, and only to clients that sent `TE: trailers`
+    pub fn set_trailer(&mut self, key: &str, value: &str) -> Result<(), HandlerError> {
+        self.announce_trailer(key)?;
+        self.start_chunked();
+        self.trailers.insert(key.to_lowercase(), value.to_string());
+        Ok(())
+    }
+    // Trailers for a chunked set_body_stream body, announced by `names` now and sent by the
+    // stream's producer once it's done. Only announced fields are written, and only to clients
+    // that sent `TE: trailers`. Dropping the sender ends the body without them
+    pub fn set_stream_trailers(
+        &mut self,
+        names: &[&str],
+        trailers: StreamTrailers,
+    ) -> Result<(), HandlerError> {
+        for name in names {
+            self.announce_trailer(name)?;
+        }
+        self.stream_trailers = Some(trailers);
+        Ok(())
+    }
+    // Serialise chunks, the last-chunk and (optionally) the trailer section
+    pub fn get_chunked_body_as_bytes(&self, include_trailers: bool) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in self.chunks.iter().flatten() {
+            body.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            body.extend_from_slice(chunk);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"0\r\n");
+        if include_trailers {
+            for (key, value) in self.trailers.iter() {
+                body.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+            }
+        }
+        body.extend_from_slice(b"\r\n");
+        body
+    }
+
+    // PRIVATE
+    fn with_body(&mut self, content_type: &str, body: Vec<u8>) -> &mut Self {
+        self.add_header("Content-Type", content_type);
+        self.add_header("Content-Length", &body.len().to_string());
+        self.body = Some(body);
+        self
+    }
+    fn get_default_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json");
+        headers
+    }
+}
+
+// For the Date header, added as the response is written. Every response needs one but it only
+// changes once a second, so the formatted value is kept until then
+pub(crate) fn http_date() -> String {
+    static CACHED: Mutex<(i64, String)> = Mutex::new((i64::MIN, String::new()));
+    let now = Utc::now();
+    let mut cached = CACHED.lock().unwrap();
+    if cached.0 != now.timestamp() {
+        let format = StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT");
+        *cached = (now.timestamp(), now.format_with_items(format).to_string());
+    }
+    cached.1.clone()
+}
+
+// Recognises the binary formats the site serves by their magic numbers
+fn sniff_media_type(data: &[u8]) -> &'static str {
+    const SIGNATURES: [(&[u8], &str); 5] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF8", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x00\x00\x01\x00", "image/x-icon"),
+    ];
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+        .map_or("application/octet-stream", |(_, media_type)| media_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builders_set_body_and_content_headers_together() {
+        let mut response = Response::new();
+        response
+            .status(404)
+            .json(&json!({ "message": "not found" }))
+            .unwrap();
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response.get_body_as_string(), r#"{"message":"not found"}"#);
+        assert_eq!(
+            response.headers.get("content-type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(response.headers.get("content-length").unwrap(), "23");
+
+        response.html("<p>hi</p>");
+        assert_eq!(
+            response.headers.get("content-type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers.get("content-length").unwrap(), "9");
+    }
+
+    #[test]
+    fn chunking_replaces_the_content_length() {
+        let mut response = Response::new();
+        response.text("hello ");
+        response.write_chunk(b"kyle");
+        assert!(!response.headers.contains_key("content-length"));
+        assert_eq!(
+            response.headers.get("transfer-encoding").unwrap(),
+            "chunked"
+        );
+        assert_eq!(response.get_body_len(), 10);
+    }
+
+    #[test]
+    fn refuses_trailers_the_head_needs() {
+        let mut response = Response::new();
+        for name in ["Content-Length", "transfer-encoding", "Host", "Trailer"] {
+            assert!(response.set_trailer(name, "1").is_err(), "{}", name);
+        }
+        assert!(!response.is_chunked());
+        assert!(!response.headers.contains_key("trailer"));
+
+        response.set_trailer("Digest", "sha-256=abc").unwrap();
+        response.announce_trailer("Server-Timing").unwrap();
+        assert_eq!(
+            response.headers.get("trailer").unwrap(),
+            "digest, server-timing"
+        );
+    }
+
+    #[test]
+    fn binary_bodies_get_a_matching_content_type() {
+        let mut response = Response::new();
+        response.set_body_bytes(b"\x89PNG\r\n\x1a\n\x00\xff".to_vec());
+        assert_eq!(response.headers.get("content-type").unwrap(), "image/png");
+        assert_eq!(response.headers.get("content-length").unwrap(), "10");
+        assert_eq!(response.get_body_as_bytes().len(), 10);
+        assert!(response.get_body_as_string().contains('\u{fffd}'));
+
+        response.set_body_bytes(vec![0, 1, 2]);
+        assert_eq!(
+            response.headers.get("content-type").unwrap(),
+            "application/octet-stream"
+        );
+    }
+}
@@ -0,0 +1,580 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use regex::Regex;
+
+use super::constants::HttpMethod;
+use super::openapi::RouteDoc;
+use super::proxy::{proxy_handler, proxy_middleware};
+use super::server::{Middleware, MiddlewareFunc, RouteHandler, RouteHandlerFunc};
+use super::util::is_param_name;
+
+// Two routes with the same method whose patterns match exactly the same paths,
+// e.g. `/users/:id` and `/users/:user_id`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateRouteError {
+    pub method: HttpMethod,
+    pub path: String,
+    pub existing_path: String,
+}
+
+impl Display for DuplicateRouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path == self.existing_path {
+            write!(f, "Route {} {} is registered twice", self.method, self.path)
+        } else {
+            write!(
+                f,
+                "Route {} {} conflicts with {} {}",
+                self.method, self.path, self.method, self.existing_path
+            )
+        }
+    }
+}
+
+impl Error for DuplicateRouteError {}
+
+// Anything routes can be registered on, i.e. the Server itself or a ScopedRouter
+pub trait Router {
+    // e.g. protect admin routes with an auth middleware without it running for public routes
+    fn route_with_middleware(
+        &mut self,
+        method: HttpMethod,
+        path: &str,
+        middlewares: &[MiddlewareFunc],
+        handler: RouteHandlerFunc,
+    ) -> Result<(), DuplicateRouteError>;
+
+    // Describes a route for the OpenAPI document, see Server::openapi. The path is the route's
+    // pattern, relative to the scope like when registering it
+    fn document(&mut self, method: HttpMethod, path: &str, doc: RouteDoc);
+
+    fn route(
+        &mut self,
+        method: HttpMethod,
+        path: &str,
+        handler: impl RouteHandler,
+    ) -> Result<(), DuplicateRouteError>
+    where
+        Self: Sized,
+    {
+        self.route_with_middleware(method, path, &[], Arc::new(handler))
+    }
+
+    // Registers a table built with the routes! macro, stopping at the first duplicate
+    fn add_routes(
+        &mut self,
+        routes: Vec<(HttpMethod, &str, Vec<MiddlewareFunc>, RouteHandlerFunc)>,
+    ) -> Result<(), DuplicateRouteError> {
+        for (method, path, middlewares, handler) in routes {
+            self.route_with_middleware(method, path, &middlewares, handler)?;
+        }
+        Ok(())
+    }
+
+    // Forwards every method on matching paths to another server, e.g.
+    // `server.proxy("/grafana/*", "http://127.0.0.1:3000")`. A trailing `/*` covers the prefix
+    // itself and everything below it
+    fn proxy(&mut self, pattern: &str, upstream: &str) -> Result<(), DuplicateRouteError> {
+        let middleware: MiddlewareFunc = Arc::new(proxy_middleware(upstream));
+        let handler: RouteHandlerFunc = Arc::new(proxy_handler);
+        let patterns = match pattern.strip_suffix("/*") {
+            Some(prefix) => vec![
+                if prefix.is_empty() { "/" } else { prefix }.to_string(),
+                format!("{}/*rest", prefix),
+            ],
+            None => vec![pattern.to_string()],
+        };
+        for pattern in patterns.iter() {
+            for method in [
+                HttpMethod::GET,
+                HttpMethod::HEAD,
+                HttpMethod::POST,
+                HttpMethod::PUT,
+                HttpMethod::PATCH,
+                HttpMethod::DELETE,
+                HttpMethod::OPTIONS,
+            ] {
+                self.route_with_middleware(
+                    method,
+                    pattern,
+                    std::slice::from_ref(&middleware),
+                    handler.clone(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Group routes under a shared prefix, e.g. `let mut v1 = server.scope("/api/v1")`
+    fn scope(&mut self, prefix: &str) -> ScopedRouter<'_>
+    where
+        Self: Sized,
+    {
+        ScopedRouter {
+            parent: self,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            middlewares: Vec::new(),
+        }
+    }
+}
+
+// Prepends its prefix and group middlewares to every route, then registers it on the parent.
+// Scopes can be nested, outer group middlewares run before inner ones
+pub struct ScopedRouter<'a> {
+    parent: &'a mut dyn Router,
+    prefix: String,
+    middlewares: Vec<MiddlewareFunc>,
+}
+
+impl ScopedRouter<'_> {
+    // Runs (after the global middlewares) for routes registered on this scope from now on
+    pub fn add_middleware(&mut self, middleware: impl Middleware) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+}
+
+impl Router for ScopedRouter<'_> {
+    fn route_with_middleware(
+        &mut self,
+        method: HttpMethod,
+        path: &str,
+        middlewares: &[MiddlewareFunc],
+        handler: RouteHandlerFunc,
+    ) -> Result<(), DuplicateRouteError> {
+        let path = format!("{}/{}", self.prefix, path.trim_start_matches('/'));
+        let middlewares: Vec<MiddlewareFunc> = self
+            .middlewares
+            .iter()
+            .chain(middlewares)
+            .cloned()
+            .collect();
+        self.parent
+            .route_with_middleware(method, &path, &middlewares, handler)
+    }
+
+    fn document(&mut self, method: HttpMethod, path: &str, doc: RouteDoc) {
+        let path = format!("{}/{}", self.prefix, path.trim_start_matches('/'));
+        self.parent.document(method, &path, doc);
+    }
+}
+
+// One parsed segment of a route pattern. `:name` captures the segment, `:name?` is an optional
+// segment, `*name` (last segment only) captures the rest of the path, `*` matches any one
+// segment and `**` matches one or more
+#[derive(Clone, Debug)]
+enum PatternSegment {
+    Static(String),
+    // `:name`, or `*` which captures nothing
+    Param(Option<String>),
+    Optional(String),
+    // A segment with a `*` inside it, e.g. `*.png`
+    Glob(String),
+    // `**`
+    MultiSegment,
+    // `*name`
+    CatchAll(String),
+}
+
+impl PatternSegment {
+    fn parse(pattern: &str) -> Vec<Self> {
+        let segments: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+        segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                let is_last = i == segments.len() - 1;
+                if let Some(name) = segment.strip_prefix(':') {
+                    match name.strip_suffix('?') {
+                        Some(name) => Self::Optional(name.to_string()),
+                        None => Self::Param(Some(name.to_string())),
+                    }
+                } else if *segment == "*" {
+                    Self::Param(None)
+                } else if *segment == "**" {
+                    Self::MultiSegment
+                } else if let Some(name) = segment
+                    .strip_prefix('*')
+                    .filter(|name| is_last && is_param_name(name))
+                {
+                    Self::CatchAll(name.to_string())
+                } else if segment.contains('*') {
+                    Self::Glob(segment.to_string())
+                } else {
+                    Self::Static(segment.to_string())
+                }
+            })
+            .collect()
+    }
+
+    // Higher is more specific
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Static(_) => 6,
+            Self::Glob(_) => 5,
+            Self::Param(Some(_)) => 4,
+            Self::Param(None) => 3,
+            Self::Optional(_) => 2,
+            Self::MultiSegment => 1,
+            Self::CatchAll(_) => 0,
+        }
+    }
+}
+
+// The names a pattern captures, in order. Unnamed wildcards capture nothing
+pub(crate) fn pattern_params(pattern: &str) -> Vec<String> {
+    PatternSegment::parse(pattern)
+        .into_iter()
+        .filter_map(|segment| match segment {
+            PatternSegment::Param(Some(name))
+            | PatternSegment::Optional(name)
+            | PatternSegment::CatchAll(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+// Orders route patterns most specific first (Less means a should be tried before b).
+// Segments are compared left to right and the first differing kind decides, so static beats
+// glob beats param beats `*` beats optional beats multi segment wildcards, and the longest
+// static prefix wins.
+// If one pattern is a prefix of the other, the longer one wins unless its next segment is
+// optional, since the shorter pattern then matches exactly what the optional one leaves out.
+// Anything else is a tie, left to registration order
+pub fn compare_specificity(a: &str, b: &str) -> Ordering {
+    let a_segments = PatternSegment::parse(a);
+    let b_segments = PatternSegment::parse(b);
+    for (a_segment, b_segment) in a_segments.iter().zip(b_segments.iter()) {
+        let comparison = b_segment.rank().cmp(&a_segment.rank());
+        if comparison != Ordering::Equal {
+            return comparison;
+        }
+    }
+
+    let common = a_segments.len().min(b_segments.len());
+    match (a_segments.get(common), b_segments.get(common)) {
+        (Some(PatternSegment::Optional(_)), None) => Ordering::Greater,
+        (None, Some(PatternSegment::Optional(_))) => Ordering::Less,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        _ => Ordering::Equal,
+    }
+}
+
+// Patterns with the same shape match exactly the same paths, whatever their params are named
+pub fn same_shape(a: &str, b: &str) -> bool {
+    let shape = |pattern: &str| -> Vec<String> {
+        PatternSegment::parse(pattern)
+            .into_iter()
+            .map(|segment| match segment {
+                PatternSegment::Static(segment) | PatternSegment::Glob(segment) => segment,
+                PatternSegment::Param(Some(_)) | PatternSegment::Param(None) => "*".to_string(),
+                PatternSegment::Optional(_) => "*?".to_string(),
+                // Both match one or more trailing segments
+                PatternSegment::MultiSegment | PatternSegment::CatchAll(_) => "**".to_string(),
+            })
+            .collect()
+    };
+    shape(a) == shape(b)
+}
+
+#[derive(Clone, Debug)]
+struct Leaf {
+    route: usize,
+    // One per capturing segment on the way down, in order. None for `*`
+    param_names: Vec<Option<String>>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    static_children: HashMap<String, Node>,
+    segment_child: Option<Box<Node>>,
+    glob_children: Vec<(String, Regex, Node)>,
+    multi_segment_child: Option<Box<Node>>,
+    catch_all_leaves: Vec<Leaf>,
+    leaves: Vec<Leaf>,
+}
+
+// Segment tree matching request paths to route indices, built once at registration time so
+// requests never compile a regex. Every matching route is returned rather than just the best
+// one, since a handler which doesn't send falls through to the next matching route
+#[derive(Clone, Debug, Default)]
+pub struct RouteTree {
+    root: Node,
+}
+
+impl RouteTree {
+    pub fn insert(&mut self, pattern: &str, route: usize) {
+        // Each optional segment doubles up the route, once with the segment and once without
+        let mut expansions: Vec<Vec<PatternSegment>> = vec![Vec::new()];
+        for segment in PatternSegment::parse(pattern) {
+            if let PatternSegment::Optional(name) = segment {
+                let without = expansions.clone();
+                for expansion in expansions.iter_mut() {
+                    expansion.push(PatternSegment::Param(Some(name.clone())));
+                }
+                expansions.extend(without);
+            } else {
+                for expansion in expansions.iter_mut() {
+                    expansion.push(segment.clone());
+                }
+            }
+        }
+
+        'expansions: for segments in expansions {
+            let mut node = &mut self.root;
+            let mut param_names = Vec::new();
+            for segment in segments {
+                node = match segment {
+                    PatternSegment::Static(segment) => {
+                        node.static_children.entry(segment).or_default()
+                    }
+                    PatternSegment::Param(name) => {
+                        param_names.push(name);
+                        node.segment_child.get_or_insert_with(Default::default)
+                    }
+                    PatternSegment::Glob(glob) => {
+                        let position =
+                            match node.glob_children.iter().position(|(g, _, _)| *g == glob) {
+                                Some(position) => position,
+                                None => {
+                                    let regex = format!(
+                                        "^{}$",
+                                        regex::escape(&glob).replace(r"\*", "[^/]+")
+                                    );
+                                    let regex = Regex::new(&regex).unwrap();
+                                    node.glob_children.push((glob, regex, Node::default()));
+                                    node.glob_children.len() - 1
+                                }
+                            };
+                        &mut node.glob_children[position].2
+                    }
+                    PatternSegment::MultiSegment => node
+                        .multi_segment_child
+                        .get_or_insert_with(Default::default),
+                    PatternSegment::CatchAll(name) => {
+                        param_names.push(Some(name));
+                        node.catch_all_leaves.push(Leaf { route, param_names });
+                        continue 'expansions;
+                    }
+                    PatternSegment::Optional(_) => unreachable!(),
+                };
+            }
+            node.leaves.push(Leaf { route, param_names });
+        }
+    }
+
+    // Matching routes and their params, ordered by route index
+    pub fn find(&self, path: &str) -> Vec<(usize, HashMap<String, String>)> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut matches = Vec::new();
+        Self::find_in(&self.root, &segments, &mut Vec::new(), &mut matches);
+
+        matches.sort_by_key(|(route, _)| *route);
+        // A route with optional segments can match more than once, keep the first
+        matches.dedup_by_key(|(route, _)| *route);
+        matches
+    }
+
+    fn find_in(
+        node: &Node,
+        segments: &[&str],
+        captured: &mut Vec<String>,
+        matches: &mut Vec<(usize, HashMap<String, String>)>,
+    ) {
+        let Some((segment, rest)) = segments.split_first() else {
+            for leaf in node.leaves.iter() {
+                matches.push((leaf.route, Self::name_params(leaf, captured)));
+            }
+            return;
+        };
+
+        if let Some(child) = node.static_children.get(*segment) {
+            Self::find_in(child, rest, captured, matches);
+        }
+        if let Some(child) = &node.segment_child {
+            captured.push(segment.to_string());
+            Self::find_in(child, rest, captured, matches);
+            captured.pop();
+        }
+        for (_, regex, child) in node.glob_children.iter() {
+            if regex.is_match(segment) {
+                Self::find_in(child, rest, captured, matches);
+            }
+        }
+        if let Some(child) = &node.multi_segment_child {
+            for consumed in 1..=segments.len() {
+                Self::find_in(child, &segments[consumed..], captured, matches);
+            }
+        }
+        if !node.catch_all_leaves.is_empty() {
+            captured.push(segments.join("/"));
+            for leaf in node.catch_all_leaves.iter() {
+                matches.push((leaf.route, Self::name_params(leaf, captured)));
+            }
+            captured.pop();
+        }
+    }
+
+    fn name_params(leaf: &Leaf, captured: &[String]) -> HashMap<String, String> {
+        leaf.param_names
+            .iter()
+            .zip(captured)
+            .filter_map(|(name, value)| Some((name.clone()?, value.clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn more_specific_pattern_sorts_first() {
+        // (more specific, less specific)
+        let cases = [
+            ("/users/me", "/users/:id"),
+            ("/users/:id", "/users/*"),
+            ("/img/*.png", "/img/:name"),
+            ("/users/:id", "/users/:id?"),
+            ("/files/:name", "/files/*path"),
+            ("/static/:file", "/static/**"),
+            ("/static/**", "/static/*path"),
+            ("/api/v1/users/:id", "/api/:version/users/me"),
+            ("/docs", "/docs/:page?"),
+            ("/static/**/x", "/static/**"),
+            ("/users/:id/posts", "/users/:id"),
+        ];
+
+        for (more_specific, less_specific) in cases {
+            assert_eq!(
+                compare_specificity(more_specific, less_specific),
+                Ordering::Less,
+                "{} should sort before {}",
+                more_specific,
+                less_specific
+            );
+            assert_eq!(
+                compare_specificity(less_specific, more_specific),
+                Ordering::Greater,
+                "{} should sort after {}",
+                less_specific,
+                more_specific
+            );
+        }
+    }
+
+    #[test]
+    fn equally_specific_patterns_tie() {
+        let cases = [
+            ("/users/:id", "/users/:user_id"),
+            ("/users/:id", "/posts/:id"),
+            ("/", "/"),
+        ];
+
+        for (a, b) in cases {
+            assert_eq!(compare_specificity(a, b), Ordering::Equal, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn best_match_wins_regardless_of_registration_order() {
+        // (patterns in registration order, request path, expected winning pattern)
+        let cases = [
+            (vec!["/users/:id", "/users/me"], "/users/me/", "/users/me"),
+            (vec!["/users/*", "/users/:id"], "/users/1/", "/users/:id"),
+            (
+                vec!["/files/*path", "/files/:name"],
+                "/files/a/",
+                "/files/:name",
+            ),
+            (
+                vec!["/files/*path", "/files/:name"],
+                "/files/a/b/",
+                "/files/*path",
+            ),
+            (vec!["/docs/:page?", "/docs"], "/docs/", "/docs"),
+            (
+                vec!["/docs/:page?", "/docs"],
+                "/docs/intro/",
+                "/docs/:page?",
+            ),
+            (
+                vec!["/**", "/api/:version/health"],
+                "/api/v1/health/",
+                "/api/:version/health",
+            ),
+            // Ties fall back to registration order
+            (
+                vec!["/users/:user_id", "/users/:id"],
+                "/users/1/",
+                "/users/:user_id",
+            ),
+            (
+                vec!["/users/:id", "/users/:user_id"],
+                "/users/1/",
+                "/users/:id",
+            ),
+        ];
+
+        for (mut patterns, path, expected) in cases {
+            patterns.sort_by(|a, b| compare_specificity(a, b));
+            let mut tree = RouteTree::default();
+            for (index, pattern) in patterns.iter().enumerate() {
+                tree.insert(pattern, index);
+            }
+
+            let matches = tree.find(path);
+            let (winner, _) = matches.first().expect("no route matched");
+            assert_eq!(patterns[*winner], expected, "for {}", path);
+        }
+    }
+
+    #[test]
+    fn duplicate_shapes_are_detected() {
+        // (a, b, conflicts)
+        let cases = [
+            ("/users/:id", "/users/:id/", true),
+            ("/users/:id", "/users/:user_id", true),
+            ("/users/:id", "/users/*", true),
+            ("/files/*path", "/files/**", true),
+            ("/docs/:page?", "/docs/:slug?", true),
+            ("/users/:id", "/users/me", false),
+            ("/docs/:page?", "/docs", false),
+            ("/docs/:page?", "/docs/:page", false),
+            ("/img/*.png", "/img/*.jpg", false),
+        ];
+
+        for (a, b, conflicts) in cases {
+            assert_eq!(same_shape(a, b), conflicts, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn params_are_captured() {
+        // (pattern, request path, expected params)
+        let cases = [
+            ("/users/:id", "/users/42/", vec![("id", "42")]),
+            ("/files/*path", "/files/a/b.txt/", vec![("path", "a/b.txt")]),
+            ("/docs/:page?", "/docs/", vec![]),
+            ("/docs/:page?", "/docs/intro/", vec![("page", "intro")]),
+            ("/x/*/:y", "/x/1/2/", vec![("y", "2")]),
+        ];
+
+        for (pattern, path, expected) in cases {
+            let mut tree = RouteTree::default();
+            tree.insert(pattern, 0);
+
+            let matches = tree.find(path);
+            let (_, params) = matches.first().expect("no route matched");
+            let expected: HashMap<String, String> = expected
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            assert_eq!(*params, expected, "{} against {}", pattern, path);
+        }
+    }
+}
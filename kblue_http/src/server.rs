@@ -0,0 +1,2197 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io::IoSlice;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use serde::Serialize;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{debug, debug_span, error, field, info, info_span, warn, Instrument, Span};
+
+use crate::ONE_KB;
+
+use super::access_log::{AccessLogConfig, AccessLogEntry, AccessLogger};
+use super::catch_panic::catch_panic;
+use super::client_ip::TrustedProxies;
+use super::config::{DefaultHeaders, RequestLimits, ServerConfig};
+use super::constants::HttpMethod;
+use super::handler_error::{ErrorPages, ErrorStatuses, HandlerError};
+use super::openapi::{OpenApi, RouteDoc};
+use super::plugin::{ClosedConnection, Plugin};
+use super::range::apply_range;
+use super::request::Request;
+use super::request_reader::{RequestParseError, RequestReader};
+use super::response::{http_date, BodyStream, Response};
+use super::router::{
+    compare_specificity, pattern_params, same_shape, DuplicateRouteError, RouteTree, Router,
+};
+use super::state::States;
+use super::status::StatusCode;
+use super::workers::{ConnectionGuard, ShutdownHandle, WorkerCounters, WorkerMetrics};
+
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**  Future returned by middlewares and route handlers, which borrows the request and response (and can be used in multithreading env (send)).
+Rust can't statically define types that return traits yet, since traits are implemented differently and have different sizes
+so we must dynamically define a Future type with Box<dyn Future...>  **/
+pub type BorrowedFuture<'a, RetType> = Pin<Box<dyn Future<Output = RetType> + Send + 'a>>;
+
+pub type RequestParam<'a> = &'a mut Request;
+pub type ResponseParam<'a> = &'a mut Response;
+
+// Each function is an Arc, since they must live as long as someone owns one. No need for mutex since they aren't mutable.
+// Middlewares run one at a time, each borrowing the request and response until its future finishes
+pub type MiddlewareFunc = Arc<dyn Middleware>;
+type Middlewares = Vec<MiddlewareFunc>;
+type Plugins = Vec<Arc<dyn Plugin>>;
+
+// Anything usable as a middleware: functions declared with middleware! or closures returned by
+// a factory such as cors_middleware(config), which can capture their own configuration. The Next
+// it returns decides what runs after it
+pub trait Middleware:
+    for<'a> Fn(&'a mut Request, &'a mut Response) -> BorrowedFuture<'a, Next> + Send + Sync + 'static
+{
+}
+
+impl<F> Middleware for F where
+    F: for<'a> Fn(&'a mut Request, &'a mut Response) -> BorrowedFuture<'a, Next>
+        + Send
+        + Sync
+        + 'static
+{
+}
+
+// What a middleware wants to happen next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Next {
+    // Carry on to the next middleware, then the route handler
+    Continue,
+    // Skip the remaining middlewares and the handler, but still run the after middlewares
+    // before sending the response. Calling response.send() and continuing does the same
+    Stop,
+    // Send the response exactly as it is, skipping everything else including after middlewares
+    Respond,
+}
+
+// Handlers get the request and response to themselves while they run, like middlewares
+pub type RouteHandlerFunc = Arc<dyn RouteHandler>;
+
+// Anything usable as a route handler: functions declared with route! or closures capturing their
+// own state, e.g.
+// `server.route(GET, "/x", move |req, res| { let db = db.clone(); Box::pin(async move { ... }) })`
+pub trait RouteHandler:
+    for<'a> Fn(&'a mut Request, &'a mut Response) -> BorrowedFuture<'a, Result<(), HandlerError>>
+    + Send
+    + Sync
+    + 'static
+{
+}
+
+impl<F> RouteHandler for F where
+    F: for<'a> Fn(
+            &'a mut Request,
+            &'a mut Response,
+        ) -> BorrowedFuture<'a, Result<(), HandlerError>>
+        + Send
+        + Sync
+        + 'static
+{
+}
+
+// How long a route handler took, given to the hooks registered with Server::on_handler_complete
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandlerTiming {
+    pub method: HttpMethod,
+    // As registered, e.g. /api/v1/projects/:id
+    pub route: String,
+    pub status: u16,
+    // The handler alone, not including middlewares
+    pub duration: Duration,
+}
+
+pub type HandlerHook = Arc<dyn Fn(&HandlerTiming) + Send + Sync>;
+
+// A registered route, see Server::routes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RouteInfo {
+    pub method: HttpMethod,
+    // As registered, e.g. /api/v1/projects/:id
+    pub pattern: String,
+    // Captured from the path, e.g. ["id"]
+    pub params: Vec<String>,
+    // Its own and its scopes', not counting the global middlewares every request goes through
+    pub middlewares: usize,
+}
+type RouteHandlers = HashMap<HttpMethod, MethodRoutes>;
+
+#[derive(Hash, PartialEq, Eq, Clone, Default, Debug)]
+pub struct Route {
+    method: HttpMethod,
+    // As registered, e.g. /files/*path
+    pattern: String,
+}
+
+// Routes for one method, in precedence order, and the tree indexing into them
+#[derive(Clone, Default)]
+struct MethodRoutes {
+    routes: Vec<RouteAndHandler>,
+    tree: RouteTree,
+}
+
+#[derive(Clone)]
+struct RouteAndHandler {
+    route: Route,
+    // Only run when this route matches, after the global middlewares
+    middlewares: Middlewares,
+    handler: RouteHandlerFunc,
+}
+
+// Everything a connection needs from the server. Built once when the server starts and shared by
+// every listener and connection through an Arc, so accepting a connection doesn't copy any routes
+struct ConnectionContext {
+    access_logger: Option<AccessLogger>,
+    handlers: RouteHandlers,
+    middlewares: Middlewares,
+    after_middlewares: Middlewares,
+    states: States,
+    trusted_proxies: TrustedProxies,
+    error_pages: ErrorPages,
+    handler_hooks: Vec<HandlerHook>,
+    plugins: Plugins,
+    limits: RequestLimits,
+    default_headers: DefaultHeaders,
+    // Becomes true once the server is shutting down
+    shutdown: watch::Receiver<bool>,
+}
+
+impl ConnectionContext {
+    fn dispatcher(&self) -> Dispatcher<'_> {
+        Dispatcher {
+            handlers: &self.handlers,
+            middlewares: &self.middlewares,
+            after_middlewares: &self.after_middlewares,
+            states: &self.states,
+            trusted_proxies: &self.trusted_proxies,
+            error_pages: &self.error_pages,
+            handler_hooks: &self.handler_hooks,
+        }
+    }
+}
+
+// Turns a parsed request into a response, whatever transport it came in on. Borrowed from the
+// Server for Server::handle, or from a ConnectionContext when serving sockets
+struct Dispatcher<'a> {
+    handlers: &'a RouteHandlers,
+    middlewares: &'a Middlewares,
+    after_middlewares: &'a Middlewares,
+    states: &'a States,
+    trusted_proxies: &'a TrustedProxies,
+    error_pages: &'a ErrorPages,
+    handler_hooks: &'a [HandlerHook],
+}
+
+impl Dispatcher<'_> {
+    // Fills in what the server knows about the request rather than the client
+    fn prepare(&self, request: &mut Request) {
+        request.states = self.states.clone();
+        request.client_ip = self.trusted_proxies.client_ip(request.remote_addr, request);
+        request.from_trusted_proxy = self.trusted_proxies.trusts_peer(request.remote_addr);
+    }
+
+    // Middlewares, the matching route (or a 404), after middlewares, then any Range
+    async fn dispatch(&self, request: &mut Request, response: &mut Response) {
+        // Only GETs are served partially
+        let range = (request.method == HttpMethod::GET)
+            .then(|| request.get_header("range").cloned())
+            .flatten();
+        let if_range = request.get_header("if-range").cloned();
+
+        let next = Server::handle_request(
+            request,
+            response,
+            self.handlers,
+            self.middlewares,
+            self.error_pages,
+            self.handler_hooks,
+        )
+        .await;
+
+        if next == Next::Stop {
+            self.run_after_middlewares(request, response).await;
+        }
+
+        apply_range(response, range.as_deref(), if_range.as_deref());
+    }
+
+    // For a request whose head was read but which can't be served, e.g. one too large. The
+    // middlewares still run, like for a 404, so a cross origin client can read the error
+    async fn reject(&self, request: &mut Request, response: &mut Response, error: &HandlerError) {
+        let mut next = Next::Continue;
+        for middleware in self.middlewares.iter() {
+            next = Server::run_middleware(middleware, request, response, self.error_pages).await;
+            if next != Next::Continue {
+                break;
+            }
+        }
+        // Unless a middleware answered first, e.g. a rate limit
+        if next == Next::Continue {
+            self.error_pages.render(error, request, response);
+            next = Next::Stop;
+        }
+        if next == Next::Stop {
+            self.run_after_middlewares(request, response).await;
+        }
+    }
+
+    async fn run_after_middlewares(&self, request: &mut Request, response: &mut Response) {
+        if self.after_middlewares.is_empty() {
+            return;
+        }
+        async {
+            for after_middleware in self.after_middlewares.iter() {
+                let next = after_middleware(request, response).await;
+                if next != Next::Continue {
+                    break;
+                }
+            }
+        }
+        .instrument(debug_span!("after_middleware"))
+        .await;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+pub struct Server {
+    pub middlewares: Middlewares,
+    // Run after the route handler, before the response is written
+    pub after_middlewares: Middlewares,
+    pub config: ServerConfig,
+    handlers: RouteHandlers,
+    listen_addresses: Vec<ListenAddress>,
+    access_log: Option<AccessLogConfig>,
+    states: States,
+    error_pages: ErrorPages,
+    handler_hooks: Vec<HandlerHook>,
+    plugins: Plugins,
+    openapi: OpenApi,
+    worker_metrics: WorkerMetrics,
+    shutdown: ShutdownHandle,
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    listen_addresses: Vec<ListenAddress>,
+    config: ServerConfig,
+}
+
+impl ServerBuilder {
+    // Can be called multiple times, e.g. "0.0.0.0:8080" and "[::]:8080" for dual stack
+    pub fn bind(mut self, address: &str) -> Self {
+        self.listen_addresses
+            .push(ListenAddress::Tcp(address.to_string()));
+        self
+    }
+    pub fn bind_unix(mut self, path: &str) -> Self {
+        self.listen_addresses
+            .push(ListenAddress::Unix(PathBuf::from(path)));
+        self
+    }
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+    pub fn build(self) -> Server {
+        let mut handlers = HashMap::new();
+        for method in HttpMethod::ALL {
+            handlers.insert(method, MethodRoutes::default());
+        }
+
+        Server {
+            middlewares: Vec::new(),
+            after_middlewares: Vec::new(),
+            config: self.config,
+            handlers,
+            listen_addresses: self.listen_addresses,
+            access_log: None,
+            states: States::default(),
+            error_pages: ErrorPages::default(),
+            handler_hooks: Vec::new(),
+            plugins: Vec::new(),
+            openapi: OpenApi::default(),
+            worker_metrics: WorkerMetrics::default(),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+}
+
+impl Router for Server {
+    fn route_with_middleware(
+        &mut self,
+        method: HttpMethod,
+        path: &str,
+        middlewares: &[MiddlewareFunc],
+        handler: RouteHandlerFunc,
+    ) -> Result<(), DuplicateRouteError> {
+        // Only OTHER methods aren't there from the start
+        let method_routes = self.handlers.entry(method.clone()).or_default();
+        let handlers_for_method = &mut method_routes.routes;
+
+        if let Some(existing) = handlers_for_method
+            .iter()
+            .find(|existing| same_shape(&existing.route.pattern, path))
+        {
+            return Err(DuplicateRouteError {
+                method,
+                path: path.to_string(),
+                existing_path: existing.route.pattern.clone(),
+            });
+        }
+
+        handlers_for_method.push(RouteAndHandler {
+            route: Route {
+                method,
+                pattern: path.to_string(),
+            },
+            middlewares: middlewares.to_vec(),
+            handler,
+        });
+
+        // Most specific first. The sort is stable, so equally specific routes keep registration order
+        handlers_for_method.sort_by(|a, b| compare_specificity(&a.route.pattern, &b.route.pattern));
+
+        // Sorting shuffles the indices, so the tree is rebuilt from scratch
+        method_routes.tree = RouteTree::default();
+        for (index, route_and_handler) in method_routes.routes.iter().enumerate() {
+            method_routes
+                .tree
+                .insert(&route_and_handler.route.pattern, index);
+        }
+        Ok(())
+    }
+
+    fn document(&mut self, method: HttpMethod, path: &str, doc: RouteDoc) {
+        self.openapi.add(method, path, doc);
+    }
+}
+
+impl Server {
+    // Listens on all IPv4 interfaces
+    pub fn new(port: u32) -> Self {
+        Server::builder().bind(&format!("0.0.0.0:{}", port)).build()
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn add_middleware(&mut self, middleware: impl Middleware) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    // After middlewares see the final status code and headers and may still change them.
+    // They run for every response, including ones sent early by a (before) middleware, unless it
+    // returned Next::Respond. An after middleware returning anything but Continue ends the chain
+    pub fn add_after_middleware(&mut self, middleware: impl Middleware) {
+        self.after_middlewares.push(Arc::new(middleware));
+    }
+
+    // Shared with every handler through request.state::<T>(), e.g. connection pools and config
+    pub fn with_state<T: Send + Sync + 'static>(&mut self, state: T) {
+        self.states.insert(state);
+    }
+
+    // Like with_state, for state also given to other servers, e.g. one per port
+    pub fn with_shared_state<T: Send + Sync + 'static>(&mut self, state: Arc<T>) {
+        self.states.insert_arc(state);
+    }
+
+    // How error responses are rendered when no page is set for their status, see set_error_page.
+    // Defaults to render_error, JSON or a plain HTML page depending on Accept
+    pub fn set_error_renderer(
+        &mut self,
+        renderer: impl Fn(&HandlerError, &Request, &mut Response) + Send + Sync + 'static,
+    ) {
+        self.error_pages.set_fallback(Arc::new(renderer));
+    }
+
+    // Renders errors with these statuses instead, e.g. a branded 404 page. An exact status wins
+    // over its class. Renderers can use response.negotiate to keep answering API clients in JSON
+    pub fn set_error_page(
+        &mut self,
+        statuses: ErrorStatuses,
+        renderer: impl Fn(&HandlerError, &Request, &mut Response) + Send + Sync + 'static,
+    ) {
+        self.error_pages.set(statuses, Arc::new(renderer));
+    }
+
+    // Called after every route handler with how long it took, e.g. to flag slow routes.
+    // Runs on the request's task, so it shouldn't block
+    pub fn on_handler_complete(&mut self, hook: impl Fn(&HandlerTiming) + Send + Sync + 'static) {
+        self.handler_hooks.push(Arc::new(hook));
+    }
+
+    // Every route, method by method, in the order they're tried for a request
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let mut methods: Vec<&HttpMethod> = self.handlers.keys().collect();
+        // ALL's order, then any OTHER methods by name
+        methods.sort_by_key(|method| {
+            let position = HttpMethod::ALL.iter().position(|known| known == *method);
+            (position.unwrap_or(HttpMethod::ALL.len()), method.as_str())
+        });
+        methods
+            .into_iter()
+            .flat_map(|method| self.handlers[method].routes.iter())
+            .map(|route_and_handler| RouteInfo {
+                method: route_and_handler.route.method.clone(),
+                pattern: route_and_handler.route.pattern.clone(),
+                params: pattern_params(&route_and_handler.route.pattern),
+                middlewares: route_and_handler.middlewares.len(),
+            })
+            .collect()
+    }
+
+    // The routes documented so far, e.g. to serve as openapi.json once they're all registered
+    pub fn openapi(&self) -> OpenApi {
+        self.openapi.clone()
+    }
+
+    // Plugins run in the order they're added, see Plugin for when each callback is called
+    pub fn add_plugin(&mut self, plugin: impl Plugin + 'static) {
+        self.plugins.push(Arc::new(plugin));
+    }
+
+    pub fn bind(&mut self, address: &str) {
+        self.listen_addresses
+            .push(ListenAddress::Tcp(address.to_string()));
+    }
+
+    // Listen on a unix domain socket, e.g. when sitting behind nginx on the same host
+    pub fn bind_unix(&mut self, path: &str) {
+        self.listen_addresses
+            .push(ListenAddress::Unix(PathBuf::from(path)));
+    }
+
+    // Record every response in Combined Log Format or JSON, after it has been written
+    pub fn enable_access_log(&mut self, config: AccessLogConfig) {
+        self.access_log = Some(config);
+    }
+
+    // Connection counts for each worker, filled in once the server starts
+    pub fn worker_metrics(&self) -> WorkerMetrics {
+        self.worker_metrics.clone()
+    }
+
+    // For stopping the server gracefully, e.g. on SIGTERM, see ShutdownHandle
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Runs a request through the middlewares and routes without a socket, e.g. from tests or
+    // another transport. remote_addr is the caller's to set
+    pub async fn handle(&self, mut request: Request) -> Response {
+        let dispatcher = self.dispatcher();
+        dispatcher.prepare(&mut request);
+        for plugin in self.plugins.iter() {
+            plugin.on_request_parsed(&mut request);
+        }
+        let mut response = Response::new();
+        dispatcher.dispatch(&mut request, &mut response).await;
+        for plugin in self.plugins.iter() {
+            plugin.on_response_ready(&request, &mut response);
+        }
+        // As if it had been written
+        Self::finish_headers(&mut response, &self.config.default_headers);
+        response
+    }
+
+    fn dispatcher(&self) -> Dispatcher<'_> {
+        Dispatcher {
+            handlers: &self.handlers,
+            middlewares: &self.middlewares,
+            after_middlewares: &self.after_middlewares,
+            states: &self.states,
+            trusted_proxies: &self.config.trusted_proxies,
+            error_pages: &self.error_pages,
+            handler_hooks: &self.handler_hooks,
+        }
+    }
+
+    // Binds every listen address up front (failing fast), then accepts on all of them concurrently.
+    // Only returns once shut down through shutdown_handle(), or on error
+    pub async fn start(&self) -> Result<(), Box<dyn Error>> {
+        if self.listen_addresses.is_empty() {
+            return Err("Server has no addresses to listen on".into());
+        }
+
+        let context = self.connection_context().await?;
+        let (open_connections, all_closed) = mpsc::channel(1);
+        let mut accept_loops = JoinSet::new();
+        for listen_address in self.listen_addresses.iter() {
+            match listen_address {
+                ListenAddress::Tcp(address) => {
+                    let workers = self.config.workers.max(1);
+                    let mut config = self.config.clone();
+                    // Otherwise only the first worker's listener could bind
+                    config.reuse_port |= workers > 1;
+                    for worker in 0..workers {
+                        let listener = Server::bind_tcp(address, &config).map_err(|e| {
+                            format!("Could not bind TCP listener to: {} ({})", address, e)
+                        })?;
+                        accept_loops.spawn(Server::accept_tcp(
+                            listener,
+                            config.clone(),
+                            context.clone(),
+                            self.worker_metrics.register(worker, address),
+                            open_connections.clone(),
+                        ));
+                    }
+                    info!(
+                        "Accepting incoming connections on {} with {} worker(s)",
+                        address, workers
+                    );
+                    self.notify_listening(listen_address);
+                }
+                ListenAddress::Unix(path) => {
+                    Server::remove_stale_socket(path)?;
+                    let listener = UnixListener::bind(path).map_err(|e| {
+                        format!(
+                            "Could not bind unix listener to: {} ({})",
+                            path.display(),
+                            e
+                        )
+                    })?;
+                    info!("Accepting incoming connections on unix:{}", path.display());
+                    self.notify_listening(listen_address);
+                    let address = format!("unix:{}", path.display());
+                    accept_loops.spawn(Server::accept_unix(
+                        listener,
+                        path.clone(),
+                        context.clone(),
+                        self.worker_metrics.register(0, &address),
+                        open_connections.clone(),
+                    ));
+                }
+            }
+        }
+
+        // The accept loops only finish when shutting down
+        while let Some(result) = accept_loops.join_next().await {
+            result?;
+        }
+        self.drain(open_connections, all_closed).await;
+        Ok(())
+    }
+
+    // A socket file left behind by a previous run would make bind fail. Anything else at the path
+    // isn't ours to delete, e.g. a mistyped path pointing at a real file
+    fn remove_stale_socket(path: &Path) -> Result<(), String> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+                .map_err(|e| format!("Could not remove stale socket {} ({})", path.display(), e)),
+            Ok(_) => Err(format!(
+                "Could not bind unix listener to: {} (it exists and isn't a socket)",
+                path.display()
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Could not inspect {} ({})", path.display(), e)),
+        }
+    }
+
+    // Accepts on a listener bound by the caller, ignoring the listen addresses and workers, e.g.
+    // on an ephemeral port in tests
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+        let context = self.connection_context().await?;
+        let (open_connections, all_closed) = mpsc::channel(1);
+        let address = listener.local_addr()?.to_string();
+        self.notify_listening(&ListenAddress::Tcp(address.clone()));
+        Server::accept_tcp(
+            listener,
+            self.config.clone(),
+            context,
+            self.worker_metrics.register(0, &address),
+            open_connections.clone(),
+        )
+        .await;
+        self.drain(open_connections, all_closed).await;
+        Ok(())
+    }
+
+    fn notify_listening(&self, address: &ListenAddress) {
+        for plugin in self.plugins.iter() {
+            plugin.on_listen(address);
+        }
+    }
+
+    // Waits for every connection's guard to be dropped, up to the shutdown timeout
+    async fn drain(&self, open_connections: mpsc::Sender<()>, mut all_closed: mpsc::Receiver<()>) {
+        drop(open_connections);
+        let still_open = || {
+            self.worker_metrics
+                .snapshot()
+                .iter()
+                .map(|stats| stats.active)
+                .sum::<u64>()
+        };
+        if still_open() > 0 {
+            info!("Waiting for {} connection(s) to finish", still_open());
+        }
+        // Nothing is ever sent, recv returns None once every sender is gone
+        if tokio::time::timeout(self.config.shutdown_timeout, all_closed.recv())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Shut down with {} connection(s) still open after {:?}",
+                still_open(),
+                self.config.shutdown_timeout
+            );
+        }
+    }
+
+    async fn connection_context(&self) -> Result<Arc<ConnectionContext>, Box<dyn Error>> {
+        let access_logger = match &self.access_log {
+            Some(config) => Some(
+                AccessLogger::start(config)
+                    .await
+                    .map_err(|e| format!("Could not open access log ({})", e))?,
+            ),
+            None => None,
+        };
+        Ok(Arc::new(ConnectionContext {
+            access_logger,
+            handlers: self.handlers.clone(),
+            middlewares: self.middlewares.clone(),
+            after_middlewares: self.after_middlewares.clone(),
+            states: self.states.clone(),
+            trusted_proxies: self.config.trusted_proxies.clone(),
+            error_pages: self.error_pages.clone(),
+            handler_hooks: self.handler_hooks.clone(),
+            plugins: self.plugins.clone(),
+            limits: self.config.limits.clone(),
+            default_headers: self.config.default_headers.clone(),
+            shutdown: self.shutdown.subscribe(),
+        }))
+    }
+
+    fn bind_tcp(address: &str, config: &ServerConfig) -> std::io::Result<TcpListener> {
+        let socket_address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Unresolvable address")
+        })?;
+        let socket = Socket::new(
+            Domain::for_address(socket_address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        // Otherwise "[::]" also claims the IPv4 port, and a separate "0.0.0.0" listener can't bind
+        if socket_address.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(config.reuse_port)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&socket_address.into())?;
+        socket.listen(config.listen_backlog)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    async fn accept_tcp(
+        listener: TcpListener,
+        config: ServerConfig,
+        context: Arc<ConnectionContext>,
+        counters: Arc<WorkerCounters>,
+        open_connections: mpsc::Sender<()>,
+    ) {
+        let mut shutdown = context.shutdown.clone();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.wait_for(|stopping| *stopping) => return,
+            };
+            let (stream, incoming) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            let guard = ConnectionGuard::new(&counters, &open_connections);
+            if let Err(e) = Server::apply_socket_options(&stream, &config) {
+                warn!("Could not set socket options: {}", e);
+            }
+
+            debug!("Incoming connection from {}", incoming.ip());
+
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &config.tls {
+                let acceptor = tls.acceptor().clone();
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let _guard = guard;
+                    // Otherwise a client that never finishes the handshake holds the task forever
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(tls_stream)) => {
+                            Server::serve_connection(tls_stream, Some(incoming), true, context)
+                                .await
+                        }
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {} failed: {}", incoming.ip(), e);
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", incoming.ip());
+                        }
+                    }
+                });
+                continue;
+            }
+
+            let context = context.clone();
+            tokio::spawn(async move {
+                let _guard = guard;
+                Server::serve_connection(stream, Some(incoming), false, context).await
+            });
+        }
+    }
+
+    fn apply_socket_options(stream: &TcpStream, config: &ServerConfig) -> std::io::Result<()> {
+        stream.set_nodelay(config.nodelay)?;
+        let socket = SockRef::from(stream);
+        if config.linger.is_some() {
+            socket.set_linger(config.linger)?;
+        }
+        if let Some(keepalive) = &config.keepalive {
+            socket.set_tcp_keepalive(
+                &TcpKeepalive::new()
+                    .with_time(keepalive.time)
+                    .with_interval(keepalive.interval)
+                    .with_retries(keepalive.retries),
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn accept_unix(
+        listener: UnixListener,
+        path: PathBuf,
+        context: Arc<ConnectionContext>,
+        counters: Arc<WorkerCounters>,
+        open_connections: mpsc::Sender<()>,
+    ) {
+        let mut shutdown = context.shutdown.clone();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.wait_for(|stopping| *stopping) => return,
+            };
+            let (stream, _) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Could not accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let guard = ConnectionGuard::new(&counters, &open_connections);
+
+            debug!("Incoming connection on unix:{}", path.display());
+
+            let context = context.clone();
+            tokio::spawn(async move {
+                let _guard = guard;
+                Server::serve_connection(stream, None, false, context).await
+            });
+        }
+    }
+
+    async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        stream: S,
+        remote_addr: Option<SocketAddr>,
+        tls: bool,
+        context: Arc<ConnectionContext>,
+    ) {
+        let mut connection = Connection {
+            stream,
+            reader: RequestReader::new(context.limits.clone()),
+            remote_addr,
+            tls,
+            remote_ip: remote_addr.map_or("unix".to_string(), |addr| addr.ip().to_string()),
+            shutdown: context.shutdown.clone(),
+            context,
+            requests: 0,
+            opened_at: Instant::now(),
+        };
+        let mut state = ConnectionState::Reading;
+        loop {
+            state = match state {
+                ConnectionState::Reading => connection.read_request().await,
+                ConnectionState::Serving(request) => connection.serve(*request).await,
+                ConnectionState::Rejecting(error, request) => {
+                    connection.reject(error, request).await
+                }
+                ConnectionState::Closing { drain } => {
+                    connection.close(drain).await;
+                    break;
+                }
+                ConnectionState::Closed => break,
+                ConnectionState::Upgrading(on_upgrade) => {
+                    let upgraded = Upgraded {
+                        io: Box::new(connection.stream),
+                        buffered: connection.reader.take_buffered(),
+                        shutdown: connection.shutdown.clone(),
+                    };
+                    on_upgrade(upgraded).await;
+                    break;
+                }
+            };
+        }
+        if !connection.context.plugins.is_empty() {
+            let closed = ClosedConnection {
+                remote_addr: connection.remote_addr,
+                requests: connection.requests,
+                duration: connection.opened_at.elapsed(),
+            };
+            for plugin in connection.context.plugins.iter() {
+                plugin.on_connection_closed(&closed);
+            }
+        }
+    }
+
+    // Runs the global middlewares once, whether or not a route matches, then the matching route
+    // handlers. Stop means the after middlewares should run before sending, Respond to send
+    // straight away. Never Continue: a request nothing handled gets a 404
+    async fn handle_request(
+        request: &mut Request,
+        response: &mut Response,
+        handlers: &RouteHandlers,
+        middlewares: &Middlewares,
+        error_pages: &ErrorPages,
+        handler_hooks: &[HandlerHook],
+    ) -> Next {
+        let request_method = request.method.clone();
+        let request_path = request.path.clone();
+
+        // Loop middlewares
+        let next = async {
+            for middleware in middlewares.iter() {
+                let next = Server::run_middleware(middleware, request, response, error_pages).await;
+                if next != Next::Continue {
+                    return next;
+                }
+            }
+            Next::Continue
+        }
+        .instrument(debug_span!("middleware"))
+        .await;
+        if next != Next::Continue {
+            return next;
+        }
+
+        let matches = handlers
+            .get(&request_method)
+            .map(|method_routes| {
+                method_routes
+                    .tree
+                    .find(&request_path)
+                    .into_iter()
+                    .map(|(index, params)| (&method_routes.routes[index], params))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        // Route middlewares shared by several matching routes, e.g. a scope's, run only once
+        let mut ran_middlewares: Vec<MiddlewareFunc> = Vec::new();
+        for (handler, params) in matches {
+            // Optional segments which weren't given are left out
+            request.params.extend(params);
+
+            let next = Server::run_route(
+                handler,
+                request,
+                response,
+                error_pages,
+                handler_hooks,
+                &mut ran_middlewares,
+            )
+            .instrument(debug_span!("handler", route = %handler.route.pattern))
+            .await;
+            if next != Next::Continue {
+                return next;
+            }
+        }
+
+        // Answer OPTIONS (and so CORS preflights) for every path with a route, unless an
+        // OPTIONS route of its own has already responded
+        if request_method == HttpMethod::OPTIONS {
+            let allowed_methods = Server::allowed_methods(handlers, &request_path);
+            if !allowed_methods.is_empty() {
+                let is_preflight = request
+                    .get_header("access-control-request-method")
+                    .is_some();
+                let allow = allowed_methods
+                    .iter()
+                    .map(|method| method.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                response.set_status_code(204);
+                response.add_header("Allow", &allow);
+                if is_preflight {
+                    // Only what's routed for the path, narrowing any methods a CORS middleware
+                    // has already allowed
+                    let cors_methods: Option<Vec<String>> = response
+                        .headers
+                        .get("access-control-allow-methods")
+                        .map(|listed| listed.split(',').map(|m| m.trim().to_string()).collect());
+                    let methods: Vec<String> = allowed_methods
+                        .iter()
+                        .map(|method| method.to_string())
+                        .filter(|method| {
+                            cors_methods
+                                .as_ref()
+                                .is_none_or(|listed| listed.contains(method))
+                        })
+                        .collect();
+                    if methods.is_empty() {
+                        response.headers.remove("Access-Control-Allow-Methods");
+                    } else {
+                        response.add_header("Access-Control-Allow-Methods", &methods.join(", "));
+                    }
+                }
+                return Next::Stop;
+            }
+        }
+
+        // Keeping whatever headers the middlewares added, e.g. so browsers can read the 404 of a
+        // cross origin request
+        let approved_preflight = request_method == HttpMethod::OPTIONS
+            && request
+                .get_header("access-control-request-method")
+                .is_some()
+            && response.headers.contains_key("access-control-allow-origin");
+        if approved_preflight {
+            // The actual request will get its own 404
+            response.set_status_code(204);
+        } else {
+            debug!("No route matched {} {}", request_method, request_path);
+            error_pages.render(&HandlerError::not_found("not found"), request, response);
+        }
+        Next::Stop
+    }
+
+    // Advertised methods with a route matching the path, plus OPTIONS itself. Empty if nothing
+    // matches
+    fn allowed_methods(handlers: &RouteHandlers, path: &str) -> Vec<HttpMethod> {
+        let mut allowed_methods: Vec<HttpMethod> = HttpMethod::ALL
+            .into_iter()
+            .filter(|method| {
+                method.is_advertised()
+                    && handlers
+                        .get(method)
+                        .is_some_and(|method_routes| !method_routes.tree.find(path).is_empty())
+            })
+            .collect();
+        if !allowed_methods.is_empty() && !allowed_methods.contains(&HttpMethod::OPTIONS) {
+            allowed_methods.push(HttpMethod::OPTIONS);
+        }
+        allowed_methods
+    }
+
+    // The route's own middlewares, skipping any an earlier matching route already ran, then its
+    // handler. Continue only if the handler called response.next(), so the next matching route
+    // gets a go
+    async fn run_route(
+        handler: &RouteAndHandler,
+        request: &mut Request,
+        response: &mut Response,
+        error_pages: &ErrorPages,
+        handler_hooks: &[HandlerHook],
+        ran_middlewares: &mut Vec<MiddlewareFunc>,
+    ) -> Next {
+        for middleware in handler.middlewares.iter() {
+            if ran_middlewares
+                .iter()
+                .any(|ran| Arc::ptr_eq(ran, middleware))
+            {
+                continue;
+            }
+            ran_middlewares.push(middleware.clone());
+            let next = Server::run_middleware(middleware, request, response, error_pages).await;
+            if next != Next::Continue {
+                return next;
+            }
+        }
+
+        // Send response
+        let handler_func: &RouteHandlerFunc = &handler.handler;
+        let started_at = Instant::now();
+        let result = catch_panic(handler_func(request, response))
+            .await
+            .unwrap_or_else(|panic| {
+                error!("Route handler panicked: {}", panic);
+                Err(HandlerError::internal("internal server error"))
+            });
+        if let Err(e) = &result {
+            if e.is_server_error() {
+                error!("Route handler failed: {}", e);
+            } else {
+                debug!("Route handler rejected the request: {}", e);
+            }
+            error_pages.render(e, request, response);
+        }
+        if !handler_hooks.is_empty() {
+            let timing = HandlerTiming {
+                method: handler.route.method.clone(),
+                route: handler.route.pattern.clone(),
+                status: response.status_code,
+                duration: started_at.elapsed(),
+            };
+            for hook in handler_hooks.iter() {
+                hook(&timing);
+            }
+        }
+        let fall_through = response.take_fall_through();
+        if result.is_ok() && fall_through && !response.should_respond() {
+            return Next::Continue;
+        }
+        Next::Stop
+    }
+
+    async fn run_middleware(
+        middleware: &MiddlewareFunc,
+        request: &mut Request,
+        response: &mut Response,
+        error_pages: &ErrorPages,
+    ) -> Next {
+        let next = match catch_panic(middleware(request, response)).await {
+            Ok(next) => next,
+            Err(panic) => {
+                error!("Middleware panicked: {}", panic);
+                let error = HandlerError::internal("internal server error");
+                error_pages.render(&error, request, response);
+                return Next::Stop;
+            }
+        };
+        if next == Next::Continue && response.should_respond() {
+            return Next::Stop;
+        }
+        next
+    }
+
+    // The head and body go out as separate slices in vectored writes, so the body is never copied
+    async fn return_response(
+        response: &mut Response,
+        default_headers: &DefaultHeaders,
+        stream: &mut (impl AsyncWrite + Unpin),
+        accepts_trailers: bool,
+    ) -> std::io::Result<()> {
+        // Chunk framing has to be added, so only plain bodies can be borrowed as they are
+        let chunked_body = response
+            .is_chunked()
+            .then(|| response.get_chunked_body_as_bytes(accepts_trailers));
+        let head = Server::serialise_head(response, default_headers, accepts_trailers);
+        let body = match &chunked_body {
+            Some(chunked_body) => chunked_body.as_slice(),
+            None => response.body.as_deref().unwrap_or_default(),
+        };
+
+        Server::write_all_vectored(stream, &mut [IoSlice::new(&head), IoSlice::new(body)]).await?;
+        if let Some(body_stream) = response.stream.as_mut() {
+            let chunked = !response.headers.contains_key("Content-Length");
+            Server::write_body_stream(body_stream, chunked, stream).await?;
+            if chunked {
+                let last_chunk = Server::last_chunk(response, accepts_trailers).await;
+                stream.write_all(&last_chunk).await?;
+            }
+        }
+        stream.flush().await
+    }
+
+    // Flushed as each piece arrives, so the client sees it as soon as the server has it
+    async fn write_body_stream(
+        body_stream: &mut BodyStream,
+        chunked: bool,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> std::io::Result<()> {
+        while let Some(data) = body_stream.recv().await {
+            let data = data?;
+            // A zero length chunk would end the body early
+            if data.is_empty() {
+                continue;
+            }
+            if chunked {
+                let size = format!("{:x}\r\n", data.len());
+                let slices = &mut [
+                    IoSlice::new(size.as_bytes()),
+                    IoSlice::new(&data),
+                    IoSlice::new(b"\r\n"),
+                ];
+                Server::write_all_vectored(stream, slices).await?;
+            } else {
+                stream.write_all(&data).await?;
+            }
+            stream.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Ends a chunked stream, with the trailers sent by its producer if the client accepts them
+    async fn last_chunk(response: &mut Response, accepts_trailers: bool) -> Vec<u8> {
+        let mut last_chunk = b"0\r\n".to_vec();
+        let trailers = match response.stream_trailers.take() {
+            Some(trailers) if accepts_trailers => trailers.await.unwrap_or_default(),
+            _ => HashMap::new(),
+        };
+        let announced = response.announced_trailers();
+        for (name, value) in trailers.iter() {
+            if announced.contains(&name.to_lowercase()) {
+                last_chunk.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+        }
+        last_chunk.extend_from_slice(b"\r\n");
+        last_chunk
+    }
+
+    // A single vectored write may only send part of the slices, like write
+    async fn write_all_vectored(
+        stream: &mut (impl AsyncWrite + Unpin),
+        mut slices: &mut [IoSlice<'_>],
+    ) -> std::io::Result<()> {
+        // Skips empty slices, e.g. an empty body
+        IoSlice::advance_slices(&mut slices, 0);
+        while !slices.is_empty() {
+            let written = stream.write_vectored(slices).await?;
+            if written == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            IoSlice::advance_slices(&mut slices, written);
+        }
+        Ok(())
+    }
+
+    // The client closed the connection, which is routine rather than a server problem
+    fn is_disconnect(error: &std::io::Error) -> bool {
+        matches!(
+            error.kind(),
+            std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+        )
+    }
+
+    // The headers every response is written with
+    fn finish_headers(response: &mut Response, default_headers: &DefaultHeaders) {
+        default_headers.apply(&mut response.headers);
+        // Set when written rather than when the response was created, which may be a while
+        // earlier for a slow handler. One relayed from a proxied upstream is kept
+        if !response.headers.contains_key("Date") {
+            response.add_header("Date", &http_date());
+        }
+    }
+
+    // The status line and headers, up to and including the blank line before the body
+    fn serialise_head(
+        response: &mut Response,
+        default_headers: &DefaultHeaders,
+        accepts_trailers: bool,
+    ) -> Vec<u8> {
+        Self::finish_headers(response, default_headers);
+        // Streamed bodies are framed by set_body_stream, and 1xx responses never have one
+        let informational = (100..200).contains(&response.status_code);
+        if !response.is_chunked() && !response.is_streaming() && !informational {
+            // Needed for the client to find the end of the response on a kept-alive connection
+            let content_length = response.body.as_ref().map_or(0, |body| body.len());
+            response.add_header("Content-Length", &content_length.to_string());
+        }
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            response.status_code, response.status_text
+        )
+        .into_bytes();
+        for (key, value) in response.headers.iter() {
+            // Don't announce trailers the client hasn't agreed to receive
+            if !accepts_trailers && key.as_str() == "trailer" {
+                continue;
+            }
+            head.extend_from_slice(key.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+}
+
+// How long a closing connection keeps reading what the client still sends, see Connection::close
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Where a connection is in its life. serve_connection moves it from state to state until it's
+// closed, keep-alive connections going back to Reading after each response
+enum ConnectionState {
+    // Waiting for the next request, or the rest of one
+    Reading,
+    // A complete request to dispatch and answer
+    Serving(Box<ParsedRequest>),
+    // A request which couldn't be read, answered with this error before closing. With its head,
+    // if that much could be parsed
+    Rejecting(HandlerError, Option<Box<Request>>),
+    // Finished with, once a FIN has been sent. Draining reads whatever the client already sent,
+    // since closing a socket with unread data makes the kernel send a RST, which can make the
+    // client throw away the response it hasn't read yet
+    Closing { drain: bool },
+    // The client went away, or the connection broke, so there's nothing left to say
+    Closed,
+    // Switched to another protocol with a 101, which runs until it's done with the connection
+    Upgrading(OnUpgrade),
+}
+
+// A connection handed over by a 101 response, see Response::on_upgrade
+// Only the websocket feature sets on_upgrade, without it the fields are never read
+#[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+pub(crate) struct Upgraded {
+    pub io: Box<dyn UpgradedIo>,
+    // Sent by the client after the request, before the 101 reached it
+    pub buffered: BytesMut,
+    // Set once the server starts shutting down, so the protocol can say goodbye
+    pub shutdown: watch::Receiver<bool>,
+}
+
+pub(crate) trait UpgradedIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> UpgradedIo for S {}
+
+pub(crate) type OnUpgrade = Box<dyn FnOnce(Upgraded) -> BorrowedFuture<'static, ()> + Send>;
+
+struct ParsedRequest {
+    request: Request,
+    span: Span,
+    // Ended once the request span's fields are recorded
+    parse_span: Span,
+}
+
+struct Connection<S> {
+    stream: S,
+    reader: RequestReader,
+    remote_addr: Option<SocketAddr>,
+    // Terminated by this server
+    tls: bool,
+    // Only for connection level logs, requests log the client IP
+    remote_ip: String,
+    context: Arc<ConnectionContext>,
+    shutdown: watch::Receiver<bool>,
+    // Responses written so far, for plugins
+    requests: u64,
+    opened_at: Instant,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection<S> {
+    async fn read_request(&mut self) -> ConnectionState {
+        let remote_ip = self.remote_ip.as_str();
+        // Both start once the first bytes of the request arrive, so idle keep-alive time
+        // isn't counted. The request span's fields are recorded once it's been parsed
+        let mut request_span: Option<Span> = None;
+        let mut parse_span: Option<Span> = None;
+
+        loop {
+            // Pipelined requests may already be fully buffered
+            if !self.reader.is_empty() {
+                let span = request_span.get_or_insert_with(|| {
+                    info_span!(
+                        "request",
+                        otel.kind = "server",
+                        method = field::Empty,
+                        path = field::Empty,
+                        remote_ip = field::Empty,
+                        traceparent = field::Empty,
+                        status = field::Empty,
+                        latency_ms = field::Empty,
+                        otel.status_code = field::Empty,
+                    )
+                });
+                let parse_span =
+                    parse_span.get_or_insert_with(|| debug_span!(parent: &*span, "parse"));
+                match parse_span.in_scope(|| self.reader.next_request()) {
+                    Ok(Some(mut request)) => {
+                        request.remote_addr = self.remote_addr;
+                        request.tls = self.tls;
+                        self.context.dispatcher().prepare(&mut request);
+                        return ConnectionState::Serving(Box::new(ParsedRequest {
+                            request,
+                            span: span.clone(),
+                            parse_span: parse_span.clone(),
+                        }));
+                    }
+                    Ok(None) => {}
+                    Err(RequestParseError::TooLarge) => {
+                        warn!(remote_ip, "Request bigger than 1MB");
+                        let error = HandlerError::new(413, "request is larger than 1MB");
+                        return ConnectionState::Rejecting(error, None);
+                    }
+                    Err(RequestParseError::BodyTooLarge(request)) => {
+                        warn!(remote_ip, "Request body bigger than 1MB");
+                        let error = HandlerError::new(413, "request is larger than 1MB");
+                        return ConnectionState::Rejecting(error, Some(request));
+                    }
+                    Err(RequestParseError::HeadersTooLarge) => {
+                        warn!(remote_ip, "Request headers over the limits");
+                        let error = HandlerError::new(431, "request headers are too large");
+                        return ConnectionState::Rejecting(error, None);
+                    }
+                    Err(RequestParseError::UriTooLong) => {
+                        warn!(remote_ip, "Request URI over the limit");
+                        let error = HandlerError::new(414, "request URI is too long");
+                        return ConnectionState::Rejecting(error, None);
+                    }
+                    Err(RequestParseError::UnsupportedTransferEncoding) => {
+                        warn!(remote_ip, "Request with a Transfer-Encoding");
+                        let error = HandlerError::new(
+                            501,
+                            "Transfer-Encoding isn't supported, send a Content-Length",
+                        );
+                        return ConnectionState::Rejecting(error, None);
+                    }
+                    Err(RequestParseError::Malformed(e)) => {
+                        warn!(remote_ip, "Malformed HTTP request: {}", e);
+                        let error = HandlerError::bad_request("malformed request");
+                        return ConnectionState::Rejecting(error, None);
+                    }
+                }
+            }
+
+            let idle = self.reader.is_empty();
+            let read = tokio::select! {
+                read = self.stream.read_buf(self.reader.read_buffer()) => read,
+                // Idle keep-alive connections are closed straight away when shutting down,
+                // but a request that has started arriving is still served
+                _ = self.shutdown.wait_for(|stopping| *stopping), if idle => {
+                    return ConnectionState::Closing { drain: false };
+                }
+            };
+            let num_bytes = match read {
+                Ok(num_bytes) => num_bytes,
+                Err(e) => {
+                    warn!(remote_ip, "Could not read from stream: {}", e);
+                    return ConnectionState::Closed;
+                }
+            };
+            if num_bytes == 0 {
+                if !self.reader.is_empty() {
+                    warn!(
+                        remote_ip,
+                        "End of stream, probably wasn't a valid HTTP request"
+                    );
+                }
+                // Otherwise the client closed an idle keep-alive connection
+                return ConnectionState::Closed;
+            }
+        }
+    }
+
+    async fn serve(&mut self, parsed: ParsedRequest) -> ConnectionState {
+        let ParsedRequest {
+            mut request,
+            span,
+            parse_span,
+        } = parsed;
+        let mut response = Response::new();
+        let started_at = Instant::now();
+        let mut access_log_entry: Option<AccessLogEntry> = None;
+        if self.context.access_logger.is_some() {
+            access_log_entry = Some(AccessLogEntry::from_request(&request));
+        }
+        span.record("method", field::display(&request.method));
+        span.record("path", request.path.as_str());
+        span.record(
+            "remote_ip",
+            request
+                .client_ip
+                .map_or(self.remote_ip.clone(), |ip| ip.to_string()),
+        );
+        if let Some(traceparent) = request.get_header("traceparent") {
+            span.record("traceparent", traceparent.as_str());
+        }
+        // Now the trace is known
+        drop(parse_span);
+        let keep_alive = request.keep_alive();
+        let accepts_trailers = request.accepts_trailers();
+        for plugin in self.context.plugins.iter() {
+            plugin.on_request_parsed(&mut request);
+        }
+
+        self.context
+            .dispatcher()
+            .dispatch(&mut request, &mut response)
+            .instrument(span.clone())
+            .await;
+
+        let on_upgrade = response
+            .on_upgrade
+            .take()
+            .filter(|_| response.status_code == 101);
+        // Finish this request, but don't wait for another when shutting down. An upgraded
+        // connection is told about the shutdown itself
+        let keep_alive = keep_alive && !*self.shutdown.borrow();
+        if !keep_alive && on_upgrade.is_none() {
+            response.add_header("Connection", "close");
+        }
+        for plugin in self.context.plugins.iter() {
+            plugin.on_response_ready(&request, &mut response);
+        }
+        span.record("status", response.status_code);
+        if StatusCode::from(response.status_code).is_server_error() {
+            span.record("otel.status_code", "error");
+        }
+        if let Some(entry) = access_log_entry.as_mut() {
+            entry.status = response.status_code;
+            entry.body_size = response.get_body_len();
+        }
+        let written = Server::return_response(
+            &mut response,
+            &self.context.default_headers,
+            &mut self.stream,
+            accepts_trailers,
+        )
+        .instrument(debug_span!(parent: &span, "write"))
+        .await;
+        self.requests += 1;
+        span.record("latency_ms", started_at.elapsed().as_secs_f64() * 1000.0);
+        span.in_scope(|| match &written {
+            Ok(()) => info!("Request completed"),
+            Err(e) if Server::is_disconnect(e) => {
+                debug!("Client went away before the response was written: {}", e)
+            }
+            Err(e) => warn!("Could not write response: {}", e),
+        });
+        if let (Some(access_logger), Some(mut entry)) =
+            (&self.context.access_logger, access_log_entry)
+        {
+            entry.duration = started_at.elapsed();
+            access_logger.log(&entry);
+        }
+
+        match (written, on_upgrade) {
+            (Err(_), _) => ConnectionState::Closed,
+            (Ok(()), Some(on_upgrade)) => ConnectionState::Upgrading(on_upgrade),
+            (Ok(()), None) if keep_alive => ConnectionState::Reading,
+            (Ok(()), None) => ConnectionState::Closing { drain: true },
+        }
+    }
+
+    // Whatever follows a request which couldn't be read can't be trusted to be the start of the
+    // next one, so the connection is closed after answering
+    async fn reject(
+        &mut self,
+        error: HandlerError,
+        request: Option<Box<Request>>,
+    ) -> ConnectionState {
+        let mut response = Response::new();
+        let request = match request {
+            Some(mut request) => {
+                request.remote_addr = self.remote_addr;
+                request.tls = self.tls;
+                let dispatcher = self.context.dispatcher();
+                dispatcher.prepare(&mut request);
+                dispatcher.reject(&mut request, &mut response, &error).await;
+                *request
+            }
+            None => {
+                // Nothing of the request can be trusted, so renderers get an empty one
+                let request = Request::default();
+                self.context
+                    .error_pages
+                    .render(&error, &request, &mut response);
+                request
+            }
+        };
+        response.add_header("Connection", "close");
+        for plugin in self.context.plugins.iter() {
+            plugin.on_response_ready(&request, &mut response);
+        }
+        self.requests += 1;
+        let default_headers = &self.context.default_headers;
+        match Server::return_response(&mut response, default_headers, &mut self.stream, false).await
+        {
+            Ok(()) => ConnectionState::Closing { drain: true },
+            Err(e) => {
+                debug!(
+                    remote_ip = self.remote_ip,
+                    "Could not send {}: {}", error.status, e
+                );
+                ConnectionState::Closed
+            }
+        }
+    }
+
+    async fn close(&mut self, drain: bool) {
+        // Sends a FIN once everything is flushed, rather than leaving the client to notice the
+        // socket being dropped
+        if let Err(e) = self.stream.shutdown().await {
+            debug!(
+                remote_ip = self.remote_ip,
+                "Could not shut down connection: {}", e
+            );
+            return;
+        }
+        if !drain {
+            return;
+        }
+        // Until the client closes its side too, having read the response, or gives up
+        let mut discard = [0; 8 * ONE_KB];
+        let _ = tokio::time::timeout(LINGER_TIMEOUT, async {
+            while matches!(self.stream.read(&mut discard).await, Ok(num_bytes) if num_bytes > 0) {}
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{route, routes};
+    use crate::{RequestParam, ResponseParam, ONE_MB};
+
+    route!(
+        hello_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.text(&format!("hello {}", request.params["name"]));
+            response.add_header("Accept-Ranges", "bytes");
+            response.send();
+            Ok(())
+        }
+    );
+
+    route!(
+        passing_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.next();
+            Ok(())
+        }
+    );
+
+    // Neither sends nor passes on, which still ends the routing
+    route!(
+        empty_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.add_header("X-Empty", "yes");
+            Ok(())
+        }
+    );
+
+    fn counting_middleware(count: &Arc<AtomicUsize>) -> impl Middleware {
+        let count = count.clone();
+        move |_request, _response| {
+            count.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Next::Continue })
+        }
+    }
+
+    fn request(method: HttpMethod, path: &str) -> Request {
+        Request {
+            method,
+            path: path.to_string(),
+            uri: path.to_string(),
+            ..Request::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_requests_without_a_socket() {
+        let mut server = Server::builder().build();
+        server.add_middleware(|_request, response| {
+            Box::pin(async move {
+                response.add_header("X-Seen", "yes");
+                Next::Continue
+            })
+        });
+        server
+            .add_routes(routes! { GET "/hello/:name" => hello_handler })
+            .unwrap();
+
+        let response = server.handle(request(HttpMethod::GET, "/hello/kyle")).await;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.get_body_as_string(), "hello kyle");
+        assert_eq!(response.headers.get("X-Seen").unwrap(), "yes");
+        let date = response.headers.get("Date").unwrap();
+        assert!(
+            chrono::DateTime::parse_from_rfc2822(date).is_ok(),
+            "{}",
+            date
+        );
+
+        let mut ranged = request(HttpMethod::GET, "/hello/kyle");
+        ranged.headers.insert("Range", "bytes=0-4");
+        let response = server.handle(ranged).await;
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.get_body_as_string(), "hello");
+
+        let response = server
+            .handle(request(HttpMethod::POST, "/hello/kyle"))
+            .await;
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response.headers.get("X-Seen").unwrap(), "yes");
+    }
+
+    route!(
+        slow_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            response.text("finished");
+            Ok(())
+        }
+    );
+
+    #[tokio::test]
+    async fn workers_share_a_port_and_drain_on_shutdown() {
+        // A free port, since every worker binds the address separately
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut server = Server::builder()
+            .bind(&address)
+            .config(ServerConfig {
+                workers: 2,
+                ..Default::default()
+            })
+            .build();
+        server
+            .add_routes(routes! { GET "/slow" => slow_handler })
+            .unwrap();
+        let metrics = server.worker_metrics();
+        let shutdown = server.shutdown_handle();
+        let running = tokio::spawn(async move { server.start().await.unwrap() });
+
+        let connect = || async {
+            for _ in 0..50 {
+                if let Ok(stream) = TcpStream::connect(&address).await {
+                    return stream;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("server never started listening");
+        };
+        let mut idle = connect().await;
+        let mut busy = connect().await;
+        busy.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stats = metrics.snapshot();
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().all(|stats| stats.address == address));
+        assert_eq!(stats.iter().map(|stats| stats.active).sum::<u64>(), 2);
+
+        shutdown.shutdown();
+        let mut received = Vec::new();
+        idle.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+        busy.read_to_end(&mut received).await.unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{}", received);
+        assert!(received.contains("connection: close\r\n"), "{}", received);
+        assert!(received.ends_with("finished"), "{}", received);
+        // Having read the response, as clients do, rather than leaving the server to linger
+        drop((idle, busy));
+
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("start returns once drained")
+            .unwrap();
+        assert!(TcpStream::connect(&address).await.is_err());
+        let stats = metrics.snapshot();
+        assert_eq!(stats.iter().map(|stats| stats.accepted).sum::<u64>(), 2);
+        assert_eq!(stats.iter().map(|stats| stats.active).sum::<u64>(), 0);
+    }
+
+    #[tokio::test]
+    async fn closing_connections_drain_unread_bytes() {
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! { GET "/hello/:name" => hello_handler })
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+        for request in [
+            &b"GET /hello/kyle HTTP/1.1\r\nConnection: close\r\n\r\n"[..],
+            // Rejected before the rest of it is read
+            &b"GET /hello/kyle HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"[..],
+        ] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            // More than the socket buffers hold, so the client is still sending when the server
+            // closes. Left unread, the rest would be answered with a RST
+            let mut bytes = request.to_vec();
+            bytes.extend(vec![b'x'; 8 * ONE_MB]);
+            let (mut read_half, mut write_half) = stream.split();
+            let (written, read) = tokio::join!(write_half.write_all(&bytes), async {
+                // Give the server time to answer and close while the bytes are in flight
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let mut received = Vec::new();
+                read_half.read_to_end(&mut received).await.map(|_| received)
+            });
+            written.unwrap();
+            let received = String::from_utf8(read.unwrap()).unwrap();
+            assert!(received.contains("connection: close\r\n"), "{}", received);
+            assert!(
+                received.ends_with("hello kyle") || received.ends_with("malformed request\"}"),
+                "{}",
+                received
+            );
+        }
+    }
+
+    #[test]
+    fn lists_routes_in_the_order_they_are_tried() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! {
+                GET "/hello/:name" => hello_handler,
+                GET "/hello/me" => hello_handler,
+                POST "/files/*path" => empty_handler,
+            })
+            .unwrap();
+        let mut admin = server.scope("/admin");
+        admin.add_middleware(counting_middleware(&count));
+        admin
+            .add_routes(routes! {
+                [counting_middleware(&count)] DELETE "/users/:id?" => empty_handler,
+            })
+            .unwrap();
+
+        let routes: Vec<(String, String, Vec<String>, usize)> = server
+            .routes()
+            .into_iter()
+            .map(|route| {
+                let method = route.method.to_string();
+                (method, route.pattern, route.params, route.middlewares)
+            })
+            .collect();
+        let expected = [
+            ("GET", "/hello/me", vec![], 0),
+            ("GET", "/hello/:name", vec!["name"], 0),
+            ("POST", "/files/*path", vec!["path"], 0),
+            ("DELETE", "/admin/users/:id?", vec!["id"], 2),
+        ]
+        .map(|(method, pattern, params, middlewares)| {
+            let params = params.into_iter().map(String::from).collect();
+            (method.to_string(), pattern.to_string(), params, middlewares)
+        });
+        assert_eq!(routes, expected);
+    }
+
+    #[tokio::test]
+    async fn answers_oversized_requests_with_413() {
+        let mut server = Server::builder().build();
+        server.add_middleware(|_request, response| {
+            Box::pin(async move {
+                response.add_header("X-Seen", "yes");
+                Next::Continue
+            })
+        });
+        server
+            .add_routes(routes! { POST "/hello/:name" => hello_handler })
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut bytes = format!(
+            "POST /hello/kyle HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            2 * ONE_MB
+        )
+        .into_bytes();
+        bytes.extend(vec![b'x'; 2 * ONE_MB]);
+        let (mut read_half, mut write_half) = stream.split();
+        let (_, read) = tokio::join!(write_half.write_all(&bytes), async {
+            let mut received = Vec::new();
+            read_half.read_to_end(&mut received).await.map(|_| received)
+        });
+        let received = String::from_utf8(read.unwrap()).unwrap();
+        assert!(
+            received.starts_with("HTTP/1.1 413 Content Too Large\r\n"),
+            "{}",
+            received
+        );
+        // Through the middlewares, like any other error
+        assert!(received.contains("x-seen: yes\r\n"), "{}", received);
+        assert!(received.contains("connection: close\r\n"), "{}", received);
+        assert!(received.ends_with(r#"{"message":"request is larger than 1MB"}"#));
+    }
+
+    // 1x1 transparent PNG, which contains plenty of non UTF-8 bytes
+    const PNG: &[u8] = &[
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f,
+        0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00,
+        0x01, 0x00, 0x00, 0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49,
+        0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    #[tokio::test]
+    async fn runs_each_middleware_once_per_request() {
+        let global = Arc::new(AtomicUsize::new(0));
+        let scoped = Arc::new(AtomicUsize::new(0));
+        let mut server = Server::builder().build();
+        server.add_middleware(counting_middleware(&global));
+        let mut users = server.scope("/users");
+        users.add_middleware(counting_middleware(&scoped));
+        users
+            .add_routes(routes! {
+                GET "/me" => passing_handler,
+                GET "/:name" => hello_handler,
+            })
+            .unwrap();
+
+        // Both routes match, so both handlers run, but the scope's middleware only once
+        let response = server.handle(request(HttpMethod::GET, "/users/me")).await;
+        assert_eq!(response.get_body_as_string(), "hello me");
+        assert_eq!(global.load(Ordering::SeqCst), 1);
+        assert_eq!(scoped.load(Ordering::SeqCst), 1);
+
+        let response = server.handle(request(HttpMethod::GET, "/nowhere")).await;
+        assert_eq!(response.status_code, 404);
+        assert_eq!(global.load(Ordering::SeqCst), 2);
+        assert_eq!(scoped.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn closures_can_be_handlers() {
+        let greeting = Arc::new("hi".to_string());
+        let mut server = Server::builder().build();
+        server
+            .route(HttpMethod::GET, "/greet/:name", move |request, response| {
+                let greeting = greeting.clone();
+                Box::pin(async move {
+                    response.text(&format!("{} {}", greeting, request.params["name"]));
+                    response.send();
+                    Ok(())
+                })
+            })
+            .unwrap();
+
+        let response = server.handle(request(HttpMethod::GET, "/greet/kyle")).await;
+        assert_eq!(response.get_body_as_string(), "hi kyle");
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_handler_unless_it_calls_next() {
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! {
+                GET "/pages/about" => empty_handler,
+                GET "/pages/:name" => hello_handler,
+                GET "/posts/draft" => passing_handler,
+            })
+            .unwrap();
+
+        let response = server
+            .handle(request(HttpMethod::GET, "/pages/about"))
+            .await;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.headers.get("X-Empty").unwrap(), "yes");
+        assert_eq!(response.get_body_as_string(), "");
+
+        // Passing on with no routes left ends in a 404
+        let response = server
+            .handle(request(HttpMethod::GET, "/posts/draft"))
+            .await;
+        assert_eq!(response.status_code, 404);
+    }
+
+    #[tokio::test]
+    async fn writes_whole_responses_and_reports_disconnects() {
+        // Much bigger than the pipe's buffer, so needs several writes
+        let body = vec![b'x'; 256 * ONE_KB];
+        let (mut server_end, mut client_end) = tokio::io::duplex(ONE_KB);
+        let mut response = Response::new();
+        response.set_body(body.clone());
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client_end.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        Server::return_response(
+            &mut response,
+            &DefaultHeaders::default(),
+            &mut server_end,
+            false,
+        )
+        .await
+        .unwrap();
+        drop(server_end);
+        assert!(reader.await.unwrap().ends_with(&body));
+
+        let (mut server_end, client_end) = tokio::io::duplex(ONE_KB);
+        drop(client_end);
+        let error = Server::return_response(
+            &mut response,
+            &DefaultHeaders::default(),
+            &mut server_end,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(Server::is_disconnect(&error), "{}", error);
+    }
+
+    async fn serialise(mut response: Response, accepts_trailers: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        Server::return_response(
+            &mut response,
+            &DefaultHeaders::default(),
+            &mut bytes,
+            accepts_trailers,
+        )
+        .await
+        .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn only_removes_stale_sockets() {
+        let dir = std::env::temp_dir().join(format!("kblue_http_socket_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        assert!(socket.exists());
+        Server::remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        Server::remove_stale_socket(&socket).unwrap();
+
+        let file = dir.join("config.toml");
+        std::fs::write(&file, "port = 8080").unwrap();
+        let error = Server::remove_stale_socket(&file).unwrap_err();
+        assert!(error.contains("isn't a socket"), "{}", error);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "port = 8080");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_default_headers() {
+        let default_headers = DefaultHeaders::new()
+            .set("Server", "kblue")
+            .set("Cache-Control", "no-store")
+            .remove("X-Powered-By");
+        let mut response = Response::new();
+        response.add_header("Cache-Control", "max-age=60");
+        // e.g. relayed from a proxied upstream
+        response.add_header("X-Powered-By", "grafana");
+        let mut bytes = Vec::new();
+        Server::return_response(&mut response, &default_headers, &mut bytes, false)
+            .await
+            .unwrap();
+
+        let head = String::from_utf8(bytes).unwrap();
+        assert!(head.contains("\r\nserver: kblue\r\n"), "{}", head);
+        assert!(
+            head.contains("\r\ncache-control: max-age=60\r\n"),
+            "{}",
+            head
+        );
+        assert!(!head.contains("no-store"), "{}", head);
+        assert!(!head.contains("x-powered-by"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn writes_streamed_bodies_as_they_arrive() {
+        let (sender, body) = mpsc::channel(2);
+        sender.send(Ok(b"hello ".to_vec())).await.unwrap();
+        sender.send(Ok(b"kyle".to_vec())).await.unwrap();
+        drop(sender);
+        let mut response = Response::new();
+        response.set_body_stream(body, None);
+        let written = String::from_utf8(serialise(response, false).await).unwrap();
+        assert!(
+            written.contains("\r\ntransfer-encoding: chunked\r\n"),
+            "{}",
+            written
+        );
+        assert!(!written.contains("content-length"), "{}", written);
+        assert!(
+            written.ends_with("\r\n\r\n6\r\nhello \r\n4\r\nkyle\r\n0\r\n\r\n"),
+            "{}",
+            written
+        );
+
+        // Cut short, which only closing the connection can tell the client
+        let (sender, body) = mpsc::channel(2);
+        sender.send(Ok(b"hel".to_vec())).await.unwrap();
+        sender
+            .send(Err(std::io::ErrorKind::TimedOut.into()))
+            .await
+            .unwrap();
+        let mut response = Response::new();
+        response.set_body_stream(body, Some(5));
+        let mut bytes = Vec::new();
+        let result =
+            Server::return_response(&mut response, &DefaultHeaders::default(), &mut bytes, false)
+                .await;
+        assert!(result.is_err());
+        assert!(String::from_utf8(bytes).unwrap().ends_with("\r\n\r\nhel"));
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_end_with_announced_trailers() {
+        let streamed = |accepts_trailers: bool| async move {
+            let (sender, body) = mpsc::channel(1);
+            let (trailer_sender, trailers) = tokio::sync::oneshot::channel();
+            let mut response = Response::new();
+            response.set_body_stream(body, None);
+            response.set_stream_trailers(&["Digest"], trailers).unwrap();
+            // The digest is only known once the body has been sent
+            tokio::spawn(async move {
+                sender.send(Ok(b"hello".to_vec())).await.unwrap();
+                drop(sender);
+                let mut fields = HashMap::new();
+                fields.insert("digest".to_string(), "sha-256=abc".to_string());
+                fields.insert("x-unannounced".to_string(), "1".to_string());
+                let _ = trailer_sender.send(fields);
+            });
+            String::from_utf8(serialise(response, accepts_trailers).await).unwrap()
+        };
+
+        let written = streamed(true).await;
+        assert!(written.contains("\r\ntrailer: digest\r\n"), "{}", written);
+        assert!(
+            written.ends_with("\r\n\r\n5\r\nhello\r\n0\r\ndigest: sha-256=abc\r\n\r\n"),
+            "{}",
+            written
+        );
+        let written = streamed(false).await;
+        assert!(!written.contains("trailer"), "{}", written);
+        assert!(
+            written.ends_with("\r\n5\r\nhello\r\n0\r\n\r\n"),
+            "{}",
+            written
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_trailers_only_go_to_clients_accepting_them() {
+        let chunked = || {
+            let mut response = Response::new();
+            response.text("hello ");
+            response.write_chunk(b"kyle");
+            response.set_trailer("Server-Timing", "db;dur=3").unwrap();
+            response
+        };
+        let written = String::from_utf8(serialise(chunked(), true).await).unwrap();
+        assert!(!written.contains("content-length"), "{}", written);
+        assert!(
+            written.contains("\r\ntrailer: server-timing\r\n"),
+            "{}",
+            written
+        );
+        assert!(
+            written.ends_with("\r\n0\r\nserver-timing: db;dur=3\r\n\r\n"),
+            "{}",
+            written
+        );
+        let written = String::from_utf8(serialise(chunked(), false).await).unwrap();
+        assert!(!written.contains("trailer"), "{}", written);
+        assert!(!written.contains("server-timing"), "{}", written);
+        assert!(
+            written.ends_with("\r\n4\r\nkyle\r\n0\r\n\r\n"),
+            "{}",
+            written
+        );
+    }
+
+    #[tokio::test]
+    async fn dates_responses_as_they_are_written() {
+        let response = Response::new();
+        assert!(!response.headers.contains_key("Date"));
+        let bytes = serialise(response, false).await;
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut parsed = httparse::Response::new(&mut headers);
+        parsed.parse(&bytes).unwrap();
+        let date = parsed
+            .headers
+            .iter()
+            .find(|header| header.name == "date")
+            .map(|header| std::str::from_utf8(header.value).unwrap())
+            .unwrap();
+        let date = chrono::DateTime::parse_from_rfc2822(date).unwrap();
+        assert!((chrono::Utc::now() - date.to_utc()).num_seconds() < 2);
+
+        let mut response = Response::new();
+        response.add_header("Date", "Mon, 01 Jan 2024 00:00:00 GMT");
+        let bytes = serialise(response, false).await;
+        let head = String::from_utf8(bytes).unwrap();
+        assert!(head.contains("date: Mon, 01 Jan 2024 00:00:00 GMT\r\n"));
+        assert_eq!(head.matches("date:").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn png_body_round_trips_through_serialisation() {
+        let mut response = Response::new();
+        response.add_header("Content-Type", "image/png");
+        response.set_body(PNG.to_vec());
+
+        let bytes = serialise(response, false).await;
+
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let head_len = match parsed.parse(&bytes).unwrap() {
+            httparse::Status::Complete(head_len) => head_len,
+            httparse::Status::Partial => panic!("Response head is incomplete"),
+        };
+        assert_eq!(parsed.code, Some(200));
+        let content_length = parsed
+            .headers
+            .iter()
+            .find(|header| header.name == "content-length")
+            .map(|header| std::str::from_utf8(header.value).unwrap())
+            .unwrap();
+        assert_eq!(content_length, PNG.len().to_string());
+        assert_eq!(&bytes[head_len..], PNG);
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_follow_the_head() {
+        let mut response = Response::new();
+        response.write_chunk(b"hello ");
+        response.write_chunk(b"world");
+
+        let bytes = serialise(response, false).await;
+
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(!text.contains("content-length"));
+        assert!(text.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn repeated_headers_are_serialised_separately() {
+        let mut response = Response::new();
+        response.append_header("Set-Cookie", "a=1");
+        response.append_header("Set-Cookie", "b=2");
+
+        let bytes = serialise(response, false).await;
+
+        let head = String::from_utf8(bytes).unwrap();
+        assert!(head.contains("set-cookie: a=1\r\nset-cookie: b=2\r\n"));
+    }
+}
@@ -0,0 +1,25 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Values shared with every handler, keyed by type. Cloning is cheap, so each request gets its own handle
+#[derive(Clone, Default)]
+pub struct States(Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>);
+
+impl States {
+    // Replaces any existing state of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, state: T) {
+        Arc::make_mut(&mut self.0).insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    // For a state shared with other servers, which all hand out the same Arc
+    pub fn insert_arc<T: Send + Sync + 'static>(&mut self, state: Arc<T>) {
+        Arc::make_mut(&mut self.0).insert(TypeId::of::<T>(), state);
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|state| state.clone().downcast::<T>().ok())
+    }
+}
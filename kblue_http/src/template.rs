@@ -0,0 +1,284 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use super::response::Response;
+
+// Partials including partials stop here, in case one includes itself
+const MAX_DEPTH: usize = 8;
+
+// A set of templates, usually compiled in with include_str! so the binary doesn't depend on files
+// next to it. `{{variable}}` tags are replaced with values, HTML escaped in .html templates so user
+// input can't add markup, and `{{> name}}` includes `partials/name` with the same extension as
+// the template including it
+#[derive(Clone)]
+pub struct Templates {
+    sources: Sources,
+}
+
+#[derive(Clone)]
+enum Sources {
+    Bundled(&'static [(&'static str, &'static str)]),
+    // Read at runtime, see with_overrides
+    Owned(Vec<(String, String)>),
+}
+
+// A template picked from a set, e.g. `PAGES.get("maintenance.html")`, for Response::render
+#[derive(Clone, Copy)]
+pub struct Template<'a> {
+    templates: &'a Templates,
+    name: &'a str,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    NotFound(String),
+    // A `{{name}}` with no value given, most likely a typo in the template
+    MissingVariable(String),
+    Unterminated(String),
+    TooDeep(String),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "no template named {}", name),
+            TemplateError::MissingVariable(name) => write!(f, "no value for {{{{{}}}}}", name),
+            TemplateError::Unterminated(name) => write!(f, "unterminated tag in {}", name),
+            TemplateError::TooDeep(name) => write!(f, "partials nested too deeply in {}", name),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Templates {
+    pub const fn new(sources: &'static [(&'static str, &'static str)]) -> Self {
+        Self {
+            sources: Sources::Bundled(sources),
+        }
+    }
+
+    // A copy with the templates that have a file of the same name under dir replaced by it, e.g.
+    // dir/partials/header.html, so they can be changed without a rebuild. Files not matching a
+    // template are ignored
+    pub fn with_overrides(&self, dir: &Path) -> Result<Templates, String> {
+        let mut sources = Vec::new();
+        for (name, source) in self.sources() {
+            let path = dir.join(name);
+            let source = match path.is_file() {
+                true => fs::read_to_string(&path)
+                    .map_err(|e| format!("Could not read {} ({})", path.display(), e))?,
+                false => source.to_string(),
+            };
+            sources.push((name.to_string(), source));
+        }
+        Ok(Templates {
+            sources: Sources::Owned(sources),
+        })
+    }
+
+    // Names and sources, partials included
+    pub fn sources(&self) -> impl Iterator<Item = (&str, &str)> {
+        let sources: Vec<(&str, &str)> = match &self.sources {
+            Sources::Bundled(sources) => sources.to_vec(),
+            Sources::Owned(sources) => sources
+                .iter()
+                .map(|(name, source)| (name.as_str(), source.as_str()))
+                .collect(),
+        };
+        sources.into_iter()
+    }
+
+    pub fn get<'a>(&'a self, name: &'a str) -> Template<'a> {
+        Template {
+            templates: self,
+            name,
+        }
+    }
+
+    pub fn render(&self, name: &str, variables: &[(&str, &str)]) -> Result<String, TemplateError> {
+        self.render_at(name, variables, 0)
+    }
+
+    // Every template but the partials, e.g. to check they all render in a test
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sources()
+            .map(|(name, _)| name)
+            .filter(|name| !name.starts_with("partials/"))
+    }
+
+    fn render_at(
+        &self,
+        name: &str,
+        variables: &[(&str, &str)],
+        depth: usize,
+    ) -> Result<String, TemplateError> {
+        if depth > MAX_DEPTH {
+            return Err(TemplateError::TooDeep(name.to_string()));
+        }
+        let source = self
+            .sources()
+            .find(|(template_name, _)| *template_name == name)
+            .map(|(_, source)| source)
+            .ok_or_else(|| TemplateError::NotFound(name.to_string()))?;
+        let extension = extension(name);
+
+        let mut rendered = String::with_capacity(source.len());
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            rendered.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| TemplateError::Unterminated(name.to_string()))?;
+            let tag = after[..end].trim();
+            rest = &after[end + 2..];
+
+            if let Some(partial) = tag.strip_prefix('>') {
+                let partial = format!("partials/{}.{}", partial.trim(), extension);
+                let partial = self.render_at(&partial, variables, depth + 1)?;
+                // Partials end in a newline, which would otherwise double up with the tag's own
+                rendered.push_str(partial.strip_suffix('\n').unwrap_or(&partial));
+                continue;
+            }
+            let value = variables
+                .iter()
+                .find(|(variable, _)| *variable == tag)
+                .map(|(_, value)| *value)
+                .ok_or_else(|| TemplateError::MissingVariable(tag.to_string()))?;
+            match extension {
+                "html" => rendered.push_str(&escape_html(value)),
+                _ => rendered.push_str(value),
+            }
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
+
+impl Template<'_> {
+    pub fn render(&self, variables: &[(&str, &str)]) -> Result<String, TemplateError> {
+        self.templates.render(self.name, variables)
+    }
+}
+
+fn extension(name: &str) -> &str {
+    name.rsplit_once('.').map_or("", |(_, extension)| extension)
+}
+
+// User data interpolated into HTML goes through this, so it can't add markup or styles
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+impl Response {
+    // Renders the template as the body, e.g. a server rendered page. .html templates are sent as
+    // HTML, anything else as plain text
+    pub fn render(
+        &mut self,
+        template: Template<'_>,
+        context: &[(&str, &str)],
+    ) -> Result<&mut Self, TemplateError> {
+        let rendered = template.render(context)?;
+        Ok(match extension(template.name) {
+            "html" => self.html(&rendered),
+            _ => self.text(&rendered),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_TEMPLATES: Templates = Templates::new(&[
+        ("page.html", "{{> header}}<p>{{ body }}</p>"),
+        ("page.txt", "{{> header}}{{body}}"),
+        ("partials/header.html", "<h1>{{title}}</h1>\n"),
+        ("partials/header.txt", "# {{title}}\n"),
+        ("loop.html", "{{> loop}}"),
+        ("partials/loop.html", "{{> loop}}"),
+        ("broken.html", "{{title"),
+    ]);
+
+    #[test]
+    fn renders_variables_and_partials() {
+        let variables = [("title", "Hi & bye"), ("body", "<b>hey</b>")];
+        assert_eq!(
+            TEST_TEMPLATES.render("page.html", &variables).unwrap(),
+            "<h1>Hi &amp; bye</h1><p>&lt;b&gt;hey&lt;/b&gt;</p>"
+        );
+        assert_eq!(
+            TEST_TEMPLATES.render("page.txt", &variables).unwrap(),
+            "# Hi & bye<b>hey</b>"
+        );
+
+        let mut response = Response::new();
+        response
+            .render(TEST_TEMPLATES.get("page.html"), &variables)
+            .unwrap();
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert!(response
+            .get_body_as_string()
+            .starts_with("<h1>Hi &amp; bye"));
+    }
+
+    #[test]
+    fn reports_template_mistakes() {
+        let cases = [
+            (
+                "page.html",
+                TemplateError::MissingVariable("title".to_string()),
+            ),
+            (
+                "missing.html",
+                TemplateError::NotFound("missing.html".to_string()),
+            ),
+            (
+                "broken.html",
+                TemplateError::Unterminated("broken.html".to_string()),
+            ),
+            (
+                "loop.html",
+                TemplateError::TooDeep("partials/loop.html".to_string()),
+            ),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(TEST_TEMPLATES.render(name, &[]), Err(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn overrides_templates_from_a_directory() {
+        let dir = std::env::temp_dir().join(format!("templates_test_{}", std::process::id()));
+        fs::create_dir_all(dir.join("partials")).unwrap();
+        fs::write(dir.join("partials/header.html"), "<h2>{{title}}</h2>\n").unwrap();
+        fs::write(dir.join("unknown.html"), "ignored").unwrap();
+        let templates = TEST_TEMPLATES.with_overrides(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let variables = [("title", "Hi"), ("body", "hey")];
+        assert_eq!(
+            templates.render("page.html", &variables).unwrap(),
+            "<h2>Hi</h2><p>hey</p>"
+        );
+        assert_eq!(templates.render("page.txt", &variables).unwrap(), "# Hihey");
+        assert!(templates.names().all(|name| name != "unknown.html"));
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
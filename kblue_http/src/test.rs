@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::de::DeserializeOwned;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use super::constants::HttpMethod;
+use super::server::Server;
+use crate::client;
+
+// Drives a Server over real TCP from tests, e.g.
+// `let server = TestServer::spawn(server).await; assert_eq!(server.get("/healthz").await.status, 200);`
+// Each request goes over a new connection. The server stops when the TestServer is dropped
+pub struct TestServer {
+    address: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    // Lower case header names. Repeated headers keep their last value
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    // Panics if the body isn't a T, with the body in the message
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("Response isn't the expected JSON ({}): {}", e, self.text()))
+    }
+}
+
+impl TestServer {
+    // Listens on an ephemeral port on localhost, ignoring the server's listen addresses
+    pub async fn spawn(server: Server) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("can bind to localhost");
+        let address = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            server.serve(listener).await.expect("test server failed");
+        });
+        Self { address, handle }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(HttpMethod::GET, path, &[], None).await
+    }
+
+    pub async fn post(&self, path: &str, content_type: &str, body: &[u8]) -> TestResponse {
+        self.request(
+            HttpMethod::POST,
+            path,
+            &[("Content-Type", content_type)],
+            Some(body),
+        )
+        .await
+    }
+
+    pub async fn post_json(&self, path: &str, body: &serde_json::Value) -> TestResponse {
+        self.post(path, "application/json", body.to_string().as_bytes())
+            .await
+    }
+
+    // Panics if the server doesn't send back a complete response
+    pub async fn request(
+        &self,
+        method: HttpMethod,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> TestResponse {
+        let description = format!("{} {}", method, path);
+        let response = client::request(method, &self.url(path), headers, body)
+            .await
+            .unwrap_or_else(|e| panic!("{} got no response: {}", description, e));
+        TestResponse {
+            status: response.status_code,
+            headers: response.headers,
+            body: response.body,
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{render_json_error, ErrorStatuses, HttpMethod};
+    use crate::{route, routes};
+    use crate::{HandlerError, RequestParam, ResponseParam, Router};
+
+    // Echoes what the router and parser made of the request
+    route!(
+        echo_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.json(&json!({
+                "params": request.params,
+                "query": request.query,
+            }))?;
+            response.send();
+            Ok(())
+        }
+    );
+
+    route!(
+        me_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.text("me");
+            response.send();
+            Ok(())
+        }
+    );
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Message {
+        name: String,
+        message: String,
+    }
+
+    route!(
+        json_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            let body = request.parse_json::<Message>()?;
+            response.text(&format!("{}: {}", body.name, body.message));
+            response.send();
+            Ok(())
+        }
+    );
+
+    route!(
+        form_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            let body = request.parse_form::<Message>()?;
+            response.text(&format!("{}: {}", body.name, body.message));
+            response.send();
+            Ok(())
+        }
+    );
+
+    route!(
+        failing_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            Err(HandlerError::not_found("no such thing"))
+        }
+    );
+
+    route!(
+        broken_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            Err(HandlerError::internal("database down"))
+        }
+    );
+
+    async fn spawn() -> TestServer {
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! {
+                // Less specific first, registration order shouldn't matter
+                GET "/users/:id" => echo_handler,
+                GET "/users/me" => me_handler,
+                GET "/files/*path" => echo_handler,
+                GET "/docs/:page?" => echo_handler,
+                GET "/missing" => failing_handler,
+                POST "/messages" => json_handler,
+                POST "/forms" => form_handler,
+            })
+            .unwrap();
+        TestServer::spawn(server).await
+    }
+
+    #[tokio::test]
+    async fn routes_to_the_most_specific_match() {
+        let server = spawn().await;
+
+        assert_eq!(server.get("/users/me").await.text(), "me");
+        let body: Value = server.get("/users/42").await.json();
+        assert_eq!(body["params"]["id"], "42");
+        let body: Value = server.get("/files/cv/2026.pdf").await.json();
+        assert_eq!(body["params"]["path"], "cv/2026.pdf");
+        let body: Value = server.get("/docs").await.json();
+        assert_eq!(body["params"], json!({}));
+        let body: Value = server.get("/docs/setup").await.json();
+        assert_eq!(body["params"]["page"], "setup");
+    }
+
+    #[tokio::test]
+    async fn parses_params_and_queries() {
+        let server = spawn().await;
+
+        let body: Value = server
+            .get("/users/kblue?tab=posts&q=rust%20web&tag=rust&tag=web")
+            .await
+            .json();
+        assert_eq!(body["params"]["id"], "kblue");
+        assert_eq!(
+            body["query"],
+            json!({ "tab": "posts", "q": "rust web", "tag": ["rust", "web"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_errors_become_json() {
+        let server = spawn().await;
+
+        let response = server.get("/missing").await;
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        let body: Value = response.json();
+        assert_eq!(body["message"], "no such thing");
+    }
+
+    #[tokio::test]
+    async fn error_pages_follow_status_and_accept() {
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! {
+                GET "/missing" => failing_handler,
+                GET "/broken" => broken_handler,
+                POST "/messages" => json_handler,
+            })
+            .unwrap();
+        server.set_error_page(
+            ErrorStatuses::Exactly(404),
+            |error, request, response| match response
+                .negotiate(request, &["application/json", "text/html"])
+            {
+                Some("text/html") => {
+                    response.status(404).html("<h1>Lost?</h1>");
+                }
+                _ => render_json_error(error, response),
+            },
+        );
+        server.set_error_page(ErrorStatuses::ServerErrors, |error, _request, response| {
+            response.status(error.status).text("sorry");
+        });
+        let server = TestServer::spawn(server).await;
+        let html = [("Accept", "text/html,*/*;q=0.8")];
+
+        // Handler errors and unrouted paths alike
+        for path in ["/missing", "/nowhere"] {
+            let response = server.request(HttpMethod::GET, path, &html, None).await;
+            assert_eq!(response.status, 404);
+            assert_eq!(response.text(), "<h1>Lost?</h1>", "{}", path);
+        }
+        let response = server.get("/missing").await;
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+
+        let response = server.get("/broken").await;
+        assert_eq!(response.status, 500);
+        assert_eq!(response.text(), "sorry");
+
+        // Nothing set for 400, so the default page
+        let headers = [html[0], ("Content-Type", "application/json")];
+        let response = server
+            .request(HttpMethod::POST, "/messages", &headers, Some(b"{"))
+            .await;
+        assert_eq!(response.status, 400);
+        assert!(response
+            .header("Content-Type")
+            .is_some_and(|content_type| content_type.starts_with("text/html")));
+        assert!(response.text().contains("<h1>400 Bad Request</h1>"));
+        assert_eq!(response.header("Vary"), Some("Accept"));
+    }
+
+    #[tokio::test]
+    async fn parses_bodies() {
+        let server = spawn().await;
+
+        let response = server
+            .post_json("/messages", &json!({ "name": "Kyle", "message": "Hi" }))
+            .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "Kyle: Hi");
+
+        let response = server
+            .post_json("/messages", &json!({ "name": "Kyle", "mesage": "Hi" }))
+            .await;
+        assert_eq!(response.status, 400);
+        let body: Value = response.json();
+        assert!(body["errors"].to_string().contains("mesage"), "{}", body);
+
+        let response = server
+            .post("/messages", "application/json", b"{\"name\": ")
+            .await;
+        assert_eq!(response.status, 400);
+
+        let response = server
+            .post(
+                "/forms",
+                "application/x-www-form-urlencoded",
+                b"name=Kyle&message=Hello+there%21",
+            )
+            .await;
+        assert_eq!(response.text(), "Kyle: Hello there!");
+    }
+
+    #[tokio::test]
+    async fn rejects_heads_over_the_limits() {
+        let server = spawn().await;
+
+        let response = server
+            .get(&format!("/users/{}", "a".repeat(9 * 1024)))
+            .await;
+        assert_eq!(response.status, 414);
+        let body: Value = response.json();
+        assert_eq!(body["message"], "request URI is too long");
+
+        let names: Vec<String> = (0..101).map(|i| format!("X-Header-{}", i)).collect();
+        let headers: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "1")).collect();
+        let response = server
+            .request(HttpMethod::GET, "/users/me", &headers, None)
+            .await;
+        assert_eq!(response.status, 431);
+        assert_eq!(response.header("Connection"), Some("close"));
+
+        let cookie = "a".repeat(9 * 1024);
+        let response = server
+            .request(HttpMethod::GET, "/users/me", &[("Cookie", &cookie)], None)
+            .await;
+        assert_eq!(response.status, 431);
+
+        // Comfortably within the limits
+        let response = server
+            .request(HttpMethod::GET, "/users/me", &headers[..50], None)
+            .await;
+        assert_eq!(response.text(), "me");
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::TlsAcceptor;
+
+// Certificate and key for serving HTTPS directly, set as ServerConfig.tls. Without it the
+// server speaks plain HTTP and expects a reverse proxy in front to terminate TLS
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsConfig {
+    // The certificate file holds the chain, leaf first. The key may be PKCS#8, PKCS#1 or SEC1
+    pub fn from_pem_files(cert_path: &str, key_path: &str) -> Result<Self, String> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Could not read certificates from {} ({})", cert_path, e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificates in {}", cert_path));
+        }
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| format!("Could not read private key from {} ({})", key_path, e))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid certificate or key ({})", e))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub(crate) fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::ServerName;
+    use rustls::RootCertStore;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+    use crate::{route, HttpMethod, RequestParam, ResponseParam, Router, Server, ServerConfig};
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.crt");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key");
+
+    route!(
+        hello_handler,
+        async move |_request: RequestParam, response: ResponseParam| {
+            response.text("hello over tls");
+            Ok(())
+        }
+    );
+
+    #[tokio::test]
+    async fn serves_https() {
+        let mut server = Server::builder()
+            .config(ServerConfig {
+                tls: Some(TlsConfig::from_pem_files(CERT, KEY).unwrap()),
+                ..Default::default()
+            })
+            .build();
+        server.route(HttpMethod::GET, "/", hello_handler).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+        // Trusts the self-signed fixture and nothing else
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(CERT).unwrap())
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        // The server may close without a close_notify, which is fine once the body has arrived
+        let _ = stream.read_to_end(&mut received).await;
+        let received = String::from_utf8_lossy(&received);
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{}", received);
+        assert!(received.ends_with("hello over tls"), "{}", received);
+
+        // Plain HTTP on the same port never reaches the handler
+        let mut plain = TcpStream::connect(address).await.unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        let _ = plain.read_to_end(&mut received).await;
+        assert!(!String::from_utf8_lossy(&received).contains("hello"));
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let error = TlsConfig::from_pem_files("/nonexistent.crt", KEY).unwrap_err();
+        assert!(error.contains("/nonexistent.crt"), "{}", error);
+        let error = TlsConfig::from_pem_files(CERT, CERT).unwrap_err();
+        assert!(error.contains("private key"), "{}", error);
+    }
+}
@@ -0,0 +1,151 @@
+use once_cell::sync::OnceCell;
+
+use super::negotiation::MediaRange;
+use super::request::Request;
+
+// `Authorization: <scheme> <credentials>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Authorization {
+    pub scheme: String,
+    pub credentials: String,
+}
+
+impl Authorization {
+    pub fn parse(header: &str) -> Option<Self> {
+        let (scheme, credentials) = header.trim().split_once(' ')?;
+        Some(Self {
+            scheme: scheme.to_string(),
+            credentials: credentials.trim().to_string(),
+        })
+    }
+
+    // The credentials if they're for this scheme, which is case insensitive
+    pub fn credentials_for(&self, scheme: &str) -> Option<&str> {
+        self.scheme
+            .eq_ignore_ascii_case(scheme)
+            .then_some(self.credentials.as_str())
+    }
+}
+
+// `If-None-Match`, either `*` or a list of entity tags
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IfNoneMatch {
+    Any,
+    // As sent, quotes and any W/ prefix included
+    Tags(Vec<String>),
+}
+
+impl IfNoneMatch {
+    pub fn parse(header: &str) -> Self {
+        if header.trim() == "*" {
+            return IfNoneMatch::Any;
+        }
+        IfNoneMatch::Tags(
+            header
+                .split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect(),
+        )
+    }
+
+    // Uses the weak comparison If-None-Match calls for, so W/"a" matches "a" (RFC 9110 13.1.2)
+    pub fn matches(&self, etag: &str) -> bool {
+        let opaque = |tag: &str| tag.trim_start_matches("W/").to_string();
+        match self {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Tags(tags) => tags.iter().any(|tag| opaque(tag) == opaque(etag)),
+        }
+    }
+}
+
+// The typed headers of a request, each parsed the first time it's asked for. Headers changed after
+// that aren't seen, which is fine since nothing changes a request's headers once it's parsed
+#[derive(Clone, Debug, Default)]
+pub struct TypedHeaders {
+    content_type: OnceCell<Option<String>>,
+    content_length: OnceCell<Option<usize>>,
+    authorization: OnceCell<Option<Authorization>>,
+    accept: OnceCell<Vec<MediaRange>>,
+    if_none_match: OnceCell<Option<IfNoneMatch>>,
+}
+
+impl Request {
+    // Media type without parameters, lowercased, e.g. `application/json`
+    pub fn content_type(&self) -> Option<&str> {
+        self.typed_headers
+            .content_type
+            .get_or_init(|| {
+                self.get_header("content-type")
+                    .and_then(|value| value.split(';').next())
+                    .map(|essence| essence.trim().to_lowercase())
+            })
+            .as_deref()
+    }
+
+    // None if missing or not a number
+    pub fn content_length(&self) -> Option<usize> {
+        *self.typed_headers.content_length.get_or_init(|| {
+            self.get_header("content-length")
+                .and_then(|value| value.trim().parse().ok())
+        })
+    }
+
+    pub fn authorization(&self) -> Option<&Authorization> {
+        self.typed_headers
+            .authorization
+            .get_or_init(|| {
+                self.get_header("authorization")
+                    .and_then(|value| Authorization::parse(value))
+            })
+            .as_ref()
+    }
+
+    // The client's Accept header, most preferred first. No header means anything goes
+    pub fn accept(&self) -> &[MediaRange] {
+        self.typed_headers.accept.get_or_init(|| {
+            MediaRange::parse_accept(
+                self.get_header("accept")
+                    .map_or("*/*", |value| value.as_str()),
+            )
+        })
+    }
+
+    pub fn if_none_match(&self) -> Option<&IfNoneMatch> {
+        self.typed_headers
+            .if_none_match
+            .get_or_init(|| {
+                self.get_header("if-none-match")
+                    .map(|value| IfNoneMatch::parse(value))
+            })
+            .as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_headers() {
+        let mut request = Request::default();
+        request
+            .headers
+            .insert("Content-Type", "Application/JSON; charset=utf-8");
+        request.headers.insert("Content-Length", "42");
+        request.headers.insert("Authorization", "bearer  abc.def ");
+        request.headers.insert("If-None-Match", "W/\"v1\", \"v2\"");
+
+        assert_eq!(request.content_type(), Some("application/json"));
+        assert_eq!(request.content_length(), Some(42));
+        let authorization = request.authorization().unwrap();
+        assert_eq!(authorization.credentials_for("Bearer"), Some("abc.def"));
+        assert_eq!(authorization.credentials_for("Basic"), None);
+        assert_eq!(request.accept()[0].media_type, "*/*");
+        let if_none_match = request.if_none_match().unwrap();
+        assert!(if_none_match.matches("\"v1\""));
+        assert!(if_none_match.matches("W/\"v2\""));
+        assert!(!if_none_match.matches("\"v3\""));
+        assert!(IfNoneMatch::parse("*").matches("\"anything\""));
+    }
+}
@@ -0,0 +1,62 @@
+// Catch all names must be identifiers, so e.g. a final `*.png` segment stays a glob
+pub fn is_param_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+pub fn normalise_path(path: &str) -> String {
+    if path.ends_with("/") {
+        path.to_string()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+// Compile time check used by the routes! macro. Paths are compared ignoring a trailing slash,
+// matching normalise_path at registration time
+pub const fn assert_unique_routes(routes: &[(&str, &str)]) {
+    let mut i = 0;
+    while i < routes.len() {
+        let mut j = i + 1;
+        while j < routes.len() {
+            if const_str_eq(routes[i].0, routes[j].0)
+                && const_path_eq(routes[i].1.as_bytes(), routes[j].1.as_bytes())
+            {
+                panic!("routes! contains a duplicate method and path");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+const fn const_str_eq(a: &str, b: &str) -> bool {
+    const_bytes_eq(a.as_bytes(), b.as_bytes(), a.len(), b.len())
+}
+
+const fn const_path_eq(a: &[u8], b: &[u8]) -> bool {
+    let a_len = if !a.is_empty() && a[a.len() - 1] == b'/' {
+        a.len() - 1
+    } else {
+        a.len()
+    };
+    let b_len = if !b.is_empty() && b[b.len() - 1] == b'/' {
+        b.len() - 1
+    } else {
+        b.len()
+    };
+    const_bytes_eq(a, b, a_len, b_len)
+}
+
+const fn const_bytes_eq(a: &[u8], b: &[u8], a_len: usize, b_len: usize) -> bool {
+    if a_len != b_len {
+        return false;
+    }
+    let mut i = 0;
+    while i < a_len {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
@@ -0,0 +1,460 @@
+use std::future::Future;
+use std::io;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{Buf, BytesMut};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+use super::constants::HttpMethod;
+use super::handler_error::HandlerError;
+use super::request::Request;
+use super::response::Response;
+use super::server::{Upgraded, UpgradedIo};
+use super::ONE_MB;
+
+// WebSockets (RFC 6455) from a route handler, e.g.
+// `WebSocketUpgrade::from_request(request)?.respond(response, |mut socket| async move {
+//     while let Some(message) = socket.recv().await { let _ = socket.send(message).await; }
+// });`
+// The callback runs on the connection's task once the 101 has been written, and the connection
+// closes when it returns
+
+// Appended to the client's key to prove the server speaks WebSocket (RFC 6455 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Like request bodies, so one client can't make the server buffer gigabytes
+const MAX_MESSAGE_LEN: usize = ONE_MB;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Close codes (RFC 6455 7.4.1)
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// A valid opening handshake, waiting to be accepted with respond
+pub struct WebSocketUpgrade {
+    accept: String,
+}
+
+impl WebSocketUpgrade {
+    // 400 unless the request is a WebSocket handshake, 426 for a version other than 13
+    pub fn from_request(request: &Request) -> Result<Self, HandlerError> {
+        let has_token = |name: &str, token: &str| {
+            request
+                .headers
+                .get_all(name)
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if request.method != HttpMethod::GET
+            || !has_token("Upgrade", "websocket")
+            || !has_token("Connection", "upgrade")
+        {
+            return Err(HandlerError::bad_request("expected a WebSocket handshake"));
+        }
+        if request
+            .get_header("Sec-WebSocket-Version")
+            .map(|v| v.trim())
+            != Some("13")
+        {
+            return Err(HandlerError::new(
+                426,
+                "only WebSocket version 13 is supported",
+            ));
+        }
+        let key = request
+            .get_header("Sec-WebSocket-Key")
+            .map(|key| key.trim())
+            .filter(|key| BASE64_STANDARD.decode(key).is_ok_and(|key| key.len() == 16))
+            .ok_or_else(|| HandlerError::bad_request("invalid Sec-WebSocket-Key"))?;
+        Ok(Self {
+            accept: accept_key(key),
+        })
+    }
+
+    // Sends the 101, after which `on_upgrade` has the connection
+    pub fn respond<F, Fut>(self, response: &mut Response, on_upgrade: F)
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        response.set_status_code(101);
+        response.headers.remove("Content-Type");
+        response.add_header("Upgrade", "websocket");
+        response.add_header("Connection", "Upgrade");
+        response.add_header("Sec-WebSocket-Accept", &self.accept);
+        response.on_upgrade = Some(Box::new(move |upgraded| {
+            Box::pin(on_upgrade(WebSocket::new(upgraded)))
+        }));
+        response.send();
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    BASE64_STANDARD.encode(sha1.finalize())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Why the connection is being closed, sent to the client in the close frame
+struct CloseReason(u16, &'static str);
+
+impl From<io::Error> for CloseReason {
+    // Nobody is listening to a close code on a broken connection, but one is needed anyway
+    fn from(_: io::Error) -> Self {
+        CloseReason(GOING_AWAY, "")
+    }
+}
+
+pub struct WebSocket {
+    io: Box<dyn UpgradedIo>,
+    buffer: BytesMut,
+    shutdown: watch::Receiver<bool>,
+    close_sent: bool,
+    closed: bool,
+}
+
+impl WebSocket {
+    fn new(upgraded: Upgraded) -> Self {
+        Self {
+            io: upgraded.io,
+            buffer: upgraded.buffered,
+            shutdown: upgraded.shutdown,
+            close_sent: false,
+            closed: false,
+        }
+    }
+
+    // The next message, or None once the connection has closed, whether the client closed it, it
+    // broke the protocol or the server is shutting down. Pings are answered along the way
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.closed {
+            return None;
+        }
+        match self.read_message().await {
+            Ok(Some(message)) => Some(message),
+            Ok(None) => {
+                self.closed = true;
+                None
+            }
+            Err(CloseReason(code, reason)) => {
+                let _ = self.close(code, reason).await;
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the WebSocket is closing",
+            ));
+        }
+        match message {
+            Message::Text(text) => self.write_frame(TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(BINARY, &data).await,
+        }
+    }
+
+    // Starts the closing handshake. recv returns None once the client has answered
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let mut payload = code.to_be_bytes().to_vec();
+        // Control frames carry at most 125 bytes
+        let mut reason_len = reason.len().min(123);
+        while !reason.is_char_boundary(reason_len) {
+            reason_len -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..reason_len]);
+        self.write_frame(CLOSE, &payload).await
+    }
+
+    // None once the closing handshake is over
+    async fn read_message(&mut self) -> Result<Option<Message>, CloseReason> {
+        let mut fragments: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = self.read_frame().await?;
+            match frame.opcode {
+                PING => {
+                    if !self.close_sent {
+                        self.write_frame(PONG, &frame.payload).await?;
+                    }
+                }
+                PONG => {}
+                CLOSE => {
+                    if !self.close_sent {
+                        // Echo the client's code back, as the handshake asks
+                        let code = match frame.payload.get(..2) {
+                            Some(code) => u16::from_be_bytes([code[0], code[1]]),
+                            None => NORMAL_CLOSURE,
+                        };
+                        let _ = self.close(code, "").await;
+                    }
+                    return Ok(None);
+                }
+                TEXT | BINARY if fragments.is_none() => {
+                    if frame.fin {
+                        return message(frame.opcode, frame.payload).map(Some);
+                    }
+                    fragments = Some((frame.opcode, frame.payload));
+                }
+                CONTINUATION if fragments.is_some() => {
+                    let (opcode, mut payload) = fragments.take().unwrap();
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                        return Err(CloseReason(MESSAGE_TOO_BIG, "message too big"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return message(opcode, payload).map(Some);
+                    }
+                    fragments = Some((opcode, payload));
+                }
+                _ => return Err(CloseReason(PROTOCOL_ERROR, "unexpected frame")),
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, CloseReason> {
+        self.fill(2).await?;
+        let (first, second) = (self.buffer[0], self.buffer[1]);
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        // No extensions are negotiated, so the reserved bits must be clear
+        if first & 0x70 != 0 {
+            return Err(CloseReason(PROTOCOL_ERROR, "reserved bits set"));
+        }
+        // Clients always mask, so a proxy can't be tricked into caching what looks like HTTP
+        if second & 0x80 == 0 {
+            return Err(CloseReason(PROTOCOL_ERROR, "frame not masked"));
+        }
+        let (len, header_len) = match second & 0x7F {
+            126 => {
+                self.fill(4).await?;
+                (
+                    u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64,
+                    4,
+                )
+            }
+            127 => {
+                self.fill(10).await?;
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.buffer[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (len as u64, 2),
+        };
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || len > 125) {
+            return Err(CloseReason(PROTOCOL_ERROR, "invalid control frame"));
+        }
+        if len > MAX_MESSAGE_LEN as u64 {
+            return Err(CloseReason(MESSAGE_TOO_BIG, "message too big"));
+        }
+
+        let len = len as usize;
+        self.fill(header_len + 4 + len).await?;
+        self.buffer.advance(header_len);
+        let mut mask = [0; 4];
+        self.buffer.copy_to_slice(&mut mask);
+        let mut payload = self.buffer.split_to(len).to_vec();
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    // Reads until at least `len` bytes are buffered. Gives up when the server starts shutting down
+    async fn fill(&mut self, len: usize) -> Result<(), CloseReason> {
+        while self.buffer.len() < len {
+            tokio::select! {
+                read = self.io.read_buf(&mut self.buffer) => {
+                    if read? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+                _ = self.shutdown.wait_for(|stopping| *stopping) => {
+                    return Err(CloseReason(GOING_AWAY, "server shutting down"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Unmasked, servers never mask
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut head = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => head.push(len as u8),
+            len @ 126..=0xFFFF => {
+                head.push(126);
+                head.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                head.push(127);
+                head.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.io.write_all(&head).await?;
+        self.io.write_all(payload).await?;
+        self.io.flush().await
+    }
+}
+
+fn message(opcode: u8, payload: Vec<u8>) -> Result<Message, CloseReason> {
+    match opcode {
+        TEXT => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| CloseReason(INVALID_PAYLOAD, "text is not UTF-8")),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::test::TestServer;
+    use crate::{route, routes, RequestParam, ResponseParam, Router, Server};
+
+    route!(
+        echo_handler,
+        async move |request: RequestParam, response: ResponseParam| {
+            WebSocketUpgrade::from_request(request)?.respond(response, |mut socket| async move {
+                while let Some(message) = socket.recv().await {
+                    let _ = socket.send(message).await;
+                }
+            });
+            Ok(())
+        }
+    );
+
+    // As a client sends it, masked
+    fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn accepts_keys_as_the_rfc_does() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes() {
+        let handshake = |version: &str, key: &str| {
+            let mut request = Request::default();
+            request.headers.insert("Upgrade", "websocket");
+            request.headers.insert("Connection", "keep-alive, Upgrade");
+            request.headers.insert("Sec-WebSocket-Version", version);
+            request.headers.insert("Sec-WebSocket-Key", key);
+            WebSocketUpgrade::from_request(&request).map_err(|e| e.status)
+        };
+        assert!(handshake("13", "dGhlIHNhbXBsZSBub25jZQ==").is_ok());
+        assert_eq!(handshake("8", "dGhlIHNhbXBsZSBub25jZQ==").err(), Some(426));
+        assert_eq!(handshake("13", "c2hvcnQ=").err(), Some(400));
+        assert_eq!(
+            WebSocketUpgrade::from_request(&Request::default())
+                .err()
+                .map(|e| e.status),
+            Some(400)
+        );
+    }
+
+    #[tokio::test]
+    async fn echoes_messages_until_closed() {
+        let mut server = Server::builder().build();
+        server
+            .add_routes(routes! { GET "/echo" => echo_handler })
+            .unwrap();
+        let server = TestServer::spawn(server).await;
+        let address = server.url("").trim_start_matches("http://").to_string();
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(
+                b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(
+            head.contains("\r\nsec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{}",
+            head
+        );
+        assert!(!head.contains("content-length"), "{}", head);
+
+        stream
+            .write_all(&frame(PING, b"are you there"))
+            .await
+            .unwrap();
+        stream.write_all(&frame(TEXT, b"hello")).await.unwrap();
+        let mut pong = [0; 15];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong[..2], [0x80 | PONG, 13]);
+        assert_eq!(&pong[2..], b"are you there");
+        let mut echo = [0; 7];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(echo, *b"\x81\x05hello");
+
+        stream
+            .write_all(&frame(CLOSE, &NORMAL_CLOSURE.to_be_bytes()))
+            .await
+            .unwrap();
+        let mut close = [0; 4];
+        stream.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x80 | CLOSE, 2, 0x03, 0xE8]);
+        assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+
+// Connection counts for every acceptor of a server, see ServerConfig.workers. Cloning is cheap
+// and clones see the same counts, so one can be handed to an endpoint before the server starts
+#[derive(Clone, Default)]
+pub struct WorkerMetrics(Arc<Mutex<Vec<Arc<WorkerCounters>>>>);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WorkerStats {
+    // Numbered from 0 for each listen address
+    pub worker: usize,
+    pub address: String,
+    // Since the server started
+    pub accepted: u64,
+    // Open right now, including idle keep-alive connections
+    pub active: u64,
+}
+
+pub(crate) struct WorkerCounters {
+    worker: usize,
+    address: String,
+    accepted: AtomicU64,
+    active: AtomicU64,
+}
+
+impl WorkerMetrics {
+    pub fn snapshot(&self) -> Vec<WorkerStats> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|counters| WorkerStats {
+                worker: counters.worker,
+                address: counters.address.clone(),
+                accepted: counters.accepted.load(Ordering::Relaxed),
+                active: counters.active.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub(crate) fn register(&self, worker: usize, address: &str) -> Arc<WorkerCounters> {
+        let counters = Arc::new(WorkerCounters {
+            worker,
+            address: address.to_string(),
+            accepted: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+        });
+        self.0.lock().unwrap().push(counters.clone());
+        counters
+    }
+}
+
+// Held by every connection's task. Counts it as active, and keeps the server's drain waiting
+// until it's dropped
+pub(crate) struct ConnectionGuard {
+    counters: Arc<WorkerCounters>,
+    _open: mpsc::Sender<()>,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn new(counters: &Arc<WorkerCounters>, open: &mpsc::Sender<()>) -> Self {
+        counters.accepted.fetch_add(1, Ordering::Relaxed);
+        counters.active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            counters: counters.clone(),
+            _open: open.clone(),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Asks a running server to stop: every worker stops accepting, idle connections are closed and
+// in-flight requests get up to ServerConfig.shutdown_timeout to finish before start() returns
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,165 @@
+use chrono::Utc;
+use kblue_http::{escape_html, route, HandlerError, RequestParam, ResponseParam};
+
+use crate::db::BlogPost;
+use crate::pages;
+use crate::state::AppState;
+
+// Atom feed of the published blog posts, linking to SITE_URL/blog/<slug>
+
+const MAX_ENTRIES: usize = 20;
+
+struct Feed<'a> {
+    site_url: &'a str,
+    author: &'a str,
+    posts: &'a [BlogPost],
+}
+
+// Escaped for XML, without the control characters XML 1.0 doesn't allow at all
+fn xml(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    escape_html(&text)
+}
+
+impl Feed<'_> {
+    // The newest post's date, which also versions the feed for caching
+    fn updated(&self) -> Option<&str> {
+        self.posts
+            .iter()
+            .filter_map(|post| post.published_at.as_deref())
+            .max()
+    }
+
+    fn render(&self) -> String {
+        let now = Utc::now().to_rfc3339();
+        let mut feed = format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+                "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+                "  <title>{author}</title>\n",
+                "  <id>{site}/</id>\n",
+                "  <link rel=\"alternate\" type=\"text/html\" href=\"{site}/\"/>\n",
+                "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{site}/feed.xml\"/>\n",
+                "  <updated>{updated}</updated>\n",
+                "  <author><name>{author}</name></author>\n",
+            ),
+            site = xml(self.site_url),
+            author = xml(self.author),
+            updated = xml(self.updated().unwrap_or(&now)),
+        );
+        for post in self.posts {
+            let Some(published_at) = &post.published_at else {
+                continue;
+            };
+            let url = format!("{}/blog/{}", self.site_url, post.slug);
+            feed.push_str(&format!(
+                concat!(
+                    "  <entry>\n",
+                    "    <title>{title}</title>\n",
+                    "    <id>{url}</id>\n",
+                    "    <link rel=\"alternate\" type=\"text/html\" href=\"{url}\"/>\n",
+                    "    <published>{published}</published>\n",
+                    "    <updated>{published}</updated>\n",
+                    "    <content type=\"html\">{content}</content>\n",
+                    "  </entry>\n",
+                ),
+                title = xml(&post.title),
+                url = xml(&url),
+                published = xml(published_at),
+                content = xml(&post.body),
+            ));
+        }
+        feed.push_str("</feed>\n");
+        feed
+    }
+}
+
+route!(
+    feed_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let state = request
+            .state::<AppState>()
+            .ok_or_else(|| HandlerError::internal("feed is not configured"))?;
+        let db = state
+            .db
+            .clone()
+            .ok_or_else(|| HandlerError::new(503, "the feed needs a database"))?;
+        let mut posts = db.list_posts(false).await?;
+        posts.truncate(MAX_ENTRIES);
+        let site_url = pages::site_url();
+        let feed = Feed {
+            site_url: site_url.trim_end_matches('/'),
+            author: &state.email.sender.name,
+            posts: &posts,
+        };
+
+        // Changes whenever a post is published, or unpublished
+        let etag = format!(
+            "\"{}-{}\"",
+            posts.len(),
+            feed.updated()
+                .unwrap_or_default()
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>()
+        );
+        response.add_header("ETag", &etag);
+        response.add_header("Cache-Control", "public, max-age=900");
+        if request
+            .if_none_match()
+            .is_some_and(|if_none_match| if_none_match.matches(&etag))
+        {
+            response.set_status_code(304);
+            response.send();
+            return Ok(());
+        }
+        response.bytes(
+            "application/atom+xml; charset=utf-8",
+            feed.render().into_bytes(),
+        );
+        response.send();
+        Ok(())
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(slug: &str, title: &str, published_at: Option<&str>) -> BlogPost {
+        BlogPost {
+            id: 1,
+            slug: slug.to_string(),
+            title: title.to_string(),
+            body: "<p>Fish & chips</p>\u{0}".to_string(),
+            published_at: published_at.map(str::to_string),
+            created_at: "2026-10-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn renders_escaped_entries() {
+        let posts = [
+            post("rust", "Rust <3", Some("2026-10-14T09:00:00+00:00")),
+            post("draft", "Draft", None),
+            post("hello", "Hello", Some("2026-10-01T09:00:00+00:00")),
+        ];
+        let feed = Feed {
+            site_url: "https://kblue.io",
+            author: "Kyle Doidge",
+            posts: &posts,
+        };
+        let xml = feed.render();
+        assert!(xml.contains("<updated>2026-10-14T09:00:00+00:00</updated>\n  <author>"));
+        assert!(xml.contains("<title>Rust &lt;3</title>"));
+        assert!(xml.contains("<id>https://kblue.io/blog/rust</id>"));
+        assert!(
+            xml.contains("<content type=\"html\">&lt;p&gt;Fish &amp; chips&lt;/p&gt;</content>")
+        );
+        assert!(!xml.contains("Draft"));
+        assert_eq!(xml.matches("<entry>").count(), 2);
+    }
+}
@@ -0,0 +1,35 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use serde_json::json;
+
+use crate::state::AppState;
+
+// Liveness, 200 as long as we're serving requests at all
+route!(
+    healthz_handler,
+    async move |_request: RequestParam, mut response: ResponseParam| {
+        response.add_header("Cache-Control", "no-store");
+        response.json(&json!({ "status": "ok" }))?;
+        response.send();
+        Ok(())
+    }
+);
+
+// Readiness, 503 while something we need to handle submissions, e.g. SMTP or the database, can't be reached
+route!(
+    readyz_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let state = request
+            .state::<AppState>()
+            .ok_or_else(|| HandlerError::internal("readiness is not configured"))?;
+        let checks = state.readiness.check().await;
+        let ready = checks.iter().all(|check| check.ok);
+        response.add_header("Cache-Control", "no-store");
+        response.status(if ready { 200 } else { 503 });
+        response.json(&json!({
+            "status": if ready { "ready" } else { "unavailable" },
+            "checks": checks,
+        }))?;
+        response.send();
+        Ok(())
+    }
+);
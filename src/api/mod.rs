@@ -1 +1,6 @@
+mod feed;
+mod health;
 pub mod v1;
+
+pub use feed::feed_handler;
+pub use health::{healthz_handler, readyz_handler};
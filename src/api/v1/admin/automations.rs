@@ -0,0 +1,54 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use serde_json::json;
+
+use crate::automations::{
+    add_rule, evaluation_log, list_rules, remove_rule, AutomationError, NewRule,
+};
+
+route!(
+    list_automations_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let body = json!({
+            "rules": list_rules(),
+            "log": evaluation_log(),
+        });
+        response.json(&body)?;
+        response.send();
+        Ok(())
+    }
+);
+
+route!(
+    create_automation_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let new_rule = request.parse_json::<NewRule>()?;
+        // Failing to save the rule is ours, not the client's
+        let rule = add_rule(new_rule).await.map_err(|e| match e {
+            AutomationError::Invalid(message) => HandlerError::bad_request(&message),
+            e => e.into(),
+        })?;
+        response.status(201).json(&rule)?;
+        response.send();
+        Ok(())
+    }
+);
+
+route!(
+    delete_automation_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let Some(id) = request
+            .params
+            .get("id")
+            .and_then(|id| id.parse::<u64>().ok())
+        else {
+            return Err(HandlerError::bad_request("invalid automation id"));
+        };
+        let removed = remove_rule(id).await?;
+        if !removed {
+            return Err(HandlerError::not_found("automation not found"));
+        }
+        response.set_status_code(204);
+        response.send();
+        Ok(())
+    }
+);
@@ -0,0 +1,55 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use serde_json::json;
+
+use crate::email::audit::{self, EmailQuery, EmailStatus};
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+// ?search=...&status=queued|sent|failed&page=1&per_page=20
+route!(
+    list_emails_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let number = |key: &str, default: usize| -> Result<usize, HandlerError> {
+            match request.query_as::<usize>(key) {
+                Ok(None) => Ok(default),
+                Ok(Some(number)) if number > 0 => Ok(number),
+                _ => Err(HandlerError::bad_request(&format!(
+                    "{} must be a positive number",
+                    key
+                ))),
+            }
+        };
+        let status = match request.query.get("status").map(|status| status.as_str()) {
+            Some("queued") => Some(EmailStatus::Queued),
+            Some("sent") => Some(EmailStatus::Sent),
+            Some("failed") => Some(EmailStatus::Failed),
+            Some(_) => {
+                return Err(HandlerError::bad_request(
+                    "status must be queued, sent or failed",
+                ))
+            }
+            None => None,
+        };
+        let query = EmailQuery {
+            search: request
+                .query
+                .get("search")
+                .filter(|search| !search.is_empty())
+                .cloned(),
+            status,
+            page: number("page", 1)?,
+            per_page: number("per_page", DEFAULT_PER_PAGE)?.min(MAX_PER_PAGE),
+        };
+
+        let (emails, total) = audit::search(&query);
+        response.json(&json!({
+            "emails": emails,
+            "page": query.page,
+            "per_page": query.per_page,
+            "total": total,
+        }))?;
+        response.send();
+        Ok(())
+    }
+);
@@ -0,0 +1,43 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::maintenance::Maintenance;
+
+#[derive(Deserialize)]
+struct MaintenanceUpdate {
+    enabled: bool,
+}
+
+route!(
+    get_maintenance_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let maintenance = request
+            .state::<Maintenance>()
+            .ok_or_else(|| HandlerError::internal("maintenance isn't registered"))?;
+        response.json(&json!({
+            "enabled": maintenance.is_enabled(),
+            "retry_after_secs": maintenance.retry_after_secs,
+        }))?;
+        response.send();
+        Ok(())
+    }
+);
+
+// `{"enabled": true}` before running migrations, then false once they're done
+route!(
+    set_maintenance_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let update = request.parse_json::<MaintenanceUpdate>()?;
+        let maintenance = request
+            .state::<Maintenance>()
+            .ok_or_else(|| HandlerError::internal("maintenance isn't registered"))?;
+        maintenance.set_enabled(update.enabled);
+        response.json(&json!({
+            "enabled": maintenance.is_enabled(),
+            "retry_after_secs": maintenance.retry_after_secs,
+        }))?;
+        response.send();
+        Ok(())
+    }
+);
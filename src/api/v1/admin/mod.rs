@@ -0,0 +1,19 @@
+mod automations;
+mod emails;
+mod maintenance;
+mod profile;
+mod reload;
+mod resume;
+mod routes;
+mod workers;
+
+pub use automations::{
+    create_automation_handler, delete_automation_handler, list_automations_handler,
+};
+pub use emails::list_emails_handler;
+pub use maintenance::{get_maintenance_handler, set_maintenance_handler};
+pub use profile::profile_handler;
+pub use reload::reload_handler;
+pub use resume::resume_stats_handler;
+pub use routes::{routes_handler, RouteList};
+pub use workers::workers_handler;
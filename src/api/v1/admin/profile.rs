@@ -0,0 +1,69 @@
+use std::env;
+use std::fmt::Write;
+use std::time::Duration;
+
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use tracing::error;
+
+const DEFAULT_PROFILE_SECONDS: u64 = 10;
+const MAX_PROFILE_SECONDS: u64 = 60;
+const SAMPLE_FREQUENCY_HZ: i32 = 100;
+
+// Samples every thread of the process for the duration, returning collapsed stacks
+// ("thread;root;...;leaf count" per line) which inferno / flamegraph.pl render directly
+fn collect_profile(seconds: u64) -> Result<String, String> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY_HZ)
+        // Unwinding through these can crash on some distros
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| e.to_string())?;
+    std::thread::sleep(Duration::from_secs(seconds));
+    let report = guard.report().build().map_err(|e| e.to_string())?;
+
+    let mut folded = String::new();
+    for (frames, count) in report.data.iter() {
+        let mut line = frames.thread_name_or_id();
+        for frame in frames.frames.iter().rev() {
+            for symbol in frame.iter().rev() {
+                write!(&mut line, ";{}", symbol).unwrap();
+            }
+        }
+        writeln!(&mut folded, "{} {}", line, count).unwrap();
+    }
+    Ok(folded)
+}
+
+route!(
+    profile_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        // Opt-in, since sampling installs a SIGPROF handler for the whole process
+        if env::var("ENABLE_PROFILER").unwrap_or_default() != "true" {
+            return Err(HandlerError::not_found("profiler is disabled"));
+        }
+
+        let seconds = match request.query.get("seconds") {
+            Some(seconds) => match seconds.parse::<u64>() {
+                Ok(seconds) if (1..=MAX_PROFILE_SECONDS).contains(&seconds) => seconds,
+                _ => {
+                    return Err(HandlerError::bad_request(&format!(
+                        "seconds must be between 1 and {}",
+                        MAX_PROFILE_SECONDS
+                    )));
+                }
+            },
+            None => DEFAULT_PROFILE_SECONDS,
+        };
+
+        // The profiler guard isn't Send and the sampling window blocks, so keep it off the async workers
+        let folded = tokio::task::spawn_blocking(move || collect_profile(seconds))
+            .await?
+            .map_err(|e| {
+                error!("Could not collect profile: {}", e);
+                HandlerError::internal("could not collect profile")
+            })?;
+        response.text(&folded);
+        response.send();
+        Ok(())
+    }
+);
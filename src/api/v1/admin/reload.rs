@@ -0,0 +1,47 @@
+use std::str::FromStr;
+
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use serde_json::{json, Map, Value};
+use strum::IntoEnumIterator;
+
+use crate::reload::{reload, ReloadTarget};
+
+route!(
+    reload_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let what = request
+            .query
+            .get("what")
+            .map_or("all", |what| what.as_str());
+        let targets: Vec<ReloadTarget> = if what == "all" {
+            ReloadTarget::iter().collect()
+        } else if let Ok(target) = ReloadTarget::from_str(what) {
+            vec![target]
+        } else {
+            return Err(HandlerError::bad_request(
+                "what must be one of templates, projects, blocklists or all",
+            ));
+        };
+
+        let mut results = Map::new();
+        let mut any_failed = false;
+        for target in targets {
+            let result = match reload(target).await {
+                Some(Ok(changed)) => json!({ "status": "reloaded", "changed": changed }),
+                Some(Err(e)) => {
+                    any_failed = true;
+                    json!({ "status": "failed", "error": e })
+                }
+                None => json!({ "status": "not_registered" }),
+            };
+            results.insert(target.to_string(), result);
+        }
+
+        if any_failed {
+            response.set_status_code(500);
+        }
+        response.json(&Value::Object(results))?;
+        response.send();
+        Ok(())
+    }
+);
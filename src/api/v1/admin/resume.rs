@@ -0,0 +1,19 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+
+use crate::state::AppState;
+
+const MAX_REFERRERS: i64 = 20;
+
+route!(
+    resume_stats_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let db = request
+            .state::<AppState>()
+            .and_then(|state| state.db.clone())
+            .ok_or_else(|| HandlerError::new(503, "resume stats need a database"))?;
+        let stats = db.resume_stats(MAX_REFERRERS).await?;
+        response.json(&stats)?;
+        response.send();
+        Ok(())
+    }
+);
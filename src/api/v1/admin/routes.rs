@@ -0,0 +1,18 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam, RouteInfo};
+
+// Every route on the main port, snapshotted once they're all registered
+pub struct RouteList(pub Vec<RouteInfo>);
+
+// What's routed, in the order each method's routes are tried, e.g. to see why a request hits the
+// wrong handler
+route!(
+    routes_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let routes = request
+            .state::<RouteList>()
+            .ok_or_else(|| HandlerError::internal("the route list isn't registered"))?;
+        response.json(&routes.0)?;
+        response.send();
+        Ok(())
+    }
+);
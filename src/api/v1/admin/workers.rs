@@ -0,0 +1,15 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam, WorkerMetrics};
+
+// Connections accepted and open per worker on the main port, to see how evenly the kernel
+// spreads them
+route!(
+    workers_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let metrics = request
+            .state::<WorkerMetrics>()
+            .ok_or_else(|| HandlerError::internal("worker metrics aren't registered"))?;
+        response.json(&metrics.snapshot())?;
+        response.send();
+        Ok(())
+    }
+);
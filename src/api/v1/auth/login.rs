@@ -0,0 +1,128 @@
+use std::env;
+use std::time::Duration;
+
+use kblue_http::{
+    route, HandlerError, JsonSchema, ObjectSchema, RequestParam, ResponseParam, RouteDoc,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::{verify_dummy_password, verify_password, Claims};
+use crate::middlewares::{constant_time_eq, RequestSession, ADMIN_SESSION_KEY};
+use crate::state::AppState;
+
+const TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+struct LoginInfo {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct Token {
+    token: String,
+    token_type: &'static str,
+    expires_in: u64,
+}
+
+impl JsonSchema for LoginInfo {
+    const NAME: Option<&'static str> = Some("Login");
+
+    fn schema() -> Value {
+        ObjectSchema::new()
+            .field::<String>("username")
+            .field::<String>("password")
+            .build()
+    }
+}
+
+impl JsonSchema for Token {
+    const NAME: Option<&'static str> = Some("Token");
+
+    fn schema() -> Value {
+        ObjectSchema::new()
+            .field::<String>("token")
+            .field::<String>("token_type")
+            .field::<u64>("expires_in")
+            .build()
+    }
+}
+
+pub fn login_doc() -> RouteDoc {
+    RouteDoc::new("Log in as an admin")
+        .tag("auth")
+        .request::<LoginInfo>()
+        .response::<Token>(200, "A bearer token for the admin routes")
+        .empty_response(204, "Logged in to the session, without JWTs configured")
+        .response::<HandlerError>(401, "Wrong username or password")
+        .response::<HandlerError>(404, "Login is disabled")
+}
+
+// Admins created with the create-admin command log in with their email, when there's a database.
+// ADMIN_USERNAME and ADMIN_PASSWORD still work alongside them
+async fn is_valid_login(state: &AppState, login: &LoginInfo) -> Result<bool, HandlerError> {
+    if let Some(db) = &state.db {
+        let hash = db.admin_password_hash(&login.username).await?;
+        let password = login.password.clone();
+        // Hashing takes a while, so keep it off the async workers
+        let valid = tokio::task::spawn_blocking(move || match hash {
+            Some(hash) => verify_password(&password, &hash),
+            None => verify_dummy_password(&password),
+        })
+        .await?;
+        if valid {
+            return Ok(true);
+        }
+    }
+    Ok(is_valid_env_login(login))
+}
+
+// Checked in full even when the username is wrong, so timing doesn't reveal which one was
+fn is_valid_env_login(login: &LoginInfo) -> bool {
+    let Ok(password) = env::var("ADMIN_PASSWORD") else {
+        return false;
+    };
+    let username = env::var("ADMIN_USERNAME").unwrap_or("admin".to_string());
+    let username_matches = constant_time_eq(login.username.as_bytes(), username.as_bytes());
+    let password_matches = constant_time_eq(login.password.as_bytes(), password.as_bytes());
+    !password.is_empty() && username_matches && password_matches
+}
+
+// Exchanges the admin username and password for a bearer token accepted by the admin routes. With
+// sessions enabled the session is logged in too, which works without JWTs
+route!(
+    login_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let state = request
+            .state::<AppState>()
+            .ok_or_else(|| HandlerError::internal("login is not configured"))?;
+        let session = request.session();
+        if state.jwt.is_none() && session.is_none() {
+            return Err(HandlerError::not_found("login is disabled"));
+        }
+
+        let login = request.parse_json::<LoginInfo>()?;
+        if !is_valid_login(&state, &login).await? {
+            return Err(HandlerError::new(401, "invalid username or password"));
+        }
+        if let Some(session) = session {
+            // A session id planted before logging in is useless afterwards
+            session.regenerate();
+            session.insert(ADMIN_SESSION_KEY, &login.username)?;
+        }
+        let Some(jwt) = state.jwt.as_ref() else {
+            response.set_status_code(204);
+            response.send();
+            return Ok(());
+        };
+        let token = jwt.sign(&Claims::new(&login.username, TOKEN_TTL))?;
+        response.json(&Token {
+            token,
+            token_type: "Bearer",
+            expires_in: TOKEN_TTL.as_secs(),
+        })?;
+        response.send();
+        Ok(())
+    }
+);
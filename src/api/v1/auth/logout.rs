@@ -0,0 +1,22 @@
+use kblue_http::{route, RequestParam, ResponseParam, RouteDoc};
+
+use crate::middlewares::RequestSession;
+
+pub fn logout_doc() -> RouteDoc {
+    RouteDoc::new("Log the session out")
+        .tag("auth")
+        .empty_response(204, "Logged out, or there was nothing to log out of")
+}
+
+// Ends a session started by POST /api/v1/auth/login. Bearer tokens stay valid until they expire
+route!(
+    logout_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        if let Some(session) = request.session() {
+            session.destroy();
+        }
+        response.set_status_code(204);
+        response.send();
+        Ok(())
+    }
+);
@@ -0,0 +1,5 @@
+mod login;
+mod logout;
+
+pub use login::{login_doc, login_handler};
+pub use logout::{logout_doc, logout_handler};
@@ -0,0 +1,45 @@
+use kblue_http::{
+    route, HandlerError, JsonSchema, ObjectSchema, RequestParam, ResponseParam, RouteDoc,
+};
+use serde_json::Value;
+
+use crate::captcha::Challenge;
+use crate::state::AppState;
+
+// A proof of work challenge to solve before submitting the contact form, 404 unless
+// CAPTCHA_PROVIDER is "pow"
+route!(
+    captcha_challenge_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let state = request
+            .state::<AppState>()
+            .ok_or_else(|| HandlerError::internal("captcha is not configured"))?;
+        let proof_of_work = state
+            .captcha
+            .proof_of_work()
+            .ok_or_else(|| HandlerError::not_found("proof of work is not enabled"))?;
+        response.add_header("Cache-Control", "no-store");
+        response.json(&proof_of_work.issue())?;
+        response.send();
+        Ok(())
+    }
+);
+
+impl JsonSchema for Challenge {
+    const NAME: Option<&'static str> = Some("CaptchaChallenge");
+
+    fn schema() -> Value {
+        ObjectSchema::new()
+            .field::<String>("challenge")
+            .field::<u32>("difficulty")
+            .field::<u64>("expires_in")
+            .build()
+    }
+}
+
+pub fn captcha_challenge_doc() -> RouteDoc {
+    RouteDoc::new("Get a proof of work challenge to solve before sending a message")
+        .tag("contact")
+        .response::<Challenge>(200, "The challenge, sent back solved as the captcha token")
+        .response::<HandlerError>(404, "Proof of work captchas aren't enabled")
+}
@@ -0,0 +1,22 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use tracing::error;
+
+use crate::state::AppState;
+
+route!(
+    github_activity_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let state = request
+            .state::<AppState>()
+            .ok_or_else(|| HandlerError::internal("GitHub is not configured"))?;
+        let activity = state.github.activity().await.map_err(|e| {
+            error!("Could not fetch GitHub activity: {}", e);
+            HandlerError::new(502, "could not fetch GitHub activity")
+        })?;
+        // Browsers and CDNs can hold on to it too, it only changes every few minutes
+        response.add_header("Cache-Control", "public, max-age=300");
+        response.json(&*activity)?;
+        response.send();
+        Ok(())
+    }
+);
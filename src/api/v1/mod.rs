@@ -1,3 +1,19 @@
+pub mod admin;
+pub mod auth;
+mod captcha;
+mod github;
+mod openapi;
+mod projects;
+mod resume;
 mod send_email;
 
-pub use send_email::send_email_handler;
+pub use captcha::{captcha_challenge_doc, captcha_challenge_handler};
+pub use github::github_activity_handler;
+pub use openapi::{openapi_handler, swagger_ui_handler, ApiDocument};
+pub use projects::{
+    create_project_doc, create_project_handler, delete_project_doc, delete_project_handler,
+    get_project_doc, get_project_handler, list_projects_doc, list_projects_handler,
+    update_project_doc, update_project_handler,
+};
+pub use resume::resume_handler;
+pub use send_email::{send_email_doc, send_email_handler};
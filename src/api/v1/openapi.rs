@@ -0,0 +1,27 @@
+use kblue_http::{route, HandlerError, RequestParam, ResponseParam};
+use serde_json::Value;
+
+// The OpenAPI document for every documented route, built once they're all registered
+pub struct ApiDocument(pub Value);
+
+route!(
+    openapi_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let document = request
+            .state::<ApiDocument>()
+            .ok_or_else(|| HandlerError::internal("the API isn't documented"))?;
+        response.json(&document.0)?;
+        response.send();
+        Ok(())
+    }
+);
+
+// Only routed when server.swagger_ui (SWAGGER_UI) is set. The page loads Swagger UI from a CDN
+route!(
+    swagger_ui_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        response.html(include_str!("swagger_ui.html"));
+        response.send();
+        Ok(())
+    }
+);
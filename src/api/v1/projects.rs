@@ -0,0 +1,309 @@
+use std::sync::Arc;
+
+use kblue_http::{
+    route, HandlerError, JsonError, JsonSchema, ObjectSchema, Request, RequestParam, ResponseParam,
+    RouteDoc,
+};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::db::{Project, ProjectInput, ProjectLink, Repository};
+use crate::state::AppState;
+
+// Portfolio projects for the frontend. Reading is public, changes need an admin token
+
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 30;
+const MAX_LINKS: usize = 10;
+const MAX_LINK_LABEL_LEN: usize = 50;
+
+fn state(request: &Request) -> Result<Arc<AppState>, HandlerError> {
+    request
+        .state::<AppState>()
+        .ok_or_else(|| HandlerError::internal("app state isn't registered"))
+}
+
+fn repository(request: &Request) -> Result<Arc<dyn Repository>, HandlerError> {
+    state(request)?
+        .db
+        .clone()
+        .ok_or_else(|| HandlerError::new(503, "projects need a database"))
+}
+
+fn project_id(request: &Request) -> Result<i64, HandlerError> {
+    request
+        .params
+        .get("id")
+        .and_then(|id| id.parse::<i64>().ok())
+        .ok_or_else(|| HandlerError::bad_request("invalid project id"))
+}
+
+fn parse_project(request: &Request) -> Result<ProjectInput, HandlerError> {
+    let mut project = request.parse_json::<ProjectInput>()?;
+    project.title = project.title.trim().to_string();
+    project.description = project.description.trim().to_string();
+    for tag in project.tags.iter_mut() {
+        *tag = tag.trim().to_string();
+    }
+    for link in project.links.iter_mut() {
+        link.label = link.label.trim().to_string();
+        link.url = link.url.trim().to_string();
+    }
+    validate(&project).map_err(HandlerError::unprocessable)?;
+    Ok(project)
+}
+
+fn validate(project: &ProjectInput) -> Result<(), JsonError> {
+    let mut error = JsonError::new("invalid project");
+    let title_len = project.title.chars().count();
+    if title_len == 0 || title_len > MAX_TITLE_LEN {
+        error = error.field(
+            "title",
+            &format!("must be 1 to {} characters", MAX_TITLE_LEN),
+        );
+    }
+    if project.description.chars().count() > MAX_DESCRIPTION_LEN {
+        error = error.field(
+            "description",
+            &format!("must be at most {} characters", MAX_DESCRIPTION_LEN),
+        );
+    }
+    if project.tags.len() > MAX_TAGS {
+        error = error.field("tags", &format!("must be at most {} tags", MAX_TAGS));
+    }
+    for (i, tag) in project.tags.iter().enumerate() {
+        let tag_len = tag.chars().count();
+        if tag_len == 0 || tag_len > MAX_TAG_LEN {
+            error = error.field(
+                &format!("tags[{}]", i),
+                &format!("must be 1 to {} characters", MAX_TAG_LEN),
+            );
+        }
+    }
+    if project.links.len() > MAX_LINKS {
+        error = error.field("links", &format!("must be at most {} links", MAX_LINKS));
+    }
+    for (i, link) in project.links.iter().enumerate() {
+        let label_len = link.label.chars().count();
+        if label_len == 0 || label_len > MAX_LINK_LABEL_LEN {
+            error = error.field(
+                &format!("links[{}].label", i),
+                &format!("must be 1 to {} characters", MAX_LINK_LABEL_LEN),
+            );
+        }
+        // Rendered as hrefs, so nothing like javascript:
+        let is_web_url = Url::parse(&link.url)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+        if !is_web_url {
+            error = error.field(&format!("links[{}].url", i), "must be an http(s) URL");
+        }
+    }
+    if error.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+route!(
+    list_projects_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let repository = repository(request)?;
+        let projects = state(request)?.projects.list(repository.as_ref()).await?;
+        response.json(&projects)?;
+        response.send();
+        Ok(())
+    }
+);
+
+route!(
+    get_project_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let project = repository(request)?
+            .get_project(project_id(request)?)
+            .await?
+            .ok_or_else(|| HandlerError::not_found("project not found"))?;
+        response.json(&project)?;
+        response.send();
+        Ok(())
+    }
+);
+
+route!(
+    create_project_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let input = parse_project(request)?;
+        let project = repository(request)?.create_project(&input).await?;
+        state(request)?.projects.clear();
+        response.status(201).json(&project)?;
+        response.send();
+        Ok(())
+    }
+);
+
+route!(
+    update_project_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let id = project_id(request)?;
+        let input = parse_project(request)?;
+        let project = repository(request)?
+            .update_project(id, &input)
+            .await?
+            .ok_or_else(|| HandlerError::not_found("project not found"))?;
+        state(request)?.projects.clear();
+        response.json(&project)?;
+        response.send();
+        Ok(())
+    }
+);
+
+route!(
+    delete_project_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let deleted = repository(request)?
+            .delete_project(project_id(request)?)
+            .await?;
+        if !deleted {
+            return Err(HandlerError::not_found("project not found"));
+        }
+        state(request)?.projects.clear();
+        response.set_status_code(204);
+        response.send();
+        Ok(())
+    }
+);
+
+impl JsonSchema for ProjectLink {
+    fn schema() -> Value {
+        ObjectSchema::new()
+            .property(
+                "label",
+                json!({ "type": "string", "maxLength": MAX_LINK_LABEL_LEN }),
+                true,
+            )
+            .property("url", json!({ "type": "string", "format": "uri" }), true)
+            .build()
+    }
+}
+
+impl JsonSchema for ProjectInput {
+    const NAME: Option<&'static str> = Some("ProjectInput");
+
+    fn schema() -> Value {
+        let tag = json!({ "type": "string", "maxLength": MAX_TAG_LEN });
+        ObjectSchema::new()
+            .property(
+                "title",
+                json!({ "type": "string", "maxLength": MAX_TITLE_LEN }),
+                true,
+            )
+            .property(
+                "description",
+                json!({ "type": "string", "maxLength": MAX_DESCRIPTION_LEN }),
+                true,
+            )
+            .property(
+                "tags",
+                json!({ "type": "array", "items": tag, "maxItems": MAX_TAGS }),
+                false,
+            )
+            .property(
+                "links",
+                json!({ "type": "array", "items": ProjectLink::schema(), "maxItems": MAX_LINKS }),
+                false,
+            )
+            .optional::<i64>("position")
+            .build()
+    }
+}
+
+impl JsonSchema for Project {
+    const NAME: Option<&'static str> = Some("Project");
+
+    fn schema() -> Value {
+        ObjectSchema::new()
+            .field::<i64>("id")
+            .field::<String>("title")
+            .field::<String>("description")
+            .field::<Vec<String>>("tags")
+            .field::<Vec<ProjectLink>>("links")
+            .field::<i64>("position")
+            .field::<String>("created_at")
+            .field::<String>("updated_at")
+            .build()
+    }
+}
+
+pub fn list_projects_doc() -> RouteDoc {
+    RouteDoc::new("List the portfolio's projects")
+        .tag("projects")
+        .response::<Vec<Project>>(200, "In display order")
+}
+
+pub fn get_project_doc() -> RouteDoc {
+    RouteDoc::new("Get a project")
+        .tag("projects")
+        .response::<Project>(200, "The project")
+        .response::<HandlerError>(404, "No such project")
+}
+
+pub fn create_project_doc() -> RouteDoc {
+    RouteDoc::new("Add a project")
+        .tag("projects")
+        .bearer_auth()
+        .request::<ProjectInput>()
+        .response::<Project>(201, "The new project")
+        .response::<HandlerError>(400, "A field is missing or invalid")
+}
+
+pub fn update_project_doc() -> RouteDoc {
+    RouteDoc::new("Replace a project")
+        .tag("projects")
+        .bearer_auth()
+        .request::<ProjectInput>()
+        .response::<Project>(200, "The updated project")
+        .response::<HandlerError>(400, "A field is missing or invalid")
+        .response::<HandlerError>(404, "No such project")
+}
+
+pub fn delete_project_doc() -> RouteDoc {
+    RouteDoc::new("Delete a project")
+        .tag("projects")
+        .bearer_auth()
+        .empty_response(204, "Deleted")
+        .response::<HandlerError>(404, "No such project")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ProjectLink;
+
+    #[test]
+    fn validate_reports_each_field() {
+        let mut project = ProjectInput {
+            title: "kblue.io".to_string(),
+            description: "This site".to_string(),
+            tags: vec!["rust".to_string()],
+            links: vec![ProjectLink {
+                label: "Source".to_string(),
+                url: "https://github.com/kyle-blue".to_string(),
+            }],
+            position: 0,
+        };
+        assert!(validate(&project).is_ok());
+
+        project.title = String::new();
+        project.tags.push("a".repeat(31));
+        project.links[0].url = "javascript:alert(1)".to_string();
+        let fields: Vec<_> = validate(&project)
+            .unwrap_err()
+            .errors
+            .into_iter()
+            .map(|e| e.field)
+            .collect();
+        assert_eq!(fields, ["title", "tags[1]", "links[0].url"]);
+    }
+}
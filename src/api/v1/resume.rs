@@ -0,0 +1,130 @@
+use std::env;
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, Utc};
+use kblue_http::{route, HandlerError, Request, RequestParam, ResponseParam};
+use tracing::error;
+use url::Url;
+
+use crate::state::AppState;
+
+// My CV. RESUME_PATH is read on every request, so it can be replaced without a restart.
+// Downloads are counted when a database is configured, see the admin resume stats
+
+const MAX_REFERRER_LEN: usize = 500;
+
+// Without the query string or fragment, which can hold tokens, e.g. `https://linkedin.com/in/kyle`
+fn referrer(request: &Request) -> Option<String> {
+    let mut url = Url::parse(request.get_header("referer")?).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    url.set_query(None);
+    url.set_fragment(None);
+    Some(url.as_str().chars().take(MAX_REFERRER_LEN).collect())
+}
+
+// Resumed downloads and the chunks a PDF viewer fetches shouldn't count as more downloads
+fn is_new_download(request: &Request) -> bool {
+    match request.get_header("range") {
+        Some(range) => range.trim().starts_with("bytes=0-"),
+        None => true,
+    }
+}
+
+route!(
+    resume_handler,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let path = env::var("RESUME_PATH").unwrap_or("static/resume.pdf".to_string());
+        let filename = env::var("RESUME_FILENAME").unwrap_or("Kyle-Doidge-CV.pdf".to_string());
+        let (data, metadata) =
+            match tokio::try_join!(tokio::fs::read(&path), tokio::fs::metadata(&path)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(HandlerError::not_found("resume not found"));
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+        let modified = metadata.modified()?;
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            metadata.len(),
+            modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        );
+        response.add_header("ETag", &etag);
+        response.add_header(
+            "Last-Modified",
+            &DateTime::<Utc>::from(modified)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        );
+        response.add_header("Cache-Control", "no-cache");
+        if request
+            .if_none_match()
+            .is_some_and(|if_none_match| if_none_match.matches(&etag))
+        {
+            response.set_status_code(304);
+            response.send();
+            return Ok(());
+        }
+
+        if is_new_download(request) {
+            if let Some(db) = request
+                .state::<AppState>()
+                .and_then(|state| state.db.clone())
+            {
+                let referrer = referrer(request);
+                tokio::spawn(async move {
+                    if let Err(e) = db.record_resume_download(referrer.as_deref()).await {
+                        error!("Could not record resume download: {}", e);
+                    }
+                });
+            }
+        }
+        response.bytes("application/pdf", data);
+        response.add_header("Accept-Ranges", "bytes");
+        response.add_header(
+            "Content-Disposition",
+            &format!("attachment; filename=\"{}\"", filename.replace('"', "")),
+        );
+        response.send();
+        Ok(())
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::default();
+        for (name, value) in headers {
+            request.headers.insert(name, value);
+        }
+        request
+    }
+
+    #[test]
+    fn referrers_drop_query_strings() {
+        assert_eq!(
+            referrer(&request(&[(
+                "Referer",
+                "https://www.linkedin.com/in/kyle?trk=abc#top"
+            )])),
+            Some("https://www.linkedin.com/in/kyle".to_string())
+        );
+        assert_eq!(referrer(&request(&[("Referer", "android-app://x")])), None);
+        assert_eq!(referrer(&request(&[])), None);
+    }
+
+    #[test]
+    fn only_counts_downloads_from_the_start() {
+        assert!(is_new_download(&request(&[])));
+        assert!(is_new_download(&request(&[("Range", "bytes=0-1023")])));
+        assert!(!is_new_download(&request(&[("Range", "bytes=1024-")])));
+    }
+}
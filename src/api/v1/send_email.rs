@@ -1,116 +1,553 @@
-use std::env;
+use kblue_http::{
+    route, HandlerError, JsonError, JsonSchema, MessageBody, ObjectSchema, Request, RequestParam,
+    Response, ResponseParam, RouteDoc, TemplateError,
+};
 
-use crate::http_server::{RequestParam, ResponseParam};
-use crate::route;
+use crate::automations::{self, Submission};
+use crate::captcha::CaptchaError;
+use crate::db::NewSubmission;
+use crate::email::template;
+use crate::email::{audit, Attachment, EmailConfig, EmailJob, MAX_ATTACHMENTS};
+use crate::notify::Notification;
+use crate::pages;
+use crate::spam::{self, SpamAction};
+use crate::state::AppState;
 
-use mail_send::mail_builder::MessageBuilder;
-use mail_send::{SmtpClient, SmtpClientBuilder};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
-use tokio_rustls::client::TlsStream;
+use serde_json::{json, Value};
+use tracing::{error, info};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct EmailInfo {
     name: String,
     email: String,
     message: String,
+    // Forwarded on the email to me. Base64 `data` in JSON bodies, file parts in multipart ones
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    // Under the field names the hCaptcha and Turnstile widgets add to forms too
+    #[serde(default, alias = "h-captcha-response", alias = "cf-turnstile-response")]
+    captcha: Option<String>,
+    // The spam filter's honeypot, a field the site's form hides from people
+    #[serde(default)]
+    website: Option<String>,
 }
 
-fn get_client_email_message<'a>(
-    name: &'a str,
-    message: &'a str,
-    email_address: &'a str,
-) -> MessageBuilder<'a> {
-    let body =  format!("
-        <style>
-            body {{margin: 0}};
-        </style>
-        <div style=\"margin: 0; padding: 0.2rem 1rem; width: 100%; background: rgb(138, 121, 173); background: linear-gradient(180deg, rgba(138, 121, 173, 1) 0%, rgba(174, 130, 181, 1) 100%);\">
-            <h1 style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; color: #ffffff\">Hello {}!</h1>
-        </div>
-        <div style=\"padding: 0.2rem 1rem\">
-            <h2 style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">I have recieved your message:</h2>
-            <p style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; text-indent: 1rem; white-space: pre-wrap; font-style: italic;\">{}</p>
-            <p style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">I will reply at my earliest convenience through my personal email address (kyle.blue.doidge@gmail.com) to the email address you provided ({}).</p>
-            <p style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">Thanks!</p>
-            <h3 style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">Kyle Doidge - kblue.io</h3>
-        </div>
-    ", name, message, email_address);
-
-    MessageBuilder::new()
-        .to(vec![("", email_address)])
-        .subject("Thank you for your message! - kblue.io")
-        .html_body(body)
+const MAX_NAME_LEN: usize = 100;
+// The longest an address can be, RFC 5321
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_MESSAGE_LEN: usize = 5000;
+
+// Deliberately loose, the real check is whether the reply arrives
+static EMAIL_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^[^\s@<>()\[\],;:"]+@[^\s@<>()\[\],;:"]+\.[^\s@<>()\[\],;:"]{2,}$"#).unwrap()
+});
+
+impl EmailInfo {
+    // Strips HTML tags and control characters. Only the message may span lines, a newline in the
+    // name or email would end up in the email's headers
+    fn sanitise(&mut self) {
+        self.name = strip_control_chars(&strip_tags(&self.name), false);
+        self.email = strip_control_chars(&self.email, false);
+        self.message = strip_control_chars(&strip_tags(&self.message), true);
+        for attachment in self.attachments.iter_mut() {
+            attachment.sanitise();
+        }
+    }
+
+    // Uploads from a plain HTML form with `enctype="multipart/form-data"`
+    fn from_multipart(request: &Request) -> Result<Self, JsonError> {
+        let mut email_info = EmailInfo {
+            name: String::new(),
+            email: String::new(),
+            message: String::new(),
+            attachments: Vec::new(),
+            captcha: None,
+            website: None,
+        };
+        let parts = request
+            .multipart()
+            .ok_or_else(|| JsonError::new("expected a multipart body"))?;
+        for part in parts {
+            let part = part.map_err(|e| JsonError::new(&e.to_string()))?;
+            if let Some(filename) = &part.filename {
+                // Browsers send an empty part when no file was picked
+                if !filename.is_empty() || !part.data.is_empty() {
+                    email_info.attachments.push(Attachment::new(
+                        filename,
+                        part.content_type
+                            .as_deref()
+                            .unwrap_or("application/octet-stream"),
+                        part.data.to_vec(),
+                    ));
+                }
+                continue;
+            }
+            let field = match part.name.as_deref() {
+                Some("name") => &mut email_info.name,
+                Some("email") => &mut email_info.email,
+                Some("message") => &mut email_info.message,
+                Some("captcha" | "h-captcha-response" | "cf-turnstile-response") => {
+                    email_info.captcha.insert(String::new())
+                }
+                Some("website") => email_info.website.insert(String::new()),
+                _ => continue,
+            };
+            *field = part
+                .text()
+                .ok_or_else(|| {
+                    JsonError::new("invalid request body").field(
+                        part.name.as_deref().unwrap_or_default(),
+                        "must be UTF-8 text",
+                    )
+                })?
+                .to_string();
+        }
+        Ok(email_info)
+    }
+
+    fn validate(&self) -> Result<(), JsonError> {
+        let mut error = JsonError::new("invalid request body");
+        let name_len = self.name.chars().count();
+        if name_len == 0 {
+            error = error.field("name", "must not be empty");
+        } else if name_len > MAX_NAME_LEN {
+            error = error.field(
+                "name",
+                &format!("must be at most {} characters", MAX_NAME_LEN),
+            );
+        }
+        if self.email.len() > MAX_EMAIL_LEN || !EMAIL_REGEX.is_match(&self.email) {
+            error = error.field("email", "must be an email address");
+        }
+        let message_len = self.message.chars().count();
+        if message_len == 0 {
+            error = error.field("message", "must not be empty");
+        } else if message_len > MAX_MESSAGE_LEN {
+            error = error.field(
+                "message",
+                &format!("must be at most {} characters", MAX_MESSAGE_LEN),
+            );
+        }
+        if self.attachments.len() > MAX_ATTACHMENTS {
+            error = error.field(
+                "attachments",
+                &format!("must be at most {} files", MAX_ATTACHMENTS),
+            );
+        }
+        for (i, attachment) in self.attachments.iter().enumerate() {
+            if let Err(message) = attachment.validate() {
+                error = error.field(&format!("attachments[{}]", i), &message);
+            }
+        }
+        if error.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+
+// Drops anything that looks like a tag, e.g. `<script>`, `</b>` or `<!-- -->`. A `<` which
+// doesn't start one, as in `3 < 5`, is kept
+fn strip_tags(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        stripped.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let starts_tag = after
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'));
+        match after.find('>') {
+            Some(end) if starts_tag => rest = &after[end + 1..],
+            _ => {
+                stripped.push('<');
+                rest = after;
+            }
+        }
+    }
+    stripped.push_str(rest);
+    stripped
 }
 
-fn get_my_email_message<'a>(
-    name: &'a str,
-    message: &'a str,
-    email_address: &'a str,
-) -> MessageBuilder<'a> {
-    let body =  format!("
-        <style>
-            body {{margin: 0}};
-        </style>
-        <div style=\"margin: 0; padding: 0.2rem 1rem; width: 100%; background: rgb(138, 121, 173); background: linear-gradient(180deg, rgba(138, 121, 173, 1) 0%, rgba(174, 130, 181, 1) 100%);\">
-            <h1 style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; color: #ffffff\">You have a message from {}!</h1>
-        </div>
-        <div style=\"padding: 0.2rem 1rem\">
-            <h2 style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">He says:</h2>
-            <p style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; text-indent: 1rem; white-space: pre-wrap; font-style: italic;\">{}</p>
-            <p style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">Reply to his email here: {}</p>
-            <p style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">Thanks!</p>
-            <h3 style=\"font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;\">kblue bot</h3>
-        </div>
-    ", name, message, email_address);
-
-    MessageBuilder::new()
-        .to(vec![("", "kyle.blue.doidge@gmail.com")])
-        .subject(format!(
-            "{} - {} sent you a message on kblue.io!",
-            name, email_address
-        ))
-        .html_body(body)
+fn strip_control_chars(text: &str, keep_newlines: bool) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || (keep_newlines && matches!(c, '\n' | '\t')))
+        .collect::<String>()
+        .trim()
+        .to_string()
 }
 
-async fn create_smtp_client() -> SmtpClient<TlsStream<TcpStream>> {
-    let email_password = env::var("EMAIL_PASSWORD").unwrap();
+fn get_client_email(
+    config: &EmailConfig,
+    name: &str,
+    message: &str,
+    email_address: &str,
+) -> Result<EmailJob, TemplateError> {
+    let title = format!("Hello {}!", name);
+    let variables = [
+        ("title", title.as_str()),
+        ("message", message),
+        ("email", email_address),
+        ("signature", &format!("{} - kblue.io", config.sender.name)),
+    ];
 
-    SmtpClientBuilder::new("smtp.gmail.com", 587)
-        .implicit_tls(false)
-        .credentials(("kyle.blue.doidge.bot@gmail.com", email_password.as_str()))
-        .connect()
-        .await
-        .unwrap()
+    Ok(EmailJob {
+        from_name: config.sender.name.clone(),
+        to: email_address.to_string(),
+        subject: "Thank you for your message! - kblue.io".to_string(),
+        html_body: template::render("client_confirmation.html", &variables)?,
+        text_body: template::render("client_confirmation.txt", &variables)?,
+        attachments: Vec::new(),
+        submission_id: None,
+        traceparent: None,
+    })
+}
+
+fn get_my_email(
+    config: &EmailConfig,
+    name: &str,
+    message: &str,
+    email_address: &str,
+    labels: &[String],
+    attachments: Vec<Attachment>,
+) -> Result<EmailJob, TemplateError> {
+    let title = format!("You have a message from {}!", name);
+    let variables = [
+        ("title", title.as_str()),
+        ("message", message),
+        ("email", email_address),
+        ("signature", &config.sender.bot_name),
+    ];
+
+    Ok(EmailJob {
+        from_name: config.sender.bot_name.clone(),
+        to: config.recipient.clone(),
+        subject: format!(
+            "{}{} - {} sent you a message on kblue.io!",
+            labels
+                .iter()
+                .map(|label| format!("[{}] ", label))
+                .collect::<String>(),
+            name,
+            email_address
+        ),
+        html_body: template::render("new_message.html", &variables)?,
+        text_body: template::render("new_message.txt", &variables)?,
+        attachments,
+        submission_id: None,
+        traceparent: None,
+    })
+}
+
+// The site's own form gets JSON, while a form posted without JavaScript gets a page to land on
+fn respond_sent(
+    request: &Request,
+    response: &mut Response,
+    name: &str,
+    email: &str,
+    is_form: bool,
+) -> Result<(), TemplateError> {
+    if is_form
+        && response.negotiate(request, &["application/json", "text/html"]) == Some("text/html")
+    {
+        let site_url = pages::site_url();
+        let variables = [
+            ("title", "Message sent"),
+            ("site_url", site_url.as_str()),
+            ("name", name),
+            ("email", email),
+        ];
+        response.render(pages::pages().get("message_sent.html"), &variables)?;
+    } else {
+        response.status(202).message("success");
+    }
+    response.send();
+    Ok(())
 }
 
 route!(
     send_email_handler,
     async move |request: RequestParam, mut response: ResponseParam| {
-        let maybe_email_info: Option<EmailInfo> = request.get_body_as_json();
-        if let Some(email_info) = maybe_email_info {
-            let bot_email = env::var("EMAIL_ADDRESS").unwrap();
-            let mut smtp_client = create_smtp_client().await;
-            let message =
-                get_client_email_message(&email_info.name, &email_info.message, &email_info.email)
-                    .from(("Kyle Doidge", bot_email.as_str()));
-            let result1 = smtp_client.send(message).await;
-            let message =
-                get_my_email_message(&email_info.name, &email_info.message, &email_info.email)
-                    .from(("KBlue Bot", bot_email.as_str()));
-
-            let result2 = smtp_client.send(message).await;
-
-            if result1.is_ok() && result2.is_ok() {
-                response.set_body_str("{\"message\": \"success\"}");
-            } else {
-                response.set_body_str("{\"message\": \"could not successfully send emails\"}");
-                response.set_status_code(500);
+        // Plain HTML forms post urlencoded or multipart bodies, the site's own form sends JSON
+        let is_form = matches!(
+            request.content_type(),
+            Some("application/x-www-form-urlencoded" | "multipart/form-data")
+        );
+        let mut email_info = match request.content_type() {
+            Some("application/x-www-form-urlencoded") => request.parse_form::<EmailInfo>()?,
+            Some("multipart/form-data") => EmailInfo::from_multipart(request)?,
+            _ => request.parse_json::<EmailInfo>()?,
+        };
+        email_info.sanitise();
+        email_info.validate().map_err(HandlerError::unprocessable)?;
+
+        let state = request
+            .state::<AppState>()
+            .ok_or_else(|| HandlerError::internal("email is not configured"))?;
+        state
+            .captcha
+            .verify(email_info.captcha.as_deref(), request.client_ip)
+            .await
+            .map_err(|e| match e {
+                CaptchaError::Unavailable(_) => {
+                    HandlerError::new(503, "could not verify the captcha, try again later")
+                        .with_source(e)
+                }
+                _ => HandlerError::new(403, "captcha verification failed"),
+            })?;
+        let spam_reasons = state.spam.check(&spam::Submission {
+            email: &email_info.email,
+            message: &email_info.message,
+            honeypot: email_info.website.as_deref(),
+        });
+        let is_spam = !spam_reasons.is_empty();
+        if is_spam {
+            info!(
+                email = email_info.email,
+                "Contact submission looks like spam: {}",
+                spam_reasons.join(", ")
+            );
+            match state.spam.action {
+                SpamAction::Drop => {
+                    let (name, email) = (&email_info.name, &email_info.email);
+                    respond_sent(request, response, name, email, is_form)?;
+                    return Ok(());
+                }
+                SpamAction::Reject => {
+                    return Err(HandlerError::new(422, "your message looks like spam"));
+                }
+                SpamAction::Flag => {}
             }
-        } else {
-            response.set_body_str("{\"message\": \"could not deserialise json body\"}");
-            response.set_status_code(400);
         }
-        response.send();
+        // Before automations run, so a double click doesn't fire webhooks twice either
+        if state
+            .dedupe
+            .is_duplicate(&email_info.email, &email_info.message)
+        {
+            return Err(HandlerError::new(
+                409,
+                "you've already sent this message, it's on its way",
+            ));
+        }
+        let mut labels = automations::evaluate(&Submission {
+            name: &email_info.name,
+            email: &email_info.email,
+            message: &email_info.message,
+        });
+        if is_spam {
+            labels.insert(0, "SPAM".to_string());
+        }
+        let client_email = get_client_email(
+            &state.email,
+            &email_info.name,
+            &email_info.message,
+            &email_info.email,
+        )?;
+        let my_email = get_my_email(
+            &state.email,
+            &email_info.name,
+            &email_info.message,
+            &email_info.email,
+            &labels,
+            std::mem::take(&mut email_info.attachments),
+        )?;
+        // No confirmation for spam, it would go to whatever address the spammer gave
+        let mut emails = match is_spam {
+            true => vec![my_email],
+            false => vec![client_email, my_email],
+        };
+        let submission_id = audit::record_submission(
+            &email_info.name,
+            &email_info.email,
+            &email_info.message,
+            emails.len() as u32,
+        );
+        for email in emails.iter_mut() {
+            email.submission_id = Some(submission_id);
+        }
+
+        // Sent in the background, so a slow or unreachable SMTP server doesn't hold up the response
+        let queue = &state.email_queue;
+        emails
+            .into_iter()
+            .try_for_each(|email| queue.enqueue(email))
+            .map_err(|e| {
+                audit::record_outcome(submission_id, Err(e.to_string()));
+                state.dedupe.forget(&email_info.email, &email_info.message);
+                HandlerError::new(503, "could not queue emails").with_source(e)
+            })?;
+        let submission = NewSubmission {
+            name: email_info.name,
+            email: email_info.email,
+            message: email_info.message,
+            labels,
+        };
+        // The emails are already on their way, so a database outage only loses the record
+        if let Some(db) = &state.db {
+            if let Err(e) = db.save_submission(&submission).await {
+                error!("Could not store contact submission: {}", e);
+            }
+        }
+        respond_sent(
+            request,
+            response,
+            &submission.name,
+            &submission.email,
+            is_form,
+        )?;
+        state.notifier.notify(Notification {
+            name: submission.name,
+            email: submission.email,
+            message: submission.message,
+            labels: submission.labels,
+        });
+        Ok(())
     }
 );
+
+impl JsonSchema for Attachment {
+    fn schema() -> Value {
+        ObjectSchema::new()
+            .field::<String>("filename")
+            .field::<String>("content_type")
+            .property(
+                "data",
+                json!({ "type": "string", "contentEncoding": "base64" }),
+                true,
+            )
+            .build()
+    }
+}
+
+impl JsonSchema for EmailInfo {
+    const NAME: Option<&'static str> = Some("ContactMessage");
+
+    fn schema() -> Value {
+        let text = |max_len: usize| json!({ "type": "string", "maxLength": max_len });
+        ObjectSchema::new()
+            .property("name", text(MAX_NAME_LEN), true)
+            .property("email", text(MAX_EMAIL_LEN), true)
+            .property("message", text(MAX_MESSAGE_LEN), true)
+            .property(
+                "attachments",
+                json!({ "type": "array", "items": Attachment::schema(), "maxItems": MAX_ATTACHMENTS }),
+                false,
+            )
+            .field::<Option<String>>("captcha")
+            .field::<Option<String>>("website")
+            .build()
+    }
+}
+
+// The same fields can also be posted as a form, which is answered with a page instead
+pub fn send_email_doc() -> RouteDoc {
+    RouteDoc::new("Send a message from the contact form")
+        .tag("contact")
+        .request::<EmailInfo>()
+        .response::<MessageBody>(202, "Sent, or queued to send")
+        .response::<HandlerError>(403, "The captcha wasn't solved")
+        .response::<HandlerError>(422, "A field is missing or invalid")
+        .response::<HandlerError>(429, "Too many messages")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email_info(name: &str, email: &str, message: &str) -> EmailInfo {
+        EmailInfo {
+            name: name.to_string(),
+            email: email.to_string(),
+            message: message.to_string(),
+            attachments: Vec::new(),
+            captcha: None,
+            website: None,
+        }
+    }
+
+    #[test]
+    fn sanitise_strips_tags_and_control_chars() {
+        let mut info = email_info(
+            " <b>Kyle</b>\r\nBcc: x@y.z ",
+            "kyle@kblue.io\n",
+            "Hi <script>alert(1)</script>there,\n3 < 5\u{7}",
+        );
+        info.sanitise();
+        assert_eq!(info.name, "KyleBcc: x@y.z");
+        assert_eq!(info.email, "kyle@kblue.io");
+        assert_eq!(info.message, "Hi alert(1)there,\n3 < 5");
+    }
+
+    #[test]
+    fn validate_reports_each_field() {
+        let fields = |info: EmailInfo| -> Vec<String> {
+            info.validate()
+                .err()
+                .map(|e| e.errors.into_iter().map(|e| e.field).collect())
+                .unwrap_or_default()
+        };
+        assert!(fields(email_info("Kyle", "kyle@kblue.io", "Hello")).is_empty());
+        assert_eq!(
+            fields(email_info("", "kyle@kblue", "")),
+            ["name", "email", "message"]
+        );
+        assert_eq!(
+            fields(email_info(
+                &"a".repeat(101),
+                "kyle @kblue.io",
+                &"a".repeat(5001)
+            )),
+            ["name", "email", "message"]
+        );
+    }
+
+    #[test]
+    fn multipart_bodies_carry_attachments() {
+        let mut request = Request::default();
+        request
+            .headers
+            .insert("Content-Type", "multipart/form-data; boundary=b");
+        request.body = Some(
+            b"--b\r\nContent-Disposition: form-data; name=\"name\"\r\n\r\nKyle\r\n\
+--b\r\nContent-Disposition: form-data; name=\"email\"\r\n\r\nkyle@kblue.io\r\n\
+--b\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\nHello\r\n\
+--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"hi.exe\"\r\n\
+Content-Type: image/png\r\n\r\nMZ\r\n--b--\r\n"
+                .to_vec(),
+        );
+        let info = EmailInfo::from_multipart(&request).unwrap();
+        assert_eq!(info.name, "Kyle");
+        assert_eq!(info.attachments[0].filename, "hi.exe");
+        let error = info.validate().unwrap_err();
+        assert_eq!(error.errors[0].field, "attachments[0]");
+    }
+
+    #[test]
+    fn forms_land_on_a_page() {
+        let mut request = Request::default();
+        request.headers.insert(
+            "Accept",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+        );
+        let mut response = Response::new();
+        respond_sent(&request, &mut response, "Kyle <3", "kyle@kblue.io", true).unwrap();
+        assert_eq!(response.status_code, 200);
+        let page = response.get_body_as_string();
+        assert!(page.contains("Thanks Kyle &lt;3"), "{}", page);
+
+        // fetch() sends */*, and JSON bodies always get JSON
+        let mut request = Request::default();
+        request.headers.insert("Accept", "*/*");
+        let mut response = Response::new();
+        respond_sent(&request, &mut response, "Kyle", "kyle@kblue.io", true).unwrap();
+        assert_eq!(response.status_code, 202);
+        let mut response = Response::new();
+        respond_sent(&request, &mut response, "Kyle", "kyle@kblue.io", false).unwrap();
+        assert_eq!(response.get_body_as_string(), r#"{"message":"success"}"#);
+    }
+}
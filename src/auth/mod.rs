@@ -0,0 +1,5 @@
+mod jwt;
+mod password;
+
+pub use jwt::{Claims, Jwt};
+pub use password::{hash_password, verify_dummy_password, verify_password};
@@ -0,0 +1,99 @@
+use std::num::NonZeroU32;
+
+use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD};
+use once_cell::sync::Lazy;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Password hashes for stored admin credentials, PBKDF2-HMAC-SHA256 in PHC string format:
+// `$pbkdf2-sha256$i=600000$<salt>$<hash>`. The iteration count is stored, so it can be raised later
+// without invalidating existing hashes
+
+// OWASP's recommendation for PBKDF2-HMAC-SHA256
+const ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+// Verified against when there's no such user, so the response takes as long either way
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| hash_password("not a real password"));
+
+pub fn hash_password(password: &str) -> String {
+    hash_with_iterations(password, NonZeroU32::new(ITERATIONS).unwrap())
+}
+
+fn hash_with_iterations(password: &str, iterations: NonZeroU32) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .expect("the OS random number generator failed");
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "$pbkdf2-sha256$i={}${}${}",
+        iterations,
+        BASE64_STANDARD_NO_PAD.encode(salt),
+        BASE64_STANDARD_NO_PAD.encode(hash)
+    )
+}
+
+// False for a malformed hash too. Constant time in the password
+pub fn verify_password(password: &str, encoded: &str) -> bool {
+    let parts: Vec<&str> = encoded.split('$').collect();
+    let ["", "pbkdf2-sha256", iterations, salt, hash] = parts.as_slice() else {
+        return false;
+    };
+    let Some(iterations) = iterations
+        .strip_prefix("i=")
+        .and_then(|iterations| iterations.parse::<NonZeroU32>().ok())
+    else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (
+        BASE64_STANDARD_NO_PAD.decode(salt),
+        BASE64_STANDARD_NO_PAD.decode(hash),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+// For a user who doesn't exist. Always false
+pub fn verify_dummy_password(password: &str) -> bool {
+    verify_password(password, &DUMMY_HASH);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_hashed_passwords() {
+        let hash = hash_with_iterations("correct horse", NonZeroU32::new(1000).unwrap());
+        assert!(hash.starts_with("$pbkdf2-sha256$i=1000$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("correct horse!", &hash));
+        assert!(!verify_password(
+            "correct horse",
+            "$argon2id$v=19$m=1,t=1,p=1$a$b"
+        ));
+        // Salted, so the same password hashes differently
+        assert_ne!(
+            hash,
+            hash_with_iterations("correct horse", NonZeroU32::new(1000).unwrap())
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use chrono::Utc;
+use kblue_http::client;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+// Rules evaluated against every contact form submission, managed at runtime through the admin API
+
+const MAX_LOG_ENTRIES: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubmissionField {
+    #[default]
+    Any,
+    Name,
+    Email,
+    Message,
+}
+
+// Case insensitive substring match on one (or any) field of the submission
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Condition {
+    #[serde(default)]
+    pub field: SubmissionField,
+    pub contains: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Action {
+    // POST the submission as JSON to the url
+    Webhook { url: String },
+    // Prefix the notification email subject with [label]
+    Label { label: String },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NewRule {
+    pub name: String,
+    pub condition: Condition,
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub id: u64,
+    pub name: String,
+    pub condition: Condition,
+    pub action: Action,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EvaluationLogEntry {
+    pub time: String,
+    pub rule_id: u64,
+    pub rule_name: String,
+    pub submission_email: String,
+    pub outcome: String,
+}
+
+pub struct Submission<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+    pub message: &'a str,
+}
+
+impl Condition {
+    fn matches(&self, submission: &Submission) -> bool {
+        let needle = self.contains.to_lowercase();
+        let fields = match self.field {
+            SubmissionField::Any => vec![submission.name, submission.email, submission.message],
+            SubmissionField::Name => vec![submission.name],
+            SubmissionField::Email => vec![submission.email],
+            SubmissionField::Message => vec![submission.message],
+        };
+        fields
+            .iter()
+            .any(|field| field.to_lowercase().contains(&needle))
+    }
+}
+
+// Why a rule couldn't be added or removed
+#[derive(Debug)]
+pub enum AutomationError {
+    // The rule is invalid, e.g. has an empty condition
+    Invalid(String),
+    // The rules couldn't be saved, so nothing changed
+    Persist(std::io::Error),
+}
+
+impl Display for AutomationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AutomationError::Invalid(message) => write!(f, "{}", message),
+            AutomationError::Persist(e) => write!(f, "Could not save automations: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AutomationError {}
+
+struct AutomationStore {
+    path: PathBuf,
+    state: RwLock<AutomationState>,
+    // Held while the rules are changed and saved, so concurrent changes can't overwrite each
+    // other. Submissions only take the RwLock, and never wait on the disk
+    writer: Mutex<()>,
+}
+
+#[derive(Default)]
+struct AutomationState {
+    rules: Vec<Rule>,
+    log: VecDeque<EvaluationLogEntry>,
+}
+
+static STORE: Lazy<AutomationStore> = Lazy::new(|| AutomationStore::load(automations_path()));
+
+fn automations_path() -> PathBuf {
+    env::var("AUTOMATIONS_PATH")
+        .unwrap_or("data/automations.json".to_string())
+        .into()
+}
+
+// Written to a temporary file and renamed, so a crash can't leave half a file behind
+fn persist(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
+
+impl AutomationStore {
+    fn load(path: PathBuf) -> Self {
+        let rules = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                error!("Could not parse automations file, starting empty: {}", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            path,
+            state: RwLock::new(AutomationState {
+                rules,
+                log: VecDeque::new(),
+            }),
+            writer: Mutex::new(()),
+        }
+    }
+
+    // Off the async runtime, the disk may be slow
+    async fn save(&self, rules: &[Rule]) -> Result<(), AutomationError> {
+        let contents =
+            serde_json::to_string_pretty(rules).map_err(|e| AutomationError::Persist(e.into()))?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || persist(&path, &contents))
+            .await
+            .map_err(|e| AutomationError::Persist(std::io::Error::other(e)))?
+            .map_err(AutomationError::Persist)
+    }
+
+    fn record(&self, rule: &Rule, submission_email: &str, outcome: String) {
+        let mut state = self.state.write().unwrap();
+        if state.log.len() == MAX_LOG_ENTRIES {
+            state.log.pop_front();
+        }
+        state.log.push_back(EvaluationLogEntry {
+            time: Utc::now().to_rfc3339(),
+            rule_id: rule.id,
+            rule_name: rule.name.clone(),
+            submission_email: submission_email.to_string(),
+            outcome,
+        });
+    }
+
+    fn list_rules(&self) -> Vec<Rule> {
+        self.state.read().unwrap().rules.clone()
+    }
+
+    fn evaluation_log(&self) -> Vec<EvaluationLogEntry> {
+        self.state
+            .read()
+            .unwrap()
+            .log
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    async fn add_rule(&self, new_rule: NewRule) -> Result<Rule, AutomationError> {
+        if new_rule.condition.contains.is_empty() {
+            return Err(AutomationError::Invalid(
+                "condition.contains must not be empty".to_string(),
+            ));
+        }
+        if let Action::Webhook { url } = &new_rule.action {
+            url::Url::parse(url).map_err(|_| {
+                AutomationError::Invalid("action.url is not a valid URL".to_string())
+            })?;
+        }
+
+        let _writer = self.writer.lock().await;
+        let mut rules = self.list_rules();
+        let rule = Rule {
+            id: rules.iter().map(|rule| rule.id).max().unwrap_or(0) + 1,
+            name: new_rule.name,
+            condition: new_rule.condition,
+            action: new_rule.action,
+        };
+        rules.push(rule.clone());
+        self.save(&rules).await?;
+        self.state.write().unwrap().rules = rules;
+        Ok(rule)
+    }
+
+    async fn remove_rule(&self, id: u64) -> Result<bool, AutomationError> {
+        let _writer = self.writer.lock().await;
+        let mut rules = self.list_rules();
+        let count = rules.len();
+        rules.retain(|rule| rule.id != id);
+        if rules.len() == count {
+            return Ok(false);
+        }
+        self.save(&rules).await?;
+        self.state.write().unwrap().rules = rules;
+        Ok(true)
+    }
+
+    fn evaluate(&'static self, submission: &Submission) -> Vec<String> {
+        let mut labels = Vec::new();
+        for rule in self.list_rules() {
+            if !rule.condition.matches(submission) {
+                continue;
+            }
+            match &rule.action {
+                Action::Label { label } => {
+                    labels.push(label.clone());
+                    self.record(&rule, submission.email, format!("labelled {}", label));
+                }
+                Action::Webhook { url } => {
+                    let url = url.clone();
+                    let submission_email = submission.email.to_string();
+                    let payload = json!({
+                        "rule": rule.name,
+                        "submission": {
+                            "name": submission.name,
+                            "email": submission.email,
+                            "message": submission.message,
+                        },
+                    });
+                    tokio::spawn(async move {
+                        let outcome = match client::post_json(&url, &payload).await {
+                            Ok(response) if response.is_success() => {
+                                format!("webhook responded {}", response.status_code)
+                            }
+                            Ok(response) => {
+                                format!("webhook failed with {}", response.status_code)
+                            }
+                            Err(e) => format!("webhook failed: {}", e),
+                        };
+                        info!(rule = rule.name, "Automation {}", outcome);
+                        self.record(&rule, &submission_email, outcome);
+                    });
+                }
+            }
+        }
+        labels
+    }
+}
+
+pub fn list_rules() -> Vec<Rule> {
+    STORE.list_rules()
+}
+
+// Most recent first
+pub fn evaluation_log() -> Vec<EvaluationLogEntry> {
+    STORE.evaluation_log()
+}
+
+pub async fn add_rule(new_rule: NewRule) -> Result<Rule, AutomationError> {
+    STORE.add_rule(new_rule).await
+}
+
+// Returns false if no rule has the id
+pub async fn remove_rule(id: u64) -> Result<bool, AutomationError> {
+    STORE.remove_rule(id).await
+}
+
+// Runs every matching rule. Labels are returned for the notification email,
+// webhooks are fired in the background so they never slow down the submission
+pub fn evaluate(submission: &Submission) -> Vec<String> {
+    STORE.evaluate(submission)
+}
+
+#[cfg(test)]
+mod tests {
+    use kblue_http::HandlerError;
+
+    use super::*;
+
+    // Each test gets its own file, the store is normally a process wide static
+    fn temp_store(name: &str) -> &'static AutomationStore {
+        let path = env::temp_dir()
+            .join(format!("kblue_automations_{}", std::process::id()))
+            .join(format!("{}.json", name));
+        let _ = fs::remove_file(&path);
+        Box::leak(Box::new(AutomationStore::load(path)))
+    }
+
+    fn label_rule(field: SubmissionField, contains: &str, label: &str) -> NewRule {
+        NewRule {
+            name: format!("label {}", label),
+            condition: Condition {
+                field,
+                contains: contains.to_string(),
+            },
+            action: Action::Label {
+                label: label.to_string(),
+            },
+        }
+    }
+
+    const SUBMISSION: Submission = Submission {
+        name: "Kyle",
+        email: "kyle@example.com",
+        message: "Are you available for a Rust CONTRACT?",
+    };
+
+    #[test]
+    fn matches_conditions_case_insensitively() {
+        let condition = |field, contains: &str| Condition {
+            field,
+            contains: contains.to_string(),
+        };
+        assert!(condition(SubmissionField::Any, "contract").matches(&SUBMISSION));
+        assert!(condition(SubmissionField::Message, "rust").matches(&SUBMISSION));
+        assert!(condition(SubmissionField::Email, "@EXAMPLE.com").matches(&SUBMISSION));
+        assert!(!condition(SubmissionField::Name, "contract").matches(&SUBMISSION));
+        assert!(!condition(SubmissionField::Any, "recruiter").matches(&SUBMISSION));
+    }
+
+    #[tokio::test]
+    async fn labels_matching_submissions_and_logs_them() {
+        let store = temp_store("labels");
+        store
+            .add_rule(label_rule(SubmissionField::Message, "contract", "work"))
+            .await
+            .unwrap();
+        store
+            .add_rule(label_rule(SubmissionField::Name, "contract", "never"))
+            .await
+            .unwrap();
+        assert_eq!(store.evaluate(&SUBMISSION), ["work"]);
+        let log = store.evaluation_log();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].outcome, "labelled work");
+        assert_eq!(log[0].submission_email, "kyle@example.com");
+    }
+
+    #[tokio::test]
+    async fn persists_added_and_removed_rules() {
+        let store = temp_store("round_trip");
+        let first = store
+            .add_rule(label_rule(SubmissionField::Any, "a", "first"))
+            .await
+            .unwrap();
+        let second = store
+            .add_rule(label_rule(SubmissionField::Any, "b", "second"))
+            .await
+            .unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert!(store.remove_rule(first.id).await.unwrap());
+        assert!(!store.remove_rule(first.id).await.unwrap());
+
+        let reloaded = AutomationStore::load(store.path.clone());
+        let rules = reloaded.list_rules();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].id, 2);
+        assert_eq!(rules[0].name, "label second");
+        // Ids aren't reused
+        let third = reloaded
+            .add_rule(label_rule(SubmissionField::Any, "c", "third"))
+            .await
+            .unwrap();
+        assert_eq!(third.id, 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_rules_and_reports_failed_saves() {
+        let store = temp_store("invalid");
+        let empty = label_rule(SubmissionField::Any, "", "empty");
+        assert!(matches!(
+            store.add_rule(empty).await,
+            Err(AutomationError::Invalid(_))
+        ));
+        let webhook = NewRule {
+            action: Action::Webhook {
+                url: "not a url".to_string(),
+            },
+            ..label_rule(SubmissionField::Any, "a", "webhook")
+        };
+        assert!(matches!(
+            store.add_rule(webhook).await,
+            Err(AutomationError::Invalid(_))
+        ));
+
+        // A directory where the file should be
+        let blocked = temp_store("blocked");
+        fs::create_dir_all(blocked.path.with_extension("json.tmp")).unwrap();
+        let error = blocked
+            .add_rule(label_rule(SubmissionField::Any, "a", "a"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AutomationError::Persist(_)), "{}", error);
+        assert!(blocked.list_rules().is_empty());
+        assert_eq!(HandlerError::from(error).status, 500);
+    }
+
+    #[test]
+    fn keeps_the_most_recent_log_entries() {
+        let store = temp_store("log");
+        let rule = Rule {
+            id: 1,
+            name: "spam".to_string(),
+            condition: Condition {
+                field: SubmissionField::Any,
+                contains: "spam".to_string(),
+            },
+            action: Action::Label {
+                label: "spam".to_string(),
+            },
+        };
+        for i in 0..MAX_LOG_ENTRIES + 5 {
+            store.record(&rule, "kyle@example.com", i.to_string());
+        }
+        let log = store.evaluation_log();
+        assert_eq!(log.len(), MAX_LOG_ENTRIES);
+        assert_eq!(log[0].outcome, (MAX_LOG_ENTRIES + 4).to_string());
+        assert_eq!(log[MAX_LOG_ENTRIES - 1].outcome, "5");
+    }
+}
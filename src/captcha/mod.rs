@@ -0,0 +1,121 @@
+use std::env;
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+
+use kblue_http::client;
+use serde::Deserialize;
+use url::form_urlencoded;
+
+mod proof_of_work;
+
+pub use proof_of_work::{Challenge, ProofOfWork};
+
+const HCAPTCHA_VERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+// Checks a contact form submission came from a person before any email is sent. Chosen with
+// CAPTCHA_PROVIDER: "hcaptcha" or "turnstile" (with CAPTCHA_SECRET), "pow" for a server issued
+// proof of work challenge (CAPTCHA_POW_DIFFICULTY), or unset to not check
+pub enum Captcha {
+    Disabled,
+    Hcaptcha { secret: String },
+    Turnstile { secret: String },
+    ProofOfWork(Box<ProofOfWork>),
+}
+
+#[derive(Debug)]
+pub enum CaptchaError {
+    Missing,
+    Rejected,
+    // The verification service couldn't be reached, so we can't tell
+    Unavailable(String),
+}
+
+impl Display for CaptchaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptchaError::Missing => write!(f, "no captcha token was sent"),
+            CaptchaError::Rejected => write!(f, "the captcha token is invalid"),
+            CaptchaError::Unavailable(e) => write!(f, "could not verify the captcha: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CaptchaError {}
+
+// Both hCaptcha and Turnstile answer with this
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+impl Captcha {
+    pub fn from_env() -> Result<Self, String> {
+        let secret = || {
+            env::var("CAPTCHA_SECRET")
+                .map_err(|_| "CAPTCHA_SECRET must be set for the captcha provider".to_string())
+        };
+        Ok(match env::var("CAPTCHA_PROVIDER").as_deref() {
+            Err(_) | Ok("") | Ok("none") => Captcha::Disabled,
+            Ok("hcaptcha") => Captcha::Hcaptcha { secret: secret()? },
+            Ok("turnstile") => Captcha::Turnstile { secret: secret()? },
+            Ok("pow") => Captcha::ProofOfWork(Box::new(ProofOfWork::from_env()?)),
+            Ok(other) => return Err(format!("Unknown CAPTCHA_PROVIDER: {}", other)),
+        })
+    }
+
+    pub async fn verify(
+        &self,
+        token: Option<&str>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), CaptchaError> {
+        let (url, secret) = match self {
+            Captcha::Disabled => return Ok(()),
+            Captcha::ProofOfWork(proof_of_work) => {
+                let token = token.ok_or(CaptchaError::Missing)?;
+                return match proof_of_work.verify(token) {
+                    true => Ok(()),
+                    false => Err(CaptchaError::Rejected),
+                };
+            }
+            Captcha::Hcaptcha { secret } => (HCAPTCHA_VERIFY_URL, secret),
+            Captcha::Turnstile { secret } => (TURNSTILE_VERIFY_URL, secret),
+        };
+        let token = token
+            .filter(|token| !token.is_empty())
+            .ok_or(CaptchaError::Missing)?;
+
+        let response = client::post_form(url, &verify_body(secret, token, client_ip))
+            .await
+            .map_err(|e| CaptchaError::Unavailable(e.to_string()))?;
+        if !response.is_success() {
+            return Err(CaptchaError::Unavailable(format!(
+                "responded with {}",
+                response.status_code
+            )));
+        }
+        let verified: SiteVerifyResponse = serde_json::from_slice(&response.body)
+            .map_err(|e| CaptchaError::Unavailable(e.to_string()))?;
+        match verified.success {
+            true => Ok(()),
+            false => Err(CaptchaError::Rejected),
+        }
+    }
+
+    pub fn proof_of_work(&self) -> Option<&ProofOfWork> {
+        match self {
+            Captcha::ProofOfWork(proof_of_work) => Some(proof_of_work),
+            _ => None,
+        }
+    }
+}
+
+fn verify_body(secret: &str, token: &str, client_ip: Option<IpAddr>) -> String {
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    form.append_pair("secret", secret)
+        .append_pair("response", token);
+    if let Some(client_ip) = client_ip {
+        form.append_pair("remoteip", &client_ip.to_string());
+    }
+    form.finish()
+}
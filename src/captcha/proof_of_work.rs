@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+
+const DEFAULT_DIFFICULTY: u32 = 20;
+const CHALLENGE_TTL: Duration = Duration::from_secs(10 * 60);
+
+// The client must find a nonce where SHA-256(`challenge:nonce`) starts with `difficulty` zero
+// bits, about 2^difficulty hashes, and sends `challenge:nonce` as the captcha token
+#[derive(Serialize, Debug)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+    pub expires_in: u64,
+}
+
+// Challenges are `expiry.random.signature`, so the server doesn't store them until they're used.
+// The signing key is random per process, restarting invalidates outstanding challenges
+pub struct ProofOfWork {
+    key: hmac::Key,
+    difficulty: u32,
+    // Solved challenges until they expire, so each can only be used once
+    used: Mutex<HashMap<String, u64>>,
+}
+
+impl ProofOfWork {
+    pub fn new(difficulty: u32) -> Self {
+        let rng = SystemRandom::new();
+        Self {
+            key: hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+                .expect("the OS random number generator failed"),
+            difficulty,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let difficulty = match env::var("CAPTCHA_POW_DIFFICULTY") {
+            Ok(difficulty) => difficulty
+                .parse::<u32>()
+                .ok()
+                .filter(|difficulty| *difficulty <= 32)
+                .ok_or(format!(
+                    "CAPTCHA_POW_DIFFICULTY must be 0 to 32: {}",
+                    difficulty
+                ))?,
+            Err(_) => DEFAULT_DIFFICULTY,
+        };
+        Ok(Self::new(difficulty))
+    }
+
+    pub fn issue(&self) -> Challenge {
+        let mut random = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut random)
+            .expect("the OS random number generator failed");
+        let payload = format!(
+            "{}.{}",
+            now() + CHALLENGE_TTL.as_secs(),
+            BASE64_URL_SAFE_NO_PAD.encode(random)
+        );
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        Challenge {
+            challenge: format!(
+                "{}.{}",
+                payload,
+                BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref())
+            ),
+            difficulty: self.difficulty,
+            expires_in: CHALLENGE_TTL.as_secs(),
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        let Some((challenge, _nonce)) = token.rsplit_once(':') else {
+            return false;
+        };
+        let Some((payload, signature)) = challenge.rsplit_once('.') else {
+            return false;
+        };
+        let Ok(signature) = BASE64_URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        if hmac::verify(&self.key, payload.as_bytes(), &signature).is_err() {
+            return false;
+        }
+        let Some(expires_at) = payload
+            .split_once('.')
+            .and_then(|(expires_at, _)| expires_at.parse::<u64>().ok())
+        else {
+            return false;
+        };
+        let now = now();
+        if expires_at <= now
+            || leading_zero_bits(digest(&SHA256, token.as_bytes()).as_ref()) < self.difficulty
+        {
+            return false;
+        }
+
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, expires_at| *expires_at > now);
+        used.insert(challenge.to_string(), expires_at).is_none()
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solve(challenge: &Challenge) -> String {
+        (0u64..)
+            .map(|nonce| format!("{}:{}", challenge.challenge, nonce))
+            .find(|token| {
+                leading_zero_bits(digest(&SHA256, token.as_bytes()).as_ref())
+                    >= challenge.difficulty
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn solved_challenges_verify_once() {
+        let proof_of_work = ProofOfWork::new(8);
+        let token = solve(&proof_of_work.issue());
+        assert!(proof_of_work.verify(&token));
+        assert!(!proof_of_work.verify(&token));
+    }
+
+    #[test]
+    fn rejects_unsolved_and_forged_challenges() {
+        let proof_of_work = ProofOfWork::new(8);
+        let challenge = proof_of_work.issue();
+        let unsolved = (0u64..)
+            .map(|nonce| format!("{}:{}", challenge.challenge, nonce))
+            .find(|token| leading_zero_bits(digest(&SHA256, token.as_bytes()).as_ref()) < 8)
+            .unwrap();
+        assert!(!proof_of_work.verify(&unsolved));
+
+        let other = ProofOfWork::new(0);
+        assert!(!other.verify(&solve(&challenge)));
+        assert!(!proof_of_work.verify("nonsense"));
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0x0f, 0xff]), 12);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+}
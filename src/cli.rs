@@ -0,0 +1,235 @@
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Command as Process;
+
+use kblue_http::{TlsConfig, TrustedProxies};
+
+use crate::auth::hash_password;
+use crate::config::Config;
+use crate::db::{self, DbConfig, Pool};
+use crate::email::ProviderConfig;
+use crate::state::AppState;
+
+// Subcommands run instead of the server, e.g.
+// `portfolio-site-backend create-admin --email kyle@kblue.io`
+
+const USAGE: &str = "Usage:
+  portfolio-site-backend                              Run the server
+  portfolio-site-backend --check                      Check the config, database, SMTP host and
+                                                      ports, then exit. Non-zero if any failed
+  portfolio-site-backend create-admin --email <email> Create an admin, or reset their password.
+                                                      The password is read from stdin";
+
+const MIN_PASSWORD_LEN: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    Check,
+    CreateAdmin { email: String },
+}
+
+// Arguments after the program name. Err is the message to show, with the usage
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let Some((command, rest)) = args.split_first() else {
+        return Ok(Command::Serve);
+    };
+    match command.as_str() {
+        "create-admin" => {
+            let mut email = None;
+            let mut rest = rest.iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--email" => email = rest.next().cloned(),
+                    _ => match arg.strip_prefix("--email=") {
+                        Some(value) => email = Some(value.to_string()),
+                        None => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE)),
+                    },
+                }
+            }
+            let email = email.ok_or(format!("--email is required\n\n{}", USAGE))?;
+            let is_address = email
+                .split_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+            if !is_address {
+                return Err(format!("{} is not an email address", email));
+            }
+            Ok(Command::CreateAdmin { email })
+        }
+        "--check" | "check" if rest.is_empty() => Ok(Command::Check),
+        "-h" | "--help" | "help" => Err(USAGE.to_string()),
+        other => Err(format!("Unknown command {}\n\n{}", other, USAGE)),
+    }
+}
+
+// Stores the admin in the database at DATABASE_URL, running any pending migrations first
+pub async fn create_admin(email: &str) -> Result<(), String> {
+    let db_config = DbConfig::from_env()?.ok_or("DATABASE_URL must be set to create an admin")?;
+    let password = read_password()?;
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!(
+            "The password must be at least {} characters",
+            MIN_PASSWORD_LEN
+        ));
+    }
+    let repository = db::connect(db_config).await?;
+    let created = repository
+        .upsert_admin(email, &hash_password(&password))
+        .await
+        .map_err(|e| format!("Could not store the admin ({})", e))?;
+    if created {
+        println!("Created admin {}", email.to_lowercase());
+    } else {
+        println!("Updated the password for admin {}", email.to_lowercase());
+    }
+    Ok(())
+}
+
+// Everything the server needs to start, without migrating or serving anything, e.g. in a deploy
+// pipeline before switching traffic over. Prints a line per check, Err if any of them failed
+pub async fn check() -> Result<(), String> {
+    let config = Config::load()?;
+    let mut checks = vec![("config".to_string(), AppState::validate(&config))];
+    if let Some(tls) = &config.server.tls {
+        let result = TlsConfig::from_pem_files(&tls.cert_path, &tls.key_path).map(drop);
+        checks.push(("tls".to_string(), result));
+    }
+    let trusted_proxies = env::var("TRUSTED_PROXIES").unwrap_or_default();
+    checks.push((
+        "trusted proxies".to_string(),
+        TrustedProxies::parse(&trusted_proxies).map(drop),
+    ));
+    if let Some(result) = check_database(DbConfig::from_env()).await {
+        checks.push(("database".to_string(), result));
+    }
+    if let ProviderConfig::Smtp(smtp) = &config.email.provider {
+        let result = match tokio::net::lookup_host((smtp.host.as_str(), smtp.port)).await {
+            Ok(mut addresses) => match addresses.next() {
+                Some(_) => Ok(()),
+                None => Err(format!("{} has no addresses", smtp.host)),
+            },
+            Err(e) => Err(format!("Could not resolve {} ({})", smtp.host, e)),
+        };
+        checks.push((format!("smtp host {}", smtp.host), result));
+    }
+
+    // Bound and released straight away, so this fails while something else holds a port
+    let mut ports = Vec::new();
+    match &config.server.unix_socket {
+        Some(path) => {
+            let directory = Path::new(path).parent().filter(|dir| dir.is_dir());
+            let result = directory
+                .map(drop)
+                .ok_or(format!("The directory for {} doesn't exist", path));
+            checks.push((format!("socket {}", path), result));
+        }
+        None => ports.push(config.server.port),
+    }
+    ports.extend(config.server.email_api_port);
+    ports.extend(config.server.metrics_port);
+    for port in ports {
+        let address = format!("{}:{}", config.server.bind, port);
+        let result = TcpListener::bind(&address)
+            .map(drop)
+            .map_err(|e| format!("Could not bind {} ({})", address, e));
+        checks.push((format!("bind {}", address), result));
+    }
+
+    let mut failed = 0;
+    for (name, result) in checks.iter() {
+        match result {
+            Ok(()) => println!("ok      {}", name),
+            Err(e) => {
+                failed += 1;
+                println!("failed  {}: {}", name, e);
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} checks failed", failed, checks.len())),
+    }
+}
+
+// None without DATABASE_URL. A DATABASE_URL or DB_POOL_SIZE that doesn't parse fails the check
+async fn check_database(db_config: Result<Option<DbConfig>, String>) -> Option<Result<(), String>> {
+    let db_config = match db_config {
+        Ok(db_config) => db_config?,
+        Err(e) => return Some(Err(e)),
+    };
+    let result = Pool::new(db_config)
+        .get()
+        .await
+        .map(drop)
+        .map_err(|e| format!("Could not connect to the database ({})", e));
+    Some(result)
+}
+
+// Prompts twice without echoing on a terminal, otherwise takes the first line, e.g. from a pipe
+fn read_password() -> Result<String, String> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        let mut password = String::new();
+        stdin
+            .lock()
+            .read_line(&mut password)
+            .map_err(|e| format!("Could not read the password ({})", e))?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let prompt = |label: &str| -> Result<String, String> {
+        eprint!("{}: ", label);
+        io::stderr().flush().ok();
+        let mut password = String::new();
+        let result = stdin.lock().read_line(&mut password);
+        eprintln!();
+        result.map_err(|e| format!("Could not read the password ({})", e))?;
+        Ok(password.trim_end_matches(['\r', '\n']).to_string())
+    };
+    let _ = Process::new("stty").arg("-echo").status();
+    let passwords = prompt("Password").and_then(|first| Ok((first, prompt("Repeat password")?)));
+    let _ = Process::new("stty").arg("echo").status();
+    let (password, repeated) = passwords?;
+    if password != repeated {
+        return Err("The passwords don't match".to_string());
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(&[]), Ok(Command::Serve));
+        assert_eq!(parse(&args(&["--check"])), Ok(Command::Check));
+        assert!(parse(&args(&["--check", "--verbose"])).is_err());
+        assert_eq!(
+            parse(&args(&["create-admin", "--email", "kyle@kblue.io"])),
+            Ok(Command::CreateAdmin {
+                email: "kyle@kblue.io".to_string()
+            })
+        );
+        assert!(parse(&args(&["create-admin", "--email=kyle@kblue.io"])).is_ok());
+        assert!(parse(&args(&["create-admin"])).is_err());
+        assert!(parse(&args(&["create-admin", "--email", "kyle"])).is_err());
+        assert!(parse(&args(&["serve-forever"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_fail_on_an_invalid_database_config() {
+        assert_eq!(check_database(Ok(None)).await, None);
+        let db_config = DbConfig::from_url("mysql://localhost/kblue").map(Some);
+        assert_eq!(
+            check_database(db_config).await,
+            Some(Err("Unsupported DATABASE_URL scheme: mysql".to_string()))
+        );
+    }
+}
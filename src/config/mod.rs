@@ -0,0 +1,652 @@
+mod toml;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing_subscriber::EnvFilter;
+
+use crate::email::EmailConfig;
+
+// Read from the TOML (or JSON) file at CONFIG_PATH, default config.toml, with environment
+// variables taking precedence over the file, see ENV_OVERRIDES. Without a file everything comes
+// from the environment, as it used to
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub environment: Environment,
+    #[serde(default)]
+    pub server: ServerSettings,
+    #[serde(default)]
+    pub cors: CorsSettings,
+    #[serde(default)]
+    pub log: LogSettings,
+    pub email: EmailConfig,
+    // Route pattern -> upstream, e.g. `"/grafana/*" = "http://127.0.0.1:3000"`, see Router::proxy
+    #[serde(default)]
+    pub proxies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub latency: LatencySettings,
+    #[serde(default)]
+    pub maintenance: MaintenanceSettings,
+}
+
+// How the server starts, it can be switched at runtime, see Maintenance
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    // Sent as Retry-After while in maintenance
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+// Route handlers taking longer than their budget are logged, see LatencyBudgets
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LatencySettings {
+    // For routes without a budget of their own. None only checks the routes listed in budgets
+    pub default_budget_ms: Option<u64>,
+    // Route pattern as registered -> milliseconds, e.g. `"/api/v1/send_email" = 2000`
+    #[serde(default)]
+    pub budgets: BTreeMap<String, u64>,
+    // Also alert NOTIFY_WEBHOOKS, at most once per route every few minutes
+    #[serde(default)]
+    pub notify: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Dev,
+    Prod,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // Listens on this socket instead of bind:port if set
+    pub unix_socket: Option<String>,
+    // Serve HTTPS on the TCP ports rather than relying on a proxy in front to terminate TLS
+    pub tls: Option<TlsSettings>,
+    // Moves POST /api/v1/send_email to its own listener on bind, e.g. to firewall it separately
+    pub email_api_port: Option<u16>,
+    // Moves /healthz and /readyz to their own listener on bind, out of public reach
+    pub metrics_port: Option<u16>,
+    // Acceptor tasks sharing each TCP port through SO_REUSEPORT, e.g. one per core
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    // Serves Swagger UI for /api/v1/openapi.json at /api/v1/docs
+    #[serde(default)]
+    pub swagger_ui: bool,
+    // Cookie sessions kept in memory, letting POST /api/v1/auth/login log a browser in without JWTs
+    #[serde(default)]
+    pub sessions: bool,
+    // Name -> value, sent with every response not setting its own, e.g. `Server = "kblue"`. An
+    // empty value strips the header instead, e.g. `X-Powered-By = ""` from proxied upstreams
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+// PEM files, see TlsConfig::from_pem_files
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsSettings {
+    // Required in prod. Entries may contain a `*` wildcard, see CorsConfig
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    // Empty allows any Host header
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogSettings {
+    // A level ("debug") or full filter directives ("info,portfolio_site_backend=trace")
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // For humans
+    #[default]
+    Pretty,
+    // For log aggregation
+    Json,
+}
+
+fn default_bind() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_retry_after_secs() -> u64 {
+    300
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after_secs: default_retry_after_secs(),
+        }
+    }
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind: default_bind(),
+            port: default_port(),
+            unix_socket: None,
+            tls: None,
+            email_api_port: None,
+            metrics_port: None,
+            workers: default_workers(),
+            swagger_ui: false,
+            sessions: false,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerSettings {
+    // The extra ports need their own port number each, which can't clash with the main one
+    fn port_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut taken = Vec::new();
+        if self.unix_socket.is_none() {
+            taken.push(("server.port (PORT)", self.port));
+        }
+        for (name, port) in [
+            (
+                "server.email_api_port (EMAIL_API_PORT)",
+                self.email_api_port,
+            ),
+            ("server.metrics_port (METRICS_PORT)", self.metrics_port),
+        ] {
+            let Some(port) = port else { continue };
+            if port == 0 {
+                problems.push(format!("{} can't be 0", name));
+            } else if let Some((other, _)) = taken.iter().find(|(_, taken)| *taken == port) {
+                problems.push(format!("{} is the same as {}", name, other));
+            }
+            taken.push((name, port));
+        }
+        problems
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Number,
+    // Comma separated
+    List,
+    // true or false, also 1 or 0
+    Bool,
+}
+
+// Environment variable, path in the config, and its type. Provider specific settings only apply
+// to the provider they belong to
+const ENV_OVERRIDES: &[(&str, &str, Kind)] = &[
+    ("ENVIRONMENT", "environment", Kind::String),
+    ("BIND_ADDRESS", "server.bind", Kind::String),
+    ("PORT", "server.port", Kind::Number),
+    ("UNIX_SOCKET_PATH", "server.unix_socket", Kind::String),
+    ("TLS_CERT_PATH", "server.tls.cert_path", Kind::String),
+    ("TLS_KEY_PATH", "server.tls.key_path", Kind::String),
+    ("EMAIL_API_PORT", "server.email_api_port", Kind::Number),
+    ("METRICS_PORT", "server.metrics_port", Kind::Number),
+    ("WORKERS", "server.workers", Kind::Number),
+    ("SWAGGER_UI", "server.swagger_ui", Kind::Bool),
+    ("SESSIONS", "server.sessions", Kind::Bool),
+    ("MAINTENANCE_MODE", "maintenance.enabled", Kind::Bool),
+    (
+        "MAINTENANCE_RETRY_AFTER",
+        "maintenance.retry_after_secs",
+        Kind::Number,
+    ),
+    ("ALLOWED_ORIGINS", "cors.allowed_origins", Kind::List),
+    ("ALLOWED_HOSTS", "cors.allowed_hosts", Kind::List),
+    ("LOG_LEVEL", "log.level", Kind::String),
+    ("LOG_FORMAT", "log.format", Kind::String),
+    (
+        "LATENCY_BUDGET_MS",
+        "latency.default_budget_ms",
+        Kind::Number,
+    ),
+    ("EMAIL_ADDRESS", "email.sender.address", Kind::String),
+    ("EMAIL_SENDER_NAME", "email.sender.name", Kind::String),
+    ("EMAIL_BOT_NAME", "email.sender.bot_name", Kind::String),
+    ("EMAIL_RECIPIENT", "email.recipient", Kind::String),
+    ("EMAIL_PROVIDER", "email.provider.type", Kind::String),
+];
+
+const PROVIDER_ENV_OVERRIDES: &[(&str, &str, &str, Kind)] = &[
+    ("smtp", "SMTP_HOST", "host", Kind::String),
+    ("smtp", "SMTP_PORT", "port", Kind::Number),
+    ("smtp", "SMTP_TLS", "tls", Kind::String),
+    ("smtp", "SMTP_USERNAME", "username", Kind::String),
+    ("smtp", "EMAIL_PASSWORD", "password", Kind::String),
+    ("smtp", "DKIM_SELECTOR", "dkim.selector", Kind::String),
+    ("smtp", "DKIM_DOMAIN", "dkim.domain", Kind::String),
+    (
+        "smtp",
+        "DKIM_PRIVATE_KEY_PATH",
+        "dkim.private_key_path",
+        Kind::String,
+    ),
+    ("sendgrid", "SENDGRID_API_KEY", "api_key", Kind::String),
+    ("mailgun", "MAILGUN_API_KEY", "api_key", Kind::String),
+    ("mailgun", "MAILGUN_DOMAIN", "domain", Kind::String),
+    ("mailgun", "MAILGUN_API_BASE", "api_base", Kind::String),
+];
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let mut value = match env::var("CONFIG_PATH") {
+            Ok(path) => read_file(&path)?,
+            Err(_) if Path::new("config.toml").exists() => read_file("config.toml")?,
+            Err(_) => Value::Object(Map::new()),
+        };
+        // The older, email only config file
+        if let Ok(path) = env::var("EMAIL_CONFIG_PATH") {
+            set(&mut value, "email", read_file(&path)?);
+        }
+        apply_env(&mut value, |name| env::var(name).ok())?;
+        Self::from_value(value)
+    }
+
+    fn from_value(value: Value) -> Result<Self, String> {
+        let config: Self = serde_path_to_error::deserialize(value).map_err(|e| {
+            match e.path().to_string().as_str() {
+                "." => format!("Invalid config: {}", e.inner()),
+                path => format!("Invalid config at {}: {}", path, e.inner()),
+            }
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Catches mistakes at startup, listing all of them at once
+    fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.server.unix_socket.is_none() && self.server.port == 0 {
+            problems.push("server.port (PORT) can't be 0".to_string());
+        }
+        problems.extend(self.server.port_problems());
+        if self.server.workers == 0 {
+            problems.push("server.workers (WORKERS) must be at least 1".to_string());
+        }
+        for (name, value) in self.server.headers.iter() {
+            let is_token = !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+            if !is_token || value.contains(['\r', '\n']) {
+                problems.push(format!(
+                    "server.headers.\"{}\" must be a header name and a single line value",
+                    name
+                ));
+            }
+        }
+        if let Some(tls) = &self.server.tls {
+            for (name, path) in [
+                ("cert_path (TLS_CERT_PATH)", &tls.cert_path),
+                ("key_path (TLS_KEY_PATH)", &tls.key_path),
+            ] {
+                if !Path::new(path).is_file() {
+                    problems.push(format!("server.tls.{} is not a file: {}", name, path));
+                }
+            }
+        }
+        if self.environment == Environment::Prod && self.cors.allowed_origins.is_empty() {
+            problems.push("cors.allowed_origins (ALLOWED_ORIGINS) must be set in prod".to_string());
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            problems.push(format!("log.level (LOG_LEVEL) is invalid: {}", e));
+        }
+        if self.latency.default_budget_ms == Some(0)
+            || self.latency.budgets.values().any(|ms| *ms == 0)
+        {
+            problems.push("latency budgets (LATENCY_BUDGET_MS) must be at least 1ms".to_string());
+        }
+        for (pattern, upstream) in self.proxies.iter() {
+            let valid = url::Url::parse(upstream)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+            if !pattern.starts_with('/') || !valid {
+                problems.push(format!(
+                    "proxies.\"{}\" must be a path forwarding to an http(s) URL",
+                    pattern
+                ));
+            }
+        }
+        if let Err(e) = self.email.validate() {
+            problems.push(e);
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid config: {}", problems.join(", ")))
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<Value, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {} ({})", path, e))?;
+    let value = if path.ends_with(".json") {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        toml::parse(&contents)
+    };
+    value.map_err(|e| format!("Could not parse {} ({})", path, e))
+}
+
+fn apply_env(value: &mut Value, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    for (name, path, kind) in ENV_OVERRIDES {
+        if let Some(env_value) = var(name) {
+            set(value, path, parse_env(name, &env_value, *kind)?);
+        }
+    }
+    // Without a config file, the provider defaults to SMTP as it always has
+    let provider = match value.pointer("/email/provider/type") {
+        Some(Value::String(provider)) => provider.clone(),
+        Some(_) => return Ok(()),
+        None => {
+            if value.get("email").is_some() {
+                set(value, "email.provider.type", Value::from("smtp"));
+            }
+            "smtp".to_string()
+        }
+    };
+    for (for_provider, name, path, kind) in PROVIDER_ENV_OVERRIDES {
+        if *for_provider != provider {
+            continue;
+        }
+        if let Some(env_value) = var(name) {
+            let env_value = parse_env(name, &env_value, *kind)?;
+            set(value, &format!("email.provider.{}", path), env_value);
+        }
+    }
+    Ok(())
+}
+
+fn parse_env(name: &str, value: &str, kind: Kind) -> Result<Value, String> {
+    Ok(match kind {
+        Kind::String => Value::from(value),
+        Kind::Number => Value::from(
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} is not a number: {}", name, value))?,
+        ),
+        Kind::List => Value::from(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>(),
+        ),
+        Kind::Bool => Value::from(match value {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => return Err(format!("{} must be true or false: {}", name, value)),
+        }),
+    })
+}
+
+// Sets the value at a dotted path, creating or replacing tables along the way
+fn set(value: &mut Value, path: &str, new_value: Value) {
+    let mut current = value;
+    for key in path.split('.') {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .expect("just made an object")
+            .entry(key)
+            .or_insert(Value::Null);
+    }
+    *current = new_value;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::email::ProviderConfig;
+
+    fn load(file: &str, vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        let mut value = toml::parse(file)?;
+        apply_env(&mut value, |name| vars.get(name).map(|v| v.to_string()))?;
+        Config::from_value(value)
+    }
+
+    const FILE: &str = r#"
+        environment = "prod"
+
+        [server]
+        port = 3000
+
+        [cors]
+        allowed_origins = ["https://kblue.io"]
+
+        [email]
+        recipient = "kyle@kblue.io"
+        sender = { address = "bot@kblue.io" }
+        provider = { type = "sendgrid", api_key = "file" }
+
+        [proxies]
+        "/grafana/*" = "http://127.0.0.1:3000"
+    "#;
+
+    #[test]
+    fn env_overrides_the_file() {
+        let config = load(
+            FILE,
+            &[
+                ("PORT", "8081"),
+                ("SESSIONS", "true"),
+                ("ALLOWED_ORIGINS", "https://a.io, https://b.io"),
+                ("SENDGRID_API_KEY", "env"),
+                // Not the configured provider
+                ("SMTP_HOST", "smtp.kblue.io"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 8081);
+        assert!(config.server.sessions);
+        assert_eq!(config.server.bind, "0.0.0.0");
+        assert_eq!(
+            config.cors.allowed_origins,
+            ["https://a.io", "https://b.io"]
+        );
+        assert_eq!(config.log.format, LogFormat::Pretty);
+        assert_eq!(config.proxies["/grafana/*"], "http://127.0.0.1:3000");
+        assert_eq!(
+            config.email.provider,
+            ProviderConfig::SendGrid {
+                api_key: "env".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn env_alone_is_enough() {
+        let config = load(
+            "",
+            &[
+                ("ENVIRONMENT", "dev"),
+                ("EMAIL_ADDRESS", "bot@kblue.io"),
+                ("EMAIL_RECIPIENT", "kyle@kblue.io"),
+                ("EMAIL_PASSWORD", "secret"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.environment, Environment::Dev);
+        assert!(matches!(config.email.provider, ProviderConfig::Smtp(_)));
+        assert!(!config.server.sessions);
+    }
+
+    #[test]
+    fn errors_say_where_the_problem_is() {
+        let error = load(FILE, &[("ENVIRONMENT", "staging")]).unwrap_err();
+        assert!(error.starts_with("Invalid config at environment: unknown variant `staging`"));
+        let error = load(FILE, &[("PORT", "http")]).unwrap_err();
+        assert_eq!(error, "PORT is not a number: http");
+        let error = load(&FILE.replace("port = 3000", "port = \"3000\""), &[]).unwrap_err();
+        assert!(
+            error.starts_with("Invalid config at server.port"),
+            "{}",
+            error
+        );
+
+        let error = load(FILE, &[("ALLOWED_ORIGINS", ""), ("LOG_LEVEL", "app=loud")]).unwrap_err();
+        assert!(error.contains("cors.allowed_origins"), "{}", error);
+        assert!(error.contains("log.level"), "{}", error);
+
+        let error = load(&FILE.replace("http://127", "127"), &[]).unwrap_err();
+        assert!(error.contains("proxies.\"/grafana/*\""), "{}", error);
+    }
+
+    #[test]
+    fn configures_listeners() {
+        let cert = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/kblue_http/testdata/localhost.crt"
+        );
+        let key = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/kblue_http/testdata/localhost.key"
+        );
+        let config = load(
+            FILE,
+            &[
+                ("BIND_ADDRESS", "127.0.0.1"),
+                ("TLS_CERT_PATH", cert),
+                ("TLS_KEY_PATH", key),
+                ("EMAIL_API_PORT", "3001"),
+                ("METRICS_PORT", "9090"),
+                ("WORKERS", "4"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.workers, 4);
+        assert_eq!(config.server.bind, "127.0.0.1");
+        assert_eq!(config.server.tls.unwrap().key_path, key);
+        assert_eq!(config.server.email_api_port, Some(3001));
+        assert_eq!(config.server.metrics_port, Some(9090));
+
+        let error = load(FILE, &[("TLS_CERT_PATH", cert)]).unwrap_err();
+        assert!(
+            error.starts_with("Invalid config at server.tls"),
+            "{}",
+            error
+        );
+        let error = load(
+            FILE,
+            &[("TLS_CERT_PATH", "/missing.crt"), ("TLS_KEY_PATH", key)],
+        )
+        .unwrap_err();
+        assert!(error.contains("server.tls.cert_path"), "{}", error);
+
+        let error = load(FILE, &[("EMAIL_API_PORT", "3000"), ("METRICS_PORT", "0")]).unwrap_err();
+        assert!(
+            error.contains("server.email_api_port (EMAIL_API_PORT) is the same as server.port"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("server.metrics_port (METRICS_PORT) can't be 0"),
+            "{}",
+            error
+        );
+        let error = load(FILE, &[("WORKERS", "0")]).unwrap_err();
+        assert!(error.contains("server.workers"), "{}", error);
+        let error = load(
+            FILE,
+            &[("EMAIL_API_PORT", "9090"), ("METRICS_PORT", "9090")],
+        )
+        .unwrap_err();
+        assert!(
+            error.contains(
+                "server.metrics_port (METRICS_PORT) is the same as server.email_api_port"
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reads_default_headers() {
+        let file = FILE.replace(
+            "port = 3000",
+            "port = 3000\nheaders = { Server = \"kblue\", X-Powered-By = \"\" }",
+        );
+        let config = load(&file, &[]).unwrap();
+        assert_eq!(config.server.headers["Server"], "kblue");
+        assert_eq!(config.server.headers["X-Powered-By"], "");
+
+        let error = load(&file.replace("X-Powered-By", "\"Powered By\""), &[]).unwrap_err();
+        assert!(error.contains("server.headers.\"Powered By\""), "{}", error);
+    }
+
+    #[test]
+    fn reads_maintenance_mode() {
+        let config = load(FILE, &[]).unwrap();
+        assert!(!config.maintenance.enabled);
+        assert_eq!(config.maintenance.retry_after_secs, 300);
+
+        let vars = [("MAINTENANCE_MODE", "1"), ("MAINTENANCE_RETRY_AFTER", "60")];
+        let config = load(FILE, &vars).unwrap();
+        assert!(config.maintenance.enabled);
+        assert_eq!(config.maintenance.retry_after_secs, 60);
+
+        let error = load(FILE, &[("MAINTENANCE_MODE", "yes")]).unwrap_err();
+        assert!(error.contains("MAINTENANCE_MODE"), "{}", error);
+    }
+}
@@ -0,0 +1,378 @@
+use serde_json::{Map, Number, Value};
+
+// Enough TOML for config files: tables, dotted keys, strings, integers, floats, booleans, arrays
+// and inline tables. Parsed into a JSON value so the config structs only need Deserialize.
+// Multi-line strings, dates and arrays of tables aren't supported
+pub fn parse(input: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+    };
+    parser.document().map_err(|e| {
+        let line = parser.chars[..parser.pos.min(parser.chars.len())]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1;
+        format!("line {}: {}", line, e)
+    })
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected {:?}, found {:?}", expected, c)),
+            None => Err(format!(
+                "expected {:?}, found the end of the file",
+                expected
+            )),
+        }
+    }
+
+    // Spaces and tabs, plus newlines and comments if `newlines`
+    fn skip_whitespace(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => self.pos += 1,
+                '#' if newlines => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // Only whitespace and a comment may follow a key/value pair or table header
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_whitespace(false);
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.pos += 1;
+            }
+        }
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.peek() == Some('\n') => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => Err(format!("expected the end of the line, found {:?}", c)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Value, String> {
+        let mut root = Map::new();
+        let mut table: Vec<String> = Vec::new();
+        // Headers already seen, which can't be repeated
+        let mut defined: Vec<Vec<String>> = Vec::new();
+        loop {
+            self.skip_whitespace(true);
+            match self.peek() {
+                None => return Ok(Value::Object(root)),
+                Some('[') => {
+                    self.pos += 1;
+                    if self.peek() == Some('[') {
+                        return Err("arrays of tables are not supported".to_string());
+                    }
+                    self.skip_whitespace(false);
+                    table = self.key()?;
+                    self.skip_whitespace(false);
+                    self.expect(']')?;
+                    if defined.contains(&table) {
+                        return Err(format!("table [{}] is defined twice", table.join(".")));
+                    }
+                    defined.push(table.clone());
+                    table_at(&mut root, &table)?;
+                    self.end_of_line()?;
+                }
+                Some(_) => {
+                    let (key, value) = self.key_value()?;
+                    insert(table_at(&mut root, &table)?, &key, value)?;
+                    self.end_of_line()?;
+                }
+            }
+        }
+    }
+
+    fn key_value(&mut self) -> Result<(Vec<String>, Value), String> {
+        let key = self.key()?;
+        self.skip_whitespace(false);
+        self.expect('=')?;
+        self.skip_whitespace(false);
+        Ok((key, self.value()?))
+    }
+
+    // e.g. `server.port` or `"quoted key"`
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(match self.peek() {
+                            Some(c) => format!("expected a key, found {:?}", c),
+                            None => "expected a key, found the end of the file".to_string(),
+                        });
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            parts.push(part);
+            self.skip_whitespace(false);
+            if self.peek() != Some('.') {
+                return Ok(parts);
+            }
+            self.pos += 1;
+            self.skip_whitespace(false);
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => Err("expected a value, found the end of the file".to_string()),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        if self.peek() == Some('"') && self.chars.get(self.pos + 1) == Some(&'"') {
+            return Err("multi-line strings are not supported".to_string());
+        }
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(match self.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.next()).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or(format!("invalid unicode escape \\u{}", hex))?
+                    }
+                    Some(c) => return Err(format!("invalid escape \\{}", c)),
+                    None => return Err("unterminated string".to_string()),
+                }),
+                Some('\n') => {
+                    self.pos -= 1;
+                    return Err("unterminated string".to_string());
+                }
+                None => return Err("unterminated string".to_string()),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    // Single quoted, without escapes
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.expect('\'')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(string),
+                Some('\n') => {
+                    self.pos -= 1;
+                    return Err("unterminated string".to_string());
+                }
+                None => return Err("unterminated string".to_string()),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    // May span lines, with a trailing comma
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace(true);
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_whitespace(true);
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {}
+                _ => return Err("expected ',' or ']' in array".to_string()),
+            }
+        }
+    }
+
+    // On one line, without a trailing comma
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut table = Map::new();
+        self.skip_whitespace(false);
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(table));
+        }
+        loop {
+            self.skip_whitespace(false);
+            let (key, value) = self.key_value()?;
+            insert(&mut table, &key, value)?;
+            self.skip_whitespace(false);
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Object(table)),
+                _ => return Err("expected ',' or '}' in inline table".to_string()),
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-_.".contains(c)) {
+            self.pos += 1;
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        match token.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+        let number = token.replace('_', "");
+        if let Ok(integer) = number.parse::<i64>() {
+            return Ok(Value::Number(integer.into()));
+        }
+        if number.contains(['.', 'e', 'E']) {
+            if let Some(float) = number.parse::<f64>().ok().and_then(Number::from_f64) {
+                return Ok(Value::Number(float));
+            }
+        }
+        match token.is_empty() {
+            true => Err(format!(
+                "expected a value, found {:?}",
+                self.peek().unwrap_or(' ')
+            )),
+            false => Err(format!("invalid value {:?}, strings need quotes", token)),
+        }
+    }
+}
+
+// The table at `path`, creating any which don't exist yet
+fn table_at<'a>(
+    root: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Result<&'a mut Map<String, Value>, String> {
+    let mut table = root;
+    for (i, key) in path.iter().enumerate() {
+        table = match table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            Value::Object(table) => table,
+            _ => return Err(format!("{} is not a table", path[..=i].join("."))),
+        };
+    }
+    Ok(table)
+}
+
+fn insert(table: &mut Map<String, Value>, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = key.split_last().expect("keys have at least one part");
+    let table = table_at(table, parents)?;
+    if table.contains_key(last) {
+        return Err(format!("{} is defined twice", key.join(".")));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parses_tables_and_values() {
+        let value = parse(
+            r#"
+            # Comment
+            environment = "prod" # trailing comment
+            [server]
+            port = 8_080
+            ratio = 0.5
+            enabled = true
+
+            [cors]
+            allowed_origins = [
+                "https://kblue.io",
+                'https://www.kblue.io', # literal
+            ]
+
+            [email.provider]
+            type = "smtp"
+            dkim = { selector = "mail", "domain" = "kblue.io" }
+            escaped = "a\"bé"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "environment": "prod",
+                "server": { "port": 8080, "ratio": 0.5, "enabled": true },
+                "cors": { "allowed_origins": ["https://kblue.io", "https://www.kblue.io"] },
+                "email": { "provider": {
+                    "type": "smtp",
+                    "dkim": { "selector": "mail", "domain": "kblue.io" },
+                    "escaped": "a\"bé",
+                } },
+            })
+        );
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        assert_eq!(
+            parse("[server]\nport = abc").unwrap_err(),
+            "line 2: invalid value \"abc\", strings need quotes"
+        );
+        assert_eq!(
+            parse("a = 1\na = 2").unwrap_err(),
+            "line 2: a is defined twice"
+        );
+        assert!(parse("[a]\n[a]").unwrap_err().contains("defined twice"));
+        assert!(parse("a = \"b").unwrap_err().contains("unterminated"));
+        assert!(parse("a = 1 b").unwrap_err().starts_with("line 1"));
+    }
+}
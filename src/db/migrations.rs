@@ -0,0 +1,72 @@
+use super::postgres::Connection;
+use super::DbError;
+
+// Applied in order, each once. Never edit one which has been deployed, add another
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (
+        1,
+        "create_portfolio_tables",
+        include_str!("migrations/0001_create_portfolio_tables.sql"),
+    ),
+    (
+        2,
+        "project_links_and_ordering",
+        include_str!("migrations/0002_project_links_and_ordering.sql"),
+    ),
+    (
+        3,
+        "resume_downloads",
+        include_str!("migrations/0003_resume_downloads.sql"),
+    ),
+    (4, "admins", include_str!("migrations/0004_admins.sql")),
+];
+
+// Any number, as long as it's the same for every instance
+const LOCK_ID: i64 = 0x6b626c7565;
+
+// Returns the names of the migrations applied
+pub async fn run(connection: &mut Connection) -> Result<Vec<String>, DbError> {
+    connection
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    // So instances starting together don't run the same migration twice
+    connection
+        .execute("SELECT pg_advisory_lock($1)", &[&LOCK_ID])
+        .await?;
+    let result = apply_pending(connection).await;
+    connection
+        .execute("SELECT pg_advisory_unlock($1)", &[&LOCK_ID])
+        .await?;
+    result
+}
+
+async fn apply_pending(connection: &mut Connection) -> Result<Vec<String>, DbError> {
+    let current: i64 = connection
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) AS version FROM schema_migrations",
+            &[],
+        )
+        .await?
+        .get("version")?;
+    let mut applied = Vec::new();
+    for (version, name, sql) in MIGRATIONS.iter().filter(|(version, ..)| *version > current) {
+        // A simple query with several statements runs as one transaction, so a failed migration
+        // leaves nothing behind
+        connection
+            .batch_execute(&format!(
+                "{};\nINSERT INTO schema_migrations (version, name) VALUES ({}, '{}')",
+                sql.trim().trim_end_matches(';'),
+                version,
+                name
+            ))
+            .await?;
+        applied.push(name.to_string());
+    }
+    Ok(applied)
+}
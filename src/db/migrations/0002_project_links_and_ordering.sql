@@ -0,0 +1,9 @@
+-- A project can link to its site, source, write up and so on, each with a label
+ALTER TABLE projects ADD COLUMN links JSONB NOT NULL DEFAULT '[]';
+UPDATE projects SET links = jsonb_build_array(jsonb_build_object('label', 'Website', 'url', url))
+    WHERE url IS NOT NULL;
+ALTER TABLE projects DROP COLUMN url;
+
+-- Lowest first, then oldest first
+ALTER TABLE projects ADD COLUMN position BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE projects ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,8 @@
+CREATE TABLE resume_downloads (
+    id BIGSERIAL PRIMARY KEY,
+    -- Where the link was clicked, NULL if the browser didn't say
+    referrer TEXT,
+    downloaded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX resume_downloads_downloaded_at ON resume_downloads (downloaded_at);
@@ -0,0 +1,8 @@
+CREATE TABLE admins (
+    id BIGSERIAL PRIMARY KEY,
+    -- Lower case, what they log in with
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,163 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+use super::postgres::Connection;
+use super::{DbConfig, DbError};
+
+// How long to wait for a free connection before giving up on the request
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Up to pool_size connections, opened as they're needed and reused afterwards
+#[derive(Clone)]
+pub struct Pool {
+    config: Arc<DbConfig>,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    permits: Arc<Semaphore>,
+}
+
+// Goes back to the pool when dropped, unless it broke or was dropped mid-query while in use
+pub struct PooledConnection {
+    connection: Option<Connection>,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    pub fn new(config: DbConfig) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.pool_size)),
+            config: Arc::new(config),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub async fn get(&self) -> Result<PooledConnection, DbError> {
+        let permit = timeout(ACQUIRE_TIMEOUT, self.permits.clone().acquire_owned())
+            .await
+            .map_err(|_| DbError::PoolTimeout)?
+            .expect("the pool's semaphore is never closed");
+        let idle = self.idle.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => Connection::connect(&self.config).await?,
+        };
+        Ok(PooledConnection {
+            connection: Some(connection),
+            idle: self.idle.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().expect("taken on drop")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut().expect("taken on drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if !connection.is_broken() {
+                self.idle.lock().unwrap().push(connection);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![kind];
+        message.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    // Answers each query with one row holding its SQL, holding back ReadyForQuery for "slow"
+    async fn fake_postgres(mut stream: TcpStream) -> std::io::Result<()> {
+        let length = stream.read_i32().await?;
+        stream.read_exact(&mut vec![0; length as usize - 4]).await?;
+        stream
+            .write_all(&message(b'R', &0i32.to_be_bytes()))
+            .await?;
+        stream.write_all(&message(b'Z', b"I")).await?;
+        let mut sql = String::new();
+        loop {
+            let kind = stream.read_u8().await?;
+            let length = stream.read_i32().await?;
+            let mut body = vec![0; length as usize - 4];
+            stream.read_exact(&mut body).await?;
+            match kind {
+                // The statement name, then the SQL
+                b'P' => {
+                    sql = String::from_utf8_lossy(body[1..].split(|b| *b == 0).next().unwrap())
+                        .into_owned()
+                }
+                b'S' => {
+                    let mut description = 1i16.to_be_bytes().to_vec();
+                    description.extend_from_slice(b"value\0");
+                    description.extend_from_slice(&[0; 18]);
+                    let mut row = 1i16.to_be_bytes().to_vec();
+                    row.extend_from_slice(&(sql.len() as i32).to_be_bytes());
+                    row.extend_from_slice(sql.as_bytes());
+                    stream.write_all(&message(b'T', &description)).await?;
+                    stream.write_all(&message(b'D', &row)).await?;
+                    stream.write_all(&message(b'C', b"SELECT 1\0")).await?;
+                    if sql == "slow" {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                    stream.write_all(&message(b'Z', b"I")).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_connections_whose_query_was_cancelled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(fake_postgres(stream));
+            }
+        });
+        let pool = Pool::new(DbConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            user: "kblue".to_string(),
+            password: String::new(),
+            database: "kblue".to_string(),
+            pool_size: 1,
+        });
+
+        let mut connection = pool.get().await.unwrap();
+        let cancelled = timeout(Duration::from_millis(50), connection.query("slow", &[])).await;
+        assert!(cancelled.is_err());
+        drop(connection);
+
+        // A reused connection would read the slow query's ReadyForQuery, and return no rows
+        let mut connection = pool.get().await.unwrap();
+        let rows = connection.query("fast", &[]).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<String>("value").unwrap(), "fast");
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+use super::scram::Scram;
+use super::{DbConfig, DbError, Row, ToSql};
+
+// A connection speaking the Postgres wire protocol (v3). Queries use the extended protocol with
+// every parameter and result in text format, so values are converted by ToSql and FromSql.
+// Plain TCP only, the database is expected to be on a private network
+pub struct Connection {
+    stream: BufStream<TcpStream>,
+    // Set when the protocol state is unknown after an error, so the pool throws it away
+    broken: bool,
+    // Set from sending a query until its ReadyForQuery is read. Still set when the connection is
+    // dropped means the query was cancelled, e.g. by a timeout, with its responses unread
+    in_flight: bool,
+}
+
+// A message from the server: its type byte and body
+struct Message {
+    kind: u8,
+    body: Vec<u8>,
+}
+
+impl Connection {
+    pub async fn connect(config: &DbConfig) -> Result<Self, DbError> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        stream.set_nodelay(true)?;
+        let mut connection = Self {
+            stream: BufStream::new(stream),
+            broken: false,
+            in_flight: false,
+        };
+        connection.startup(config).await?;
+        Ok(connection)
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken || self.in_flight
+    }
+
+    async fn startup(&mut self, config: &DbConfig) -> Result<(), DbError> {
+        let mut body = Vec::new();
+        // Protocol version 3.0
+        body.extend_from_slice(&196608i32.to_be_bytes());
+        for (name, value) in [
+            ("user", config.user.as_str()),
+            ("database", config.database.as_str()),
+            ("client_encoding", "UTF8"),
+            // So timestamps come back in a known format
+            ("TimeZone", "UTC"),
+        ] {
+            put_cstr(&mut body, name);
+            put_cstr(&mut body, value);
+        }
+        body.push(0);
+        let mut message = ((body.len() + 4) as i32).to_be_bytes().to_vec();
+        message.extend_from_slice(&body);
+        self.stream.write_all(&message).await?;
+        self.stream.flush().await?;
+
+        let mut scram = None;
+        loop {
+            let message = self.receive().await?;
+            match message.kind {
+                b'R' => {
+                    let mut reader = Reader::new(&message.body);
+                    match reader.i32()? {
+                        0 => {}
+                        // Cleartext
+                        3 => {
+                            let mut body = Vec::new();
+                            put_cstr(&mut body, &config.password);
+                            self.send(b'p', &body).await?;
+                        }
+                        10 => {
+                            let mechanisms = reader.rest();
+                            if !mechanisms
+                                .split(|byte| *byte == 0)
+                                .any(|mechanism| mechanism == b"SCRAM-SHA-256")
+                            {
+                                return Err(DbError::protocol(
+                                    "server doesn't offer SCRAM-SHA-256",
+                                ));
+                            }
+                            let client = Scram::new("", &config.password);
+                            let first = client.client_first();
+                            let mut body = Vec::new();
+                            put_cstr(&mut body, "SCRAM-SHA-256");
+                            body.extend_from_slice(&(first.len() as i32).to_be_bytes());
+                            body.extend_from_slice(first.as_bytes());
+                            self.send(b'p', &body).await?;
+                            scram = Some(client);
+                        }
+                        11 => {
+                            let client = scram
+                                .as_mut()
+                                .ok_or(DbError::protocol("SCRAM continue before start"))?;
+                            let server_first = String::from_utf8_lossy(reader.rest());
+                            let client_final = client
+                                .client_final(&server_first)
+                                .map_err(DbError::protocol)?;
+                            self.send(b'p', client_final.as_bytes()).await?;
+                        }
+                        12 => {
+                            let client = scram
+                                .as_ref()
+                                .ok_or(DbError::protocol("SCRAM final before start"))?;
+                            client
+                                .verify_server_final(&String::from_utf8_lossy(reader.rest()))
+                                .map_err(DbError::protocol)?;
+                        }
+                        code => {
+                            return Err(DbError::protocol(format!(
+                                "unsupported authentication method {}, use scram-sha-256",
+                                code
+                            )))
+                        }
+                    }
+                }
+                b'E' => return Err(error_response(&message.body)),
+                b'Z' => return Ok(()),
+                // Parameter status, backend key data and notices
+                _ => {}
+            }
+        }
+    }
+
+    // Runs one or more statements without parameters, e.g. a migration
+    pub async fn batch_execute(&mut self, sql: &str) -> Result<(), DbError> {
+        let mut body = Vec::new();
+        put_cstr(&mut body, sql);
+        self.in_flight = true;
+        self.send(b'Q', &body).await?;
+        self.finish(|_, _| Ok(())).await.map(|_| ())
+    }
+
+    // Returns the number of rows affected
+    pub async fn execute(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<u64, DbError> {
+        self.extended(sql, params).await?;
+        let mut affected = 0;
+        self.finish(|message, _| {
+            if message.kind == b'C' {
+                // e.g. "INSERT 0 1" or "UPDATE 3"
+                let tag = Reader::new(&message.body).cstr()?;
+                affected = tag
+                    .rsplit(' ')
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0);
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(affected)
+    }
+
+    pub async fn query(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<Row>, DbError> {
+        self.extended(sql, params).await?;
+        let mut rows = Vec::new();
+        self.finish(|message, columns| {
+            if message.kind == b'D' {
+                rows.push(data_row(&message.body, columns)?);
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(rows)
+    }
+
+    pub async fn query_opt(
+        &mut self,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Option<Row>, DbError> {
+        Ok(self.query(sql, params).await?.into_iter().next())
+    }
+
+    pub async fn query_one(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<Row, DbError> {
+        self.query_opt(sql, params)
+            .await?
+            .ok_or(DbError::protocol("query returned no rows"))
+    }
+
+    // Parse, bind, describe, execute and sync, sent together so a query is one round trip
+    async fn extended(&mut self, sql: &str, params: &[&dyn ToSql]) -> Result<(), DbError> {
+        let mut parse = Vec::new();
+        put_cstr(&mut parse, "");
+        put_cstr(&mut parse, sql);
+        parse.extend_from_slice(&0i16.to_be_bytes());
+
+        let mut bind = Vec::new();
+        put_cstr(&mut bind, "");
+        put_cstr(&mut bind, "");
+        bind.extend_from_slice(&0i16.to_be_bytes());
+        bind.extend_from_slice(&(params.len() as i16).to_be_bytes());
+        for param in params {
+            match param.to_sql() {
+                Some(value) => {
+                    bind.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                    bind.extend_from_slice(value.as_bytes());
+                }
+                None => bind.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+        }
+        bind.extend_from_slice(&0i16.to_be_bytes());
+
+        let mut describe = vec![b'P'];
+        put_cstr(&mut describe, "");
+        let mut execute = Vec::new();
+        put_cstr(&mut execute, "");
+        execute.extend_from_slice(&0i32.to_be_bytes());
+
+        self.in_flight = true;
+        for (kind, body) in [
+            (b'P', parse.as_slice()),
+            (b'B', &bind),
+            (b'D', &describe),
+            (b'E', &execute),
+            (b'S', &[]),
+        ] {
+            self.write(kind, body).await?;
+        }
+        self.flush().await
+    }
+
+    // Reads messages until the server is ready for the next query, passing each to `handle` along
+    // with the result's column names. The first error is returned once the server has recovered
+    async fn finish(
+        &mut self,
+        mut handle: impl FnMut(&Message, &[String]) -> Result<(), DbError>,
+    ) -> Result<(), DbError> {
+        let mut columns = Vec::new();
+        let mut error = None;
+        loop {
+            let message = self.receive().await?;
+            match message.kind {
+                b'Z' => {
+                    self.in_flight = false;
+                    return error.map_or(Ok(()), Err);
+                }
+                b'E' => {
+                    error.get_or_insert(error_response(&message.body));
+                }
+                b'T' => columns = row_description(&message.body)?,
+                _ if error.is_none() => {
+                    if let Err(e) = handle(&message, &columns) {
+                        error = Some(e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, kind: u8, body: &[u8]) -> Result<(), DbError> {
+        self.write(kind, body).await?;
+        self.flush().await
+    }
+
+    async fn write(&mut self, kind: u8, body: &[u8]) -> Result<(), DbError> {
+        let result = async {
+            self.stream.write_u8(kind).await?;
+            self.stream.write_i32(body.len() as i32 + 4).await?;
+            self.stream.write_all(body).await
+        }
+        .await;
+        self.broken |= result.is_err();
+        Ok(result?)
+    }
+
+    async fn flush(&mut self) -> Result<(), DbError> {
+        let result = self.stream.flush().await;
+        self.broken |= result.is_err();
+        Ok(result?)
+    }
+
+    async fn receive(&mut self) -> Result<Message, DbError> {
+        let result = async {
+            let kind = self.stream.read_u8().await?;
+            let length = self.stream.read_i32().await?;
+            let mut body = vec![0; (length.max(4) - 4) as usize];
+            self.stream.read_exact(&mut body).await?;
+            Ok::<_, std::io::Error>(Message { kind, body })
+        }
+        .await;
+        self.broken |= result.is_err();
+        Ok(result?)
+    }
+}
+
+fn put_cstr(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(0);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DbError> {
+        if self.bytes.len() < count {
+            return Err(DbError::protocol("message is shorter than expected"));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> Result<i16, DbError> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, DbError> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn cstr(&mut self) -> Result<String, DbError> {
+        let end = self
+            .bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(DbError::protocol("string is not terminated"))?;
+        let value = String::from_utf8_lossy(&self.bytes[..end]).into_owned();
+        self.bytes = &self.bytes[end + 1..];
+        Ok(value)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+fn row_description(body: &[u8]) -> Result<Vec<String>, DbError> {
+    let mut reader = Reader::new(body);
+    let count = reader.i16()?;
+    (0..count)
+        .map(|_| {
+            let name = reader.cstr()?;
+            // Table, column number, type, size, modifier and format
+            reader.take(18)?;
+            Ok(name)
+        })
+        .collect()
+}
+
+fn data_row(body: &[u8], columns: &[String]) -> Result<Row, DbError> {
+    let mut reader = Reader::new(body);
+    let count = reader.i16()?;
+    let mut values = HashMap::new();
+    for i in 0..count as usize {
+        let length = reader.i32()?;
+        let value = match length {
+            -1 => None,
+            length => Some(String::from_utf8_lossy(reader.take(length as usize)?).into_owned()),
+        };
+        let column = columns
+            .get(i)
+            .ok_or(DbError::protocol("row has more values than columns"))?;
+        values.insert(column.clone(), value);
+    }
+    Ok(Row { values })
+}
+
+// Fields are a type byte and a string, e.g. 'M' for the message and 'C' for the SQLSTATE code
+fn error_response(body: &[u8]) -> DbError {
+    let mut reader = Reader::new(body);
+    let mut message = String::new();
+    let mut code = None;
+    while let Ok([kind]) = reader.take(1).map(|kind| [kind[0]]) {
+        if kind == 0 {
+            break;
+        }
+        let Ok(value) = reader.cstr() else {
+            break;
+        };
+        match kind {
+            b'M' => message = value,
+            b'C' => code = Some(value),
+            _ => {}
+        }
+    }
+    DbError::Postgres { message, code }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rows_and_errors() {
+        let mut description = 2i16.to_be_bytes().to_vec();
+        for name in ["id", "title"] {
+            put_cstr(&mut description, name);
+            description.extend_from_slice(&[0; 18]);
+        }
+        let columns = row_description(&description).unwrap();
+
+        let mut data = 2i16.to_be_bytes().to_vec();
+        data.extend_from_slice(&1i32.to_be_bytes());
+        data.push(b'7');
+        data.extend_from_slice(&(-1i32).to_be_bytes());
+        let row = data_row(&data, &columns).unwrap();
+        assert_eq!(row.get::<i64>("id").unwrap(), 7);
+        assert_eq!(row.get::<Option<String>>("title").unwrap(), None);
+
+        let mut error = vec![b'S'];
+        put_cstr(&mut error, "ERROR");
+        error.push(b'C');
+        put_cstr(&mut error, "23505");
+        error.push(b'M');
+        put_cstr(&mut error, "duplicate key");
+        error.push(0);
+        let error = error_response(&error);
+        assert!(error.is_unique_violation());
+        assert_eq!(error.to_string(), "duplicate key");
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use super::{DbError, Project, Repository};
+use crate::reload::changed_entries;
+
+// The project list as last read from the database, so the public list doesn't query it on every
+// page view. Changes through the API clear it, and the admin reload endpoint re-reads it after
+// the database was edited some other way
+#[derive(Default)]
+pub struct ProjectCache {
+    cached: RwLock<Cached>,
+}
+
+#[derive(Default)]
+struct Cached {
+    projects: Option<Vec<Project>>,
+    // Bumped by every clear and reload. A list read from the database is only kept if it hasn't
+    // changed since the read started, otherwise a change made meanwhile would be overwritten
+    generation: u64,
+}
+
+impl ProjectCache {
+    pub async fn list(&self, repository: &dyn Repository) -> Result<Vec<Project>, DbError> {
+        let generation = {
+            let cached = self.cached.read().unwrap();
+            if let Some(projects) = cached.projects.as_ref() {
+                return Ok(projects.clone());
+            }
+            cached.generation
+        };
+        let projects = repository.list_projects().await?;
+        let mut cached = self.cached.write().unwrap();
+        if cached.generation == generation {
+            cached.projects = Some(projects.clone());
+        }
+        Ok(projects)
+    }
+
+    pub fn clear(&self) {
+        let mut cached = self.cached.write().unwrap();
+        cached.projects = None;
+        cached.generation += 1;
+    }
+
+    // Titles of the projects added, removed or changed since the list was cached
+    pub async fn reload(&self, repository: &dyn Repository) -> Result<Vec<String>, DbError> {
+        let generation = self.cached.read().unwrap().generation;
+        let projects = repository.list_projects().await?;
+        let by_id = |projects: &[Project]| -> BTreeMap<i64, Project> {
+            projects.iter().map(|p| (p.id, p.clone())).collect()
+        };
+        let mut cached = self.cached.write().unwrap();
+        let old = by_id(cached.projects.as_deref().unwrap_or_default());
+        let new = by_id(&projects);
+        let changed = changed_entries(&old, &new)
+            .into_iter()
+            .filter_map(|id| new.get(&id).or(old.get(&id)))
+            .map(|project| project.title.clone())
+            .collect();
+        // Cleared meanwhile, so leave it to the next list to read the newer state
+        if cached.generation == generation {
+            cached.projects = Some(projects);
+        }
+        cached.generation += 1;
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::db::{BlogPost, DbFuture, NewBlogPost, NewSubmission, ProjectInput, ResumeStats};
+
+    // Lists its projects as they were when the read started, finishing once `gate` is released
+    #[derive(Default)]
+    struct SlowRepository {
+        projects: Mutex<Vec<Project>>,
+        gate: Mutex<Option<oneshot::Receiver<()>>>,
+    }
+
+    impl Repository for SlowRepository {
+        fn list_projects(&self) -> DbFuture<'_, Vec<Project>> {
+            let projects = self.projects.lock().unwrap().clone();
+            let gate = self.gate.lock().unwrap().take();
+            Box::pin(async move {
+                if let Some(gate) = gate {
+                    let _ = gate.await;
+                }
+                Ok(projects)
+            })
+        }
+
+        fn ping(&self) -> DbFuture<'_, ()> {
+            unimplemented!()
+        }
+        fn get_project(&self, _: i64) -> DbFuture<'_, Option<Project>> {
+            unimplemented!()
+        }
+        fn create_project<'a>(&'a self, _: &'a ProjectInput) -> DbFuture<'a, Project> {
+            unimplemented!()
+        }
+        fn update_project<'a>(
+            &'a self,
+            _: i64,
+            _: &'a ProjectInput,
+        ) -> DbFuture<'a, Option<Project>> {
+            unimplemented!()
+        }
+        fn delete_project(&self, _: i64) -> DbFuture<'_, bool> {
+            unimplemented!()
+        }
+        fn list_posts(&self, _: bool) -> DbFuture<'_, Vec<BlogPost>> {
+            unimplemented!()
+        }
+        fn get_post<'a>(&'a self, _: &'a str) -> DbFuture<'a, Option<BlogPost>> {
+            unimplemented!()
+        }
+        fn create_post<'a>(&'a self, _: &'a NewBlogPost) -> DbFuture<'a, BlogPost> {
+            unimplemented!()
+        }
+        fn save_submission<'a>(&'a self, _: &'a NewSubmission) -> DbFuture<'a, i64> {
+            unimplemented!()
+        }
+        fn upsert_admin<'a>(&'a self, _: &'a str, _: &'a str) -> DbFuture<'a, bool> {
+            unimplemented!()
+        }
+        fn admin_password_hash<'a>(&'a self, _: &'a str) -> DbFuture<'a, Option<String>> {
+            unimplemented!()
+        }
+        fn record_resume_download<'a>(&'a self, _: Option<&'a str>) -> DbFuture<'a, ()> {
+            unimplemented!()
+        }
+        fn resume_stats(&self, _: i64) -> DbFuture<'_, ResumeStats> {
+            unimplemented!()
+        }
+    }
+
+    fn project(id: i64, title: &str) -> Project {
+        Project {
+            id,
+            title: title.to_string(),
+            description: String::new(),
+            tags: Vec::new(),
+            links: Vec::new(),
+            position: id,
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    fn titles(projects: &[Project]) -> Vec<&str> {
+        projects.iter().map(|p| p.title.as_str()).collect()
+    }
+
+    // `created` is created while the list is being read, clearing the cache mid read
+    async fn create_during_read<T>(
+        repository: &SlowRepository,
+        cache: &ProjectCache,
+        created: Project,
+        read: impl std::future::Future<Output = T>,
+    ) -> T {
+        let (release, gate) = oneshot::channel();
+        *repository.gate.lock().unwrap() = Some(gate);
+        let (result, ()) = tokio::join!(read, async {
+            repository.projects.lock().unwrap().push(created);
+            cache.clear();
+            release.send(()).unwrap();
+        });
+        result
+    }
+
+    #[tokio::test]
+    async fn keeps_changes_made_during_a_read() {
+        let repository = SlowRepository::default();
+        let created = project(1, "kblue.io");
+        repository.projects.lock().unwrap().push(created);
+        let cache = ProjectCache::default();
+
+        let created = project(2, "kblue_http");
+        let read = create_during_read(&repository, &cache, created, cache.list(&repository)).await;
+        // That caller saw the list from before, but it isn't cached for everyone else
+        assert_eq!(titles(&read.unwrap()), ["kblue.io"]);
+        let listed = cache.list(&repository).await.unwrap();
+        assert_eq!(titles(&listed), ["kblue.io", "kblue_http"]);
+
+        let created = project(3, "notify");
+        let reload = cache.reload(&repository);
+        create_during_read(&repository, &cache, created, reload)
+            .await
+            .unwrap();
+        let listed = cache.list(&repository).await.unwrap();
+        assert_eq!(titles(&listed), ["kblue.io", "kblue_http", "notify"]);
+    }
+}
@@ -0,0 +1,424 @@
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::Pin;
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::pool::Pool;
+use super::{DbError, Row};
+
+pub type DbFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, DbError>> + Send + 'a>>;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Project {
+    pub id: i64,
+    pub title: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub links: Vec<ProjectLink>,
+    pub position: i64,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectLink {
+    // e.g. "Source" or "Live demo"
+    pub label: String,
+    pub url: String,
+}
+
+// Creates a project, or replaces all of one's fields
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectInput {
+    pub title: String,
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub links: Vec<ProjectLink>,
+    #[serde(default)]
+    pub position: i64,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BlogPost {
+    pub id: i64,
+    pub slug: String,
+    pub title: String,
+    pub body: String,
+    // None while a draft
+    pub published_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NewBlogPost {
+    pub slug: String,
+    pub title: String,
+    pub body: String,
+    #[serde(default)]
+    pub published: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ResumeStats {
+    pub total: i64,
+    pub last_30_days: i64,
+    pub last_downloaded_at: Option<String>,
+    // Most common first
+    pub referrers: Vec<ReferrerCount>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ReferrerCount {
+    // None for downloads without a referrer
+    pub referrer: Option<String>,
+    pub count: i64,
+}
+
+pub struct NewSubmission {
+    pub name: String,
+    pub email: String,
+    pub message: String,
+    pub labels: Vec<String>,
+}
+
+// Everything stored about the portfolio. PgRepository is the real one, tests can swap in their own
+pub trait Repository: Send + Sync {
+    fn ping(&self) -> DbFuture<'_, ()>;
+
+    // By position
+    fn list_projects(&self) -> DbFuture<'_, Vec<Project>>;
+    fn get_project(&self, id: i64) -> DbFuture<'_, Option<Project>>;
+    fn create_project<'a>(&'a self, project: &'a ProjectInput) -> DbFuture<'a, Project>;
+    // None if there's no project with the id
+    fn update_project<'a>(
+        &'a self,
+        id: i64,
+        project: &'a ProjectInput,
+    ) -> DbFuture<'a, Option<Project>>;
+    // Whether there was a project with the id
+    fn delete_project(&self, id: i64) -> DbFuture<'_, bool>;
+
+    // Newest first, drafts only if `include_drafts`
+    fn list_posts(&self, include_drafts: bool) -> DbFuture<'_, Vec<BlogPost>>;
+    fn get_post<'a>(&'a self, slug: &'a str) -> DbFuture<'a, Option<BlogPost>>;
+    fn create_post<'a>(&'a self, post: &'a NewBlogPost) -> DbFuture<'a, BlogPost>;
+
+    // Returns the new submission's id
+    fn save_submission<'a>(&'a self, submission: &'a NewSubmission) -> DbFuture<'a, i64>;
+
+    // Creates the admin, or replaces their password. Returns whether they were created
+    fn upsert_admin<'a>(&'a self, email: &'a str, password_hash: &'a str) -> DbFuture<'a, bool>;
+    fn admin_password_hash<'a>(&'a self, email: &'a str) -> DbFuture<'a, Option<String>>;
+
+    fn record_resume_download<'a>(&'a self, referrer: Option<&'a str>) -> DbFuture<'a, ()>;
+    // With the `max_referrers` most common referrers
+    fn resume_stats(&self, max_referrers: i64) -> DbFuture<'_, ResumeStats>;
+}
+
+pub struct PgRepository {
+    pool: Pool,
+}
+
+impl PgRepository {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+const PROJECT_COLUMNS: &str = "id, title, description, tags::text AS tags, links::text AS links, \
+    position, created_at, updated_at";
+const POST_COLUMNS: &str = "id, slug, title, body, published_at, created_at";
+
+fn timestamp(row: &Row, column: &str) -> Result<Option<String>, DbError> {
+    Ok(row
+        .get::<Option<DateTime<Utc>>>(column)?
+        .map(|time| time.to_rfc3339()))
+}
+
+// A JSONB column, selected as text
+fn json<T: DeserializeOwned>(row: &Row, column: &str) -> Result<T, DbError> {
+    serde_json::from_str(&row.get::<String>(column)?)
+        .map_err(|e| DbError::Decode(format!("column {} ({})", column, e)))
+}
+
+fn to_json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).expect("strings always serialise")
+}
+
+impl Project {
+    fn from_row(row: &Row) -> Result<Self, DbError> {
+        Ok(Self {
+            id: row.get("id")?,
+            title: row.get("title")?,
+            description: row.get("description")?,
+            tags: json(row, "tags")?,
+            links: json(row, "links")?,
+            position: row.get("position")?,
+            created_at: timestamp(row, "created_at")?.unwrap_or_default(),
+            updated_at: timestamp(row, "updated_at")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl BlogPost {
+    fn from_row(row: &Row) -> Result<Self, DbError> {
+        Ok(Self {
+            id: row.get("id")?,
+            slug: row.get("slug")?,
+            title: row.get("title")?,
+            body: row.get("body")?,
+            published_at: timestamp(row, "published_at")?,
+            created_at: timestamp(row, "created_at")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl Repository for PgRepository {
+    fn ping(&self) -> DbFuture<'_, ()> {
+        Box::pin(async move { self.pool.get().await?.batch_execute("SELECT 1").await })
+    }
+
+    fn list_projects(&self) -> DbFuture<'_, Vec<Project>> {
+        Box::pin(async move {
+            let sql = format!(
+                "SELECT {} FROM projects ORDER BY position, id",
+                PROJECT_COLUMNS
+            );
+            let rows = self.pool.get().await?.query(&sql, &[]).await?;
+            rows.iter().map(Project::from_row).collect()
+        })
+    }
+
+    fn get_project(&self, id: i64) -> DbFuture<'_, Option<Project>> {
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM projects WHERE id = $1", PROJECT_COLUMNS);
+            let row = self.pool.get().await?.query_opt(&sql, &[&id]).await?;
+            row.as_ref().map(Project::from_row).transpose()
+        })
+    }
+
+    fn create_project<'a>(&'a self, project: &'a ProjectInput) -> DbFuture<'a, Project> {
+        Box::pin(async move {
+            let sql = format!(
+                "INSERT INTO projects (title, description, tags, links, position)
+                 VALUES ($1, $2, $3::jsonb, $4::jsonb, $5) RETURNING {}",
+                PROJECT_COLUMNS
+            );
+            let row = self
+                .pool
+                .get()
+                .await?
+                .query_one(
+                    &sql,
+                    &[
+                        &project.title,
+                        &project.description,
+                        &to_json(&project.tags),
+                        &to_json(&project.links),
+                        &project.position,
+                    ],
+                )
+                .await?;
+            Project::from_row(&row)
+        })
+    }
+
+    fn update_project<'a>(
+        &'a self,
+        id: i64,
+        project: &'a ProjectInput,
+    ) -> DbFuture<'a, Option<Project>> {
+        Box::pin(async move {
+            let sql = format!(
+                "UPDATE projects SET title = $2, description = $3, tags = $4::jsonb,
+                 links = $5::jsonb, position = $6, updated_at = now() WHERE id = $1 RETURNING {}",
+                PROJECT_COLUMNS
+            );
+            let row = self
+                .pool
+                .get()
+                .await?
+                .query_opt(
+                    &sql,
+                    &[
+                        &id,
+                        &project.title,
+                        &project.description,
+                        &to_json(&project.tags),
+                        &to_json(&project.links),
+                        &project.position,
+                    ],
+                )
+                .await?;
+            row.as_ref().map(Project::from_row).transpose()
+        })
+    }
+
+    fn delete_project(&self, id: i64) -> DbFuture<'_, bool> {
+        Box::pin(async move {
+            let deleted = self
+                .pool
+                .get()
+                .await?
+                .execute("DELETE FROM projects WHERE id = $1", &[&id])
+                .await?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn list_posts(&self, include_drafts: bool) -> DbFuture<'_, Vec<BlogPost>> {
+        Box::pin(async move {
+            let sql = format!(
+                "SELECT {} FROM blog_posts WHERE $1 OR published_at IS NOT NULL
+                 ORDER BY COALESCE(published_at, created_at) DESC",
+                POST_COLUMNS
+            );
+            let rows = self
+                .pool
+                .get()
+                .await?
+                .query(&sql, &[&include_drafts])
+                .await?;
+            rows.iter().map(BlogPost::from_row).collect()
+        })
+    }
+
+    fn get_post<'a>(&'a self, slug: &'a str) -> DbFuture<'a, Option<BlogPost>> {
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM blog_posts WHERE slug = $1", POST_COLUMNS);
+            let row = self.pool.get().await?.query_opt(&sql, &[&slug]).await?;
+            row.as_ref().map(BlogPost::from_row).transpose()
+        })
+    }
+
+    fn create_post<'a>(&'a self, post: &'a NewBlogPost) -> DbFuture<'a, BlogPost> {
+        Box::pin(async move {
+            let sql = format!(
+                "INSERT INTO blog_posts (slug, title, body, published_at)
+                 VALUES ($1, $2, $3, CASE WHEN $4::boolean THEN now() END) RETURNING {}",
+                POST_COLUMNS
+            );
+            let row = self
+                .pool
+                .get()
+                .await?
+                .query_one(
+                    &sql,
+                    &[&post.slug, &post.title, &post.body, &post.published],
+                )
+                .await?;
+            BlogPost::from_row(&row)
+        })
+    }
+
+    fn save_submission<'a>(&'a self, submission: &'a NewSubmission) -> DbFuture<'a, i64> {
+        Box::pin(async move {
+            self.pool
+                .get()
+                .await?
+                .query_one(
+                    "INSERT INTO contact_submissions (name, email, message, labels)
+                     VALUES ($1, $2, $3, $4::jsonb) RETURNING id",
+                    &[
+                        &submission.name,
+                        &submission.email,
+                        &submission.message,
+                        &to_json(&submission.labels),
+                    ],
+                )
+                .await?
+                .get("id")
+        })
+    }
+
+    fn upsert_admin<'a>(&'a self, email: &'a str, password_hash: &'a str) -> DbFuture<'a, bool> {
+        Box::pin(async move {
+            // xmax is only 0 for a freshly inserted row
+            self.pool
+                .get()
+                .await?
+                .query_one(
+                    "INSERT INTO admins (email, password_hash) VALUES (lower($1), $2)
+                     ON CONFLICT (email)
+                     DO UPDATE SET password_hash = excluded.password_hash, updated_at = now()
+                     RETURNING (xmax = 0) AS created",
+                    &[&email, &password_hash],
+                )
+                .await?
+                .get("created")
+        })
+    }
+
+    fn admin_password_hash<'a>(&'a self, email: &'a str) -> DbFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let row = self
+                .pool
+                .get()
+                .await?
+                .query_opt(
+                    "SELECT password_hash FROM admins WHERE email = lower($1)",
+                    &[&email],
+                )
+                .await?;
+            row.map(|row| row.get("password_hash")).transpose()
+        })
+    }
+
+    fn record_resume_download<'a>(&'a self, referrer: Option<&'a str>) -> DbFuture<'a, ()> {
+        Box::pin(async move {
+            self.pool
+                .get()
+                .await?
+                .execute(
+                    "INSERT INTO resume_downloads (referrer) VALUES ($1)",
+                    &[&referrer],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn resume_stats(&self, max_referrers: i64) -> DbFuture<'_, ResumeStats> {
+        Box::pin(async move {
+            let mut connection = self.pool.get().await?;
+            let totals = connection
+                .query_one(
+                    "SELECT COUNT(*) AS total,
+                        COUNT(*) FILTER (WHERE downloaded_at > now() - interval '30 days') AS recent,
+                        MAX(downloaded_at) AS last_downloaded_at
+                     FROM resume_downloads",
+                    &[],
+                )
+                .await?;
+            let referrers = connection
+                .query(
+                    "SELECT referrer, COUNT(*) AS count FROM resume_downloads
+                     GROUP BY referrer ORDER BY count DESC, referrer LIMIT $1",
+                    &[&max_referrers],
+                )
+                .await?
+                .iter()
+                .map(|row| {
+                    Ok(ReferrerCount {
+                        referrer: row.get("referrer")?,
+                        count: row.get("count")?,
+                    })
+                })
+                .collect::<Result<_, DbError>>()?;
+            Ok(ResumeStats {
+                total: totals.get("total")?,
+                last_30_days: totals.get("recent")?,
+                last_downloaded_at: timestamp(&totals, "last_downloaded_at")?,
+                referrers,
+            })
+        })
+    }
+}
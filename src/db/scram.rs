@@ -0,0 +1,135 @@
+use std::num::NonZeroU32;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+
+// SCRAM-SHA-256 (RFC 7677), Postgres' default password authentication. Without channel binding,
+// as we don't use TLS to the database
+pub struct Scram {
+    password: String,
+    client_first_bare: String,
+    nonce: String,
+    // Set once the server's first message has been handled
+    server_signature: Option<Vec<u8>>,
+}
+
+impl Scram {
+    // Postgres ignores the username here in favour of the startup message's, so it can be empty
+    pub fn new(username: &str, password: &str) -> Self {
+        let mut bytes = [0u8; 18];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .expect("the OS random number generator failed");
+        Self::with_nonce(username, password, &BASE64_STANDARD.encode(bytes))
+    }
+
+    fn with_nonce(username: &str, password: &str, nonce: &str) -> Self {
+        Self {
+            password: password.to_string(),
+            client_first_bare: format!("n={},r={}", username, nonce),
+            nonce: nonce.to_string(),
+            server_signature: None,
+        }
+    }
+
+    pub fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    pub fn client_final(&mut self, server_first: &str) -> Result<String, String> {
+        let attribute = |name: &str| {
+            server_first
+                .split(',')
+                .find_map(|part| part.strip_prefix(name))
+                .ok_or(format!("SCRAM server message has no {}", name))
+        };
+        let nonce = attribute("r=")?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err("SCRAM server nonce doesn't extend ours".to_string());
+        }
+        let salt = BASE64_STANDARD
+            .decode(attribute("s=")?)
+            .map_err(|_| "SCRAM salt is not base64")?;
+        let iterations = attribute("i=")?
+            .parse::<NonZeroU32>()
+            .map_err(|_| "SCRAM iteration count is invalid")?;
+
+        let mut salted_password = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            self.password.as_bytes(),
+            &mut salted_password,
+        );
+        let salted_key = hmac::Key::new(hmac::HMAC_SHA256, &salted_password);
+        let client_key = hmac::sign(&salted_key, b"Client Key");
+        let stored_key = digest::digest(&digest::SHA256, client_key.as_ref());
+
+        // "biws" is "n,," in base64
+        let client_final_without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final_without_proof
+        );
+        let client_signature = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, stored_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        let proof: Vec<u8> = client_key
+            .as_ref()
+            .iter()
+            .zip(client_signature.as_ref())
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+
+        let server_key = hmac::sign(&salted_key, b"Server Key");
+        let server_signature = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, server_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        self.server_signature = Some(server_signature.as_ref().to_vec());
+        Ok(format!(
+            "{},p={}",
+            client_final_without_proof,
+            BASE64_STANDARD.encode(proof)
+        ))
+    }
+
+    // The server proves it knows the password too
+    pub fn verify_server_final(&self, server_final: &str) -> Result<(), String> {
+        let signature = server_final
+            .strip_prefix("v=")
+            .and_then(|signature| BASE64_STANDARD.decode(signature).ok());
+        match (signature, &self.server_signature) {
+            (Some(signature), Some(expected)) if signature == *expected => Ok(()),
+            _ => Err("SCRAM server signature is invalid".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From RFC 7677
+    #[test]
+    fn matches_the_rfc_example() {
+        let mut scram = Scram::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let client_final = scram
+            .client_final(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(scram
+            .verify_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_ok());
+        assert!(scram.verify_server_final("v=AAAA").is_err());
+    }
+}
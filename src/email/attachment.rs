@@ -0,0 +1,149 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use kblue_http::ONE_KB;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const MAX_ATTACHMENTS: usize = 3;
+// Requests are limited to 1MB, which base64 in a JSON body shrinks to ~750KB of files
+pub const MAX_ATTACHMENT_SIZE: usize = 512 * ONE_KB;
+
+// Content types which may be attached, with the bytes their content must start with
+const ALLOWED_TYPES: &[(&str, &[u8])] = &[
+    ("application/pdf", b"%PDF-"),
+    ("image/png", b"\x89PNG\r\n\x1a\n"),
+    ("image/jpeg", b"\xff\xd8\xff"),
+    ("image/gif", b"GIF8"),
+    ("text/plain", b""),
+];
+
+const MAX_FILENAME_LEN: usize = 100;
+
+// A file sent on an email. `data` is base64 when serialised, which is also how JSON request
+// bodies carry it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn new(filename: &str, content_type: &str, data: Vec<u8>) -> Self {
+        let mut attachment = Self {
+            filename: filename.to_string(),
+            content_type: content_type.to_string(),
+            data,
+        };
+        attachment.sanitise();
+        attachment
+    }
+
+    // The filename ends up in a Content-Disposition header, so only its last path segment is
+    // kept, without control characters or quotes. Content type parameters are dropped
+    pub fn sanitise(&mut self) {
+        let filename: String = self
+            .filename
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_control() && !matches!(c, '"' | ';'))
+            .take(MAX_FILENAME_LEN)
+            .collect();
+        self.filename = match filename.trim() {
+            "" | "." | ".." => "attachment".to_string(),
+            filename => filename.to_string(),
+        };
+        self.content_type = self
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+    }
+
+    // Checks the type is allowed and the content really is that type, so e.g. an executable
+    // can't be sent as a PNG
+    pub fn validate(&self) -> Result<(), String> {
+        if self.data.is_empty() {
+            return Err("must not be empty".to_string());
+        }
+        if self.data.len() > MAX_ATTACHMENT_SIZE {
+            return Err(format!(
+                "must be at most {}KB",
+                MAX_ATTACHMENT_SIZE / ONE_KB
+            ));
+        }
+        let Some((_, signature)) = ALLOWED_TYPES
+            .iter()
+            .find(|(content_type, _)| *content_type == self.content_type)
+        else {
+            let allowed: Vec<&str> = ALLOWED_TYPES.iter().map(|(t, _)| *t).collect();
+            return Err(format!("must be one of {}", allowed.join(", ")));
+        };
+        let matches_type = match self.content_type.as_str() {
+            "text/plain" => std::str::from_utf8(&self.data).is_ok(),
+            _ => self.data.starts_with(signature),
+        };
+        if !matches_type {
+            return Err(format!("is not a valid {} file", self.content_type));
+        }
+        Ok(())
+    }
+}
+
+fn to_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64_STANDARD.encode(data))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64_STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| serde::de::Error::custom("must be base64"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitises_filenames_and_content_types() {
+        let attachment = Attachment::new(
+            "../../etc/\"pass\r\nwd\".pdf",
+            "Application/PDF; name=x",
+            b"%PDF-1.7".to_vec(),
+        );
+        assert_eq!(attachment.filename, "passwd.pdf");
+        assert_eq!(attachment.content_type, "application/pdf");
+        assert_eq!(
+            Attachment::new("dir/", "text/plain", vec![]).filename,
+            "attachment"
+        );
+    }
+
+    #[test]
+    fn validates_type_content_and_size() {
+        let validate = |content_type: &str, data: &[u8]| {
+            Attachment::new("file", content_type, data.to_vec()).validate()
+        };
+        assert!(validate("image/png", b"\x89PNG\r\n\x1a\n...").is_ok());
+        assert!(validate("text/plain", b"hello").is_ok());
+        assert!(validate("image/png", b"MZ\x90\x00").is_err());
+        assert!(validate("application/x-msdownload", b"MZ\x90\x00").is_err());
+        assert!(validate("text/plain", b"\xff\xfe").is_err());
+        assert!(validate("text/plain", &vec![b'a'; MAX_ATTACHMENT_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn data_is_base64_in_json() {
+        let attachment = Attachment::new("a.txt", "text/plain", b"hi".to_vec());
+        let json = serde_json::to_string(&attachment).unwrap();
+        assert!(json.contains(r#""data":"aGk=""#));
+        assert_eq!(
+            serde_json::from_str::<Attachment>(&json).unwrap(),
+            attachment
+        );
+    }
+}
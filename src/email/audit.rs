@@ -0,0 +1,218 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+// Every contact form submission and what happened to its emails. Each change appends the whole
+// record as a JSON line, and the last line for an id wins when the file is read back
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailStatus {
+    Queued,
+    Sent,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EmailRecord {
+    pub id: u64,
+    pub submitted_at: String,
+    pub name: String,
+    pub email: String,
+    pub message: String,
+    pub status: EmailStatus,
+    // The first error from the provider, e.g. an SMTP rejection
+    pub error: Option<String>,
+    // Emails for the submission not yet sent or given up on
+    pending: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct EmailQuery {
+    // Case insensitive substring of the name, email or message
+    pub search: Option<String>,
+    pub status: Option<EmailStatus>,
+    // From 1
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl EmailQuery {
+    fn matches(&self, record: &EmailRecord) -> bool {
+        if self.status.is_some_and(|status| status != record.status) {
+            return false;
+        }
+        let Some(search) = &self.search else {
+            return true;
+        };
+        let search = search.to_lowercase();
+        [&record.name, &record.email, &record.message]
+            .iter()
+            .any(|field| field.to_lowercase().contains(&search))
+    }
+}
+
+static RECORDS: Lazy<RwLock<Vec<EmailRecord>>> = Lazy::new(|| {
+    let records = match fs::read_to_string(log_path()) {
+        Ok(contents) => replay(&contents),
+        Err(_) => Vec::new(),
+    };
+    RwLock::new(records)
+});
+
+fn log_path() -> PathBuf {
+    env::var("EMAIL_LOG_PATH")
+        .unwrap_or("data/email_log.jsonl".to_string())
+        .into()
+}
+
+fn replay(contents: &str) -> Vec<EmailRecord> {
+    let mut records: Vec<EmailRecord> = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let record: EmailRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Skipping unparseable line in the email log: {}", e);
+                continue;
+            }
+        };
+        match records.iter_mut().find(|existing| existing.id == record.id) {
+            Some(existing) => *existing = record,
+            None => records.push(record),
+        }
+    }
+    records
+}
+
+fn append(record: &EmailRecord) {
+    let path = log_path();
+    let result = (|| -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let mut line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| e.to_string())
+    })();
+    // The emails still go out, losing their history isn't worth failing the submission over
+    if let Err(e) = result {
+        error!("Could not write to the email log {}: {}", path.display(), e);
+    }
+}
+
+// Returns the id to put on the submission's emails, so their outcomes are recorded against it
+pub fn record_submission(name: &str, email: &str, message: &str, emails: u32) -> u64 {
+    let mut records = RECORDS.write().unwrap();
+    let record = EmailRecord {
+        id: records.iter().map(|record| record.id).max().unwrap_or(0) + 1,
+        submitted_at: Utc::now().to_rfc3339(),
+        name: name.to_string(),
+        email: email.to_string(),
+        message: message.to_string(),
+        status: EmailStatus::Queued,
+        error: None,
+        pending: emails,
+    };
+    append(&record);
+    let id = record.id;
+    records.push(record);
+    id
+}
+
+// A submission is sent once all its emails are, and failed as soon as one is
+pub fn record_outcome(id: u64, result: Result<(), String>) {
+    let mut records = RECORDS.write().unwrap();
+    let Some(record) = records.iter_mut().find(|record| record.id == id) else {
+        return;
+    };
+    record.pending = record.pending.saturating_sub(1);
+    match result {
+        Err(e) => {
+            record.status = EmailStatus::Failed;
+            record.error.get_or_insert(e);
+        }
+        Ok(()) if record.pending == 0 && record.status == EmailStatus::Queued => {
+            record.status = EmailStatus::Sent;
+        }
+        Ok(()) => {}
+    }
+    append(record);
+}
+
+// Most recent first, with the number of matches across all pages
+pub fn search(query: &EmailQuery) -> (Vec<EmailRecord>, usize) {
+    let records = RECORDS.read().unwrap();
+    let matches: Vec<&EmailRecord> = records
+        .iter()
+        .rev()
+        .filter(|record| query.matches(record))
+        .collect();
+    let page = matches
+        .iter()
+        .skip(query.page.saturating_sub(1) * query.per_page)
+        .take(query.per_page)
+        .map(|record| (*record).clone())
+        .collect();
+    (page, matches.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, name: &str, status: EmailStatus) -> EmailRecord {
+        EmailRecord {
+            id,
+            submitted_at: String::new(),
+            name: name.to_string(),
+            email: "kyle@kblue.io".to_string(),
+            message: "Hello".to_string(),
+            status,
+            error: None,
+            pending: 0,
+        }
+    }
+
+    #[test]
+    fn replay_keeps_the_last_line_for_each_id() {
+        let lines: Vec<String> = [
+            record(1, "Kyle", EmailStatus::Queued),
+            record(2, "Bob", EmailStatus::Queued),
+            record(1, "Kyle", EmailStatus::Sent),
+        ]
+        .iter()
+        .map(|record| serde_json::to_string(record).unwrap())
+        .collect();
+        let contents = format!("{}\nnot json\n", lines.join("\n"));
+        let records = replay(&contents);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].status, EmailStatus::Sent);
+    }
+
+    #[test]
+    fn queries_filter_by_status_and_search() {
+        let query = EmailQuery {
+            search: Some("KYL".to_string()),
+            status: Some(EmailStatus::Failed),
+            ..Default::default()
+        };
+        assert!(query.matches(&record(1, "Kyle", EmailStatus::Failed)));
+        assert!(!query.matches(&record(1, "Kyle", EmailStatus::Sent)));
+        assert!(!query.matches(&EmailRecord {
+            email: "bob@kblue.io".to_string(),
+            ..record(1, "Bob", EmailStatus::Failed)
+        }));
+    }
+}
@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use super::dkim::DkimConfig;
+use super::mailgun::MailgunProvider;
+use super::sendgrid::SendGridProvider;
+use super::smtp::SmtpProvider;
+use super::EmailProvider;
+
+// Who emails are from and to, and how they're sent. The [email] section of Config, which lists
+// the environment variables that override it
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub sender: SenderConfig,
+    // Where contact form messages are delivered
+    pub recipient: String,
+    pub provider: ProviderConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SenderConfig {
+    // Every email is sent from this address
+    pub address: String,
+    // Shown on the confirmation sent to whoever used the contact form
+    #[serde(default = "default_name")]
+    pub name: String,
+    // Shown on the notification sent to the recipient
+    #[serde(default = "default_bot_name")]
+    pub bot_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ProviderConfig {
+    Smtp(SmtpConfig),
+    SendGrid {
+        api_key: String,
+    },
+    Mailgun {
+        api_key: String,
+        domain: String,
+        // https://api.eu.mailgun.net for domains in the EU region
+        #[serde(default = "default_mailgun_api_base")]
+        api_base: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    #[serde(default = "default_smtp_host")]
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: TlsMode,
+    // The sender address if unset
+    pub username: Option<String>,
+    pub password: String,
+    // Emails aren't signed if unset
+    pub dkim: Option<DkimConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    // Plain connection upgraded with STARTTLS, usually port 587
+    #[default]
+    StartTls,
+    // TLS from the start, usually port 465
+    Implicit,
+}
+
+fn default_name() -> String {
+    "Kyle Doidge".to_string()
+}
+
+fn default_bot_name() -> String {
+    "KBlue Bot".to_string()
+}
+
+fn default_mailgun_api_base() -> String {
+    "https://api.mailgun.net".to_string()
+}
+
+fn default_smtp_host() -> String {
+    "smtp.gmail.com".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+impl EmailConfig {
+    // Catches typos at startup rather than when the first message fails to send
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (field, address) in [
+            ("sender address", &self.sender.address),
+            ("recipient", &self.recipient),
+        ] {
+            if !is_address(address) {
+                problems.push(format!("{} is not an email address: {:?}", field, address));
+            }
+        }
+        for (field, name) in [
+            ("sender name", &self.sender.name),
+            ("bot name", &self.sender.bot_name),
+        ] {
+            // Names go in the From header
+            if name.trim().is_empty() || name.chars().any(|c| c.is_control() || c == '"') {
+                problems.push(format!("{} is empty or has invalid characters", field));
+            }
+        }
+        match &self.provider {
+            ProviderConfig::Smtp(smtp) => {
+                if smtp.host.trim().is_empty() {
+                    problems.push("SMTP host is empty".to_string());
+                }
+                if smtp.port == 0 {
+                    problems.push("SMTP port is 0".to_string());
+                }
+            }
+            ProviderConfig::Mailgun { domain, .. } if domain.trim().is_empty() => {
+                problems.push("Mailgun domain is empty".to_string());
+            }
+            _ => {}
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid email config: {}", problems.join(", ")))
+        }
+    }
+
+    // Fails if the DKIM key can't be loaded
+    pub fn provider(&self) -> Result<Arc<dyn EmailProvider>, String> {
+        let address = &self.sender.address;
+        Ok(match &self.provider {
+            ProviderConfig::Smtp(smtp) => Arc::new(SmtpProvider::new(address, smtp)?),
+            ProviderConfig::SendGrid { api_key } => {
+                Arc::new(SendGridProvider::new(address, api_key))
+            }
+            ProviderConfig::Mailgun {
+                api_key,
+                domain,
+                api_base,
+            } => Arc::new(MailgunProvider::new(address, api_key, domain, api_base)),
+        })
+    }
+}
+
+fn is_address(address: &str) -> bool {
+    match address.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !address
+                    .chars()
+                    .any(|c| c.is_whitespace() || "<>\",;".contains(c))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_config_file_with_defaults() {
+        let config: EmailConfig = serde_json::from_str(
+            r#"{
+                "sender": { "address": "bot@kblue.io" },
+                "recipient": "kyle@kblue.io",
+                "provider": { "type": "smtp", "password": "secret", "tls": "implicit", "port": 465 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.sender.name, "Kyle Doidge");
+        let ProviderConfig::Smtp(smtp) = &config.provider else {
+            panic!("expected SMTP");
+        };
+        assert_eq!(smtp.host, "smtp.gmail.com");
+        assert_eq!(smtp.tls, TlsMode::Implicit);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let mut config = EmailConfig {
+            sender: SenderConfig {
+                address: "bot@kblue.io".to_string(),
+                name: default_name(),
+                bot_name: "Bot\r\nBcc: x@y.z".to_string(),
+            },
+            recipient: "kyle.kblue.io".to_string(),
+            provider: ProviderConfig::SendGrid {
+                api_key: "key".to_string(),
+            },
+        };
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("recipient"), "{}", error);
+        assert!(error.contains("bot name"), "{}", error);
+
+        config.recipient = "kyle@kblue.io".to_string();
+        config.sender.bot_name = default_bot_name();
+        assert!(config.validate().is_ok());
+        assert!(serde_json::from_str::<EmailConfig>(r#"{"recipient": "x", "typo": 1}"#).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::digest::{digest, SHA256};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// Remembers recent submissions by a hash of their email and message, so a double click or a
+// resubmitted form doesn't send the same message twice
+pub struct Deduplicator {
+    window: Duration,
+    seen: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // CONTACT_DEDUPE_WINDOW in seconds, 0 turns deduplication off
+    pub fn from_env() -> Result<Self, String> {
+        let window = match env::var("CONTACT_DEDUPE_WINDOW") {
+            Ok(seconds) => Duration::from_secs(
+                seconds
+                    .parse()
+                    .map_err(|_| format!("CONTACT_DEDUPE_WINDOW is not a number: {}", seconds))?,
+            ),
+            Err(_) => DEFAULT_WINDOW,
+        };
+        Ok(Self::new(window))
+    }
+
+    // True if the same email and message were submitted within the window, otherwise remembers
+    // this submission
+    pub fn is_duplicate(&self, email: &str, message: &str) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+        let key = Self::key(email, message);
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, submitted_at| now.duration_since(*submitted_at) < self.window);
+        if seen.contains_key(&key) {
+            return true;
+        }
+        seen.insert(key, now);
+        false
+    }
+
+    // For submissions which never got sent, so resubmitting isn't rejected
+    pub fn forget(&self, email: &str, message: &str) {
+        self.seen.lock().unwrap().remove(&Self::key(email, message));
+    }
+
+    // Case and whitespace differences don't make a message different
+    fn key(email: &str, message: &str) -> Vec<u8> {
+        let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+        let normalised = format!(
+            "{}\n{}",
+            email.trim().to_lowercase(),
+            message.to_lowercase()
+        );
+        digest(&SHA256, normalised.as_bytes()).as_ref().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_repeats_within_the_window() {
+        let dedupe = Deduplicator::new(Duration::from_secs(60));
+        assert!(!dedupe.is_duplicate("kyle@kblue.io", "Hello there"));
+        assert!(dedupe.is_duplicate("Kyle@kblue.io ", "hello   there"));
+        assert!(!dedupe.is_duplicate("kyle@kblue.io", "Something else"));
+
+        dedupe.forget("kyle@kblue.io", "Hello there");
+        assert!(!dedupe.is_duplicate("kyle@kblue.io", "Hello there"));
+
+        let disabled = Deduplicator::new(Duration::ZERO);
+        assert!(!disabled.is_duplicate("kyle@kblue.io", "Hello there"));
+        assert!(!disabled.is_duplicate("kyle@kblue.io", "Hello there"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs;
+
+use mail_send::mail_auth::common::crypto::{RsaKey, Sha256};
+use mail_send::mail_auth::dkim::{DkimSigner, Done};
+use serde::Deserialize;
+
+pub type Signer = DkimSigner<RsaKey<Sha256>, Done>;
+
+// Headers covered by the signature, so none of them can be changed in transit
+const SIGNED_HEADERS: [&str; 7] = [
+    "From",
+    "To",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "MIME-Version",
+    "Content-Type",
+];
+
+// Signs emails sent over SMTP. The public key is published in DNS as a TXT record at
+// `<selector>._domainkey.<domain>`. SendGrid and Mailgun sign with their own keys
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DkimConfig {
+    pub selector: String,
+    // The sender address's domain if unset
+    pub domain: Option<String>,
+    // An RSA key as PKCS#1 or PKCS#8 PEM, either the path to it or the PEM itself
+    pub private_key_path: Option<String>,
+    pub private_key: Option<String>,
+}
+
+impl DkimConfig {
+    pub fn signer(&self, sender_address: &str) -> Result<Signer, String> {
+        let pem = match (&self.private_key, &self.private_key_path) {
+            (Some(pem), _) => pem.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|e| format!("Could not read DKIM key {} ({})", path, e))?,
+            (None, None) => return Err("DKIM needs a private_key or private_key_path".to_string()),
+        };
+        let key = if pem.contains("BEGIN RSA PRIVATE KEY") {
+            RsaKey::<Sha256>::from_rsa_pem(&pem)
+        } else {
+            RsaKey::<Sha256>::from_pkcs8_pem(&pem)
+        }
+        .map_err(|e| format!("Invalid DKIM key ({})", e))?;
+
+        let domain = match &self.domain {
+            Some(domain) => domain.clone(),
+            None => sender_address
+                .rsplit_once('@')
+                .map(|(_, domain)| domain.to_string())
+                .ok_or("DKIM domain is not set and the sender address has none")?,
+        };
+        Ok(DkimSigner::from_key(key)
+            .domain(domain)
+            .selector(&self.selector)
+            .headers(SIGNED_HEADERS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_send::mail_auth::common::headers::HeaderWriter;
+
+    use super::*;
+
+    #[test]
+    fn signs_with_the_configured_selector_and_domain() {
+        let config = DkimConfig {
+            selector: "mail".to_string(),
+            domain: None,
+            private_key_path: Some("src/auth/testdata/rs256_test_key.pem".to_string()),
+            private_key: None,
+        };
+        let signer = config.signer("bot@kblue.io").unwrap();
+        let signature = signer
+            .sign(b"From: bot@kblue.io\r\nTo: kyle@kblue.io\r\nSubject: Hi\r\n\r\nHi\r\n")
+            .unwrap()
+            .to_header();
+        assert!(signature.starts_with("DKIM-Signature:"), "{}", signature);
+        assert!(signature.contains("d=kblue.io"), "{}", signature);
+        assert!(signature.contains("s=mail"), "{}", signature);
+
+        let invalid = DkimConfig {
+            private_key_path: None,
+            private_key: Some("not a key".to_string()),
+            ..config
+        };
+        assert!(invalid.signer("bot@kblue.io").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use kblue_http::{client, HttpMethod};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::provider::{http_status_error, EmailProvider, SendError, SendFuture};
+use super::EmailJob;
+
+pub struct MailgunProvider {
+    address: String,
+    api_key: String,
+    // e.g. https://api.mailgun.net/v3/mg.kblue.io/messages
+    url: String,
+}
+
+impl MailgunProvider {
+    pub fn new(address: &str, api_key: &str, domain: &str, api_base: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            api_key: api_key.to_string(),
+            url: format!("{}/v3/{}/messages", api_base.trim_end_matches('/'), domain),
+        }
+    }
+
+    // multipart/form-data, which Mailgun needs for attachments
+    fn body(&self, email: &EmailJob, boundary: &str) -> Vec<u8> {
+        let from = format!("{} <{}>", email.from_name, self.address);
+        let fields = [
+            ("from", from.as_str()),
+            ("to", email.to.as_str()),
+            ("subject", email.subject.as_str()),
+            ("text", email.text_body.as_str()),
+            ("html", email.html_body.as_str()),
+        ];
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    boundary, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        for attachment in email.attachments.iter() {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"attachment\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                    boundary, attachment.filename, attachment.content_type
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&attachment.data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        body
+    }
+}
+
+// Random, so it can't appear in the message or an attachment
+fn new_boundary() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the OS random number generator failed");
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("kblue-{}", hex)
+}
+
+impl EmailProvider for MailgunProvider {
+    fn send<'a>(&'a self, email: &'a EmailJob) -> SendFuture<'a> {
+        Box::pin(async move {
+            // Basic auth with the username "api"
+            let authorization = format!(
+                "Basic {}",
+                BASE64_STANDARD.encode(format!("api:{}", self.api_key))
+            );
+            let boundary = new_boundary();
+            let content_type = format!("multipart/form-data; boundary={}", boundary);
+            let body = self.body(email, &boundary);
+            let response = client::request(
+                HttpMethod::POST,
+                &self.url,
+                &[
+                    ("Authorization", &authorization),
+                    ("Content-Type", &content_type),
+                ],
+                Some(&body),
+            )
+            .await
+            .map_err(|e| SendError::Transient(format!("Could not reach Mailgun: {}", e)))?;
+            if !response.is_success() {
+                return Err(http_status_error(
+                    "Mailgun",
+                    response.status_code,
+                    &response.get_body_as_string(),
+                ));
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::Attachment;
+    use kblue_http::Multipart;
+
+    #[test]
+    fn body_is_multipart_with_attachments() {
+        let provider = MailgunProvider::new("bot@kblue.io", "key", "mg.kblue.io", "https://x/");
+        assert_eq!(provider.url, "https://x/v3/mg.kblue.io/messages");
+        let email = EmailJob {
+            from_name: "KBlue Bot".to_string(),
+            to: "kyle@kblue.io".to_string(),
+            subject: "Hi".to_string(),
+            html_body: "<p>Hi</p>".to_string(),
+            text_body: "Hi".to_string(),
+            attachments: vec![Attachment::new(
+                "cv.pdf",
+                "application/pdf",
+                b"%PDF-".to_vec(),
+            )],
+            submission_id: None,
+            traceparent: None,
+        };
+        let body = provider.body(&email, "b");
+        let parts: Vec<_> = Multipart::new(&body, "b").map(Result::unwrap).collect();
+        assert_eq!(parts[0].text(), Some("KBlue Bot <bot@kblue.io>"));
+        assert_eq!(parts[5].filename.as_deref(), Some("cv.pdf"));
+        assert_eq!(parts[5].data, b"%PDF-");
+    }
+}
@@ -0,0 +1,19 @@
+mod attachment;
+pub mod audit;
+mod config;
+mod dedupe;
+mod dkim;
+mod mailgun;
+mod provider;
+mod queue;
+mod sendgrid;
+mod smtp;
+pub mod template;
+
+pub use attachment::{Attachment, MAX_ATTACHMENTS};
+#[allow(unused_imports)]
+pub use config::{EmailConfig, ProviderConfig};
+pub use dedupe::Deduplicator;
+#[allow(unused_imports)]
+pub use provider::{EmailProvider, SendError, SendFuture};
+pub use queue::{EmailJob, EmailQueue, QueueConfig};
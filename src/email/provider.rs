@@ -0,0 +1,55 @@
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::pin::Pin;
+
+use kblue_http::StatusCode;
+
+use super::EmailJob;
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), SendError>> + Send + 'a>>;
+
+#[derive(Debug)]
+pub enum SendError {
+    // Worth retrying, e.g. the connection failed or the provider is rate limiting us
+    Transient(String),
+    Permanent(String),
+}
+
+impl SendError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, SendError::Transient(_))
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Transient(message) | SendError::Permanent(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+// Something which can deliver an email, chosen by EmailConfig
+pub trait EmailProvider: Send + Sync {
+    fn send<'a>(&'a self, email: &'a EmailJob) -> SendFuture<'a>;
+
+    // Whether the provider can be reached, for /readyz. HTTP APIs are only checked by sending
+    fn check(&self) -> SendFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// HTTP APIs: rate limiting and server errors are worth retrying, anything else is our mistake
+pub(super) fn http_status_error(provider: &str, status_code: u16, body: &str) -> SendError {
+    let message = format!("{} responded with {}: {}", provider, status_code, body);
+    let status = StatusCode::from(status_code);
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        SendError::Transient(message)
+    } else {
+        SendError::Permanent(message)
+    }
+}
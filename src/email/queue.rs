@@ -0,0 +1,366 @@
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug_span, error, field, info, info_span, warn, Instrument};
+
+use super::{audit, Attachment, EmailProvider};
+use crate::telemetry;
+
+// Plain data rather than a provider specific message, so pending jobs can be written to disk
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EmailJob {
+    // Sent from the provider's address under this name
+    pub from_name: String,
+    pub to: String,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    // The contact form submission the email is for, its outcome is recorded in the audit log
+    #[serde(default)]
+    pub submission_id: Option<u64>,
+    // Trace of the request which queued the email, so its sending shows up in the same trace
+    #[serde(skip)]
+    pub traceparent: Option<String>,
+}
+
+pub struct QueueConfig {
+    // Jobs waiting to be sent, enqueue fails once this many are waiting
+    pub capacity: usize,
+    // Including the first, a job is dropped after this many transient failures
+    pub max_attempts: u32,
+    // Doubled after each failed attempt, up to max_backoff
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Pending jobs are written here on shutdown and sent on the next start. None loses them
+    pub persist_path: Option<PathBuf>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            persist_path: None,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn from_env() -> Self {
+        Self {
+            // e.g. data/email_queue.json
+            persist_path: env::var("EMAIL_QUEUE_PATH").ok().map(PathBuf::from),
+            ..Default::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug)]
+pub enum EnqueueError {
+    Full,
+    // The queue has been shut down
+    Closed,
+}
+
+impl Display for EnqueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnqueueError::Full => write!(f, "the email queue is full"),
+            EnqueueError::Closed => write!(f, "the email queue has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for EnqueueError {}
+
+struct Worker {
+    shutdown: Notify,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+// Sends emails from a background task, retrying transient SMTP failures, so handlers don't wait
+// on the SMTP server
+#[derive(Clone)]
+pub struct EmailQueue {
+    sender: mpsc::Sender<EmailJob>,
+    worker: Arc<Worker>,
+}
+
+impl EmailQueue {
+    // Spawns the worker, which first sends any jobs persisted by the previous run
+    pub fn start(provider: Arc<dyn EmailProvider>, config: QueueConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity);
+        for job in load_persisted(&config) {
+            if sender.try_send(job).is_err() {
+                error!("Email queue is full, dropping a persisted email");
+            }
+        }
+
+        let worker = Arc::new(Worker {
+            shutdown: Notify::new(),
+            handle: Mutex::new(None),
+        });
+        let handle = tokio::spawn(run(provider, receiver, config, worker.clone()));
+        *worker.handle.try_lock().unwrap() = Some(handle);
+        Self { sender, worker }
+    }
+
+    pub fn enqueue(&self, mut job: EmailJob) -> Result<(), EnqueueError> {
+        job.traceparent = job.traceparent.or_else(telemetry::current_traceparent);
+        self.sender.try_send(job).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => EnqueueError::Full,
+            mpsc::error::TrySendError::Closed(_) => EnqueueError::Closed,
+        })
+    }
+
+    // Stops the worker once any send in progress finishes, persisting the jobs still waiting
+    pub async fn shutdown(&self) {
+        self.worker.shutdown.notify_one();
+        if let Some(handle) = self.worker.handle.lock().await.take() {
+            let _ = handle.await;
+        }
+    }
+}
+
+async fn run(
+    provider: Arc<dyn EmailProvider>,
+    mut receiver: mpsc::Receiver<EmailJob>,
+    config: QueueConfig,
+    worker: Arc<Worker>,
+) {
+    let mut pending = Vec::new();
+    loop {
+        let job = tokio::select! {
+            job = receiver.recv() => match job {
+                Some(job) => job,
+                None => break,
+            },
+            _ = worker.shutdown.notified() => break,
+        };
+        let span = info_span!(
+            "send_email",
+            submission_id = job.submission_id,
+            attempts = field::Empty,
+            traceparent = job.traceparent.as_deref(),
+        );
+        if let Some(interrupted) = deliver(provider.as_ref(), job, &config, &worker)
+            .instrument(span)
+            .await
+        {
+            pending.push(interrupted);
+            break;
+        }
+    }
+
+    receiver.close();
+    while let Ok(job) = receiver.try_recv() {
+        pending.push(job);
+    }
+    persist(&config, &pending);
+}
+
+// Returns the job if shutdown interrupted its retries
+async fn deliver(
+    provider: &dyn EmailProvider,
+    job: EmailJob,
+    config: &QueueConfig,
+    worker: &Worker,
+) -> Option<EmailJob> {
+    let mut attempt = 1;
+    loop {
+        tracing::Span::current().record("attempts", attempt);
+        let result = provider
+            .send(&job)
+            .instrument(debug_span!("attempt", attempt))
+            .await;
+        match result {
+            Ok(()) => {
+                record_outcome(&job, Ok(()));
+                return None;
+            }
+            Err(e) if e.is_transient() && attempt < config.max_attempts => {
+                let backoff = config.backoff(attempt);
+                warn!(
+                    "Could not send email to {}, retrying in {:?}: {}",
+                    job.to, backoff, e
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = worker.shutdown.notified() => return Some(job),
+                }
+                attempt += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Could not send email to {} after {} attempt(s): {}",
+                    job.to, attempt, e
+                );
+                record_outcome(&job, Err(e.to_string()));
+                return None;
+            }
+        }
+    }
+}
+
+fn record_outcome(job: &EmailJob, result: Result<(), String>) {
+    if let Some(id) = job.submission_id {
+        audit::record_outcome(id, result);
+    }
+}
+
+// The file is removed once read, so jobs aren't sent twice if this run crashes
+fn load_persisted(config: &QueueConfig) -> Vec<EmailJob> {
+    let Some(path) = &config.persist_path else {
+        return Vec::new();
+    };
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let _ = fs::remove_file(path);
+    match serde_json::from_str::<Vec<EmailJob>>(&contents) {
+        Ok(jobs) => {
+            info!("Resending {} email(s) from the previous run", jobs.len());
+            jobs
+        }
+        Err(e) => {
+            error!("Could not parse the email queue file, dropping it: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Written to a temporary file and renamed, so a crash can't leave half a file behind
+fn persist(config: &QueueConfig, jobs: &[EmailJob]) {
+    if jobs.is_empty() {
+        return;
+    }
+    let Some(path) = &config.persist_path else {
+        warn!(
+            "Dropping {} unsent email(s), EMAIL_QUEUE_PATH is not set",
+            jobs.len()
+        );
+        return;
+    };
+    let result = (|| -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let contents = serde_json::to_string_pretty(jobs).map_err(|e| e.to_string())?;
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, contents).map_err(|e| e.to_string())?;
+        fs::rename(&temp_path, path).map_err(|e| e.to_string())
+    })();
+    match result {
+        Ok(()) => info!("Saved {} unsent email(s) to {}", jobs.len(), path.display()),
+        Err(e) => error!("Could not save {} unsent email(s): {}", jobs.len(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::email::{SendError, SendFuture};
+
+    // Fails with the given errors in turn, then succeeds
+    struct FlakyProvider {
+        failures: std::sync::Mutex<Vec<SendError>>,
+        attempts: AtomicU32,
+    }
+
+    impl EmailProvider for FlakyProvider {
+        fn send<'a>(&'a self, _email: &'a EmailJob) -> SendFuture<'a> {
+            Box::pin(async move {
+                self.attempts.fetch_add(1, Ordering::SeqCst);
+                match self.failures.lock().unwrap().pop() {
+                    Some(error) => Err(error),
+                    None => Ok(()),
+                }
+            })
+        }
+    }
+
+    async fn attempts_to_send(failures: Vec<SendError>) -> u32 {
+        let provider = Arc::new(FlakyProvider {
+            failures: std::sync::Mutex::new(failures),
+            attempts: AtomicU32::new(0),
+        });
+        let queue = EmailQueue::start(
+            provider.clone(),
+            QueueConfig {
+                initial_backoff: Duration::from_millis(1),
+                max_attempts: 3,
+                ..Default::default()
+            },
+        );
+        queue.enqueue(job("a@kblue.io")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        queue.shutdown().await;
+        provider.attempts.load(Ordering::SeqCst)
+    }
+
+    fn job(to: &str) -> EmailJob {
+        EmailJob {
+            from_name: "Kyle Doidge".to_string(),
+            to: to.to_string(),
+            subject: "Hi".to_string(),
+            html_body: "<p>Hi</p>".to_string(),
+            text_body: "Hi".to_string(),
+            attachments: Vec::new(),
+            submission_id: None,
+            traceparent: None,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = QueueConfig::default();
+        let backoffs: Vec<u64> = (1..=7).map(|a| config.backoff(a).as_secs()).collect();
+        assert_eq!(backoffs, [2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_only() {
+        let transient = || SendError::Transient("timed out".to_string());
+        assert_eq!(attempts_to_send(vec![]).await, 1);
+        assert_eq!(attempts_to_send(vec![transient(), transient()]).await, 3);
+        assert_eq!(
+            attempts_to_send(vec![transient(), transient(), transient()]).await,
+            3
+        );
+        let permanent = SendError::Permanent("no such user".to_string());
+        assert_eq!(attempts_to_send(vec![permanent]).await, 1);
+    }
+
+    #[test]
+    fn persisted_jobs_are_loaded_once() {
+        let path = env::temp_dir().join(format!("email_queue_test_{}.json", std::process::id()));
+        let config = QueueConfig {
+            persist_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let jobs = vec![job("a@kblue.io"), job("b@kblue.io")];
+        persist(&config, &jobs);
+        assert_eq!(load_persisted(&config), jobs);
+        assert!(!path.exists());
+        assert!(load_persisted(&config).is_empty());
+    }
+}
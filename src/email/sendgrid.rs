@@ -0,0 +1,79 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use kblue_http::{client, HttpMethod};
+use serde_json::json;
+
+use super::provider::{http_status_error, EmailProvider, SendError, SendFuture};
+use super::EmailJob;
+
+const SENDGRID_URL: &str = "https://api.sendgrid.com/v3/mail/send";
+
+pub struct SendGridProvider {
+    address: String,
+    api_key: String,
+}
+
+impl SendGridProvider {
+    pub fn new(address: &str, api_key: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    fn body(&self, email: &EmailJob) -> serde_json::Value {
+        let attachments: Vec<serde_json::Value> = email
+            .attachments
+            .iter()
+            .map(|attachment| {
+                json!({
+                    "content": BASE64_STANDARD.encode(&attachment.data),
+                    "type": attachment.content_type,
+                    "filename": attachment.filename,
+                    "disposition": "attachment",
+                })
+            })
+            .collect();
+        // SendGrid requires text/plain before text/html, and rejects an empty attachments array
+        let mut body = json!({
+            "personalizations": [{ "to": [{ "email": email.to }] }],
+            "from": { "email": self.address, "name": email.from_name },
+            "subject": email.subject,
+            "content": [
+                { "type": "text/plain", "value": email.text_body },
+                { "type": "text/html", "value": email.html_body },
+            ],
+        });
+        if !attachments.is_empty() {
+            body["attachments"] = attachments.into();
+        }
+        body
+    }
+}
+
+impl EmailProvider for SendGridProvider {
+    fn send<'a>(&'a self, email: &'a EmailJob) -> SendFuture<'a> {
+        Box::pin(async move {
+            let authorization = format!("Bearer {}", self.api_key);
+            let body = self.body(email).to_string();
+            let response = client::request(
+                HttpMethod::POST,
+                SENDGRID_URL,
+                &[
+                    ("Authorization", &authorization),
+                    ("Content-Type", "application/json"),
+                ],
+                Some(body.as_bytes()),
+            )
+            .await
+            .map_err(|e| SendError::Transient(format!("Could not reach SendGrid: {}", e)))?;
+            if !response.is_success() {
+                return Err(http_status_error(
+                    "SendGrid",
+                    response.status_code,
+                    &response.get_body_as_string(),
+                ));
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,153 @@
+use mail_send::mail_builder::MessageBuilder;
+use mail_send::{SmtpClient, SmtpClientBuilder};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+use super::config::{SmtpConfig, TlsMode};
+use super::dkim::Signer;
+use super::provider::{EmailProvider, SendError, SendFuture};
+use super::EmailJob;
+
+// Keeps one SMTP connection open between emails rather than doing the TLS and auth handshake
+// for every message
+pub struct SmtpProvider {
+    address: String,
+    config: SmtpConfig,
+    signer: Option<Signer>,
+    client: Mutex<Option<SmtpClient<TlsStream<TcpStream>>>>,
+}
+
+impl SmtpProvider {
+    pub fn new(address: &str, config: &SmtpConfig) -> Result<Self, String> {
+        let signer = match &config.dkim {
+            Some(dkim) => Some(dkim.signer(address)?),
+            None => None,
+        };
+        Ok(Self {
+            address: address.to_string(),
+            config: config.clone(),
+            signer,
+            client: Mutex::new(None),
+        })
+    }
+
+    async fn send_message(&self, message: MessageBuilder<'_>) -> Result<(), mail_send::Error> {
+        let mut client = self.client.lock().await;
+        if let Some(connected) = client.as_mut() {
+            match self.deliver(connected, message.clone()).await {
+                Ok(()) => return Ok(()),
+                // The server drops idle connections, so reconnect and try again
+                Err(e) if Self::is_connection_error(&e) => {
+                    debug!("SMTP connection lost, reconnecting: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        *client = None;
+        let connected = client.insert(self.connect().await?);
+        self.deliver(connected, message).await
+    }
+
+    async fn deliver(
+        &self,
+        client: &mut SmtpClient<TlsStream<TcpStream>>,
+        message: MessageBuilder<'_>,
+    ) -> Result<(), mail_send::Error> {
+        match &self.signer {
+            Some(signer) => client.send_signed(message, signer).await,
+            None => client.send(message).await,
+        }
+    }
+
+    async fn connect(&self) -> Result<SmtpClient<TlsStream<TcpStream>>, mail_send::Error> {
+        let username = self.config.username.as_deref().unwrap_or(&self.address);
+        SmtpClientBuilder::new(self.config.host.as_str(), self.config.port)
+            .implicit_tls(self.config.tls == TlsMode::Implicit)
+            .credentials((username, self.config.password.as_str()))
+            .connect()
+            .await
+    }
+
+    fn is_connection_error(error: &mail_send::Error) -> bool {
+        matches!(
+            error,
+            mail_send::Error::Io(_)
+                | mail_send::Error::Tls(_)
+                | mail_send::Error::Timeout
+                | mail_send::Error::UnparseableReply
+                | mail_send::Error::UnexpectedReply(_)
+        )
+    }
+}
+
+impl EmailProvider for SmtpProvider {
+    // A NOOP on the open connection, connecting first if there isn't one
+    fn check(&self) -> SendFuture<'_> {
+        Box::pin(async move {
+            let mut client = self.client.lock().await;
+            if let Some(connected) = client.as_mut() {
+                if connected.noop().await.is_ok() {
+                    return Ok(());
+                }
+            }
+            *client = None;
+            let connected = self
+                .connect()
+                .await
+                .map_err(|e| SendError::Transient(e.to_string()))?;
+            *client = Some(connected);
+            Ok(())
+        })
+    }
+
+    fn send<'a>(&'a self, email: &'a EmailJob) -> SendFuture<'a> {
+        Box::pin(async move {
+            let mut message = MessageBuilder::new()
+                .from((email.from_name.as_str(), self.address.as_str()))
+                .to(vec![("", email.to.as_str())])
+                .subject(email.subject.as_str())
+                .html_body(email.html_body.as_str())
+                .text_body(email.text_body.as_str());
+            for attachment in email.attachments.iter() {
+                message = message.attachment(
+                    attachment.content_type.as_str(),
+                    attachment.filename.as_str(),
+                    attachment.data.as_slice(),
+                );
+            }
+            self.send_message(message).await.map_err(|e| {
+                if is_transient(&e) {
+                    SendError::Transient(e.to_string())
+                } else {
+                    SendError::Permanent(e.to_string())
+                }
+            })
+        })
+    }
+}
+
+// The connection failed or the server replied with a 4xx, e.g. rate limiting
+fn is_transient(error: &mail_send::Error) -> bool {
+    match error {
+        mail_send::Error::Io(_)
+        | mail_send::Error::Tls(_)
+        | mail_send::Error::Timeout
+        | mail_send::Error::UnparseableReply => true,
+        mail_send::Error::UnexpectedReply(reply) => (400..500).contains(&reply.code),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_connection_failures_are_retried() {
+        assert!(is_transient(&mail_send::Error::Timeout));
+        assert!(!is_transient(&mail_send::Error::MissingRcptTo));
+    }
+}
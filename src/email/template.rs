@@ -0,0 +1,62 @@
+use kblue_http::{TemplateError, Templates};
+
+use crate::reload::{ReloadResult, ReloadableTemplates};
+
+// Compiled in, so the binary doesn't depend on files next to it, see Templates. TEMPLATES_DIR/email
+// can override them
+const TEMPLATES: Templates = Templates::new(&[
+    (
+        "client_confirmation.html",
+        include_str!("templates/client_confirmation.html"),
+    ),
+    (
+        "client_confirmation.txt",
+        include_str!("templates/client_confirmation.txt"),
+    ),
+    (
+        "new_message.html",
+        include_str!("templates/new_message.html"),
+    ),
+    ("new_message.txt", include_str!("templates/new_message.txt")),
+    (
+        "partials/header.html",
+        include_str!("templates/partials/header.html"),
+    ),
+    (
+        "partials/footer.html",
+        include_str!("templates/partials/footer.html"),
+    ),
+    (
+        "partials/footer.txt",
+        include_str!("templates/partials/footer.txt"),
+    ),
+]);
+
+static EMAIL_TEMPLATES: ReloadableTemplates = ReloadableTemplates::new(TEMPLATES, "email");
+
+pub fn render(name: &str, variables: &[(&str, &str)]) -> Result<String, TemplateError> {
+    EMAIL_TEMPLATES.get().render(name, variables)
+}
+
+pub fn reload() -> ReloadResult {
+    EMAIL_TEMPLATES.reload()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Catches typos in the real templates at test time rather than when someone sends a message
+    #[test]
+    fn bundled_templates_render() {
+        let variables = [
+            ("title", "t"),
+            ("message", "m"),
+            ("email", "e"),
+            ("signature", "s"),
+        ];
+        for name in TEMPLATES.names() {
+            render(name, &variables).unwrap();
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use kblue_http::client::{self, ClientError};
+use kblue_http::HttpMethod;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+// My public GitHub repos and recent activity, fetched server side so the frontend isn't subject to
+// GitHub's per visitor rate limit and GITHUB_TOKEN (optional, raises the limit) never leaves here
+
+const DEFAULT_CACHE_SECONDS: u64 = 600;
+const MAX_REPOS: usize = 10;
+const MAX_EVENTS: usize = 20;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Activity {
+    pub repos: Vec<Repo>,
+    pub events: Vec<Event>,
+    pub fetched_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Repo {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(rename(deserialize = "html_url"))]
+    pub url: String,
+    pub language: Option<String>,
+    #[serde(rename(deserialize = "stargazers_count"))]
+    pub stars: u64,
+    #[serde(rename(deserialize = "forks_count"))]
+    pub forks: u64,
+    #[serde(rename(deserialize = "pushed_at"))]
+    pub updated_at: Option<String>,
+    #[serde(skip_serializing)]
+    fork: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Event {
+    // e.g. "push" or "pull_request"
+    pub kind: String,
+    pub repo: String,
+    pub summary: String,
+    pub created_at: String,
+}
+
+// Only the fields we use from the events API
+#[derive(Deserialize)]
+struct RawEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    repo: RawEventRepo,
+    #[serde(default)]
+    payload: Value,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+struct RawEventRepo {
+    name: String,
+}
+
+pub struct GithubClient {
+    username: String,
+    token: Option<String>,
+    api_base: String,
+    cache_for: Duration,
+    // Held while fetching, so a burst of requests after expiry makes one call to GitHub
+    cached: Mutex<Option<(Instant, Arc<Activity>)>>,
+}
+
+impl GithubClient {
+    // GITHUB_USERNAME, GITHUB_TOKEN, GITHUB_CACHE_SECONDS and GITHUB_API_BASE
+    pub fn from_env() -> Result<Self, String> {
+        let cache_seconds = match env::var("GITHUB_CACHE_SECONDS") {
+            Ok(seconds) => seconds
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid GITHUB_CACHE_SECONDS: {}", seconds))?,
+            Err(_) => DEFAULT_CACHE_SECONDS,
+        };
+        Ok(Self {
+            username: env::var("GITHUB_USERNAME").unwrap_or("kyle-blue".to_string()),
+            token: env::var("GITHUB_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            api_base: env::var("GITHUB_API_BASE")
+                .unwrap_or("https://api.github.com".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            cache_for: Duration::from_secs(cache_seconds),
+            cached: Mutex::new(None),
+        })
+    }
+
+    // From the cache if it's fresh. If GitHub can't be reached the last good copy is served,
+    // however old, so the section of the site only disappears if we've never fetched it
+    pub async fn activity(&self) -> Result<Arc<Activity>, ClientError> {
+        let mut cached = self.cached.lock().await;
+        if let Some((fetched_at, activity)) = cached.as_ref() {
+            if fetched_at.elapsed() < self.cache_for {
+                return Ok(activity.clone());
+            }
+        }
+        match self.fetch().await {
+            Ok(activity) => {
+                let activity = Arc::new(activity);
+                *cached = Some((Instant::now(), activity.clone()));
+                Ok(activity)
+            }
+            Err(e) => match cached.as_ref() {
+                Some((_, activity)) => {
+                    warn!(
+                        "Could not refresh GitHub activity, serving the cached copy: {}",
+                        e
+                    );
+                    Ok(activity.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn fetch(&self) -> Result<Activity, ClientError> {
+        let repos_url = format!(
+            "{}/users/{}/repos?type=owner&sort=pushed&per_page=30",
+            self.api_base, self.username
+        );
+        let events_url = format!(
+            "{}/users/{}/events/public?per_page=50",
+            self.api_base, self.username
+        );
+        let (repos, events) = tokio::try_join!(self.get(&repos_url), self.get(&events_url))?;
+        Ok(Activity {
+            repos: trim_repos(serde_json::from_slice(&repos)?),
+            events: trim_events(serde_json::from_slice(&events)?),
+            fetched_at: chrono::Utc::now().to_rfc3339(),
+        })
+    }
+
+    async fn get(&self, url: &str) -> Result<Vec<u8>, ClientError> {
+        let authorization = self.token.as_ref().map(|token| format!("Bearer {}", token));
+        let mut headers = vec![
+            ("Accept", "application/vnd.github+json"),
+            ("X-GitHub-Api-Version", "2022-11-28"),
+        ];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let response = client::request(HttpMethod::GET, url, &headers, None).await?;
+        if !response.is_success() {
+            return Err(format!("GitHub responded with {}", response.status_code).into());
+        }
+        Ok(response.body)
+    }
+}
+
+// Most recently pushed first, as requested, without forks of other people's projects
+fn trim_repos(repos: Vec<Repo>) -> Vec<Repo> {
+    repos
+        .into_iter()
+        .filter(|repo| !repo.fork)
+        .take(MAX_REPOS)
+        .collect()
+}
+
+fn trim_events(events: Vec<RawEvent>) -> Vec<Event> {
+    events
+        .into_iter()
+        .filter_map(|event| {
+            let payload = &event.payload;
+            let action = payload["action"].as_str().unwrap_or("updated");
+            let (kind, summary) = match event.kind.as_str() {
+                "PushEvent" => {
+                    let commits = payload["size"]
+                        .as_u64()
+                        .or_else(|| payload["commits"].as_array().map(|c| c.len() as u64))
+                        .unwrap_or(1);
+                    let plural = if commits == 1 { "" } else { "s" };
+                    ("push", format!("Pushed {} commit{}", commits, plural))
+                }
+                "CreateEvent" => (
+                    "create",
+                    format!(
+                        "Created {}",
+                        payload["ref_type"].as_str().unwrap_or("repository")
+                    ),
+                ),
+                "PullRequestEvent" => (
+                    "pull_request",
+                    format!("{} a pull request", capitalise(action)),
+                ),
+                "IssuesEvent" => ("issue", format!("{} an issue", capitalise(action))),
+                "ReleaseEvent" => (
+                    "release",
+                    format!(
+                        "Released {}",
+                        payload["release"]["tag_name"]
+                            .as_str()
+                            .unwrap_or("a new version")
+                    ),
+                ),
+                "WatchEvent" => ("star", "Starred".to_string()),
+                "ForkEvent" => ("fork", "Forked".to_string()),
+                // Comments, reviews, membership changes and so on aren't interesting enough
+                _ => return None,
+            };
+            Some(Event {
+                kind: kind.to_string(),
+                repo: event.repo.name,
+                summary,
+                created_at: event.created_at,
+            })
+        })
+        .take(MAX_EVENTS)
+        .collect()
+}
+
+fn capitalise(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn trims_github_responses() {
+        let repos: Vec<Repo> = serde_json::from_value(json!([
+            {
+                "name": "portfolio-site-backend", "description": null, "fork": false,
+                "html_url": "https://github.com/kyle-blue/portfolio-site-backend",
+                "language": "Rust", "stargazers_count": 3, "forks_count": 0,
+                "pushed_at": "2026-10-14T10:00:00Z", "owner": { "login": "kyle-blue" }
+            },
+            {
+                "name": "someone-elses", "description": null, "fork": true,
+                "html_url": "https://github.com/kyle-blue/someone-elses", "language": null,
+                "stargazers_count": 0, "forks_count": 0, "pushed_at": null
+            }
+        ]))
+        .unwrap();
+        let repos = trim_repos(repos);
+        assert_eq!(repos.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&repos[0]).unwrap()["url"],
+            "https://github.com/kyle-blue/portfolio-site-backend"
+        );
+
+        let events: Vec<RawEvent> = serde_json::from_value(json!([
+            {
+                "type": "PushEvent", "repo": { "name": "kyle-blue/site" },
+                "payload": { "size": 2 }, "created_at": "2026-10-14T10:00:00Z"
+            },
+            {
+                "type": "IssueCommentEvent", "repo": { "name": "kyle-blue/site" },
+                "payload": {}, "created_at": "2026-10-14T09:00:00Z"
+            },
+            {
+                "type": "PullRequestEvent", "repo": { "name": "rust-lang/rust" },
+                "payload": { "action": "opened" }, "created_at": "2026-10-13T09:00:00Z"
+            }
+        ]))
+        .unwrap();
+        let summaries: Vec<_> = trim_events(events)
+            .into_iter()
+            .map(|event| event.summary)
+            .collect();
+        assert_eq!(summaries, ["Pushed 2 commits", "Opened a pull request"]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
+
+use crate::db::Repository;
+use crate::email::EmailProvider;
+
+const DEFAULT_CACHE_SECONDS: u64 = 10;
+// Orchestrators give up on a probe after a few seconds, so don't wait for the connect timeout
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Checks the dependencies we can't serve without for /readyz. Results are cached for
+// HEALTH_CACHE_SECONDS, so frequent probes don't each open an SMTP connection
+pub struct Readiness {
+    email: Arc<dyn EmailProvider>,
+    db: Option<Arc<dyn Repository>>,
+    cache_for: Duration,
+    // Held while checking, so concurrent probes wait for one check rather than starting their own
+    cached: Mutex<Option<(Instant, Vec<CheckResult>)>>,
+}
+
+impl Readiness {
+    pub fn from_env(
+        email: Arc<dyn EmailProvider>,
+        db: Option<Arc<dyn Repository>>,
+    ) -> Result<Self, String> {
+        let cache_seconds = match env::var("HEALTH_CACHE_SECONDS") {
+            Ok(seconds) => seconds
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid HEALTH_CACHE_SECONDS: {}", seconds))?,
+            Err(_) => DEFAULT_CACHE_SECONDS,
+        };
+        Ok(Self::new(email, db, Duration::from_secs(cache_seconds)))
+    }
+
+    pub fn new(
+        email: Arc<dyn EmailProvider>,
+        db: Option<Arc<dyn Repository>>,
+        cache_for: Duration,
+    ) -> Self {
+        Self {
+            email,
+            db,
+            cache_for,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub async fn check(&self) -> Vec<CheckResult> {
+        let mut cached = self.cached.lock().await;
+        if let Some((checked_at, results)) = cached.as_ref() {
+            if checked_at.elapsed() < self.cache_for {
+                return results.clone();
+            }
+        }
+        let mut results = vec![check_result(
+            "email",
+            timeout(CHECK_TIMEOUT, self.email.check()).await,
+        )];
+        if let Some(db) = &self.db {
+            results.push(check_result(
+                "database",
+                timeout(CHECK_TIMEOUT, db.ping()).await,
+            ));
+        }
+        *cached = Some((Instant::now(), results.clone()));
+        results
+    }
+}
+
+fn check_result<E: ToString>(
+    name: &'static str,
+    result: Result<Result<(), E>, Elapsed>,
+) -> CheckResult {
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    CheckResult {
+        name,
+        ok: error.is_none(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::email::{EmailJob, SendError, SendFuture};
+
+    #[derive(Default)]
+    struct DownProvider {
+        checks: AtomicU32,
+    }
+
+    impl EmailProvider for DownProvider {
+        fn send<'a>(&'a self, _email: &'a EmailJob) -> SendFuture<'a> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn check(&self) -> SendFuture<'_> {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Err(SendError::Transient("connection refused".to_string())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn results_are_cached() {
+        let provider = Arc::new(DownProvider::default());
+        let readiness = Readiness::new(provider.clone(), None, Duration::from_secs(60));
+        let results = readiness.check().await;
+        assert!(!results[0].ok);
+        assert_eq!(results[0].error.as_deref(), Some("connection refused"));
+        readiness.check().await;
+        assert_eq!(provider.checks.load(Ordering::SeqCst), 1);
+
+        let readiness = Readiness::new(provider.clone(), None, Duration::ZERO);
+        readiness.check().await;
+        readiness.check().await;
+        assert_eq!(provider.checks.load(Ordering::SeqCst), 3);
+    }
+}
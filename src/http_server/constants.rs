@@ -1,62 +0,0 @@
-use strum_macros::{Display, EnumIter};
-
-#[derive(Display, Debug, Clone, Default, PartialEq, Eq, Hash, EnumIter)]
-#[allow(clippy::upper_case_acronyms)]
-pub enum HttpMethod {
-    #[default]
-    GET,
-    POST,
-    PUT,
-    DELETE,
-    HEAD,
-    OPTIONS,
-    CONNECT,
-    TRACE,
-    PATCH,
-    OTHER(String),
-}
-
-impl HttpMethod {
-    pub fn from_str(s: &str) -> Self {
-        match s {
-            "GET" => HttpMethod::GET,
-            "POST" => HttpMethod::POST,
-            "PUT" => HttpMethod::PUT,
-            "DELETE" => HttpMethod::DELETE,
-            "HEAD" => HttpMethod::HEAD,
-            "OPTIONS" => HttpMethod::OPTIONS,
-            "CONNECT" => HttpMethod::CONNECT,
-            "TRACE" => HttpMethod::TRACE,
-            "PATCH" => HttpMethod::PATCH,
-            _ => HttpMethod::OTHER(s.to_string()),
-        }
-    }
-}
-
-pub fn get_status_text(code: u16) -> &'static str {
-    match code {
-        100 => "Continue",
-        101 => "Switching Protocols",
-        102 => "Processing",
-        200 => "OK",
-        201 => "Created",
-        202 => "Accepted",
-        204 => "No Content",
-        301 => "Moved Permanently",
-        302 => "Found",
-        304 => "Not Modified",
-        400 => "Bad Request",
-        401 => "Unauthorized",
-        403 => "Forbidden",
-        404 => "Not Found",
-        405 => "Method Not Allowed",
-        500 => "Internal Server Error",
-        501 => "Not Implemented",
-        502 => "Bad Gateway",
-        503 => "Service Unavailable",
-        _ => "Unknown Status",
-    }
-}
-
-pub const ONE_KB: usize = 1_024;
-pub const ONE_MB: usize = 1_048_576;
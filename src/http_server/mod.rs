@@ -1,13 +0,0 @@
-#![allow(unused)]
-
-mod constants;
-mod r#macro;
-mod request;
-mod response;
-mod server;
-mod util;
-
-pub use constants::*;
-pub use request::*;
-pub use response::*;
-pub use server::*;
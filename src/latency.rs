@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use kblue_http::HandlerTiming;
+use tracing::warn;
+
+use crate::config::LatencySettings;
+use crate::notify::Notifier;
+
+// Flags route handlers slower than their budget, e.g. /api/v1/send_email when the SMTP server is
+// struggling. Every slow request is logged, webhook alerts are throttled per route
+
+const ALERT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+pub struct LatencyBudgets {
+    default: Option<Duration>,
+    budgets: HashMap<String, Duration>,
+    // None unless alerts are enabled
+    notifier: Option<Notifier>,
+    // "<method> <route>" -> when it was last alerted on
+    last_alerted: Mutex<HashMap<String, Instant>>,
+}
+
+impl LatencyBudgets {
+    pub fn new(settings: &LatencySettings, notifier: Notifier) -> Self {
+        Self {
+            default: settings.default_budget_ms.map(Duration::from_millis),
+            budgets: settings
+                .budgets
+                .iter()
+                .map(|(route, ms)| (route.clone(), Duration::from_millis(*ms)))
+                .collect(),
+            notifier: settings.notify.then_some(notifier),
+            last_alerted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Nothing to check, so there's no need to hook into the server
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.budgets.is_empty()
+    }
+
+    fn budget_for(&self, route: &str) -> Option<Duration> {
+        self.budgets.get(route).copied().or(self.default)
+    }
+
+    pub fn check(&self, timing: &HandlerTiming) {
+        let Some(budget) = self.budget_for(&timing.route) else {
+            return;
+        };
+        if timing.duration <= budget {
+            return;
+        }
+        // Fractional, so 1.4ms over a 1ms budget doesn't read as 1ms
+        let duration_ms = (timing.duration.as_secs_f64() * 10_000.0).round() / 10.0;
+        let budget_ms = budget.as_millis();
+        warn!(
+            method = %timing.method,
+            route = %timing.route,
+            status = timing.status,
+            duration_ms,
+            budget_ms,
+            "Route handler exceeded its latency budget"
+        );
+
+        let Some(notifier) = &self.notifier else {
+            return;
+        };
+        let key = format!("{} {}", timing.method, timing.route);
+        if self.should_alert(&key, Instant::now()) {
+            notifier.alert(&format!(
+                "{} took {}ms, over its {}ms budget (status {})",
+                key, duration_ms, budget_ms, timing.status
+            ));
+        }
+    }
+
+    fn should_alert(&self, key: &str, now: Instant) -> bool {
+        let mut last_alerted = self.last_alerted.lock().unwrap();
+        match last_alerted.get(key) {
+            Some(at) if now.duration_since(*at) < ALERT_INTERVAL => false,
+            _ => {
+                last_alerted.insert(key.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn budgets(default_budget_ms: Option<u64>) -> LatencyBudgets {
+        let settings = LatencySettings {
+            default_budget_ms,
+            budgets: BTreeMap::from([("/api/v1/send_email".to_string(), 2000)]),
+            notify: false,
+        };
+        LatencyBudgets::new(&settings, Notifier::from_env().unwrap())
+    }
+
+    #[test]
+    fn routes_fall_back_to_the_default_budget() {
+        let budgets = budgets(Some(500));
+        assert_eq!(
+            budgets.budget_for("/api/v1/send_email"),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            budgets.budget_for("/api/v1/projects/:id"),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(self::budgets(None).budget_for("/healthz"), None);
+    }
+
+    #[test]
+    fn alerts_at_most_once_per_interval() {
+        let budgets = budgets(None);
+        let now = Instant::now();
+        assert!(budgets.should_alert("POST /api/v1/send_email", now));
+        assert!(!budgets.should_alert("POST /api/v1/send_email", now + Duration::from_secs(60)));
+        assert!(budgets.should_alert("GET /feed.xml", now));
+        assert!(budgets.should_alert("POST /api/v1/send_email", now + ALERT_INTERVAL));
+    }
+}
@@ -0,0 +1,42 @@
+use std::backtrace::Backtrace;
+
+use tracing::error;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+use crate::config::{LogFormat, LogSettings};
+use crate::telemetry::{OtlpConfig, OtlpLayer};
+
+// Config::validate has already checked the level. Each layer filters for itself, so the request
+// phase spans (debug) can be exported as traces without changing what gets logged
+pub fn init_logging(settings: &LogSettings) {
+    let filter = EnvFilter::try_new(&settings.level).unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let fmt_layer = match settings.format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+    };
+    let otlp_layer = OtlpConfig::from_env().map(|config| {
+        OtlpLayer::new(config).with_filter(
+            Targets::new()
+                .with_default(LevelFilter::WARN)
+                .with_target(env!("CARGO_CRATE_NAME"), LevelFilter::DEBUG),
+        )
+    });
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter))
+        .with(otlp_layer)
+        .init();
+
+    // Handler panics are caught and answered with a 500, so make sure the cause still reaches the logs
+    std::panic::set_hook(Box::new(|info| {
+        error!(backtrace = %Backtrace::force_capture(), "{}", info);
+    }));
+}
@@ -1,49 +1,363 @@
 mod api;
-mod http_server;
+mod auth;
+mod automations;
+mod captcha;
+mod cli;
+mod config;
+mod db;
+mod email;
+mod github;
+mod health;
+mod latency;
+mod logging;
+mod maintenance;
 mod middlewares;
+mod notify;
+mod pages;
+mod reload;
+mod security;
+mod spam;
+mod state;
+mod telemetry;
 
-use http_server::*;
-use middlewares::cors_middleware;
+use config::Config;
+use kblue_http::*;
+use latency::LatencyBudgets;
+use maintenance::Maintenance;
+use middlewares::{
+    admin_auth_middleware, canonical_host_middleware, cors_middleware, maintenance_middleware,
+    rate_limit_middleware, security_headers_middleware, CorsConfig, MemorySessionStore, RateLimit,
+    RateLimitConfig, SessionMiddleware,
+};
+use reload::{register_reloader, ReloadTarget};
+use security::{init_security, SecurityPreset};
+use state::AppState;
 use std::env;
 use std::error::Error;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
 
-fn env_var_check() {
-    let required_envs = [
-        "ENVIRONMENT",
-        "EMAIL_ADDRESS",
-        "EMAIL_PASSWORD",
-        "ALLOWED_ORIGINS",
-    ];
-    let mut missing_envs = Vec::new();
-
-    for env_str in required_envs {
-        let env_var = env::var(env_str);
-        if env_var.is_err() {
-            missing_envs.push(env_str);
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    match cli::parse(&env::args().skip(1).collect::<Vec<_>>()) {
+        Ok(cli::Command::Serve) => {}
+        Ok(cli::Command::Check) => {
+            if let Err(e) = cli::check().await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Ok(cli::Command::CreateAdmin { email }) => {
+            if let Err(e) = cli::create_admin(&email).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Err(usage) => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
         }
     }
-    if !missing_envs.is_empty() {
-        panic!("Missing environment variables: {:?}", missing_envs);
-    }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_var_check();
+    // Before logging, which it configures
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    logging::init_logging(&config.log);
+    init_security(SecurityPreset::from_config(&config));
 
     rustls::crypto::ring::default_provider()
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    let mut server = Server::new(8080);
-    server.add_middleware(cors_middleware);
-    server.route(
+    let tls = match &config.server.tls {
+        Some(tls) => Some(TlsConfig::from_pem_files(&tls.cert_path, &tls.key_path)?),
+        None => None,
+    };
+    // e.g. "127.0.0.1, 10.0.0.0/8" for the reverse proxy in front of us
+    let trusted_proxies = TrustedProxies::parse(&env::var("TRUSTED_PROXIES").unwrap_or_default())?;
+    let default_headers =
+        config
+            .server
+            .headers
+            .iter()
+            .fold(
+                DefaultHeaders::new(),
+                |headers, (name, value)| match value.as_str() {
+                    "" => headers.remove(name),
+                    value => headers.set(name, value),
+                },
+            );
+    let server_config = ServerConfig {
+        trusted_proxies,
+        default_headers,
+        tls,
+        workers: config.server.workers,
+        ..Default::default()
+    };
+    let access_log = access_log_config();
+    let state = Arc::new(AppState::new(&config).await?);
+    let jwt = state.jwt.clone();
+    let email_queue = state.email_queue.clone();
+    let latency_budgets = Arc::new(LatencyBudgets::new(&config.latency, state.notifier.clone()));
+    let maintenance = Arc::new(Maintenance::new(&config.maintenance));
+    register_reloaders(&state);
+
+    // Every port shares the state, TLS, access log, latency budgets and maintenance switch
+    let new_server = |builder: ServerBuilder| {
+        let mut server = builder.config(server_config.clone()).build();
+        if let Some(access_log) = &access_log {
+            server.enable_access_log(access_log.clone());
+        }
+        if !latency_budgets.is_empty() {
+            let latency_budgets = latency_budgets.clone();
+            server.on_handler_complete(move |timing| latency_budgets.check(timing));
+        }
+        server.with_shared_state(state.clone());
+        server.with_shared_state(maintenance.clone());
+        server.set_error_page(ErrorStatuses::Exactly(404), pages::error_page);
+        server.set_error_page(ErrorStatuses::ServerErrors, pages::error_page);
+        server
+    };
+    let extra_port =
+        |port: u16| Server::builder().bind(&format!("{}:{}", config.server.bind, port));
+
+    let mut server = new_server(match &config.server.unix_socket {
+        Some(path) => Server::builder().bind_unix(path),
+        None => Server::builder().bind(&format!("{}:{}", config.server.bind, config.server.port)),
+    });
+    server.with_state(server.worker_metrics());
+    add_public_middlewares(&mut server);
+    if config.server.sessions {
+        let sessions = SessionMiddleware::new(MemorySessionStore::new());
+        server.add_middleware(sessions.load());
+        server.add_after_middleware(sessions.save());
+    }
+    server.add_routes(routes! {
+        GET "/feed.xml" => api::feed_handler,
+    })?;
+
+    let metrics_server = match config.server.metrics_port {
+        Some(port) => {
+            let mut metrics_server = new_server(extra_port(port));
+            add_health_routes(&mut metrics_server)?;
+            Some(metrics_server)
+        }
+        None => {
+            add_health_routes(&mut server)?;
+            None
+        }
+    };
+    let email_server = match config.server.email_api_port {
+        Some(port) => {
+            let mut email_server = new_server(extra_port(port));
+            add_public_middlewares(&mut email_server);
+            add_email_routes(&mut email_server)?;
+            Some(email_server)
+        }
+        None => {
+            add_email_routes(&mut server)?;
+            None
+        }
+    };
+
+    for (pattern, upstream) in config.proxies.iter() {
+        server.proxy(pattern, upstream)?;
+        info!("Proxying {} to {}", pattern, upstream);
+    }
+    let mut v1 = server.scope("/api/v1");
+    v1.add_routes(routes! {
+        POST "/auth/login" => api::v1::auth::login_handler,
+        POST "/auth/logout" => api::v1::auth::logout_handler,
+        GET "/captcha/challenge" => api::v1::captcha_challenge_handler,
+        GET "/resume" => api::v1::resume_handler,
+        GET "/github/activity" => api::v1::github_activity_handler,
+        GET "/projects" => api::v1::list_projects_handler,
+        GET "/projects/:id" => api::v1::get_project_handler,
+        [admin_auth_middleware(jwt.clone())] POST "/projects" => api::v1::create_project_handler,
+        [admin_auth_middleware(jwt.clone())] PUT "/projects/:id" => api::v1::update_project_handler,
+        [admin_auth_middleware(jwt.clone())] DELETE "/projects/:id" => api::v1::delete_project_handler,
+        GET "/openapi.json" => api::v1::openapi_handler,
+    })?;
+    v1.document(HttpMethod::POST, "/auth/login", api::v1::auth::login_doc());
+    v1.document(
         HttpMethod::POST,
-        "/api/v1/send_email",
-        api::v1::send_email_handler,
+        "/auth/logout",
+        api::v1::auth::logout_doc(),
+    );
+    v1.document(
+        HttpMethod::GET,
+        "/captcha/challenge",
+        api::v1::captcha_challenge_doc(),
+    );
+    v1.document(HttpMethod::GET, "/projects", api::v1::list_projects_doc());
+    v1.document(HttpMethod::GET, "/projects/:id", api::v1::get_project_doc());
+    v1.document(HttpMethod::POST, "/projects", api::v1::create_project_doc());
+    v1.document(
+        HttpMethod::PUT,
+        "/projects/:id",
+        api::v1::update_project_doc(),
     );
+    v1.document(
+        HttpMethod::DELETE,
+        "/projects/:id",
+        api::v1::delete_project_doc(),
+    );
+    if config.server.swagger_ui {
+        v1.route(HttpMethod::GET, "/docs", api::v1::swagger_ui_handler)?;
+    }
+    let mut admin = v1.scope("/admin");
+    admin.add_middleware(admin_auth_middleware(jwt));
+    admin.add_routes(routes! {
+        POST "/reload" => api::v1::admin::reload_handler,
+        GET "/profile" => api::v1::admin::profile_handler,
+        GET "/automations" => api::v1::admin::list_automations_handler,
+        POST "/automations" => api::v1::admin::create_automation_handler,
+        DELETE "/automations/:id" => api::v1::admin::delete_automation_handler,
+        GET "/emails" => api::v1::admin::list_emails_handler,
+        GET "/resume/stats" => api::v1::admin::resume_stats_handler,
+        GET "/workers" => api::v1::admin::workers_handler,
+        GET "/maintenance" => api::v1::admin::get_maintenance_handler,
+        PUT "/maintenance" => api::v1::admin::set_maintenance_handler,
+        GET "/routes" => api::v1::admin::routes_handler,
+    })?;
+
+    // Once every route is documented, including any on the email port
+    let mut openapi = server.openapi();
+    if let Some(email_server) = &email_server {
+        openapi.merge(email_server.openapi());
+    }
+    server.with_state(api::v1::ApiDocument(
+        openapi.document("kblue.io API", env!("CARGO_PKG_VERSION")),
+    ));
+    server.with_state(api::v1::admin::RouteList(server.routes()));
 
-    server.start().await?;
+    let shutdown_handles: Vec<ShutdownHandle> = [
+        Some(&server),
+        metrics_server.as_ref(),
+        email_server.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(Server::shutdown_handle)
+    .collect();
+    let servers = async {
+        tokio::try_join!(
+            server.start(),
+            start_if_configured(&metrics_server),
+            start_if_configured(&email_server),
+        )
+    };
+    tokio::pin!(servers);
+    // For switching without an admin token, e.g. `docker kill -s USR1`
+    let mut toggle_maintenance = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while toggle_maintenance.recv().await.is_some() {
+            maintenance.toggle();
+        }
+    });
+    let mut terminate = signal(SignalKind::terminate())?;
+    let signal_name = tokio::select! {
+        result = &mut servers => {
+            result?;
+            None
+        }
+        _ = terminate.recv() => Some("SIGTERM"),
+        _ = tokio::signal::ctrl_c() => Some("SIGINT"),
+    };
+    if let Some(signal_name) = signal_name {
+        info!("Received {}, shutting down", signal_name);
+        // Every port stops accepting at once, then in-flight requests are given time to finish
+        for shutdown_handle in shutdown_handles.iter() {
+            shutdown_handle.shutdown();
+        }
+        servers.await?;
+    }
+    // Unsent emails are saved to EMAIL_QUEUE_PATH, if set, and sent on the next start
+    email_queue.shutdown().await;
 
     Ok(())
 }
+
+// "stdout" or a file path
+fn access_log_config() -> Option<AccessLogConfig> {
+    let access_log = env::var("ACCESS_LOG").ok()?;
+    Some(AccessLogConfig {
+        format: match env::var("ACCESS_LOG_FORMAT").as_deref() {
+            Ok("json") => AccessLogFormat::Json,
+            _ => AccessLogFormat::Combined,
+        },
+        target: match access_log.as_str() {
+            "stdout" => AccessLogTarget::Stdout,
+            path => AccessLogTarget::File(path.into()),
+        },
+    })
+}
+
+// For the ports browsers reach, not the metrics port
+fn add_public_middlewares(server: &mut Server) {
+    server.add_middleware(canonical_host_middleware);
+    server.add_middleware(security_headers_middleware);
+    server.add_middleware(cors_middleware(CorsConfig::from_security_config(
+        security::security_config(),
+    )));
+    // After CORS, so browsers can read the 503 and 429
+    server.add_middleware(maintenance_middleware);
+    server.add_middleware(rate_limit_middleware(
+        RateLimitConfig::new(RateLimit::per_minute(120))
+            .route("/api/v1/send_email", RateLimit::per_hour(10))
+            .route("/api/v1/auth/login", RateLimit::per_minute(5)),
+    ));
+}
+
+fn add_health_routes(server: &mut Server) -> Result<(), DuplicateRouteError> {
+    server.add_routes(routes! {
+        GET "/healthz" => api::healthz_handler,
+        GET "/readyz" => api::readyz_handler,
+    })
+}
+
+fn add_email_routes(server: &mut Server) -> Result<(), DuplicateRouteError> {
+    let mut v1 = server.scope("/api/v1");
+    v1.add_routes(routes! {
+        POST "/send_email" => api::v1::send_email_handler,
+    })?;
+    v1.document(HttpMethod::POST, "/send_email", api::v1::send_email_doc());
+    Ok(())
+}
+
+// For POST /api/v1/admin/reload
+fn register_reloaders(state: &Arc<AppState>) {
+    register_reloader(ReloadTarget::Templates, || async {
+        let mut changed = email::template::reload()?;
+        changed.extend(pages::reload()?);
+        Ok(changed)
+    });
+    let spam_state = state.clone();
+    register_reloader(ReloadTarget::Blocklists, move || {
+        let state = spam_state.clone();
+        async move { state.spam.reload_keywords() }
+    });
+    // Without a database there's no project list to reload
+    if let Some(db) = state.db.clone() {
+        let state = state.clone();
+        register_reloader(ReloadTarget::Projects, move || {
+            let (state, db) = (state.clone(), db.clone());
+            async move {
+                let changed = state.projects.reload(db.as_ref()).await;
+                changed.map_err(|e| e.to_string())
+            }
+        });
+    }
+}
+
+async fn start_if_configured(server: &Option<Server>) -> Result<(), Box<dyn Error>> {
+    match server {
+        Some(server) => server.start().await,
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::info;
+
+use crate::config::MaintenanceSettings;
+
+// While enabled, the public ports answer 503 to everything but health checks, login and the
+// admin API, e.g. while running migrations. Shared by every server as state, and switched with
+// PUT /api/v1/admin/maintenance or SIGUSR1
+pub struct Maintenance {
+    enabled: AtomicBool,
+    pub retry_after_secs: u64,
+}
+
+impl Maintenance {
+    pub fn new(settings: &MaintenanceSettings) -> Self {
+        Self {
+            enabled: AtomicBool::new(settings.enabled),
+            retry_after_secs: settings.retry_after_secs,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            info!(
+                "Maintenance mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    // Returns whether it's now enabled
+    pub fn toggle(&self) -> bool {
+        let enabled = !self.enabled.fetch_xor(true, Ordering::Relaxed);
+        info!(
+            "Maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        enabled
+    }
+}
@@ -0,0 +1,97 @@
+use std::env;
+use std::sync::Arc;
+
+use kblue_http::{Middleware, Next};
+
+use super::auth::{bearer_auth_middleware, constant_time_eq, Principal};
+use super::session::RequestSession;
+use crate::auth::Jwt;
+
+// Holds the admin's username in sessions logged in through POST /api/v1/auth/login
+pub const ADMIN_SESSION_KEY: &str = "admin";
+
+// Accepts ADMIN_TOKEN, a token from POST /api/v1/auth/login when JWTs are configured, or a session
+// logged in there when sessions are enabled. Admin endpoints are disabled entirely unless one of
+// them is
+pub fn admin_auth_middleware(jwt: Option<Arc<Jwt>>) -> impl Middleware {
+    let bearer_auth = bearer_auth_middleware(move |token| {
+        let admin_token = env::var("ADMIN_TOKEN").unwrap_or_default();
+        if !admin_token.is_empty() && constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+            return Some(Principal {
+                id: "admin".to_string(),
+            });
+        }
+        let claims = jwt.as_ref()?.verify(token).ok()?;
+        Some(Principal { id: claims.sub })
+    });
+    move |request, response| {
+        let admin = request
+            .session()
+            .and_then(|session| session.get::<String>(ADMIN_SESSION_KEY));
+        match admin {
+            Some(admin) => {
+                request.extensions.insert(Principal { id: admin });
+                Box::pin(async { Next::Continue })
+            }
+            None => bearer_auth(request, response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kblue_http::test::TestServer;
+    use kblue_http::{route, routes, HttpMethod, RequestParam, ResponseParam, Router, Server};
+
+    use super::*;
+    use crate::middlewares::{MemorySessionStore, SessionMiddleware};
+
+    route!(
+        session_login_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            let session = request.session().unwrap();
+            session.regenerate();
+            session.insert(ADMIN_SESSION_KEY, "kyle")?;
+            response.send();
+            Ok(())
+        }
+    );
+
+    route!(
+        whoami_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.message(&request.extensions.get::<Principal>().unwrap().id);
+            Ok(())
+        }
+    );
+
+    #[tokio::test]
+    async fn sessions_logged_in_as_admin_are_let_through() {
+        let sessions =
+            SessionMiddleware::new(MemorySessionStore::new()).cookie_attributes("Path=/");
+        let mut server = Server::builder().build();
+        server.add_middleware(sessions.load());
+        server.add_after_middleware(sessions.save());
+        server
+            .add_routes(routes! {
+                POST "/login" => session_login_handler,
+                [admin_auth_middleware(None)] GET "/whoami" => whoami_handler,
+            })
+            .unwrap();
+        let server = TestServer::spawn(server).await;
+
+        assert_eq!(server.get("/whoami").await.status, 401);
+        let login = server.post("/login", "text/plain", b"").await;
+        let cookie = login
+            .header("Set-Cookie")
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap();
+        let response = server
+            .request(HttpMethod::GET, "/whoami", &[("Cookie", cookie)], None)
+            .await;
+        assert_eq!(response.status, 200);
+        assert!(response.text().contains("kyle"), "{}", response.text());
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use kblue_http::{Middleware, Next, Request};
+
+use crate::auth::Jwt;
+
+// Whoever authenticated the request. Added to request.extensions by the auth middlewares
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    pub id: String,
+}
+
+const REALM: &str = "kblue.io";
+
+// Compares every byte whatever the contents, so response times don't reveal how much of a
+// secret was guessed correctly
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let decoded = BASE64_STANDARD
+        .decode(request.authorization()?.credentials_for("Basic")?)
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+// HTTP Basic auth against a username -> password map. Only use over TLS, since the password is
+// sent with every request
+#[allow(dead_code)]
+pub fn basic_auth_middleware(users: HashMap<String, String>) -> impl Middleware {
+    let users = Arc::new(users);
+    move |request, response| {
+        let users = users.clone();
+        Box::pin(async move {
+            let authenticated = basic_credentials(request).filter(|(username, password)| {
+                users.get(username).is_some_and(|expected| {
+                    constant_time_eq(expected.as_bytes(), password.as_bytes())
+                })
+            });
+            let Some((username, _)) = authenticated else {
+                response.add_header(
+                    "WWW-Authenticate",
+                    &format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM),
+                );
+                response.status(401).message("unauthorized");
+                return Next::Stop;
+            };
+            request.extensions.insert(Principal { id: username });
+            Next::Continue
+        })
+    }
+}
+
+// `Authorization: Bearer <token>`, where the verifier decides who (if anyone) the token belongs to
+pub fn bearer_auth_middleware(
+    verifier: impl Fn(&str) -> Option<Principal> + Send + Sync + 'static,
+) -> impl Middleware {
+    let verifier = Arc::new(verifier);
+    move |request, response| {
+        let verifier = verifier.clone();
+        Box::pin(async move {
+            let token = request
+                .authorization()
+                .and_then(|authorization| authorization.credentials_for("Bearer"));
+            let principal = token.and_then(|token| verifier(token));
+            let Some(principal) = principal else {
+                // Tell the client whether it sent a bad token or none at all
+                let challenge = match token {
+                    Some(_) => format!("Bearer realm=\"{}\", error=\"invalid_token\"", REALM),
+                    None => format!("Bearer realm=\"{}\"", REALM),
+                };
+                response.add_header("WWW-Authenticate", &challenge);
+                response.status(401).message("unauthorized");
+                return Next::Stop;
+            };
+            request.extensions.insert(principal);
+            Next::Continue
+        })
+    }
+}
+
+// Bearer tokens issued by POST /api/v1/auth/login, the principal being the token's subject
+#[allow(dead_code)]
+pub fn jwt_auth_middleware(jwt: Arc<Jwt>) -> impl Middleware {
+    bearer_auth_middleware(move |token| {
+        let claims = jwt.verify(token).ok()?;
+        Some(Principal { id: claims.sub })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kblue_http::Response;
+
+    async fn run(
+        middleware: &impl Middleware,
+        authorization: Option<&str>,
+    ) -> (Next, Request, Response) {
+        let mut request = Request::default();
+        if let Some(authorization) = authorization {
+            request.headers.insert("Authorization", authorization);
+        }
+        let mut response = Response::new();
+        let next = middleware(&mut request, &mut response).await;
+        (next, request, response)
+    }
+
+    #[tokio::test]
+    async fn basic_auth_checks_users() {
+        let users = HashMap::from([("kyle".to_string(), "hunter2".to_string())]);
+        let middleware = basic_auth_middleware(users);
+        let cases = [
+            (Some("Basic a3lsZTpodW50ZXIy"), true),
+            (Some("basic a3lsZTpodW50ZXIy"), true),
+            (Some("Basic a3lsZTp3cm9uZw=="), false),
+            (Some("Basic !!!"), false),
+            (Some("Bearer a3lsZTpodW50ZXIy"), false),
+            (None, false),
+        ];
+        for (authorization, allowed) in cases {
+            let (next, request, response) = run(&middleware, authorization).await;
+            if allowed {
+                assert_eq!(next, Next::Continue, "{:?}", authorization);
+                assert_eq!(request.extensions.get::<Principal>().unwrap().id, "kyle");
+            } else {
+                assert_eq!(next, Next::Stop, "{:?}", authorization);
+                assert_eq!(response.status_code, 401);
+                assert!(response
+                    .headers
+                    .get("www-authenticate")
+                    .unwrap()
+                    .starts_with("Basic"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_auth_uses_the_verifier() {
+        let middleware = bearer_auth_middleware(|token| {
+            (token == "good").then(|| Principal {
+                id: "bot".to_string(),
+            })
+        });
+
+        let (next, request, _) = run(&middleware, Some("Bearer good")).await;
+        assert_eq!(next, Next::Continue);
+        assert_eq!(request.extensions.get::<Principal>().unwrap().id, "bot");
+
+        let (next, _, response) = run(&middleware, Some("Bearer bad")).await;
+        assert_eq!(next, Next::Stop);
+        assert!(response
+            .headers
+            .get("www-authenticate")
+            .unwrap()
+            .contains("invalid_token"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::env;
+
+use kblue_http::{middleware, Next, Request, RequestParam, ResponseParam};
+use tracing::error;
+use url::Url;
+
+// Paths which must answer on any host, e.g. orchestrator probes hitting the pod IP directly
+fn get_exempt_paths() -> Vec<String> {
+    parse_exempt_paths(
+        &env::var("CANONICAL_HOST_EXEMPT_PATHS").unwrap_or("/healthz, /readyz".to_string()),
+    )
+}
+
+// Comma separated, e.g. "/healthz,/readyz", normalised like request paths
+fn parse_exempt_paths(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(|path| path.trim_end_matches('/').to_string() + "/")
+        .collect()
+}
+
+// Where to send a request that didn't arrive on the canonical origin, None if it did or is exempt
+fn canonical_redirect(
+    request: &Request,
+    canonical_url: &Url,
+    exempt_paths: &[String],
+) -> Option<String> {
+    if exempt_paths.contains(&request.path) {
+        return None;
+    }
+    let canonical_host = match (canonical_url.host_str(), canonical_url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return None,
+    };
+    let host = request
+        .get_header("host")
+        .map(|host| host.to_lowercase())
+        .unwrap_or_default();
+    // TLS is terminated by the reverse proxy, which tells us the original scheme. Nobody else
+    // gets to, or they could bounce visitors between schemes
+    let scheme_matches = request
+        .get_header("x-forwarded-proto")
+        .filter(|_| request.from_trusted_proxy)
+        .is_none_or(|scheme| scheme.eq_ignore_ascii_case(canonical_url.scheme()));
+    if host == canonical_host && scheme_matches {
+        return None;
+    }
+
+    // From the parsed path and query rather than the raw target, which could carry another host,
+    // e.g. @evil.com/x. request.path always ends in a slash, so it's dropped if the visitor's didn't
+    let target_path = request.uri.split('?').next().unwrap_or_default();
+    let path = match target_path.ends_with('/') {
+        true => request.path.as_str(),
+        false => request.path.strip_suffix('/').unwrap_or(&request.path),
+    };
+    let mut location = canonical_url.clone();
+    location.set_path(path);
+    location.set_query(None);
+    if !request.query.is_empty() {
+        location
+            .query_pairs_mut()
+            .extend_pairs(request.query.iter());
+    }
+    Some(location.to_string())
+}
+
+middleware!(
+    canonical_host_middleware,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        // e.g. https://kblue.io, enforcement is disabled when unset
+        let Ok(canonical_origin) = env::var("CANONICAL_ORIGIN") else {
+            return Next::Continue;
+        };
+        let Ok(canonical_url) = Url::parse(&canonical_origin) else {
+            error!("CANONICAL_ORIGIN is not a valid URL: {}", canonical_origin);
+            return Next::Continue;
+        };
+        let Some(location) = canonical_redirect(request, &canonical_url, &get_exempt_paths())
+        else {
+            return Next::Continue;
+        };
+        response.add_header("Location", &location);
+        response.status(301).message("moved permanently");
+        Next::Stop
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use kblue_http::QueryMap;
+
+    use super::*;
+
+    fn request(target: &str, host: &str, headers: &[(&str, &str)]) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut request = Request {
+            path: path.trim_end_matches('/').to_string() + "/",
+            uri: target.to_string(),
+            query: url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect::<QueryMap>(),
+            ..Request::default()
+        };
+        request.headers.insert("Host", host);
+        for (name, value) in headers {
+            request.headers.insert(name, value);
+        }
+        request
+    }
+
+    fn redirect(request: &Request) -> Option<String> {
+        let canonical_url = Url::parse("https://kblue.io").unwrap();
+        canonical_redirect(request, &canonical_url, &parse_exempt_paths("/healthz"))
+    }
+
+    fn request_with_path(path: &str) -> Request {
+        Request {
+            path: path.to_string() + "/",
+            uri: path.to_string(),
+            ..request("/", "www.kblue.io", &[])
+        }
+    }
+
+    #[test]
+    fn redirects_other_hosts_to_the_canonical_origin() {
+        assert_eq!(redirect(&request("/about", "kblue.io", &[])), None);
+        assert_eq!(
+            redirect(&request("/about?tag=rust&q=a%20b", "www.kblue.io", &[])).as_deref(),
+            Some("https://kblue.io/about?tag=rust&q=a+b")
+        );
+        assert_eq!(
+            redirect(&request("/blog/", "KBLUE.io:8080", &[])).as_deref(),
+            Some("https://kblue.io/blog/")
+        );
+        assert_eq!(redirect(&request("/healthz", "10.0.0.7:8080", &[])), None);
+    }
+
+    #[test]
+    fn never_redirects_off_the_canonical_host() {
+        // The router saw /x, the raw target would have made evil.com the host
+        let request = Request {
+            path: "/x/".to_string(),
+            uri: "@evil.com/x".to_string(),
+            ..request("/x", "www.kblue.io", &[])
+        };
+        assert_eq!(redirect(&request).as_deref(), Some("https://kblue.io/x"));
+        let request = request_with_path("//evil.com/x");
+        assert_eq!(
+            Url::parse(&redirect(&request).unwrap()).unwrap().host_str(),
+            Some("kblue.io")
+        );
+    }
+
+    #[test]
+    fn only_believes_the_scheme_from_trusted_proxies() {
+        let http = [("X-Forwarded-Proto", "http")];
+        let mut proxied = request("/about", "kblue.io", &http);
+        assert_eq!(redirect(&proxied), None);
+        proxied.from_trusted_proxy = true;
+        assert_eq!(
+            redirect(&proxied).as_deref(),
+            Some("https://kblue.io/about")
+        );
+        let https = [("X-Forwarded-Proto", "https")];
+        let mut proxied = request("/about", "kblue.io", &https);
+        proxied.from_trusted_proxy = true;
+        assert_eq!(redirect(&proxied), None);
+    }
+
+    #[test]
+    fn parses_exempt_paths() {
+        assert_eq!(
+            parse_exempt_paths("/healthz,/readyz/, ,/metrics "),
+            ["/healthz/", "/readyz/", "/metrics/"]
+        );
+        assert!(parse_exempt_paths("").is_empty());
+    }
+}
@@ -1,52 +1,417 @@
-use strum::IntoEnumIterator;
-
-use crate::{
-    http_server::{HttpMethod, RequestParam, ResponseParam},
-    route,
-};
-use std::env;
-
-route!(
-    cors_middleware,
-    async move |request: RequestParam, mut response: ResponseParam| {
-        let default_origin = "https://www.kblue-dev.ido".to_string();
-        let origin = request.headers.get("origin").unwrap_or(&default_origin);
-
-        let environment = env::var("ENVIRONMENT").unwrap_or("prod".to_string());
-        let allowed_origins = env::var("ALLOWED_ORIGINS").unwrap_or("".to_string());
-
-        // Origin & creds header needed on pre-flight & actual requests
-        if &environment == "dev" {
-            response.add_header("Access-Control-Allow-Origin", origin);
-        } else if allowed_origins
-            .split(", ")
-            .collect::<Vec<&str>>()
-            .contains(&origin.as_str())
-        {
-            response.add_header("Access-Control-Allow-Origin", origin);
+use std::sync::Arc;
+use std::time::Duration;
+
+use kblue_http::{HttpMethod, Middleware, Next};
+
+use crate::security::SecurityConfig;
+
+// CORS policy, built up with the methods below and turned into a middleware with cors_middleware
+#[derive(Clone, Debug, Default)]
+pub struct CorsConfig {
+    // Exact origins, or with a single `*` wildcard, e.g. https://*.kblue.io
+    allowed_origins: Vec<String>,
+    allow_any_origin: bool,
+    // None leaves Access-Control-Allow-Methods to the server, which lists the methods routed for the path.
+    // Otherwise the server still leaves out any of these without a route
+    allowed_methods: Option<Vec<HttpMethod>>,
+    allowed_headers: Vec<String>,
+    // Echo whatever Access-Control-Request-Headers asks for
+    allow_any_header: bool,
+    exposed_headers: Vec<String>,
+    max_age: Option<Duration>,
+    allow_credentials: bool,
+}
+
+impl CorsConfig {
+    // Allows nothing until origins are added
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Any origin in dev, otherwise the preset's allow list
+    pub fn from_security_config(security: &SecurityConfig) -> Self {
+        let config = match &security.allowed_origins {
+            Some(origins) => Self::new().allow_origins(origins),
+            None => Self::new().allow_any_origin(),
+        };
+        config
+            .allow_headers(&["Authorization", "Content-Type"])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600))
+    }
+
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.allowed_origins
+            .push(origin.trim_end_matches('/').to_lowercase());
+        self
+    }
+
+    pub fn allow_origins(self, origins: &[String]) -> Self {
+        origins
+            .iter()
+            .fold(self, |config, origin| config.allow_origin(origin))
+    }
+
+    pub fn allow_any_origin(mut self) -> Self {
+        self.allow_any_origin = true;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn allow_methods(mut self, methods: &[HttpMethod]) -> Self {
+        self.allowed_methods = Some(methods.to_vec());
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.allowed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn allow_any_header(mut self) -> Self {
+        self.allow_any_header = true;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn allow_credentials(mut self, allow_credentials: bool) -> Self {
+        self.allow_credentials = allow_credentials;
+        self
+    }
+
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        if self.allow_any_origin {
+            return true;
+        }
+        let origin = origin.to_lowercase();
+        self.allowed_origins
+            .iter()
+            .any(|allowed| match allowed.split_once('*') {
+                Some((prefix, suffix)) => {
+                    origin.len() > prefix.len() + suffix.len()
+                        && origin.starts_with(prefix)
+                        && origin.ends_with(suffix)
+                        // The wildcard stands in for subdomains, never a path or port
+                        && !origin[prefix.len()..origin.len() - suffix.len()].contains(['/', ':'])
+                }
+                None => *allowed == origin,
+            })
+    }
+
+    // The headers a preflight asked for which may be sent, as the browser named them. With no
+    // Access-Control-Request-Headers, every allowed header
+    fn allowed_request_headers(&self, requested: Option<&str>) -> Vec<String> {
+        let Some(requested) = requested else {
+            return self.allowed_headers.clone();
+        };
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter(|name| {
+                self.allow_any_header
+                    || self
+                        .allowed_headers
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            })
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Browsers reject a literal `*` on credentialed requests, so the origin is echoed instead
+    fn allow_origin_value(&self, origin: &str) -> String {
+        if self.allow_any_origin && !self.allow_credentials {
+            "*".to_string()
+        } else {
+            origin.to_string()
         }
-        response.add_header("Access-Control-Allow-Credentials", "true");
+    }
+}
+
+pub fn cors_middleware(config: CorsConfig) -> impl Middleware {
+    let config = Arc::new(config);
+    move |request, response| {
+        let config = config.clone();
+        Box::pin(async move {
+            // The response depends on the Origin header, so caches must key on it
+            response.add_vary("Origin");
+
+            // Not a cross origin request
+            let Some(origin) = request.headers.get("Origin") else {
+                return Next::Continue;
+            };
+            // Leaving the headers off makes the browser block the response
+            if !config.is_origin_allowed(origin) {
+                return Next::Continue;
+            }
+
+            response.add_header(
+                "Access-Control-Allow-Origin",
+                &config.allow_origin_value(origin),
+            );
+            if config.allow_credentials {
+                response.add_header("Access-Control-Allow-Credentials", "true");
+            }
+
+            let is_preflight = request.method == HttpMethod::OPTIONS
+                && request
+                    .headers
+                    .contains_key("Access-Control-Request-Method");
+            if !is_preflight {
+                if !config.exposed_headers.is_empty() {
+                    response.add_header(
+                        "Access-Control-Expose-Headers",
+                        &config.exposed_headers.join(", "),
+                    );
+                }
+                return Next::Continue;
+            }
+
+            // The server answers the pre flight itself once the headers are in place
+            if let Some(methods) = &config.allowed_methods {
+                let methods: Vec<&str> = methods
+                    .iter()
+                    .filter(|method| method.is_advertised())
+                    .map(HttpMethod::as_str)
+                    .collect();
+                response.add_header("Access-Control-Allow-Methods", &methods.join(", "));
+            }
+            // The answer depends on what was asked for, so caches must key on it
+            response.add_vary("Access-Control-Request-Headers");
+            let allowed_headers = config.allowed_request_headers(
+                request
+                    .get_header("Access-Control-Request-Headers")
+                    .map(String::as_str),
+            );
+            if !allowed_headers.is_empty() {
+                response.add_header("Access-Control-Allow-Headers", &allowed_headers.join(", "));
+            }
+            if let Some(max_age) = config.max_age {
+                response.add_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+            }
+            Next::Continue
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kblue_http::{route, routes, Request, RequestParam, ResponseParam, Router, Server};
 
-        if request.method != HttpMethod::OPTIONS {
-            return;
+    use super::*;
+
+    route!(
+        ok_handler,
+        async move |request: RequestParam, mut response: ResponseParam| {
+            response.text("ok");
+            response.send();
+            Ok(())
         }
+    );
+
+    fn server() -> Server {
+        server_with(
+            CorsConfig::new()
+                .allow_origin("https://kblue.io")
+                .allow_headers(&["Content-Type"]),
+        )
+    }
 
-        // Other pre flight cors headers
+    fn server_with(config: CorsConfig) -> Server {
+        let mut server = Server::builder().build();
+        server.add_middleware(cors_middleware(config));
+        server
+            .add_routes(routes! {
+                GET "/users/me" => ok_handler,
+                POST "/messages" => ok_handler,
+            })
+            .unwrap();
+        server
+    }
 
-        let mut methods_to_allow = Vec::new();
-        for method in HttpMethod::iter() {
-            methods_to_allow.push(method.to_string());
+    fn request(method: HttpMethod, path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request {
+            method,
+            path: path.to_string(),
+            uri: path.to_string(),
+            ..Request::default()
+        };
+        for (name, value) in headers {
+            request.headers.insert(name, value);
         }
+        request
+    }
+
+    #[tokio::test]
+    async fn applies_cors() {
+        let server = server();
+
+        let response = server
+            .handle(request(
+                HttpMethod::GET,
+                "/users/me",
+                &[("Origin", "https://kblue.io")],
+            ))
+            .await;
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin").unwrap(),
+            "https://kblue.io"
+        );
+        let response = server
+            .handle(request(
+                HttpMethod::GET,
+                "/users/me",
+                &[("Origin", "https://evil.example")],
+            ))
+            .await;
+        assert!(response
+            .headers
+            .get("Access-Control-Allow-Origin")
+            .is_none());
 
-        response.add_header(
-            "Access-Control-Allow-Methods",
-            methods_to_allow.join(", ").as_str(),
+        let preflight = server
+            .handle(request(
+                HttpMethod::OPTIONS,
+                "/messages",
+                &[
+                    ("Origin", "https://kblue.io"),
+                    ("Access-Control-Request-Method", "POST"),
+                ],
+            ))
+            .await;
+        assert_eq!(preflight.status_code, 204);
+        assert_eq!(
+            preflight
+                .headers
+                .get("Access-Control-Allow-Methods")
+                .unwrap(),
+            "POST, OPTIONS"
         );
-        response.add_header(
-            "Access-Control-Allow-Headers",
-            "Authorization, Content-Type",
+        assert_eq!(
+            preflight
+                .headers
+                .get("Access-Control-Allow-Headers")
+                .unwrap(),
+            "Content-Type"
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_preflights_with_what_was_asked_for() {
+        let preflight = |headers: &'static str| {
+            request(
+                HttpMethod::OPTIONS,
+                "/messages",
+                &[
+                    ("Origin", "https://kblue.io"),
+                    ("Access-Control-Request-Method", "POST"),
+                    ("Access-Control-Request-Headers", headers),
+                ],
+            )
+        };
+
+        let server = server_with(
+            CorsConfig::new()
+                .allow_origin("https://kblue.io")
+                .allow_methods(&[HttpMethod::GET, HttpMethod::POST, HttpMethod::DELETE])
+                .allow_headers(&["Content-Type"]),
+        );
+        let response = server.handle(preflight("content-type, x-trace-id")).await;
+        // Only POST is routed for /messages
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Methods")
+                .unwrap(),
+            "POST"
+        );
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Headers")
+                .unwrap(),
+            "content-type"
+        );
+
+        let server = server_with(
+            CorsConfig::new()
+                .allow_origin("https://kblue.io")
+                .allow_any_header(),
+        );
+        let response = server.handle(preflight("content-type, x-trace-id")).await;
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Headers")
+                .unwrap(),
+            "content-type, x-trace-id"
+        );
+        let response = server.handle(preflight("")).await;
+        assert!(response
+            .headers
+            .get("Access-Control-Allow-Headers")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn applies_cors_to_unmatched_paths() {
+        let server = server();
+
+        let response = server
+            .handle(request(
+                HttpMethod::GET,
+                "/nowhere",
+                &[("Origin", "https://kblue.io")],
+            ))
+            .await;
+        assert_eq!(response.status_code, 404);
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin").unwrap(),
+            "https://kblue.io"
+        );
+
+        let preflight = server
+            .handle(request(
+                HttpMethod::OPTIONS,
+                "/nowhere",
+                &[
+                    ("Origin", "https://kblue.io"),
+                    ("Access-Control-Request-Method", "DELETE"),
+                ],
+            ))
+            .await;
+        assert_eq!(preflight.status_code, 204);
+        assert_eq!(
+            preflight
+                .headers
+                .get("Access-Control-Allow-Origin")
+                .unwrap(),
+            "https://kblue.io"
         );
 
-        response.send();
+        // Not approved by the CORS middleware, so there's nothing to answer
+        let preflight = server
+            .handle(request(
+                HttpMethod::OPTIONS,
+                "/nowhere",
+                &[
+                    ("Origin", "https://evil.example"),
+                    ("Access-Control-Request-Method", "DELETE"),
+                ],
+            ))
+            .await;
+        assert_eq!(preflight.status_code, 404);
     }
-);
+}
@@ -0,0 +1,63 @@
+use kblue_http::{middleware, HttpMethod, Next, RequestParam, ResponseParam};
+use tracing::error;
+
+use crate::maintenance::Maintenance;
+use crate::pages;
+
+// Paths are normalised with a trailing slash. Login and the admin API stay up so maintenance
+// can be switched off again
+fn is_exempt(path: &str) -> bool {
+    matches!(path, "/healthz/" | "/readyz/" | "/api/v1/auth/login/")
+        || path.starts_with("/api/v1/admin/")
+}
+
+middleware!(
+    maintenance_middleware,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        let Some(maintenance) = request.state::<Maintenance>() else {
+            return Next::Continue;
+        };
+        // Preflights go through, so browsers get to read the 503 of the request itself
+        if !maintenance.is_enabled()
+            || is_exempt(&request.path)
+            || request.method == HttpMethod::OPTIONS
+        {
+            return Next::Continue;
+        }
+
+        let retry_after = maintenance.retry_after_secs.to_string();
+        response.status(503);
+        response.add_header("Retry-After", &retry_after);
+        if response.negotiate(request, &["application/json", "text/html"]) == Some("text/html") {
+            let site_url = pages::site_url();
+            let variables = [
+                ("title", "Down for maintenance"),
+                ("site_url", site_url.as_str()),
+                ("retry_after", retry_after.as_str()),
+            ];
+            // Only an overridden maintenance.html can fail to render
+            if let Err(e) = response.render(pages::pages().get("maintenance.html"), &variables) {
+                error!("Could not render maintenance.html: {}", e);
+                response.message("down for maintenance");
+            }
+        } else {
+            response.message("down for maintenance");
+        }
+        Next::Stop
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_health_and_admin_up() {
+        for path in ["/healthz/", "/readyz/", "/api/v1/admin/maintenance/"] {
+            assert!(is_exempt(path), "{}", path);
+        }
+        for path in ["/", "/api/v1/send_email/", "/api/v1/administrator/"] {
+            assert!(!is_exempt(path), "{}", path);
+        }
+    }
+}
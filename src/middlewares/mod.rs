@@ -1,3 +1,20 @@
+mod admin_auth;
+mod auth;
+mod canonical_host;
 mod cors;
+mod maintenance;
+mod rate_limit;
+mod security_headers;
+mod session;
 
-pub use cors::cors_middleware;
+pub use admin_auth::{admin_auth_middleware, ADMIN_SESSION_KEY};
+#[allow(unused_imports)]
+pub use auth::{
+    basic_auth_middleware, bearer_auth_middleware, constant_time_eq, jwt_auth_middleware, Principal,
+};
+pub use canonical_host::canonical_host_middleware;
+pub use cors::{cors_middleware, CorsConfig};
+pub use maintenance::maintenance_middleware;
+pub use rate_limit::{rate_limit_middleware, RateLimit, RateLimitConfig};
+pub use security_headers::security_headers_middleware;
+pub use session::{MemorySessionStore, RequestSession, SessionMiddleware};
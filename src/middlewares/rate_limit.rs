@@ -0,0 +1,338 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kblue_http::{HttpMethod, Middleware, Next};
+use tracing::warn;
+
+const SHARDS: usize = 16;
+// A new client past this many buckets makes the shard drop the ones which have refilled, then the
+// least recently used, so neither idle clients nor a flood of addresses can grow it without bound
+const MAX_BUCKETS_PER_SHARD: usize = 4096;
+// Evicting down to this leaves room for plenty of new clients before the next sweep
+const EVICT_TO: usize = MAX_BUCKETS_PER_SHARD * 3 / 4;
+
+// `capacity` requests per `period`, allowing bursts of up to `capacity`
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub fn per_minute(capacity: u32) -> Self {
+        Self {
+            capacity,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    pub fn per_hour(capacity: u32) -> Self {
+        Self {
+            capacity,
+            period: Duration::from_secs(60 * 60),
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    default: RateLimit,
+    // Checked in order, a path matches if it starts with the prefix
+    overrides: Vec<(String, RateLimit)>,
+}
+
+impl RateLimitConfig {
+    pub fn new(default: RateLimit) -> Self {
+        Self {
+            default,
+            overrides: Vec::new(),
+        }
+    }
+
+    // Requests under the path prefix get their own, separate, bucket with this limit
+    pub fn route(mut self, path_prefix: &str, limit: RateLimit) -> Self {
+        let path_prefix = path_prefix.trim_end_matches('/').to_string() + "/";
+        self.overrides.push((path_prefix, limit));
+        self
+    }
+
+    // The bucket key is the override prefix, or empty for the default limit
+    fn limit_for(&self, path: &str) -> (&str, RateLimit) {
+        self.overrides
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map_or(("", self.default), |(prefix, limit)| (prefix, *limit))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    // Also when the client was last seen, for evicting the least recently used
+    updated_at: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.refill_per_second())
+            .min(self.limit.capacity as f64);
+        self.updated_at = now;
+    }
+
+    // Without refilling, which would lose when the bucket was last used
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens + elapsed * self.limit.refill_per_second() >= self.limit.capacity as f64
+    }
+}
+
+// Token buckets keyed by client and limit, split into shards so concurrent requests rarely
+// contend on the same lock
+struct RateLimiter {
+    shards: Vec<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    // Ok if the request may go ahead, otherwise how long until a token is available
+    fn try_acquire(&self, key: String, limit: &RateLimit) -> Result<(), Duration> {
+        self.try_acquire_at(key, limit, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: String, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let shard = &self.shards[hasher.finish() as usize % SHARDS];
+        let mut buckets = shard.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS_PER_SHARD && !buckets.contains_key(&key) {
+            evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+            limit: *limit,
+        });
+        bucket.refill(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / limit.refill_per_second();
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+// Down to EVICT_TO buckets, refilled ones first as forgetting them changes nothing. Then the least
+// recently used, which may let a client that's gone quiet start over with a full bucket
+fn evict(buckets: &mut HashMap<String, Bucket>, now: Instant) {
+    buckets.retain(|_, bucket| !bucket.is_full_at(now));
+    if buckets.len() <= EVICT_TO {
+        return;
+    }
+    let mut last_used: Vec<Instant> = buckets.values().map(|bucket| bucket.updated_at).collect();
+    let excess = buckets.len() - EVICT_TO;
+    let (_, cutoff, _) = last_used.select_nth_unstable(excess - 1);
+    let cutoff = *cutoff;
+    buckets.retain(|_, bucket| bucket.updated_at > cutoff);
+}
+
+// IPv6 clients usually get a whole /64, so each address in it would otherwise have its own bucket
+fn client_key(client_ip: Option<IpAddr>) -> String {
+    // Mapped IPv4 addresses are keyed as the IPv4 address they are
+    match client_ip.map(|ip| ip.to_canonical()) {
+        Some(IpAddr::V6(ip)) => {
+            let network = Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128));
+            format!("{}/64", network)
+        }
+        Some(ip) => ip.to_string(),
+        // Unix socket clients with no forwarding headers all share one bucket
+        None => "unknown".to_string(),
+    }
+}
+
+pub fn rate_limit_middleware(config: RateLimitConfig) -> impl Middleware {
+    let config = Arc::new(config);
+    let limiter = Arc::new(RateLimiter::new());
+    move |request, response| {
+        let config = config.clone();
+        let limiter = limiter.clone();
+        Box::pin(async move {
+            // Pre flights would otherwise use up the budget of the request they precede
+            if request.method == HttpMethod::OPTIONS {
+                return Next::Continue;
+            }
+            let client_ip = client_key(request.client_ip);
+            let (bucket, limit) = config.limit_for(&request.path);
+
+            let Err(retry_after) = limiter.try_acquire(format!("{} {}", client_ip, bucket), &limit)
+            else {
+                return Next::Continue;
+            };
+            warn!(client_ip, path = request.path, "Rate limited");
+            response.add_header("Retry-After", &retry_after.as_secs_f64().ceil().to_string());
+            response.status(429).message("too many requests");
+            Next::Stop
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kblue_http::{route, routes, Request, RequestParam, ResponseParam, Router, Server};
+
+    use super::*;
+
+    route!(
+        ok_handler,
+        async move |_request: RequestParam, mut response: ResponseParam| {
+            response.text("ok");
+            response.send();
+            Ok(())
+        }
+    );
+
+    fn server(config: RateLimitConfig) -> Server {
+        let mut server = Server::builder().build();
+        server.add_middleware(rate_limit_middleware(config));
+        server
+            .add_routes(routes! {
+                GET "/projects" => ok_handler,
+                POST "/api/v1/login" => ok_handler,
+            })
+            .unwrap();
+        server
+    }
+
+    fn request(method: HttpMethod, path: &str, client: &str) -> Request {
+        Request {
+            method,
+            path: path.to_string(),
+            uri: path.to_string(),
+            remote_addr: Some(client.parse().unwrap()),
+            ..Request::default()
+        }
+    }
+
+    #[test]
+    fn refills_over_the_period() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit::per_minute(2);
+        let start = Instant::now();
+        let acquire = |after: u64| {
+            limiter.try_acquire_at(
+                "client".to_string(),
+                &limit,
+                start + Duration::from_secs(after),
+            )
+        };
+        assert!(acquire(0).is_ok());
+        assert!(acquire(0).is_ok());
+        assert_eq!(acquire(0), Err(Duration::from_secs(30)));
+        assert!(acquire(30).is_ok());
+        assert!(acquire(30).is_err());
+        // Never more than the capacity, however long the client was away
+        assert!(acquire(3600).is_ok());
+        assert!(acquire(3600).is_ok());
+        assert!(acquire(3600).is_err());
+    }
+
+    #[tokio::test]
+    async fn responds_429_with_retry_after() {
+        let server = server(RateLimitConfig::new(RateLimit::per_minute(1)));
+        let client = "203.0.113.9:5000";
+        let response = server
+            .handle(request(HttpMethod::GET, "/projects", client))
+            .await;
+        assert_eq!(response.status_code, 200);
+        let response = server
+            .handle(request(HttpMethod::GET, "/projects", client))
+            .await;
+        assert_eq!(response.status_code, 429);
+        assert_eq!(response.headers.get("Retry-After").unwrap(), "60");
+        // Pre flights don't count
+        let response = server
+            .handle(request(HttpMethod::OPTIONS, "/projects", client))
+            .await;
+        assert_ne!(response.status_code, 429);
+        // Other clients have their own budget
+        let response = server
+            .handle(request(HttpMethod::GET, "/projects", "203.0.113.10:5000"))
+            .await;
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[tokio::test]
+    async fn limits_overridden_routes_separately() {
+        let config = RateLimitConfig::new(RateLimit::per_minute(1))
+            .route("/api/v1/login", RateLimit::per_hour(2));
+        let server = server(config);
+        let client = "203.0.113.9:5000";
+        let login = || request(HttpMethod::POST, "/api/v1/login/", client);
+        assert_eq!(server.handle(login()).await.status_code, 200);
+        assert_eq!(server.handle(login()).await.status_code, 200);
+        let response = server.handle(login()).await;
+        assert_eq!(response.status_code, 429);
+        assert_eq!(response.headers.get("Retry-After").unwrap(), "1800");
+        // The default budget is untouched by the logins
+        let projects = || request(HttpMethod::GET, "/projects", client);
+        assert_eq!(server.handle(projects()).await.status_code, 200);
+        assert_eq!(server.handle(projects()).await.status_code, 429);
+    }
+
+    #[test]
+    fn keys_ipv6_clients_by_their_64() {
+        let key = |ip: &str| client_key(Some(ip.parse().unwrap()));
+        assert_eq!(key("2001:db8:1:2:aaaa::1"), "2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:2:bbbb::9"), key("2001:db8:1:2:aaaa::1"));
+        assert_ne!(key("2001:db8:1:3::1"), key("2001:db8:1:2::1"));
+        assert_eq!(key("::ffff:203.0.113.9"), "203.0.113.9");
+        assert_eq!(key("203.0.113.9"), "203.0.113.9");
+        assert_eq!(client_key(None), "unknown");
+    }
+
+    #[test]
+    fn evicts_past_the_cap() {
+        let limiter = RateLimiter::new();
+        let limit = RateLimit::per_hour(10);
+        let start = Instant::now();
+        // Every bucket has been used, so none have refilled
+        for i in 0..SHARDS * MAX_BUCKETS_PER_SHARD * 2 {
+            let now = start + Duration::from_millis(i as u64);
+            limiter.try_acquire_at(i.to_string(), &limit, now).unwrap();
+        }
+        for shard in limiter.shards.iter() {
+            assert!(shard.lock().unwrap().len() <= MAX_BUCKETS_PER_SHARD);
+        }
+
+        // Refilled buckets go first, keeping the client still using up its budget
+        let mut buckets = HashMap::new();
+        for i in 0..MAX_BUCKETS_PER_SHARD {
+            let bucket = Bucket {
+                tokens: if i == 0 { 0.0 } else { 10.0 },
+                updated_at: start,
+                limit,
+            };
+            buckets.insert(i.to_string(), bucket);
+        }
+        evict(&mut buckets, start);
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains_key("0"));
+    }
+}
@@ -0,0 +1,95 @@
+use kblue_http::{middleware, Next, Request, RequestParam, Response, ResponseParam};
+
+use crate::security::{security_config, SecurityConfig};
+
+// The Host header without its port. IPv6 addresses are bracketed, e.g. [::1]:8080, so only a
+// colon after the closing bracket starts the port
+fn host_name(host: &str) -> &str {
+    let name_end = host.rfind(']').map_or(0, |bracket| bracket + 1);
+    match host[name_end..].find(':') {
+        Some(colon) => &host[..name_end + colon],
+        None => host,
+    }
+}
+
+fn apply_security_headers(
+    security: &SecurityConfig,
+    request: &Request,
+    response: &mut Response,
+) -> Next {
+    let host = request.get_header("host").cloned().unwrap_or_default();
+    // Ignore the port, the allow list is of host names
+    if !security.is_host_allowed(host_name(&host)) {
+        response.status(421).message("misdirected request");
+        return Next::Stop;
+    }
+
+    if let Some(hsts) = &security.hsts {
+        response.add_header("Strict-Transport-Security", hsts);
+    }
+    Next::Continue
+}
+
+middleware!(
+    security_headers_middleware,
+    async move |request: RequestParam, mut response: ResponseParam| {
+        apply_security_headers(security_config(), request, response)
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::SecurityPreset;
+
+    fn production(allowed_hosts: &[&str]) -> SecurityConfig {
+        SecurityPreset::Production {
+            origins: Vec::new(),
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+        }
+        .config()
+    }
+
+    fn apply(security: &SecurityConfig, host: &str) -> (Next, Response) {
+        let mut request = Request::default();
+        request.headers.insert("Host", host);
+        let mut response = Response::default();
+        let next = apply_security_headers(security, &request, &mut response);
+        (next, response)
+    }
+
+    #[test]
+    fn strips_the_port_from_hosts() {
+        assert_eq!(host_name("kblue.io"), "kblue.io");
+        assert_eq!(host_name("kblue.io:443"), "kblue.io");
+        assert_eq!(host_name("[::1]"), "[::1]");
+        assert_eq!(host_name("[::1]:8080"), "[::1]");
+        assert_eq!(host_name(""), "");
+    }
+
+    #[test]
+    fn only_answers_allowed_hosts() {
+        let security = production(&["kblue.io", "[::1]"]);
+        for host in ["kblue.io", "KBLUE.io:443", "[::1]", "[::1]:8080"] {
+            let (next, response) = apply(&security, host);
+            assert_eq!(next, Next::Continue, "{}", host);
+            assert_eq!(
+                response.headers.get("Strict-Transport-Security").unwrap(),
+                "max-age=31536000; includeSubDomains"
+            );
+        }
+        for host in ["evil.com", "evil.com:443", "[::2]:8080", ""] {
+            let (next, response) = apply(&security, host);
+            assert_eq!(next, Next::Stop, "{}", host);
+            assert_eq!(response.status_code, 421);
+            assert!(response.headers.get("Strict-Transport-Security").is_none());
+        }
+    }
+
+    #[test]
+    fn dev_allows_any_host_without_hsts() {
+        let (next, response) = apply(&SecurityPreset::DevLocalhost.config(), "[::1]:3000");
+        assert_eq!(next, Next::Continue);
+        assert!(response.headers.get("Strict-Transport-Security").is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use kblue_http::{Middleware, Next, Request};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::security::security_config;
+
+pub type SessionData = HashMap<String, Value>;
+
+// Where sessions live between requests. Implement this for e.g. Redis to share sessions between
+// instances, MemorySessionStore is enough for a single one
+pub trait SessionStore: Send + Sync {
+    // None if the session doesn't exist or has expired
+    fn load(&self, id: &str) -> Option<SessionData>;
+    // Creates or replaces the session, which expires `ttl` from now
+    fn save(&self, id: &str, data: SessionData, ttl: Duration);
+    fn destroy(&self, id: &str);
+}
+
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some((data, expires_at)) if *expires_at > Instant::now() => Some(data.clone()),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn save(&self, id: &str, data: SessionData, ttl: Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        // Sessions which are never loaded again would otherwise pile up
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        sessions.insert(id.to_string(), (data, now + ttl));
+    }
+
+    fn destroy(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    // None until something is stored, so visitors who never log in don't get a cookie
+    id: Option<String>,
+    // The id sent by the client, removed from the store if the session is regenerated or destroyed
+    previous_id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    destroyed: bool,
+}
+
+// The current request's session, from request.session(). Changes are saved once the response is
+// ready, by SessionMiddleware::save
+#[derive(Clone, Default)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+impl Session {
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let state = self.0.lock().unwrap();
+        serde_json::from_value(state.data.get(key)?.clone()).ok()
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<(), serde_json::Error> {
+        let value = serde_json::to_value(value)?;
+        let mut state = self.0.lock().unwrap();
+        state.data.insert(key.to_string(), value);
+        state.changed = true;
+        state.destroyed = false;
+        Ok(())
+    }
+
+    // Gives the session a new id, keeping its data. Call on login, so an id planted before
+    // logging in (session fixation) is useless afterwards
+    pub fn regenerate(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.id = None;
+        state.changed = true;
+    }
+
+    // Removes the session from the store and clears the cookie, e.g. on logout
+    pub fn destroy(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.data.clear();
+        state.id = None;
+        state.destroyed = true;
+    }
+}
+
+// request.session(), since Request belongs to kblue_http
+pub trait RequestSession {
+    // Set by SessionMiddleware::load, None on routes without it
+    fn session(&self) -> Option<Session>;
+}
+
+impl RequestSession for Request {
+    fn session(&self) -> Option<Session> {
+        self.extensions.get::<Session>().cloned()
+    }
+}
+
+struct SessionConfig {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    // How long a session lasts since it was last changed
+    ttl: Duration,
+    // Path, HttpOnly, SameSite and Secure, from the security preset
+    cookie_attributes: String,
+}
+
+// Cookie backed sessions. Add `load()` to the routes that use sessions and `save()` as an after
+// middleware, which writes any changes back and sets the cookie:
+// `server.add_middleware(sessions.load()); server.add_after_middleware(sessions.save());`
+#[derive(Clone)]
+pub struct SessionMiddleware {
+    config: Arc<SessionConfig>,
+}
+
+impl SessionMiddleware {
+    pub fn new(store: impl SessionStore + 'static) -> Self {
+        Self {
+            config: Arc::new(SessionConfig {
+                store: Arc::new(store),
+                cookie_name: "session".to_string(),
+                ttl: Duration::from_secs(60 * 60 * 24),
+                cookie_attributes: security_config().cookie_attributes(),
+            }),
+        }
+    }
+
+    // Instead of the security preset's, so tests don't depend on SECURITY_PRESET
+    #[cfg(test)]
+    pub fn cookie_attributes(mut self, attributes: &str) -> Self {
+        Arc::get_mut(&mut self.config)
+            .expect("SessionMiddleware is configured before it's used")
+            .cookie_attributes = attributes.to_string();
+        self
+    }
+
+    pub fn load(&self) -> impl Middleware {
+        let config = self.config.clone();
+        move |request, _response| {
+            let config = config.clone();
+            Box::pin(async move {
+                let mut state = SessionState::default();
+                if let Some(id) = request.cookie(&config.cookie_name) {
+                    if let Some(data) = config.store.load(id) {
+                        state.id = Some(id.to_string());
+                        state.data = data;
+                    }
+                    state.previous_id = Some(id.to_string());
+                }
+                request
+                    .extensions
+                    .insert(Session(Arc::new(Mutex::new(state))));
+                Next::Continue
+            })
+        }
+    }
+
+    pub fn save(&self) -> impl Middleware {
+        let config = self.config.clone();
+        move |request, response| {
+            let config = config.clone();
+            Box::pin(async move {
+                let Some(session) = request.session() else {
+                    return Next::Continue;
+                };
+                if let Some(cookie) = config.persist(&session) {
+                    response.append_header("Set-Cookie", &cookie);
+                }
+                Next::Continue
+            })
+        }
+    }
+}
+
+impl SessionConfig {
+    // Writes the session's changes to the store, returning the Set-Cookie value if the client's
+    // cookie needs updating
+    fn persist(&self, session: &Session) -> Option<String> {
+        let mut state = session.0.lock().unwrap();
+        let previous_id = state.previous_id.clone();
+        if state.destroyed {
+            let previous_id = previous_id?;
+            self.store.destroy(&previous_id);
+            return Some(self.cookie(&previous_id, Duration::ZERO));
+        }
+        if !state.changed {
+            return None;
+        }
+
+        let id = state.id.get_or_insert_with(new_session_id).clone();
+        if let Some(previous_id) = previous_id.filter(|previous_id| *previous_id != id) {
+            self.store.destroy(&previous_id);
+        }
+        self.store.save(&id, state.data.clone(), self.ttl);
+        Some(self.cookie(&id, self.ttl))
+    }
+
+    fn cookie(&self, id: &str, max_age: Duration) -> String {
+        format!(
+            "{}={}; Max-Age={}; {}",
+            self.cookie_name,
+            id,
+            max_age.as_secs(),
+            self.cookie_attributes
+        )
+    }
+}
+
+// 256 random bits, so ids can't be guessed
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the OS random number generator failed");
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::SecurityPreset;
+    use kblue_http::Response;
+
+    // Runs load, lets `handle` use the session, then runs save. Returns the Set-Cookie header
+    async fn request(
+        sessions: &SessionMiddleware,
+        cookie: Option<&str>,
+        handle: impl FnOnce(&Session),
+    ) -> Option<String> {
+        let mut request = Request::default();
+        if let Some(cookie) = cookie {
+            request.headers.insert("Cookie", cookie);
+        }
+        let mut response = Response::new();
+        sessions.load()(&mut request, &mut response).await;
+        handle(&request.session().unwrap());
+        sessions.save()(&mut request, &mut response).await;
+        response.headers.get("set-cookie").cloned()
+    }
+
+    fn cookie_pair(set_cookie: &str) -> String {
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn sessions_persist_between_requests() {
+        let sessions = SessionMiddleware::new(MemorySessionStore::new())
+            .cookie_attributes(&SecurityPreset::DevLocalhost.config().cookie_attributes());
+
+        assert_eq!(request(&sessions, None, |_| {}).await, None);
+
+        let set_cookie = request(&sessions, None, |session| {
+            session.insert("user", "kyle").unwrap();
+        })
+        .await
+        .unwrap();
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax"));
+        let cookie = cookie_pair(&set_cookie);
+
+        request(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get::<String>("user").as_deref(), Some("kyle"));
+        })
+        .await;
+
+        let set_cookie = request(&sessions, Some(&cookie), |session| session.destroy())
+            .await
+            .unwrap();
+        assert!(set_cookie.contains("Max-Age=0"));
+        request(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get::<String>("user"), None);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn regenerate_replaces_the_id() {
+        let sessions = SessionMiddleware::new(MemorySessionStore::new());
+        let cookie = cookie_pair(
+            &request(&sessions, None, |session| {
+                session.insert("user", "kyle").unwrap();
+            })
+            .await
+            .unwrap(),
+        );
+
+        let new_cookie = cookie_pair(
+            &request(&sessions, Some(&cookie), |session| session.regenerate())
+                .await
+                .unwrap(),
+        );
+        assert_ne!(cookie, new_cookie);
+        request(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get::<String>("user"), None);
+        })
+        .await;
+        request(&sessions, Some(&new_cookie), |session| {
+            assert_eq!(session.get::<String>("user").as_deref(), Some("kyle"));
+        })
+        .await;
+    }
+
+    #[test]
+    fn memory_store_expires_sessions() {
+        let store = MemorySessionStore::new();
+        store.save("a", SessionData::new(), Duration::ZERO);
+        store.save("b", SessionData::new(), Duration::from_secs(60));
+        assert!(store.load("a").is_none());
+        assert!(store.load("b").is_some());
+    }
+}
@@ -0,0 +1,262 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use kblue_http::client::{self, ClientResponse};
+use kblue_http::StatusCode;
+use serde_json::{json, Value};
+use tracing::{error, warn};
+use url::Url;
+
+// Chat notifications for each contact form submission, alongside the email to me, and alerts
+// about the backend itself. Targets are NOTIFY_WEBHOOKS, a comma separated list of Discord or
+// Slack incoming webhook URLs, or any other URL which is sent the submission as plain JSON
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// Longest Retry-After we'll wait for
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Discord rejects longer content, Slack truncates it
+const MAX_MESSAGE_CHARS: usize = 1500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WebhookKind {
+    Discord,
+    Slack,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+struct Webhook {
+    url: String,
+    kind: WebhookKind,
+}
+
+pub struct Notification {
+    pub name: String,
+    pub email: String,
+    pub message: String,
+    pub labels: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Notifier {
+    webhooks: Arc<Vec<Webhook>>,
+    // Including the first, per webhook
+    max_attempts: u32,
+}
+
+impl Webhook {
+    fn parse(url: &str) -> Result<Self, String> {
+        let parsed = Url::parse(url).map_err(|_| format!("Invalid webhook URL: {}", url))?;
+        let kind = match parsed.host_str() {
+            Some("discord.com" | "discordapp.com") => WebhookKind::Discord,
+            Some("hooks.slack.com") => WebhookKind::Slack,
+            _ => WebhookKind::Json,
+        };
+        Ok(Self {
+            url: url.to_string(),
+            kind,
+        })
+    }
+
+    fn payload(&self, notification: &Notification) -> Value {
+        if self.kind == WebhookKind::Json {
+            return json!({
+                "name": notification.name,
+                "email": notification.email,
+                "message": notification.message,
+                "labels": notification.labels,
+            });
+        }
+        let labels: String = notification
+            .labels
+            .iter()
+            .map(|label| format!("[{}] ", label))
+            .collect();
+        let mut message: String = notification
+            .message
+            .chars()
+            .take(MAX_MESSAGE_CHARS)
+            .collect();
+        if message.len() < notification.message.len() {
+            message.push('…');
+        }
+        let text = format!(
+            "{}New message from {} ({}):\n>>> {}",
+            labels, notification.name, notification.email, message
+        );
+        self.text_payload(&text)
+    }
+
+    fn alert_payload(&self, alert: &str) -> Value {
+        match self.kind {
+            WebhookKind::Json => json!({ "alert": alert }),
+            _ => self.text_payload(alert),
+        }
+    }
+
+    fn text_payload(&self, text: &str) -> Value {
+        match self.kind {
+            // Don't let a message @everyone
+            WebhookKind::Discord => json!({ "content": text, "allowed_mentions": { "parse": [] } }),
+            _ => json!({ "text": text }),
+        }
+    }
+}
+
+impl Notifier {
+    pub fn from_env() -> Result<Self, String> {
+        let webhooks = env::var("NOTIFY_WEBHOOKS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(Webhook::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let max_attempts = match env::var("NOTIFY_MAX_ATTEMPTS") {
+            Ok(attempts) => attempts
+                .parse::<u32>()
+                .ok()
+                .filter(|attempts| *attempts > 0)
+                .ok_or(format!(
+                    "NOTIFY_MAX_ATTEMPTS must be at least 1: {}",
+                    attempts
+                ))?,
+            Err(_) => DEFAULT_MAX_ATTEMPTS,
+        };
+        Ok(Self {
+            webhooks: Arc::new(webhooks),
+            max_attempts,
+        })
+    }
+
+    // Posts to every webhook in the background, so a slow one never holds up the submission
+    pub fn notify(&self, notification: Notification) {
+        for webhook in self.webhooks.iter() {
+            let webhook = webhook.clone();
+            let payload = webhook.payload(&notification);
+            let max_attempts = self.max_attempts;
+            tokio::spawn(async move { deliver(&webhook, &payload, max_attempts).await });
+        }
+    }
+
+    // A one line message about the backend itself, e.g. a route running slow
+    pub fn alert(&self, alert: &str) {
+        for webhook in self.webhooks.iter() {
+            let webhook = webhook.clone();
+            let payload = webhook.alert_payload(alert);
+            let max_attempts = self.max_attempts;
+            tokio::spawn(async move { deliver(&webhook, &payload, max_attempts).await });
+        }
+    }
+}
+
+async fn deliver(webhook: &Webhook, payload: &Value, max_attempts: u32) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=max_attempts {
+        let error = match client::post_json(&webhook.url, payload).await {
+            Ok(response) if response.is_success() => return,
+            Ok(response) if !is_retryable(&response) => {
+                error!(
+                    "Webhook notification to {:?} failed with {}",
+                    webhook.kind, response.status_code
+                );
+                return;
+            }
+            Ok(response) => {
+                if let Some(retry_after) = retry_after(&response) {
+                    backoff = retry_after;
+                }
+                format!("responded with {}", response.status_code)
+            }
+            Err(e) => e.to_string(),
+        };
+        if attempt == max_attempts {
+            error!(
+                "Webhook notification to {:?} failed after {} attempt(s): {}",
+                webhook.kind, attempt, error
+            );
+            return;
+        }
+        warn!(
+            "Webhook notification to {:?} failed, retrying in {:?}: {}",
+            webhook.kind, backoff, error
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Rate limited or a server error, anything else won't go differently next time
+fn is_retryable(response: &ClientResponse) -> bool {
+    let status = StatusCode::from(response.status_code);
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Seconds, as both Discord and Slack send it
+fn retry_after(response: &ClientResponse) -> Option<Duration> {
+    let seconds = response
+        .headers
+        .get("retry-after")?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    Some(Duration::from_secs_f64(seconds.max(0.0)).min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn notification(message: &str) -> Notification {
+        Notification {
+            name: "Kyle".to_string(),
+            email: "kyle@kblue.io".to_string(),
+            message: message.to_string(),
+            labels: vec!["SPAM".to_string()],
+        }
+    }
+
+    #[test]
+    fn payloads_match_each_service() {
+        let discord = Webhook::parse("https://discord.com/api/webhooks/1/abc").unwrap();
+        let payload = discord.payload(&notification("Hi"));
+        assert_eq!(
+            payload["content"],
+            "[SPAM] New message from Kyle (kyle@kblue.io):\n>>> Hi"
+        );
+
+        let slack = Webhook::parse("https://hooks.slack.com/services/T/B/X").unwrap();
+        let payload = slack.payload(&notification(&"a".repeat(2000)));
+        assert!(payload["text"].as_str().unwrap().ends_with("a…"));
+
+        let other = Webhook::parse("https://example.com/hook").unwrap();
+        assert_eq!(other.payload(&notification("Hi"))["labels"][0], "SPAM");
+        assert_eq!(other.alert_payload("Slow")["alert"], "Slow");
+        assert_eq!(discord.alert_payload("Slow")["content"], "Slow");
+        assert!(Webhook::parse("not a url").is_err());
+    }
+
+    #[test]
+    fn retries_rate_limits_and_server_errors() {
+        let response = |status_code, retry_after: Option<&str>| ClientResponse {
+            status_code,
+            headers: retry_after
+                .map(|value| HashMap::from([("retry-after".to_string(), value.to_string())]))
+                .unwrap_or_default(),
+            header_list: Vec::new(),
+            body: Vec::new(),
+        };
+        assert!(is_retryable(&response(429, None)));
+        assert!(is_retryable(&response(502, None)));
+        assert!(!is_retryable(&response(404, None)));
+        assert_eq!(
+            retry_after(&response(429, Some("1.5"))),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_after(&response(429, Some("600"))), Some(MAX_BACKOFF));
+    }
+}
@@ -0,0 +1,86 @@
+use std::env;
+use std::sync::Arc;
+
+use kblue_http::{render_json_error, HandlerError, Request, Response, StatusCode, Templates};
+use tracing::error;
+
+use crate::reload::{ReloadResult, ReloadableTemplates};
+
+// Server rendered pages, for clients that can't use the JSON API, e.g. a form posted without
+// JavaScript. Every page takes a title and site_url. TEMPLATES_DIR/pages can override them
+const PAGES: Templates = Templates::new(&[
+    ("error.html", include_str!("templates/error.html")),
+    (
+        "maintenance.html",
+        include_str!("templates/maintenance.html"),
+    ),
+    (
+        "message_sent.html",
+        include_str!("templates/message_sent.html"),
+    ),
+    (
+        "partials/header.html",
+        include_str!("templates/partials/header.html"),
+    ),
+    (
+        "partials/footer.html",
+        include_str!("templates/partials/footer.html"),
+    ),
+]);
+
+static RELOADABLE_PAGES: ReloadableTemplates = ReloadableTemplates::new(PAGES, "pages");
+
+// e.g. `response.render(pages::pages().get("message_sent.html"), &variables)`
+pub fn pages() -> Arc<Templates> {
+    RELOADABLE_PAGES.get()
+}
+
+pub fn reload() -> ReloadResult {
+    RELOADABLE_PAGES.reload()
+}
+
+// The site the pages link back to
+pub fn site_url() -> String {
+    env::var("SITE_URL").unwrap_or("https://kblue.io".to_string())
+}
+
+// Set for 404s and 5xx errors, so a mistyped link shows the site rather than bare JSON. API
+// clients still get JSON
+pub fn error_page(error: &HandlerError, request: &Request, response: &mut Response) {
+    if response.negotiate(request, &["application/json", "text/html"]) != Some("text/html") {
+        return render_json_error(error, response);
+    }
+    let title = StatusCode::from(error.status).to_string();
+    let site_url = site_url();
+    let variables = [
+        ("title", title.as_str()),
+        ("site_url", site_url.as_str()),
+        ("message", error.message.as_str()),
+    ];
+    response.status(error.status);
+    // Only an overridden error.html can fail to render
+    if let Err(e) = response.render(pages().get("error.html"), &variables) {
+        error!("Could not render error.html: {}", e);
+        render_json_error(error, response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_render() {
+        let variables = [
+            ("title", "t"),
+            ("site_url", "https://kblue.io"),
+            ("name", "n"),
+            ("email", "e"),
+            ("retry_after", "60"),
+            ("message", "m"),
+        ];
+        for name in PAGES.names() {
+            PAGES.render(name, &variables).unwrap();
+        }
+    }
+}
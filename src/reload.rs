@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use kblue_http::Templates;
+use once_cell::sync::Lazy;
+use strum_macros::{Display, EnumIter, EnumString};
+use tracing::error;
+
+// In-memory registries which can be rebuilt at runtime without restarting the container
+#[derive(Display, EnumString, EnumIter, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "lowercase")]
+pub enum ReloadTarget {
+    Templates,
+    Projects,
+    Blocklists,
+}
+
+/// Rebuilds a registry and swaps it in as a whole, so readers never see a half loaded state.
+/// Returns the names of entries which were added, removed or modified.
+pub type ReloadResult = Result<Vec<String>, String>;
+
+type Reloader = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = ReloadResult> + Send>> + Send + Sync>;
+
+static RELOADERS: Lazy<RwLock<HashMap<ReloadTarget, Reloader>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Async, since some registries are read from the database
+pub fn register_reloader<F, Fut>(target: ReloadTarget, reloader: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ReloadResult> + Send + 'static,
+{
+    let reloader: Reloader = Arc::new(move || Box::pin(reloader()));
+    RELOADERS.write().unwrap().insert(target, reloader);
+}
+
+// None if nothing has registered a reloader for the target
+pub async fn reload(target: ReloadTarget) -> Option<ReloadResult> {
+    let reloader = RELOADERS.read().unwrap().get(&target)?.clone();
+    Some(reloader().await)
+}
+
+// Keys added, removed or with a different value, in order
+pub fn changed_entries<K: Ord + Clone, V: PartialEq>(
+    old: &BTreeMap<K, V>,
+    new: &BTreeMap<K, V>,
+) -> Vec<K> {
+    let removed = old.keys().filter(|key| !new.contains_key(*key));
+    let added_or_modified = new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(*value))
+        .map(|(key, _)| key);
+    let mut changed: Vec<K> = removed.chain(added_or_modified).cloned().collect();
+    changed.sort();
+    changed
+}
+
+// Templates compiled into the binary, which files under TEMPLATES_DIR/<subdirectory> override
+// when set, e.g. TEMPLATES_DIR/email/new_message.html. Read on first use and again on reload
+pub struct ReloadableTemplates {
+    bundled: Templates,
+    subdirectory: &'static str,
+    current: RwLock<Option<Arc<Templates>>>,
+}
+
+impl ReloadableTemplates {
+    pub const fn new(bundled: Templates, subdirectory: &'static str) -> Self {
+        Self {
+            bundled,
+            subdirectory,
+            current: RwLock::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Templates> {
+        if let Some(current) = self.current.read().unwrap().as_ref() {
+            return current.clone();
+        }
+        let templates = Arc::new(self.load().unwrap_or_else(|e| {
+            error!("{}, using the bundled templates", e);
+            self.bundled.clone()
+        }));
+        *self.current.write().unwrap() = Some(templates.clone());
+        templates
+    }
+
+    // Names of the templates which changed, e.g. "email/new_message.html". A file which can't be
+    // read leaves the current templates in place
+    pub fn reload(&self) -> ReloadResult {
+        let templates = self.load()?;
+        let sources = |templates: &Templates| -> BTreeMap<String, String> {
+            templates
+                .sources()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect()
+        };
+        let mut current = self.current.write().unwrap();
+        // Never used yet, so what's served would have been the bundled ones
+        let old = current.as_deref().unwrap_or(&self.bundled);
+        let changed = changed_entries(&sources(old), &sources(&templates));
+        *current = Some(Arc::new(templates));
+        Ok(changed
+            .into_iter()
+            .map(|name| format!("{}/{}", self.subdirectory, name))
+            .collect())
+    }
+
+    fn load(&self) -> Result<Templates, String> {
+        match env::var("TEMPLATES_DIR") {
+            Ok(dir) => self
+                .bundled
+                .with_overrides(&Path::new(&dir).join(self.subdirectory)),
+            Err(_) => Ok(self.bundled.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reloads_registered_targets() {
+        static RELOADS: AtomicUsize = AtomicUsize::new(0);
+        register_reloader(ReloadTarget::Blocklists, || async {
+            RELOADS.fetch_add(1, Ordering::Relaxed);
+            Ok(vec!["spam keywords".to_string()])
+        });
+        assert_eq!(
+            reload(ReloadTarget::Blocklists).await,
+            Some(Ok(vec!["spam keywords".to_string()]))
+        );
+        assert_eq!(RELOADS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn lists_changed_entries() {
+        let old = BTreeMap::from([("a", 1), ("b", 2), ("c", 3)]);
+        let new = BTreeMap::from([("a", 1), ("b", 4), ("d", 5)]);
+        assert_eq!(changed_entries(&old, &new), ["b", "c", "d"]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::env;
+
+use once_cell::sync::OnceCell;
+use strum_macros::Display;
+
+use crate::config::{Config, Environment};
+
+// Bundles of related security settings, so CORS, cookies, HSTS and host checks can't drift apart
+#[derive(Clone, Debug)]
+pub enum SecurityPreset {
+    // Any origin and host, no HSTS, cookies usable over plain http
+    DevLocalhost,
+    Production {
+        origins: Vec<String>,
+        // Empty allows any Host header
+        allowed_hosts: Vec<String>,
+    },
+}
+
+#[derive(Display, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Clone, Debug)]
+pub struct SecurityConfig {
+    // None allows every origin. Entries may contain a `*` wildcard, see CorsConfig
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    // Value for the Strict-Transport-Security header
+    pub hsts: Option<String>,
+}
+
+impl SecurityPreset {
+    // ENVIRONMENT=dev selects DevLocalhost, anything else is production.
+    // ALLOWED_ORIGINS and ALLOWED_HOSTS are ", " separated lists
+    pub fn from_env() -> Self {
+        if env::var("ENVIRONMENT").unwrap_or("prod".to_string()) == "dev" {
+            return SecurityPreset::DevLocalhost;
+        }
+        SecurityPreset::Production {
+            origins: split_env_list("ALLOWED_ORIGINS"),
+            allowed_hosts: split_env_list("ALLOWED_HOSTS"),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        match config.environment {
+            Environment::Dev => SecurityPreset::DevLocalhost,
+            Environment::Prod => SecurityPreset::Production {
+                origins: config.cors.allowed_origins.clone(),
+                allowed_hosts: config.cors.allowed_hosts.clone(),
+            },
+        }
+    }
+
+    pub fn config(&self) -> SecurityConfig {
+        match self {
+            SecurityPreset::DevLocalhost => SecurityConfig {
+                allowed_origins: None,
+                allowed_hosts: None,
+                cookie_secure: false,
+                cookie_same_site: SameSite::Lax,
+                hsts: None,
+            },
+            SecurityPreset::Production {
+                origins,
+                allowed_hosts,
+            } => SecurityConfig {
+                allowed_origins: Some(origins.clone()),
+                allowed_hosts: (!allowed_hosts.is_empty()).then(|| allowed_hosts.clone()),
+                cookie_secure: true,
+                cookie_same_site: SameSite::Strict,
+                hsts: Some("max-age=31536000; includeSubDomains".to_string()),
+            },
+        }
+    }
+}
+
+impl SecurityConfig {
+    pub fn is_host_allowed(&self, host: &str) -> bool {
+        match &self.allowed_hosts {
+            Some(hosts) => hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host)),
+            None => true,
+        }
+    }
+
+    // Attributes to append to every Set-Cookie header
+    pub fn cookie_attributes(&self) -> String {
+        let mut attributes = format!("Path=/; HttpOnly; SameSite={}", self.cookie_same_site);
+        // Browsers reject SameSite=None without Secure
+        if self.cookie_secure || self.cookie_same_site == SameSite::None {
+            attributes.push_str("; Secure");
+        }
+        attributes
+    }
+}
+
+fn split_env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(", ")
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .collect()
+}
+
+static SECURITY_CONFIG: OnceCell<SecurityConfig> = OnceCell::new();
+
+pub fn init_security(preset: SecurityPreset) {
+    SECURITY_CONFIG
+        .set(preset.config())
+        .expect("Security preset already initialised");
+}
+
+pub fn security_config() -> &'static SecurityConfig {
+    SECURITY_CONFIG.get_or_init(|| SecurityPreset::from_env().config())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_decide_cookie_attributes() {
+        assert_eq!(
+            SecurityPreset::DevLocalhost.config().cookie_attributes(),
+            "Path=/; HttpOnly; SameSite=Lax"
+        );
+        let production = SecurityPreset::Production {
+            origins: vec!["https://kblue.io".to_string()],
+            allowed_hosts: Vec::new(),
+        };
+        assert_eq!(
+            production.config().cookie_attributes(),
+            "Path=/; HttpOnly; SameSite=Strict; Secure"
+        );
+        let cross_site = SecurityConfig {
+            cookie_secure: false,
+            cookie_same_site: SameSite::None,
+            ..SecurityPreset::DevLocalhost.config()
+        };
+        assert!(cross_site.cookie_attributes().ends_with("; Secure"));
+    }
+}
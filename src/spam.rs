@@ -0,0 +1,231 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::reload::changed_entries;
+
+// Heuristics run on every contact form submission before any email is sent. Configured with
+// SPAM_ACTION, SPAM_MAX_LINKS, SPAM_KEYWORDS (comma separated) and SPAM_DOMAIN_LIMIT, the most
+// submissions per email domain per hour. SPAM_KEYWORDS_PATH, a file with a keyword per line, takes
+// precedence over SPAM_KEYWORDS and is re-read by the admin reload endpoint
+
+const DEFAULT_MAX_LINKS: usize = 3;
+const DEFAULT_DOMAIN_LIMIT: usize = 10;
+const DOMAIN_WINDOW: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_KEYWORDS: &[&str] = &[
+    "backlinks",
+    "casino",
+    "crypto investment",
+    "seo services",
+    "viagra",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpamAction {
+    // Pretend it was sent, so the sender can't tell they were caught
+    Drop,
+    // Send the notification with [SPAM] in the subject, in case it's a false positive
+    Flag,
+    // 422, telling the sender why
+    Reject,
+}
+
+pub struct SpamFilter {
+    pub action: SpamAction,
+    max_links: usize,
+    keywords: RwLock<Vec<String>>,
+    // SPAM_KEYWORDS_PATH
+    keywords_path: Option<PathBuf>,
+    domain_limit: usize,
+    // Recent submission times per email domain
+    domains: Mutex<HashMap<String, Vec<Instant>>>,
+}
+
+pub struct Submission<'a> {
+    pub email: &'a str,
+    pub message: &'a str,
+    // A form field hidden from people, so only bots fill it in
+    pub honeypot: Option<&'a str>,
+}
+
+impl SpamFilter {
+    pub fn from_env() -> Result<Self, String> {
+        let number = |name: &str, default: usize| match env::var(name) {
+            Ok(value) => value
+                .parse::<usize>()
+                .map_err(|_| format!("{} is not a number: {}", name, value)),
+            Err(_) => Ok(default),
+        };
+        let keywords_path = env::var("SPAM_KEYWORDS_PATH").ok().map(PathBuf::from);
+        let keywords = match (&keywords_path, env::var("SPAM_KEYWORDS")) {
+            (Some(path), _) => read_keywords(path)?,
+            (None, Ok(keywords)) => normalise_keywords(keywords.split(',')),
+            (None, Err(_)) => DEFAULT_KEYWORDS.iter().map(|k| k.to_string()).collect(),
+        };
+        Ok(Self {
+            action: match env::var("SPAM_ACTION").as_deref() {
+                Ok("drop") => SpamAction::Drop,
+                Ok("flag") | Err(_) => SpamAction::Flag,
+                Ok("reject") => SpamAction::Reject,
+                Ok(other) => return Err(format!("Unknown SPAM_ACTION: {}", other)),
+            },
+            max_links: number("SPAM_MAX_LINKS", DEFAULT_MAX_LINKS)?,
+            keywords: RwLock::new(keywords),
+            keywords_path,
+            domain_limit: number("SPAM_DOMAIN_LIMIT", DEFAULT_DOMAIN_LIMIT)?,
+            domains: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Why the submission looks like spam, empty if it doesn't. Every submission counts towards
+    // its domain's limit
+    pub fn check(&self, submission: &Submission) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if submission
+            .honeypot
+            .is_some_and(|value| !value.trim().is_empty())
+        {
+            reasons.push("filled in the honeypot field".to_string());
+        }
+
+        let message = submission.message.to_lowercase();
+        let links = ["http://", "https://", "www."]
+            .iter()
+            .map(|prefix| message.matches(prefix).count())
+            .sum::<usize>();
+        if links > self.max_links {
+            reasons.push(format!("has {} links", links));
+        }
+        let keywords = self.keywords.read().unwrap();
+        if let Some(keyword) = keywords.iter().find(|k| message.contains(k.as_str())) {
+            reasons.push(format!("mentions \"{}\"", keyword));
+        }
+        drop(keywords);
+
+        let domain = submission
+            .email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase())
+            .unwrap_or_default();
+        let now = Instant::now();
+        let mut domains = self.domains.lock().unwrap();
+        domains.retain(|_, times| {
+            times.retain(|time| now.duration_since(*time) < DOMAIN_WINDOW);
+            !times.is_empty()
+        });
+        let times = domains.entry(domain.clone()).or_default();
+        times.push(now);
+        if times.len() > self.domain_limit {
+            reasons.push(format!(
+                "{} submissions from {} this hour",
+                times.len(),
+                domain
+            ));
+        }
+        reasons
+    }
+
+    // The keywords added to or removed from SPAM_KEYWORDS_PATH since it was last read. The old
+    // ones stay if it can't be read
+    pub fn reload_keywords(&self) -> Result<Vec<String>, String> {
+        // The environment can't change while running
+        let Some(path) = &self.keywords_path else {
+            return Ok(Vec::new());
+        };
+        let keywords = read_keywords(path)?;
+        let as_map = |keywords: &[String]| -> BTreeMap<String, ()> {
+            keywords.iter().map(|k| (k.clone(), ())).collect()
+        };
+        let mut current = self.keywords.write().unwrap();
+        let changed = changed_entries(&as_map(&current), &as_map(&keywords));
+        *current = keywords;
+        Ok(changed)
+    }
+}
+
+fn read_keywords(path: &Path) -> Result<Vec<String>, String> {
+    let keywords = fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Could not read SPAM_KEYWORDS_PATH {} ({})",
+            path.display(),
+            e
+        )
+    })?;
+    Ok(normalise_keywords(keywords.lines()))
+}
+
+fn normalise_keywords<'a>(keywords: impl Iterator<Item = &'a str>) -> Vec<String> {
+    keywords
+        .map(|keyword| keyword.trim().to_lowercase())
+        .filter(|keyword| !keyword.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> SpamFilter {
+        SpamFilter {
+            action: SpamAction::Flag,
+            max_links: 1,
+            keywords: RwLock::new(vec!["casino".to_string()]),
+            keywords_path: None,
+            domain_limit: 2,
+            domains: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn submission<'a>(email: &'a str, message: &'a str) -> Submission<'a> {
+        Submission {
+            email,
+            message,
+            honeypot: None,
+        }
+    }
+
+    #[test]
+    fn flags_honeypots_links_and_keywords() {
+        let filter = filter();
+        assert!(filter
+            .check(&submission("a@kblue.io", "Hi, see https://kblue.io"))
+            .is_empty());
+        let reasons = filter.check(&Submission {
+            honeypot: Some("http://spam.example"),
+            ..submission("b@example.com", "Best CASINO at http://a.b and www.c.d")
+        });
+        assert_eq!(reasons.len(), 3, "{:?}", reasons);
+    }
+
+    #[test]
+    fn reloads_keywords_from_the_file() {
+        let path = std::env::temp_dir().join(format!("spam_keywords_{}.txt", std::process::id()));
+        fs::write(&path, "Casino\nlottery\n\n").unwrap();
+        let filter = SpamFilter {
+            keywords_path: Some(path.clone()),
+            ..filter()
+        };
+        let changed = filter.reload_keywords();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(changed, Ok(vec!["lottery".to_string()]));
+        assert_eq!(
+            filter
+                .check(&submission("a@kblue.io", "Win the LOTTERY"))
+                .len(),
+            1
+        );
+        assert!(filter.reload_keywords().is_err());
+    }
+
+    #[test]
+    fn throttles_busy_domains() {
+        let filter = filter();
+        assert!(filter.check(&submission("a@spam.io", "Hi")).is_empty());
+        assert!(filter.check(&submission("b@SPAM.io", "Hi")).is_empty());
+        assert_eq!(filter.check(&submission("c@spam.io", "Hi")).len(), 1);
+        assert!(filter.check(&submission("a@kblue.io", "Hi")).is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use crate::auth::Jwt;
+use crate::captcha::Captcha;
+use crate::config::Config;
+use crate::db::{self, DbConfig, ProjectCache, Repository};
+use crate::email::{Deduplicator, EmailConfig, EmailQueue, QueueConfig};
+use crate::github::GithubClient;
+use crate::health::Readiness;
+use crate::notify::Notifier;
+use crate::spam::SpamFilter;
+
+// Shared with every handler through request.state::<AppState>()
+pub struct AppState {
+    pub email: EmailConfig,
+    pub email_queue: EmailQueue,
+    pub dedupe: Deduplicator,
+    pub captcha: Captcha,
+    pub spam: SpamFilter,
+    pub notifier: Notifier,
+    pub github: GithubClient,
+    // None unless DATABASE_URL is set
+    pub db: Option<Arc<dyn Repository>>,
+    pub projects: ProjectCache,
+    pub readiness: Readiness,
+    // None unless JWT_SECRET or JWT_PRIVATE_KEY_PATH is set, which disables login
+    pub jwt: Option<Arc<Jwt>>,
+}
+
+impl AppState {
+    // Everything but the email settings is still read straight from the environment
+    pub async fn new(config: &Config) -> Result<Self, String> {
+        let db = match DbConfig::from_env()? {
+            Some(db_config) => Some(db::connect(db_config).await?),
+            None => None,
+        };
+        let email = config.email.clone();
+        let provider = email.provider()?;
+        Ok(Self {
+            email_queue: EmailQueue::start(provider.clone(), QueueConfig::from_env()),
+            readiness: Readiness::from_env(provider, db.clone())?,
+            db,
+            projects: ProjectCache::default(),
+            email,
+            dedupe: Deduplicator::from_env()?,
+            captcha: Captcha::from_env()?,
+            spam: SpamFilter::from_env()?,
+            notifier: Notifier::from_env()?,
+            github: GithubClient::from_env()?,
+            jwt: Jwt::from_env()?.map(Arc::new),
+        })
+    }
+
+    // Reads the same settings as new without connecting to the database or starting the email
+    // queue, see cli::check
+    pub fn validate(config: &Config) -> Result<(), String> {
+        DbConfig::from_env()?;
+        Readiness::from_env(config.email.provider()?, None)?;
+        Deduplicator::from_env()?;
+        Captcha::from_env()?;
+        SpamFilter::from_env()?;
+        Notifier::from_env()?;
+        GithubClient::from_env()?;
+        Jwt::from_env()?;
+        Ok(())
+    }
+}
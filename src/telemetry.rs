@@ -0,0 +1,492 @@
+use std::env;
+use std::fmt::Write as _;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use kblue_http::{client, HttpMethod};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{warn, Dispatch, Event, Level, Span, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+// Exports spans to an OpenTelemetry collector (e.g. Grafana Tempo) as OTLP/HTTP JSON, configured
+// with the standard OTEL_* variables. Spans can set `otel.kind` ("server", "client", ...) and
+// `otel.status_code` ("error"), a span with an ERROR event is marked as failed too, and a
+// `traceparent` field (W3C Trace Context) continues a trace started elsewhere
+
+const BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+// Spans waiting to be exported. More than this and new ones are dropped
+const QUEUE_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtlpConfig {
+    // Full URL spans are POSTed to, e.g. http://tempo:4318/v1/traces
+    pub endpoint: String,
+    pub service_name: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl OtlpConfig {
+    // None (no export) unless OTEL_EXPORTER_OTLP_TRACES_ENDPOINT or OTEL_EXPORTER_OTLP_ENDPOINT is set
+    pub fn from_env() -> Option<Self> {
+        let endpoint = env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .ok()
+            .or_else(|| {
+                env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .ok()
+                    .map(|base| format!("{}/v1/traces", base.trim_end_matches('/')))
+            })
+            .filter(|endpoint| !endpoint.is_empty())?;
+        Some(Self {
+            endpoint,
+            service_name: env::var("OTEL_SERVICE_NAME")
+                .unwrap_or("portfolio-site-backend".to_string()),
+            // e.g. "Authorization=Basic abc,X-Scope-OrgID=kblue"
+            headers: env::var("OTEL_EXPORTER_OTLP_HEADERS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|header| header.split_once('='))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect(),
+        })
+    }
+}
+
+type TraceId = [u8; 16];
+type SpanId = [u8; 8];
+
+#[derive(Clone, Debug, PartialEq)]
+enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl AttributeValue {
+    fn to_json(&self) -> Value {
+        match self {
+            AttributeValue::String(value) => json!({ "stringValue": value }),
+            // 64 bit integers are strings in OTLP JSON
+            AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+            AttributeValue::Double(value) => json!({ "doubleValue": value }),
+            AttributeValue::Bool(value) => json!({ "boolValue": value }),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldValues(Vec<(String, AttributeValue)>);
+
+impl FieldValues {
+    fn set(&mut self, name: &str, value: AttributeValue) {
+        match self.0.iter_mut().find(|(existing, _)| existing == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((name.to_string(), value)),
+        }
+    }
+
+    fn take(&mut self, name: &str) -> Option<AttributeValue> {
+        let index = self.0.iter().position(|(existing, _)| existing == name)?;
+        Some(self.0.remove(index).1)
+    }
+
+    fn to_json(&self) -> Vec<Value> {
+        self.0
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value.to_json() }))
+            .collect()
+    }
+}
+
+impl Visit for FieldValues {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), AttributeValue::Int(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field.name(), AttributeValue::Int(value as i64));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field.name(), AttributeValue::Double(value));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), AttributeValue::Bool(value));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), AttributeValue::String(value.to_string()));
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field.name(), AttributeValue::String(format!("{:?}", value)));
+    }
+}
+
+struct SpanEvent {
+    time: SystemTime,
+    name: String,
+    attributes: FieldValues,
+}
+
+// Kept in the span's extensions while it's open
+struct SpanState {
+    // Only used by root spans. Children take their root's at export, since the root may only
+    // learn its traceparent after they started
+    trace_id: TraceId,
+    span_id: SpanId,
+    // Set for root spans continuing a remote trace
+    remote_parent_id: Option<SpanId>,
+    start: SystemTime,
+    attributes: FieldValues,
+    events: Vec<SpanEvent>,
+    failed: bool,
+}
+
+impl SpanState {
+    fn new(attributes: FieldValues) -> Self {
+        let mut state = Self {
+            trace_id: random_id(),
+            span_id: random_id(),
+            remote_parent_id: None,
+            start: SystemTime::now(),
+            attributes,
+            events: Vec::new(),
+            failed: false,
+        };
+        state.adopt_traceparent();
+        state
+    }
+
+    fn adopt_traceparent(&mut self) {
+        if let Some(AttributeValue::String(traceparent)) = self.attributes.take("traceparent") {
+            if let Some((trace_id, parent_id)) = parse_traceparent(&traceparent) {
+                self.trace_id = trace_id;
+                self.remote_parent_id = Some(parent_id);
+            }
+        }
+    }
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0; N];
+    SystemRandom::new()
+        .fill(&mut id)
+        .expect("system randomness is available");
+    id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    // All zero ids are invalid
+    bytes.iter().any(|byte| *byte != 0).then_some(bytes)
+}
+
+// `00-<trace id>-<parent span id>-<flags>`
+fn parse_traceparent(traceparent: &str) -> Option<(TraceId, SpanId)> {
+    let mut parts = traceparent.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = from_hex(parts.next()?)?;
+    let parent_id = from_hex(parts.next()?)?;
+    parts.next()?;
+    (version.len() == 2 && version != "ff").then_some((trace_id, parent_id))
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn span_kind(kind: Option<AttributeValue>) -> u8 {
+    match kind {
+        Some(AttributeValue::String(kind)) => match kind.to_lowercase().as_str() {
+            "server" => 2,
+            "client" => 3,
+            "producer" => 4,
+            "consumer" => 5,
+            _ => 1,
+        },
+        _ => 1,
+    }
+}
+
+pub struct OtlpLayer {
+    sender: mpsc::Sender<Value>,
+}
+
+impl OtlpLayer {
+    // Must be called inside the tokio runtime, which runs the exporter
+    pub fn new(config: OtlpConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(export(config, receiver));
+        Self { sender }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for OtlpLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut attributes = FieldValues::default();
+        attrs.record(&mut attributes);
+        span.extensions_mut().insert(SpanState::new(attributes));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let is_root = span.parent().is_none();
+        let mut extensions = span.extensions_mut();
+        if let Some(state) = extensions.get_mut::<SpanState>() {
+            values.record(&mut state.attributes);
+            if is_root {
+                state.adopt_traceparent();
+            }
+        }
+    }
+
+    // Warnings and errors become span events, so they show up in the trace
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(state) = extensions.get_mut::<SpanState>() {
+            let mut attributes = FieldValues::default();
+            event.record(&mut attributes);
+            let name = match attributes.take("message") {
+                Some(AttributeValue::String(message)) => message,
+                _ => event.metadata().name().to_string(),
+            };
+            attributes.set("level", AttributeValue::String(level.to_string()));
+            state.failed |= level == Level::ERROR;
+            state.events.push(SpanEvent {
+                time: SystemTime::now(),
+                name,
+                attributes,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(mut state) = span.extensions_mut().remove::<SpanState>() else {
+            return;
+        };
+        let parent = span.parent();
+        let parent_id = match &parent {
+            Some(parent) => parent.extensions().get::<SpanState>().map(|p| p.span_id),
+            None => state.remote_parent_id,
+        };
+        let trace_id = span
+            .scope()
+            .from_root()
+            .next()
+            .filter(|root| root.id() != id)
+            .and_then(|root| root.extensions().get::<SpanState>().map(|r| r.trace_id))
+            .unwrap_or(state.trace_id);
+
+        let kind = span_kind(state.attributes.take("otel.kind"));
+        if let Some(AttributeValue::String(status)) = state.attributes.take("otel.status_code") {
+            state.failed |= status.eq_ignore_ascii_case("error");
+        }
+        let mut exported = json!({
+            "traceId": hex(&trace_id),
+            "spanId": hex(&state.span_id),
+            "name": span.name(),
+            "kind": kind,
+            "startTimeUnixNano": unix_nanos(state.start),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": state.attributes.to_json(),
+            "events": state.events.iter().map(|event| json!({
+                "timeUnixNano": unix_nanos(event.time),
+                "name": event.name,
+                "attributes": event.attributes.to_json(),
+            })).collect::<Vec<_>>(),
+            // 1 is OK, 2 is ERROR
+            "status": { "code": if state.failed { 2 } else { 1 } },
+        });
+        if let Some(parent_id) = parent_id {
+            exported["parentSpanId"] = json!(hex(&parent_id));
+        }
+        // The queue is full when the collector can't keep up, better to lose spans than block requests
+        let _ = self.sender.try_send(exported);
+    }
+}
+
+// Sends batches of spans every few seconds, or as soon as a batch fills up
+async fn export(config: OtlpConfig, mut receiver: mpsc::Receiver<Value>) {
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            span = receiver.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick() => {}
+        }
+        if !batch.is_empty() {
+            send_batch(&config, std::mem::take(&mut batch)).await;
+        }
+    }
+}
+
+async fn send_batch(config: &OtlpConfig, spans: Vec<Value>) {
+    let count = spans.len();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": config.service_name } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+    let mut headers = vec![("Content-Type", "application/json")];
+    headers.extend(
+        config
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    let body = body.to_string();
+    match client::request(
+        HttpMethod::POST,
+        &config.endpoint,
+        &headers,
+        Some(body.as_bytes()),
+    )
+    .await
+    {
+        Ok(response) if response.is_success() => {}
+        Ok(response) => warn!(
+            "Could not export {} span(s), the collector answered {}: {}",
+            count,
+            response.status_code,
+            response.get_body_as_string()
+        ),
+        Err(e) => warn!("Could not export {} span(s): {}", count, e),
+    }
+}
+
+// W3C traceparent of the current span, to carry the trace over to work done elsewhere,
+// e.g. a queued email. None if spans aren't being exported
+pub fn current_traceparent() -> Option<String> {
+    Span::current()
+        .with_subscriber(|(id, dispatch): (&Id, &Dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let span_id = span.extensions().get::<SpanState>()?.span_id;
+            let root = span.scope().from_root().next()?;
+            let trace_id = root.extensions().get::<SpanState>()?.trace_id;
+            Some(format!("00-{}-{}-01", hex(&trace_id), hex(&span_id)))
+        })
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_traceparents() {
+        let (trace_id, parent_id) =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(hex(&trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&parent_id), "00f067aa0ba902b7");
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "00-xyz92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert!(parse_traceparent(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn exports_nested_spans_in_one_trace() {
+        let (sender, mut receiver) = mpsc::channel(16);
+        let subscriber = tracing_subscriber::layer::SubscriberExt::with(
+            Registry::default(),
+            OtlpLayer { sender },
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!(
+                "request",
+                otel.kind = "server",
+                path = "/api/v1/send_email",
+                status = tracing::field::Empty,
+                traceparent = tracing::field::Empty,
+            );
+            {
+                let _entered = request.enter();
+                // Started before the root learns which trace it belongs to
+                let handler = tracing::debug_span!("handler");
+                let _entered = handler.enter();
+                request.record(
+                    "traceparent",
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                );
+                assert_eq!(
+                    current_traceparent().unwrap()[..35],
+                    *"00-4bf92f3577b34da6a3ce929d0e0e4736"
+                );
+                tracing::error!("Route handler failed");
+            }
+            request.record("status", 500);
+        });
+
+        let handler = receiver.try_recv().unwrap();
+        let request = receiver.try_recv().unwrap();
+        assert_eq!(handler["name"], "handler");
+        assert_eq!(handler["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(handler["parentSpanId"], request["spanId"]);
+        assert_eq!(handler["status"]["code"], 2);
+        assert_eq!(handler["events"][0]["name"], "Route handler failed");
+
+        assert_eq!(request["kind"], 2);
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(request["status"]["code"], 1);
+        let attributes = request["attributes"].as_array().unwrap();
+        assert!(attributes.contains(&json!({ "key": "status", "value": { "intValue": "500" } })));
+        assert!(!attributes.iter().any(|a| a["key"] == "otel.kind"));
+    }
+}